@@ -0,0 +1,145 @@
+//! Counts the allocations made putting events out, with
+//! `cargo test --release alloc_bench -- --ignored --nocapture` (or `just bench`).
+//!
+//! For 10k events, before source names were shared and outputs kept their buffers:
+//!
+//! ```text
+//!     events:  10002    plain:      0    json:      0    waybar:  20000
+//!        csv:  49994  msgpack:  70000     log:  90000
+//! ```
+//!
+//! and after:
+//!
+//! ```text
+//!     events:      2    plain:      0    json:      0    waybar:      2
+//!        csv:  19994  msgpack:  50002     log:  50001
+//! ```
+//!
+//! What's left is chrono formatting timestamps, the line each log record is, and MessagePack
+//! buffering the flattened event, as it can't know the length of the map up front.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+
+use log::{Level, Record};
+
+use crate::logging::LogFormat;
+use crate::output::{
+    CsvOutput, DeviceState, Event, EventKind, JsonOutput, MsgpackOutput, Output, PlainOutput,
+    StateTexts, WaybarOutput,
+};
+use crate::SourceDatum;
+
+const EVENTS: u64 = 10_000;
+
+thread_local! {
+    /// Only this thread's, as other tests run alongside
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+/// Allocations `run` makes on this thread
+fn allocations(run: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.with(Cell::get);
+    run();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+fn texts() -> StateTexts {
+    StateTexts {
+        mute: "MUTED".into(),
+        unmute: "UNMUTED".into(),
+        nosource: "NO_SOURCE".into(),
+        sink_mute: "SINK_MUTED".into(),
+        sink_unmute: "SINK_UNMUTED".into(),
+        nosink: "NO_SINK".into(),
+    }
+}
+
+fn source() -> SourceDatum {
+    SourceDatum::named("alsa_input.usb-Focusrite_Scarlett_Solo_USB-00.analog-stereo")
+}
+
+/// Mute events for the same source, as flipping it back and forth reports them
+fn mute_events(src: &SourceDatum, mut emit: impl FnMut(&Event)) {
+    for n in 0..EVENTS {
+        emit(&Event::new(EventKind::Mute {
+            source: src.display_name().clone(),
+            device: DeviceState {
+                index: 1,
+                muted: n % 2 == 0,
+                volume: src.volume_percent(),
+            },
+            default: true,
+        }));
+    }
+}
+
+fn through(name: &str, mut output: impl Output) {
+    let mut events = vec![];
+    mute_events(&source(), |event| events.push(event.clone()));
+    let count = allocations(|| {
+        for event in &events {
+            output.emit(event).unwrap();
+        }
+    });
+    println!(
+        "{:>10}: {:>6} allocations for {} events",
+        name, count, EVENTS
+    );
+}
+
+#[test]
+#[ignore]
+fn alloc_bench() {
+    let src = source();
+    let count = allocations(|| mute_events(&src, |_| {}));
+    println!(
+        "{:>10}: {:>6} allocations for {} events",
+        "events", count, EVENTS
+    );
+
+    through("plain", PlainOutput::new(io::sink(), texts()));
+    through("json", JsonOutput::new(io::sink()));
+    through("waybar", WaybarOutput::new(io::sink(), texts()));
+    through("csv", CsvOutput::new(io::sink()));
+    through("msgpack", MsgpackOutput::new(io::sink()));
+
+    let count = allocations(|| {
+        for _ in 0..EVENTS {
+            LogFormat::Text.line(
+                &Record::builder()
+                    .args(format_args!("Source {} muted", 1))
+                    .level(Level::Info)
+                    .file(Some("src/main.rs"))
+                    .line(Some(1))
+                    .build(),
+            );
+        }
+    });
+    println!(
+        "{:>10}: {:>6} allocations for {} records",
+        "log", count, EVENTS
+    );
+}
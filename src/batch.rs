@@ -0,0 +1,373 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crossbeam_channel::{self as channel, Receiver, RecvTimeoutError, Sender};
+use log::trace;
+use pulse::{
+    callbacks::ListResult,
+    context::{Context, State},
+    volume::ChannelVolumes,
+};
+
+use crate::card::{CardDatum, Cards};
+use crate::client::{ClientDatum, Clients};
+use crate::lock::MainloopGuard;
+use crate::mainloop::Mainloop;
+use crate::module::{ModuleDatum, Modules};
+use crate::sink::{SinkDatum, Sinks};
+use crate::source_output::{SourceOutputDatum, SourceOutputs};
+use crate::{callback, Errors, ServerDefaults, SourceDatum};
+
+/// How often waiting on answers checks the connection is still there to give them. Callbacks
+/// lost with the connection are never called, and the batch's own end of the channel keeps it
+/// from telling.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something to ask the server, or to have it do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Defaults,
+    Sources,
+    Sinks,
+    SourceOutputs,
+    Cards,
+    Clients,
+    /// Every module, not only those affecting capture
+    Modules,
+    SourceByIndex(u32),
+    /// The server answers a name it doesn't know with an error, rather than nothing
+    SourceByName(String),
+    SinkByIndex(u32),
+    SourceOutputByIndex(u32),
+    CardByIndex(u32),
+    ClientByIndex(u32),
+    ModuleByIndex(u32),
+    SetSourceMute(u32, bool),
+    SetSourceVolume(u32, ChannelVolumes),
+    SetSinkMute(u32, bool),
+    SetDefaultSource(String),
+}
+
+impl Query {
+    /// What the server failing to answer means
+    fn failed(&self) -> Errors {
+        match self {
+            Query::Defaults | Query::Sources | Query::SourceByIndex(_) | Query::SourceByName(_) => {
+                Errors::SrcListError
+            }
+            Query::Sinks | Query::SinkByIndex(_) => Errors::SinkListError,
+            Query::SourceOutputs | Query::SourceOutputByIndex(_) => Errors::SourceOutputListError,
+            Query::Cards | Query::CardByIndex(_) => Errors::CardListError,
+            Query::Clients | Query::ClientByIndex(_) => Errors::ClientListError,
+            Query::Modules | Query::ModuleByIndex(_) => Errors::ModuleListError,
+            Query::SetSourceMute(idx, _) => {
+                Errors::ContextError(format!("failed to set mute for source {}", idx))
+            }
+            Query::SetSourceVolume(idx, _) => {
+                Errors::ContextError(format!("failed to set volume for source {}", idx))
+            }
+            Query::SetSinkMute(idx, _) => {
+                Errors::ContextError(format!("failed to set mute for sink {}", idx))
+            }
+            Query::SetDefaultSource(name) => {
+                Errors::ContextError(format!("failed to set default source to {}", name))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Defaults(ServerDefaults),
+    Source(u32, Box<SourceDatum>),
+    Sink(u32, SinkDatum),
+    SourceOutput(u32, SourceOutputDatum),
+    Card(u32, CardDatum),
+    Client(u32, ClientDatum),
+    Module(u32, ModuleDatum),
+    Done,
+    Failed,
+}
+
+/// A reply, with the batch and the query in it that it's for
+type Tagged = (u64, usize, Reply);
+
+/// What the server answered. Things looked up by index land alongside those listed, so a map
+/// without the index asked for means the server doesn't have it.
+#[derive(Debug, Default)]
+pub struct Answers {
+    pub defaults: ServerDefaults,
+    /// Every source the server listed, before any are filtered out
+    pub sources: HashMap<u32, SourceDatum>,
+    pub sinks: Sinks,
+    pub source_outputs: SourceOutputs,
+    pub cards: Cards,
+    pub clients: Clients,
+    pub modules: Modules,
+    /// Sources looked up one at a time, in the order they were asked for, with `None` for those
+    /// that aren't there
+    pub lookups: Vec<Option<(u32, SourceDatum)>>,
+}
+
+/// Asks the server several things at once: all under a single lock of the mainloop, and all
+/// answered over one channel that's kept for as long as the connection. Reconnecting asks for a
+/// lot at once, which locking and unlocking around every question held up, and every question
+/// used to bring its own channel along.
+#[derive(Debug)]
+pub struct Batch {
+    tx: Sender<Tagged>,
+    rx: Receiver<Tagged>,
+    /// Tells answers apart from those still coming in for an earlier batch that gave up
+    generation: Cell<u64>,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        let (tx, rx) = channel::unbounded();
+        Batch {
+            tx,
+            rx,
+            generation: Cell::new(0),
+        }
+    }
+}
+
+/// The reply to one item of a list, or to the list having ended
+fn listed<T: ?Sized>(result: ListResult<&T>, item: impl FnOnce(&T) -> Reply) -> Reply {
+    match result {
+        ListResult::Item(info) => item(info),
+        ListResult::End => Reply::Done,
+        ListResult::Error => Reply::Failed,
+    }
+}
+
+fn done(success: bool) -> Reply {
+    match success {
+        true => Reply::Done,
+        false => Reply::Failed,
+    }
+}
+
+impl Batch {
+    /// Ask everything in `queries`, then wait on every answer
+    pub fn ask(
+        &self,
+        queries: &[Query],
+        context: &mut Context,
+        mainloop: &mut Mainloop,
+    ) -> Result<Answers, Errors> {
+        let generation = self.generation.get() + 1;
+        self.generation.set(generation);
+
+        // Which of the answers' lookups each query fills in, if it's a lookup
+        let mut lookups = 0;
+        let slots: Vec<Option<usize>> = queries
+            .iter()
+            .map(|query| match query {
+                Query::SourceByIndex(_) | Query::SourceByName(_) => {
+                    lookups += 1;
+                    Some(lookups - 1)
+                }
+                _ => None,
+            })
+            .collect();
+
+        let guard = MainloopGuard::lock(mainloop);
+        let mut introspector = context.introspect();
+        for (position, query) in queries.iter().enumerate() {
+            let answer = {
+                let tx = self.tx.clone();
+                move |reply| callback::reply(&tx, (generation, position, reply))
+            };
+            match query {
+                Query::Defaults => {
+                    introspector.get_server_info(move |info| {
+                        answer(Reply::Defaults(ServerDefaults {
+                            source: info
+                                .default_source_name
+                                .as_ref()
+                                .map(|name| name.to_string()),
+                            sink: info.default_sink_name.as_ref().map(|name| name.to_string()),
+                        }));
+                    });
+                }
+                Query::Sources => {
+                    introspector.get_source_info_list(move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Source(info.index, Box::new(SourceDatum::from_info(info)))
+                        }))
+                    });
+                }
+                Query::Sinks => {
+                    introspector.get_sink_info_list(move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Sink(info.index, SinkDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::SourceOutputs => {
+                    introspector.get_source_output_info_list(move |result| {
+                        answer(listed(result, |info| {
+                            Reply::SourceOutput(info.index, SourceOutputDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::Cards => {
+                    introspector.get_card_info_list(move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Card(info.index, CardDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::Clients => {
+                    introspector.get_client_info_list(move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Client(info.index, ClientDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::Modules => {
+                    introspector.get_module_info_list(move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Module(info.index, ModuleDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::SourceByIndex(idx) => {
+                    introspector.get_source_info_by_index(*idx, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Source(info.index, Box::new(SourceDatum::from_info(info)))
+                        }))
+                    });
+                }
+                Query::SourceByName(name) => {
+                    introspector.get_source_info_by_name(name, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Source(info.index, Box::new(SourceDatum::from_info(info)))
+                        }))
+                    });
+                }
+                Query::SinkByIndex(idx) => {
+                    introspector.get_sink_info_by_index(*idx, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Sink(info.index, SinkDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::SourceOutputByIndex(idx) => {
+                    introspector.get_source_output_info(*idx, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::SourceOutput(info.index, SourceOutputDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::CardByIndex(idx) => {
+                    introspector.get_card_info_by_index(*idx, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Card(info.index, CardDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::ClientByIndex(idx) => {
+                    introspector.get_client_info(*idx, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Client(info.index, ClientDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::ModuleByIndex(idx) => {
+                    introspector.get_module_info(*idx, move |result| {
+                        answer(listed(result, |info| {
+                            Reply::Module(info.index, ModuleDatum::from_info(info))
+                        }))
+                    });
+                }
+                Query::SetSourceMute(idx, mute) => {
+                    introspector.set_source_mute_by_index(
+                        *idx,
+                        *mute,
+                        Some(Box::new(move |success| answer(done(success)))),
+                    );
+                }
+                Query::SetSourceVolume(idx, volume) => {
+                    introspector.set_source_volume_by_index(
+                        *idx,
+                        volume,
+                        Some(Box::new(move |success| answer(done(success)))),
+                    );
+                }
+                Query::SetSinkMute(idx, mute) => {
+                    introspector.set_sink_mute_by_index(
+                        *idx,
+                        *mute,
+                        Some(Box::new(move |success| answer(done(success)))),
+                    );
+                }
+                Query::SetDefaultSource(name) => {
+                    context.set_default_source(name, move |success| answer(done(success)));
+                }
+            }
+        }
+        // Let pulseaudio call all of the above back
+        drop(guard);
+
+        let mut answers = Answers {
+            lookups: (0..lookups).map(|_| None).collect(),
+            ..Answers::default()
+        };
+        let mut remaining = queries.len();
+        while remaining > 0 {
+            let (answered, position, reply) = match mainloop.recv_timeout(&self.rx, CHECK_INTERVAL)
+            {
+                Ok(tagged) => tagged,
+                Err(RecvTimeoutError::Timeout) => match context.get_state() {
+                    State::Failed | State::Terminated => return Err(Errors::Disconnected),
+                    _ => continue,
+                },
+                // Can't happen while the batch holds a sender itself
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Errors::RecvError(channel::RecvError))
+                }
+            };
+            if answered != generation {
+                trace!("Dropping a late answer for an earlier batch");
+                continue;
+            }
+            match reply {
+                Reply::Defaults(defaults) => {
+                    answers.defaults = defaults;
+                    remaining -= 1;
+                }
+                Reply::Source(idx, src) => match slots[position] {
+                    Some(slot) => answers.lookups[slot] = Some((idx, *src)),
+                    None => {
+                        answers.sources.insert(idx, *src);
+                    }
+                },
+                Reply::Sink(idx, sink) => {
+                    answers.sinks.insert(idx, sink);
+                }
+                Reply::SourceOutput(idx, output) => {
+                    answers.source_outputs.insert(idx, output);
+                }
+                Reply::Card(idx, card) => {
+                    answers.cards.insert(idx, card);
+                }
+                Reply::Client(idx, client) => {
+                    answers.clients.insert(idx, client);
+                }
+                Reply::Module(idx, module) => {
+                    answers.modules.insert(idx, module);
+                }
+                Reply::Done => remaining -= 1,
+                Reply::Failed => {
+                    trace!("Failed to answer {:?}", queries[position]);
+                    return Err(queries[position].failed());
+                }
+            }
+        }
+        trace!("Answered {:?}", queries);
+        Ok(answers)
+    }
+}
@@ -0,0 +1,699 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use glob::Pattern;
+use log::info;
+use regex::Regex;
+
+use crate::changes::{self, Backpressure, QueuePolicy};
+use crate::consumer::{ConsumerPolicy, DropPolicy};
+use crate::filter::SourceFilter;
+use crate::health::Health;
+use crate::latency::Latency;
+use crate::mainloop::MainloopKind;
+use crate::output::{Output, Sequence};
+#[cfg(feature = "webhook")]
+use crate::webhook::{self, WebhookPolicy};
+#[cfg(feature = "websocket")]
+use crate::ws;
+use crate::{
+    http, metrics, socket, Aggregate, ConnectOptions, Errors, ListenerConfig, Report, RequestTx,
+    SourceListener, Watch,
+};
+
+fn parse_regexes(patterns: &[String], flag: &str) -> Result<Vec<Regex>, Errors> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .map_err(|err| Errors::ConfigError(format!("invalid {} regex: {}", flag, err)))
+        })
+        .collect()
+}
+
+/// How to reach the server and what to follow on it, for a [`SourceListener`]. The command
+/// line tool's options are translated into one of these too, so both are checked alike once
+/// connecting.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use pulseaudio_sink_listener::{Report, SourceListener, Watch};
+///
+/// let listener = SourceListener::builder()
+///     .server("tcp:studio.local:4713")
+///     .watch(Watch::Both)
+///     .reports([Report::Mute, Report::Volume])
+///     .exclude("(?i)webcam")
+///     .debounce(Duration::from_millis(100))
+///     .connect()?;
+/// # Ok::<(), pulseaudio_sink_listener::Errors>(())
+/// ```
+///
+/// Events can also be served to other programs, as the tool serves them:
+///
+/// ```no_run
+/// use pulseaudio_sink_listener::SourceListener;
+///
+/// let listener = SourceListener::builder()
+///     .http_listen("127.0.0.1:8080".parse().unwrap())
+///     .metrics_listen("127.0.0.1:9100".parse().unwrap())
+///     .connect()?;
+/// # Ok::<(), pulseaudio_sink_listener::Errors>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct SourceListenerBuilder {
+    servers: Vec<String>,
+    autospawn: bool,
+    nofail: bool,
+    connect_timeout: Option<Duration>,
+    /// `None` keeps trying until the server appears
+    connect_retries: Option<u32>,
+    mainloop: MainloopKind,
+    reconnect: bool,
+    watch: Watch,
+    reports: Vec<Report>,
+    watch_clients: bool,
+    aggregate: Option<Aggregate>,
+    sources: Vec<String>,
+    index: Option<u32>,
+    prefer: Vec<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    include_monitors: bool,
+    only_hardware: bool,
+    monitor_sink_names: bool,
+    stall_timeout: Option<Duration>,
+    on_stall: Option<fn(Duration)>,
+    heartbeat: Option<Duration>,
+    latency: Option<Latency>,
+    notify_systemd: bool,
+    backpressure: Backpressure,
+    change_queue_size: usize,
+    debounce: Option<Duration>,
+    backends: Backends,
+}
+
+impl Default for SourceListenerBuilder {
+    fn default() -> Self {
+        SourceListenerBuilder {
+            servers: vec![],
+            autospawn: false,
+            nofail: false,
+            connect_timeout: None,
+            connect_retries: Some(0),
+            mainloop: MainloopKind::default(),
+            reconnect: true,
+            watch: Watch::default(),
+            reports: Report::DEFAULTS.to_vec(),
+            watch_clients: false,
+            aggregate: None,
+            sources: vec![],
+            index: None,
+            prefer: vec![],
+            include: vec![],
+            exclude: vec![],
+            include_monitors: false,
+            only_hardware: false,
+            monitor_sink_names: false,
+            stall_timeout: None,
+            on_stall: None,
+            heartbeat: None,
+            latency: None,
+            notify_systemd: false,
+            backpressure: Backpressure::default(),
+            change_queue_size: changes::DEFAULT_CAPACITY,
+            debounce: Some(Duration::from_millis(changes::DEFAULT_WINDOW_MS)),
+            backends: Backends::default(),
+        }
+    }
+}
+
+impl SourceListenerBuilder {
+    /// Connect to this server, e.g. `tcp:studio.local:4713`, rather than the default one. The
+    /// command line tool can be given several, a listener only follows one.
+    pub fn server(mut self, server: impl Into<String>) -> Self {
+        self.servers.push(server.into());
+        self
+    }
+
+    /// Start PulseAudio if it isn't running yet, rather than failing
+    pub fn autospawn(mut self, autospawn: bool) -> Self {
+        self.autospawn = autospawn;
+        self
+    }
+
+    /// Wait for the server to appear if it isn't there yet, rather than failing
+    pub fn nofail(mut self, nofail: bool) -> Self {
+        self.nofail = nofail;
+        self
+    }
+
+    /// Give up on a connection attempt the server hasn't answered in this long
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Try connecting this many more times before giving up, waiting longer between each
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.connect_retries = Some(retries);
+        self
+    }
+
+    /// Keep trying to connect until the server appears
+    pub fn wait_for_server(mut self) -> Self {
+        self.connect_retries = None;
+        self
+    }
+
+    /// Which of libpulse's mainloops runs the server's callbacks
+    pub fn mainloop(mut self, mainloop: MainloopKind) -> Self {
+        self.mainloop = mainloop;
+        self
+    }
+
+    /// Reconnect once the server's back after losing it (the default), or stop
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Which default device(s) to watch
+    pub fn watch(mut self, watch: Watch) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// Which changes of the watched device(s) to report, rather than [`Report::DEFAULTS`]
+    pub fn reports(mut self, reports: impl IntoIterator<Item = Report>) -> Self {
+        self.reports = reports.into_iter().collect();
+        self
+    }
+
+    /// Also report clients connecting and disconnecting
+    pub fn watch_clients(mut self, watch_clients: bool) -> Self {
+        self.watch_clients = watch_clients;
+        self
+    }
+
+    /// Report one mute state combined across all sources, rather than the default source's
+    pub fn aggregate(mut self, aggregate: Aggregate) -> Self {
+        self.aggregate = Some(aggregate);
+        self
+    }
+
+    /// Watch this source, by name or glob pattern. The first replaces the server's default
+    /// source, the rest are watched alongside it.
+    pub fn source(mut self, pattern: impl Into<String>) -> Self {
+        self.sources.push(pattern.into());
+        self
+    }
+
+    /// Follow the source with this index, rather than any by name
+    pub fn index(mut self, index: u32) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    /// Fall back on a source matching this glob pattern when the server has no default source,
+    /// after those preferred before it
+    pub fn prefer(mut self, pattern: impl Into<String>) -> Self {
+        self.prefer.push(pattern.into());
+        self
+    }
+
+    /// Only consider sources whose name or description matches this regex, or any other given
+    pub fn include(mut self, regex: impl Into<String>) -> Self {
+        self.include.push(regex.into());
+        self
+    }
+
+    /// Ignore sources whose name or description matches this regex
+    pub fn exclude(mut self, regex: impl Into<String>) -> Self {
+        self.exclude.push(regex.into());
+        self
+    }
+
+    /// Consider monitor sources of sinks like any other source
+    pub fn include_monitors(mut self, include_monitors: bool) -> Self {
+        self.include_monitors = include_monitors;
+        self
+    }
+
+    /// Ignore virtual and network sources
+    pub fn only_hardware(mut self, only_hardware: bool) -> Self {
+        self.only_hardware = only_hardware;
+        self
+    }
+
+    /// Name monitor sources after their sink in events
+    pub fn monitor_sink_names(mut self, monitor_sink_names: bool) -> Self {
+        self.monitor_sink_names = monitor_sink_names;
+        self
+    }
+
+    /// Reconnect if the server hasn't answered a check in this long, or once handling one of
+    /// its events has taken that long
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Run `on_stall` from another thread as soon as handling one event has taken longer than
+    /// the stall timeout, rather than waiting for it to finish
+    pub(crate) fn on_stall(mut self, on_stall: fn(Duration)) -> Self {
+        self.on_stall = Some(on_stall);
+        self
+    }
+
+    /// Report the current state this often, even when nothing changed, as snapshot events
+    pub fn heartbeat(mut self, every: Duration) -> Self {
+        self.heartbeat = Some(every);
+        self
+    }
+
+    /// Record how long the listener takes to catch up with the server and write out events
+    pub(crate) fn measure_latency(mut self, latency: Latency) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Tell systemd when the listener is ready, what it's watching, and feed its watchdog. Only
+    /// for the program itself, as it speaks for the whole process.
+    pub(crate) fn notify_systemd(mut self) -> Self {
+        self.notify_systemd = true;
+        self
+    }
+
+    /// What to do when server changes come in faster than they can be handled
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// How many server changes can wait to be handled before backpressure applies
+    pub fn change_queue_size(mut self, size: usize) -> Self {
+        self.change_queue_size = size;
+        self
+    }
+
+    /// Gather server changes for this long after the first of a burst, merging those for the
+    /// same device. Zero handles each straight away.
+    pub fn debounce(mut self, window: Duration) -> Self {
+        self.debounce = (!window.is_zero()).then_some(window);
+        self
+    }
+
+    /// Serve events, and take requests, on a unix socket at `path`
+    pub fn socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.backends.socket = Some(path.into());
+        self
+    }
+
+    /// Serve events, and answer queries, over TCP on `addr`
+    pub fn tcp_listen(mut self, addr: SocketAddr) -> Self {
+        self.backends.tcp = Some(addr);
+        self
+    }
+
+    /// Also take commands over TCP, from anyone who can reach its address
+    pub fn tcp_control(mut self, control: bool) -> Self {
+        self.backends.tcp_control = control;
+        self
+    }
+
+    /// Serve the state, and take requests, over HTTP on `addr`
+    pub fn http_listen(mut self, addr: SocketAddr) -> Self {
+        self.backends.http = Some(addr);
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP on `addr`
+    pub fn metrics_listen(mut self, addr: SocketAddr) -> Self {
+        self.backends.metrics = Some(addr);
+        self
+    }
+
+    /// Serve events, and take requests, over websockets on `addr`
+    #[cfg(feature = "websocket")]
+    pub fn ws_listen(mut self, addr: SocketAddr) -> Self {
+        self.backends.websocket = Some(addr);
+        self
+    }
+
+    /// Also take commands over websockets, from anyone who can reach its address
+    #[cfg(feature = "websocket")]
+    pub fn ws_control(mut self, control: bool) -> Self {
+        self.backends.ws_control = control;
+        self
+    }
+
+    /// Let browsers connect over websockets from pages at `origin`, e.g.
+    /// `http://localhost:8000`, along with any other given. Programs that aren't browsers don't
+    /// say where they're from, and are let connect anyway.
+    #[cfg(feature = "websocket")]
+    pub fn ws_origin(mut self, origin: impl Into<String>) -> Self {
+        self.backends.ws_origins.push(origin.into());
+        self
+    }
+
+    /// POST each event to `url`, along with any other given
+    #[cfg(feature = "webhook")]
+    pub fn webhook(mut self, url: impl Into<String>) -> Self {
+        self.backends.webhooks.push(url.into());
+        self
+    }
+
+    /// How webhooks are sent
+    #[cfg(feature = "webhook")]
+    pub fn webhook_policy(mut self, policy: WebhookPolicy) -> Self {
+        self.backends.webhook_policy = policy;
+        self
+    }
+
+    /// What to do for a network client that falls behind, or a program that isn't taking a
+    /// listener's events as fast as they come
+    pub fn consumer_drop_policy(mut self, drop: DropPolicy) -> Self {
+        self.backends.consumers.drop = drop;
+        self
+    }
+
+    /// How many events can wait on each network client, or to be taken from a listener, before
+    /// the drop policy applies
+    pub fn consumer_queue_size(mut self, size: usize) -> Self {
+        self.backends.consumers.capacity = size;
+        self
+    }
+
+    /// Connect, and keep following the server on a thread of the listener's own
+    pub fn connect(self) -> Result<SourceListener, Errors> {
+        SourceListener::start(self)
+    }
+
+    /// One per server to connect to, which is just the default one unless any were given
+    pub(crate) fn connect_options(&self) -> Result<Vec<ConnectOptions>, Errors> {
+        let options = |server: Option<&String>| ConnectOptions {
+            server: server.cloned(),
+            autospawn: self.autospawn,
+            nofail: self.nofail,
+            timeout: self.connect_timeout,
+            retries: self.connect_retries,
+            mainloop: self.mainloop,
+        };
+        if self.servers.is_empty() {
+            return Ok(vec![options(None)]);
+        }
+        let mut servers: Vec<ConnectOptions> = vec![];
+        for server in &self.servers {
+            if servers
+                .iter()
+                .any(|known| known.server.as_ref() == Some(server))
+            {
+                return Err(Errors::ConfigError(format!(
+                    "--server {} is given more than once",
+                    server
+                )));
+            }
+            servers.push(options(Some(server)));
+        }
+        Ok(servers)
+    }
+
+    pub(crate) fn listener_config(&self) -> Result<ListenerConfig, Errors> {
+        let zero = [
+            (
+                "--stall-timeout",
+                self.stall_timeout == Some(Duration::ZERO),
+            ),
+            ("--heartbeat", self.heartbeat == Some(Duration::ZERO)),
+            ("--change-queue-size", self.change_queue_size == 0),
+            (
+                "--consumer-queue-size",
+                self.backends.consumers.capacity == 0,
+            ),
+        ];
+        if let Some((flag, _)) = zero.iter().find(|(_, zero)| *zero) {
+            return Err(Errors::ConfigError(format!("{} must be more than 0", flag)));
+        }
+        if self.index.is_some() && !self.sources.is_empty() {
+            return Err(Errors::ConfigError(
+                "a source is followed either by index or by name, not both".to_string(),
+            ));
+        }
+        let source_patterns = self
+            .sources
+            .iter()
+            .map(|name| Pattern::new(name))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| Errors::ConfigError(format!("invalid source name pattern: {}", err)))?;
+        let preferred = self
+            .prefer
+            .iter()
+            .map(|pattern| Pattern::new(pattern.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| Errors::ConfigError(format!("invalid --prefer pattern: {}", err)))?;
+
+        // Callbacks waiting for room would hold up the very loop that makes it
+        if self.backpressure == Backpressure::Block && self.mainloop == MainloopKind::Standard {
+            return Err(Errors::ConfigError(
+                "--backpressure block can't be used with --mainloop standard, which runs \
+                 callbacks on the event loop's own thread"
+                    .to_string(),
+            ));
+        }
+
+        Ok(ListenerConfig {
+            watch: self.watch,
+            reports: self.reports.clone(),
+            watch_clients: self.watch_clients,
+            aggregate: self.aggregate,
+            source_patterns,
+            source_index: self.index,
+            preferred,
+            source_filter: SourceFilter {
+                include: parse_regexes(&self.include, "--include")?,
+                exclude: parse_regexes(&self.exclude, "--exclude")?,
+                include_monitors: self.include_monitors,
+                only_hardware: self.only_hardware,
+            },
+            monitor_sink_names: self.monitor_sink_names,
+            stall_timeout: self.stall_timeout,
+            on_stall: self.on_stall,
+            change_queue: QueuePolicy {
+                backpressure: self.backpressure,
+                capacity: self.change_queue_size,
+                window: self.debounce,
+            },
+            heartbeat: self.heartbeat,
+            health: Health::default(),
+            latency: self.latency.clone(),
+            sequence: Sequence::default(),
+            notify_systemd: self.notify_systemd,
+        })
+    }
+
+    pub(crate) fn reconnects(&self) -> bool {
+        self.reconnect
+    }
+
+    pub(crate) fn consumers(&self) -> ConsumerPolicy {
+        self.backends.consumers
+    }
+
+    pub(crate) fn backends(&self) -> &Backends {
+        &self.backends
+    }
+}
+
+/// The servers and clients events are handed to besides the listener's own, which requests
+/// come back from
+#[derive(Debug, Clone, Default)]
+pub struct Backends {
+    socket: Option<PathBuf>,
+    tcp: Option<SocketAddr>,
+    tcp_control: bool,
+    http: Option<SocketAddr>,
+    metrics: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    ws_control: bool,
+    #[cfg(feature = "websocket")]
+    ws_origins: Vec<String>,
+    #[cfg(feature = "webhook")]
+    webhooks: Vec<String>,
+    #[cfg(feature = "webhook")]
+    webhook_policy: WebhookPolicy,
+    consumers: ConsumerPolicy,
+}
+
+impl Backends {
+    /// Start serving, sending requests to `tx` and answering `health` from the listener's.
+    /// Without `tx`, only those taking no requests start.
+    pub fn start(
+        &self,
+        tx: Option<&RequestTx>,
+        health: &Health,
+    ) -> Result<Vec<Box<dyn Output>>, Errors> {
+        let mut outputs: Vec<Box<dyn Output>> = vec![];
+
+        if let Some(path) = &self.socket {
+            match tx {
+                Some(tx) => outputs.push(Box::new(socket::SocketOutput::unix(
+                    path.clone(),
+                    tx.clone(),
+                    self.consumers,
+                    health.clone(),
+                )?)),
+                None => info!(
+                    "Not serving {}, there's no server to act on",
+                    path.display()
+                ),
+            }
+        }
+
+        if let Some(addr) = self.tcp {
+            match tx {
+                Some(tx) => outputs.push(Box::new(socket::SocketOutput::tcp(
+                    addr,
+                    self.tcp_control,
+                    tx.clone(),
+                    self.consumers,
+                    health.clone(),
+                )?)),
+                None => info!("Not serving TCP, there's no server to act on"),
+            }
+        }
+
+        if let Some(addr) = self.metrics {
+            outputs.push(Box::new(metrics::MetricsOutput::start(addr)?));
+        }
+
+        if let Some(addr) = self.http {
+            match tx {
+                Some(tx) => outputs.push(Box::new(http::HttpOutput::start(
+                    addr,
+                    tx.clone(),
+                    self.consumers,
+                    health.clone(),
+                )?)),
+                None => info!("Not serving HTTP, there's no server to act on"),
+            }
+        }
+
+        #[cfg(feature = "websocket")]
+        if let Some(addr) = self.websocket {
+            match tx {
+                Some(tx) => outputs.push(Box::new(ws::WsOutput::start(
+                    addr,
+                    self.ws_control,
+                    &self.ws_origins,
+                    tx.clone(),
+                    self.consumers,
+                    health.clone(),
+                )?)),
+                None => info!("Not serving websockets, there's no server to act on"),
+            }
+        }
+
+        #[cfg(feature = "webhook")]
+        if !self.webhooks.is_empty() {
+            outputs.push(Box::new(webhook::WebhookOutput::new(
+                self.webhooks.clone(),
+                self.webhook_policy.clone(),
+                tx.cloned(),
+            )?));
+        }
+
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Args;
+
+    fn refused(builder: SourceListenerBuilder) -> String {
+        match builder.listener_config() {
+            Err(Errors::ConfigError(reason)) => reason,
+            Err(err) => panic!("expected a config error, got {}", err),
+            Ok(_) => panic!("expected a config error"),
+        }
+    }
+
+    #[test]
+    fn defaults_match_the_command_line() {
+        let config = SourceListenerBuilder::default().listener_config().unwrap();
+        let args = Args::try_parse_from([env!("CARGO_PKG_NAME")]).unwrap();
+        assert_eq!(config.reports, args.report);
+        assert_eq!(
+            config.change_queue.window,
+            Some(Duration::from_millis(changes::DEFAULT_WINDOW_MS))
+        );
+        let servers = SourceListenerBuilder::default().connect_options().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].retries, Some(0));
+    }
+
+    #[test]
+    fn the_last_monitor_flag_wins() {
+        let includes = |flags: &[&str]| {
+            let args = Args::try_parse_from(
+                std::iter::once(env!("CARGO_PKG_NAME")).chain(flags.iter().copied()),
+            )
+            .unwrap();
+            args.builder()
+                .listener_config()
+                .unwrap()
+                .source_filter
+                .include_monitors
+        };
+        assert!(!includes(&[]));
+        assert!(includes(&["--no-monitors", "--include-monitors"]));
+        assert!(!includes(&["--include-monitors", "--no-monitors"]));
+    }
+
+    #[test]
+    fn follows_by_index_or_by_name() {
+        let builder = SourceListenerBuilder::default().index(3).source("mic");
+        assert!(refused(builder).contains("not both"));
+    }
+
+    #[test]
+    fn zero_stall_timeout_is_refused() {
+        let builder = SourceListenerBuilder::default().stall_timeout(Duration::ZERO);
+        assert!(refused(builder).contains("--stall-timeout"));
+    }
+
+    #[test]
+    fn zero_heartbeat_is_refused() {
+        let builder = SourceListenerBuilder::default().heartbeat(Duration::ZERO);
+        assert!(refused(builder).contains("--heartbeat"));
+    }
+
+    #[test]
+    fn empty_change_queue_is_refused() {
+        let builder = SourceListenerBuilder::default().change_queue_size(0);
+        assert!(refused(builder).contains("--change-queue-size"));
+    }
+
+    #[test]
+    fn empty_consumer_queue_is_refused() {
+        let builder = SourceListenerBuilder::default().consumer_queue_size(0);
+        assert!(refused(builder).contains("--consumer-queue-size"));
+    }
+
+    #[test]
+    fn zero_debounce_handles_changes_straight_away() {
+        let config = SourceListenerBuilder::default()
+            .debounce(Duration::ZERO)
+            .listener_config()
+            .unwrap();
+        assert_eq!(config.change_queue.window, None);
+    }
+}
@@ -0,0 +1,21 @@
+use crossbeam_channel::Sender;
+use log::{debug, trace};
+
+use crate::{CallbackComms, CBTX};
+
+/// Answer whoever asked the server something. Callbacks run on libpulse's thread, where a panic
+/// would unwind into C, and the asker may well have stopped waiting (e.g. after timing out), so
+/// an answer nobody takes is simply dropped.
+pub fn reply<T>(tx: &Sender<T>, answer: T) {
+    if tx.send(answer).is_err() {
+        trace!("Nobody waiting on an answer from the server any more");
+    }
+}
+
+/// Pass something on to the event loop. Each loop holds its own receiver, so one that's gone
+/// has already stopped listening and there's nobody left to tell.
+pub fn notify(tx: &CBTX, message: CallbackComms) {
+    if let Err(unsent) = tx.send(message) {
+        debug!("Event loop gone, dropping {:?}", unsent.0);
+    }
+}
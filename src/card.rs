@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+
+use pulse::context::introspect::CardInfo;
+
+pub type Cards = HashMap<u32, CardDatum>;
+
+#[derive(Debug, Clone)]
+pub struct CardDatum {
+    pub name: String,
+    pub active_profile: Option<String>,
+    pub active_profile_description: Option<String>,
+}
+
+impl CardDatum {
+    pub fn from_info(info: &CardInfo<'_>) -> Self {
+        let profile = info.active_profile.as_ref();
+        CardDatum {
+            name: match &info.name {
+                None => "unknown".to_string(),
+                Some(name) => name.to_string(),
+            },
+            active_profile: profile
+                .and_then(|profile| profile.name.as_ref().map(|name| name.to_string())),
+            active_profile_description: profile
+                .and_then(|profile| profile.description.as_ref().map(|desc| desc.to_string())),
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use log::warn;
+use tracing::Span;
+
+use crate::{callback, CallbackComms, PulseChange, CBTX};
+
+/// Changes that can wait on the event loop before the queue counts as full
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// How long, in milliseconds, changes are gathered after the first of a burst by default.
+/// Plugging in a headset sends several for each of its devices within a few milliseconds.
+pub const DEFAULT_WINDOW_MS: u64 = 50;
+
+/// Longest a callback waits for room with the `block` policy. The loop may itself be waiting on
+/// the mainloop the callback holds, so waiting any longer could never end.
+const BLOCK_LIMIT: Duration = Duration::from_millis(100);
+
+/// What to do with server changes arriving faster than the event loop gets through them
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Drop changes already waiting for the same device, and when still full resync instead
+    #[default]
+    Coalesce,
+    /// Drop the oldest waiting change, resyncing once the queue is through
+    DropOldest,
+    /// Hold up the server's thread until there's room, warning that it had to, and drop the
+    /// oldest change if there isn't any soon
+    Block,
+}
+
+/// How server changes are queued for the event loop
+#[derive(Debug, Clone, Copy)]
+pub struct QueuePolicy {
+    pub backpressure: Backpressure,
+    pub capacity: usize,
+    /// Hold changes back this long after the first of a burst, merging those for the same
+    /// device, or hand them over straight away
+    pub window: Option<Duration>,
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        QueuePolicy {
+            backpressure: Backpressure::default(),
+            capacity: DEFAULT_CAPACITY,
+            window: Some(Duration::from_millis(DEFAULT_WINDOW_MS)),
+        }
+    }
+}
+
+/// A change waiting for the loop, with the span it's traced under and when the server told us
+/// about it. Changes merged into one are timed from the first of them.
+pub type Pending = (PulseChange, Span, Instant);
+
+/// What happened to a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    New,
+    Change,
+    Drop,
+}
+
+/// The kind of device a change is about, its index, and what happened to it, or `None` for a
+/// change to the server itself
+fn split(change: &PulseChange) -> Option<(&'static str, u32, Operation)> {
+    Some(match *change {
+        PulseChange::SourceNew(idx) => ("source", idx, Operation::New),
+        PulseChange::SourceChange(idx) => ("source", idx, Operation::Change),
+        PulseChange::SourceDrop(idx) => ("source", idx, Operation::Drop),
+        PulseChange::SinkNew(idx) => ("sink", idx, Operation::New),
+        PulseChange::SinkChange(idx) => ("sink", idx, Operation::Change),
+        PulseChange::SinkDrop(idx) => ("sink", idx, Operation::Drop),
+        PulseChange::SourceOutputNew(idx) => ("source_output", idx, Operation::New),
+        PulseChange::SourceOutputChange(idx) => ("source_output", idx, Operation::Change),
+        PulseChange::SourceOutputDrop(idx) => ("source_output", idx, Operation::Drop),
+        PulseChange::CardNew(idx) => ("card", idx, Operation::New),
+        PulseChange::CardChange(idx) => ("card", idx, Operation::Change),
+        PulseChange::CardDrop(idx) => ("card", idx, Operation::Drop),
+        PulseChange::ClientNew(idx) => ("client", idx, Operation::New),
+        PulseChange::ClientChange(idx) => ("client", idx, Operation::Change),
+        PulseChange::ClientDrop(idx) => ("client", idx, Operation::Drop),
+        PulseChange::ModuleNew(idx) => ("module", idx, Operation::New),
+        PulseChange::ModuleDrop(idx) => ("module", idx, Operation::Drop),
+        PulseChange::Server => return None,
+    })
+}
+
+/// Fold `change` into those waiting for the same device, returning whether it still needs
+/// queueing. Handling a change fetches the device as it is by then, so only the first change
+/// and any drop matter.
+fn merge(changes: &mut VecDeque<Pending>, change: &PulseChange) -> bool {
+    let Some((facility, index, operation)) = split(change) else {
+        // Every server change refetches the same defaults
+        return !changes
+            .iter()
+            .any(|(queued, ..)| *queued == PulseChange::Server);
+    };
+    let earlier = changes
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(at, (queued, ..))| {
+            split(queued)
+                .filter(|(queued_facility, queued_index, _)| {
+                    *queued_facility == facility && *queued_index == index
+                })
+                .map(|(_, _, earlier)| (at, earlier))
+        });
+    let Some((at, earlier)) = earlier else {
+        return true;
+    };
+    match (earlier, operation) {
+        // Gone, then back, perhaps as another device at the same index
+        (Operation::Drop, _) | (_, Operation::New) => true,
+        (Operation::New | Operation::Change, Operation::Change) => false,
+        // Came and went within the window, so there's nothing to show for it
+        (Operation::New, Operation::Drop) => {
+            changes.remove(at);
+            false
+        }
+        (Operation::Change, Operation::Drop) => {
+            changes.remove(at);
+            true
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queued {
+    changes: VecDeque<Pending>,
+    /// Changes were dropped, so the state has to be fetched again
+    overflowed: bool,
+    /// Until when the changes are held back, gathering a burst
+    held_until: Option<Instant>,
+}
+
+/// Server changes on their way from the subscribe callback to the event loop, bounded so a
+/// flood of them (e.g. from a misbehaving driver) can't grow without limit while the loop is
+/// busy. Only one `CallbackComms::Changes` is sent for the loop at a time, however many changes
+/// are waiting.
+pub struct ChangeQueue {
+    queued: Mutex<Queued>,
+    room: Condvar,
+    policy: QueuePolicy,
+}
+
+impl ChangeQueue {
+    pub fn new(policy: QueuePolicy) -> Self {
+        ChangeQueue {
+            queued: Mutex::default(),
+            room: Condvar::new(),
+            policy,
+        }
+    }
+
+    /// Queue a change from the subscribe callback, waking the loop if nothing was waiting
+    pub fn push(&self, change: PulseChange, span: Span, tx: &CBTX) {
+        let mut queued = self.queued.lock().unwrap();
+        match self.policy.backpressure {
+            // Handling a change fetches the device as it is by then, so one is as good as two
+            Backpressure::Coalesce if queued.changes.iter().any(|(known, ..)| *known == change) => {
+                return;
+            }
+            Backpressure::Coalesce if queued.changes.len() >= self.policy.capacity => {
+                warn!(
+                    "{} server changes waiting, resyncing instead",
+                    queued.changes.len()
+                );
+                queued.changes.clear();
+                queued.overflowed = true;
+            }
+            Backpressure::Block if queued.changes.len() >= self.policy.capacity => {
+                warn!("Server changes waiting on the event loop, holding up the server");
+                queued = self
+                    .room
+                    .wait_timeout_while(queued, BLOCK_LIMIT, |queued| {
+                        queued.changes.len() >= self.policy.capacity
+                    })
+                    .unwrap()
+                    .0;
+                if queued.changes.len() >= self.policy.capacity {
+                    queued.changes.pop_front();
+                    queued.overflowed = true;
+                }
+            }
+            Backpressure::DropOldest if queued.changes.len() >= self.policy.capacity => {
+                queued.changes.pop_front();
+                if !queued.overflowed {
+                    warn!("Server changes waiting on the event loop, dropping the oldest");
+                }
+                queued.overflowed = true;
+            }
+            _ => {}
+        }
+        let wake = queued.changes.is_empty();
+        if let Some(window) = self.policy.window {
+            if !merge(&mut queued.changes, &change) {
+                return;
+            }
+            if wake {
+                queued.held_until = Some(Instant::now() + window);
+            }
+        }
+        queued.changes.push_back((change, span, Instant::now()));
+        // Held changes still wake the loop, to wait out their window
+        if wake {
+            callback::notify(tx, CallbackComms::Changes);
+        }
+    }
+
+    /// How much longer the waiting changes are held back, if they are
+    pub fn held_for(&self) -> Option<Duration> {
+        let queued = self.queued.lock().unwrap();
+        queued
+            .held_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+    }
+
+    /// Let held changes through once their window has passed, waking the loop for them
+    pub fn release(&self, tx: &CBTX) {
+        let mut queued = self.queued.lock().unwrap();
+        if queued
+            .held_until
+            .is_some_and(|until| Instant::now() >= until)
+        {
+            queued.held_until = None;
+            if !queued.changes.is_empty() {
+                let _ = tx.send(CallbackComms::Changes);
+            }
+        }
+    }
+
+    /// Take the next change for the loop, waking it again if more are waiting
+    pub fn pop(&self, tx: &CBTX) -> Option<Pending> {
+        let mut queued = self.queued.lock().unwrap();
+        if queued.held_until.is_some() {
+            return None;
+        }
+        let next = queued.changes.pop_front();
+        self.room.notify_one();
+        if next.is_some() && !queued.changes.is_empty() {
+            // Other messages get a look in between changes
+            let _ = tx.send(CallbackComms::Changes);
+        }
+        next
+    }
+
+    /// Whether changes were dropped since last asked, leaving the state to be fetched again
+    pub fn take_overflowed(&self) -> bool {
+        std::mem::take(&mut self.queued.lock().unwrap().overflowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mainloop::{self, Inbox};
+
+    use super::*;
+
+    fn pending(changes: &[PulseChange]) -> VecDeque<Pending> {
+        changes
+            .iter()
+            .map(|change| (change.clone(), Span::none(), Instant::now()))
+            .collect()
+    }
+
+    fn left(changes: &VecDeque<Pending>) -> Vec<PulseChange> {
+        changes.iter().map(|(change, ..)| change.clone()).collect()
+    }
+
+    fn queue(backpressure: Backpressure, capacity: usize) -> ChangeQueue {
+        ChangeQueue::new(QueuePolicy {
+            backpressure,
+            capacity,
+            window: None,
+        })
+    }
+
+    fn push_all(queue: &ChangeQueue, changes: &[PulseChange], tx: &CBTX) {
+        for change in changes {
+            queue.push(change.clone(), Span::none(), tx);
+        }
+    }
+
+    fn pop_all(queue: &ChangeQueue, tx: &CBTX) -> Vec<PulseChange> {
+        std::iter::from_fn(|| queue.pop(tx))
+            .map(|(change, ..)| change)
+            .collect()
+    }
+
+    #[test]
+    fn added_then_removed_cancels_out() {
+        let mut changes = pending(&[PulseChange::SourceNew(1)]);
+        assert!(!merge(&mut changes, &PulseChange::SourceDrop(1)));
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn removal_replaces_a_change() {
+        let mut changes = pending(&[PulseChange::SourceChange(1), PulseChange::SinkChange(1)]);
+        assert!(merge(&mut changes, &PulseChange::SourceDrop(1)));
+        assert_eq!(left(&changes), vec![PulseChange::SinkChange(1)]);
+    }
+
+    #[test]
+    fn added_again_after_removal_is_kept() {
+        let mut changes = pending(&[PulseChange::SourceDrop(1)]);
+        assert!(merge(&mut changes, &PulseChange::SourceNew(1)));
+        assert_eq!(left(&changes), vec![PulseChange::SourceDrop(1)]);
+    }
+
+    #[test]
+    fn later_changes_fold_into_the_first() {
+        let mut changes = pending(&[PulseChange::SourceNew(1), PulseChange::Server]);
+        assert!(!merge(&mut changes, &PulseChange::SourceChange(1)));
+        assert!(!merge(&mut changes, &PulseChange::Server));
+        assert!(merge(&mut changes, &PulseChange::SourceChange(2)));
+        assert_eq!(changes.len(), 2);
+    }
+
+    #[test]
+    fn held_until_the_window_passes() {
+        let (tx, rx) = mainloop::channel();
+        let held = ChangeQueue::new(QueuePolicy {
+            window: Some(Duration::from_secs(3600)),
+            ..QueuePolicy::default()
+        });
+        held.push(PulseChange::SourceChange(1), Span::none(), &tx);
+        assert!(matches!(
+            rx.receiver().try_recv(),
+            Ok(CallbackComms::Changes)
+        ));
+        assert!(held.held_for().is_some_and(|left| !left.is_zero()));
+        held.release(&tx);
+        assert!(held.pop(&tx).is_none());
+
+        let passed = ChangeQueue::new(QueuePolicy {
+            window: Some(Duration::ZERO),
+            ..QueuePolicy::default()
+        });
+        push_all(
+            &passed,
+            &[PulseChange::SourceChange(1), PulseChange::SourceChange(1)],
+            &tx,
+        );
+        assert!(passed.pop(&tx).is_none());
+        passed.release(&tx);
+        assert_eq!(pop_all(&passed, &tx), vec![PulseChange::SourceChange(1)]);
+    }
+
+    #[test]
+    fn coalescing_resyncs_when_full() {
+        let (tx, _rx) = mainloop::channel();
+        let queue = queue(Backpressure::Coalesce, 2);
+        push_all(
+            &queue,
+            &[
+                PulseChange::SourceChange(1),
+                PulseChange::SourceChange(1),
+                PulseChange::SourceChange(2),
+            ],
+            &tx,
+        );
+        assert!(!queue.take_overflowed());
+        queue.push(PulseChange::SourceChange(3), Span::none(), &tx);
+        assert!(queue.take_overflowed());
+        assert_eq!(pop_all(&queue, &tx), vec![PulseChange::SourceChange(3)]);
+    }
+
+    #[test]
+    fn dropping_the_oldest_when_full() {
+        let (tx, _rx) = mainloop::channel();
+        let queue = queue(Backpressure::DropOldest, 2);
+        push_all(
+            &queue,
+            &[
+                PulseChange::SourceChange(1),
+                PulseChange::SourceChange(2),
+                PulseChange::SourceChange(3),
+            ],
+            &tx,
+        );
+        assert!(queue.take_overflowed());
+        assert_eq!(
+            pop_all(&queue, &tx),
+            vec![PulseChange::SourceChange(2), PulseChange::SourceChange(3)]
+        );
+    }
+
+    #[test]
+    fn blocking_gives_up_on_the_oldest() {
+        let (tx, _rx) = mainloop::channel();
+        let queue = queue(Backpressure::Block, 1);
+        let started = Instant::now();
+        push_all(
+            &queue,
+            &[PulseChange::SourceChange(1), PulseChange::SourceChange(2)],
+            &tx,
+        );
+        assert!(started.elapsed() >= BLOCK_LIMIT);
+        assert!(queue.take_overflowed());
+        assert_eq!(pop_all(&queue, &tx), vec![PulseChange::SourceChange(2)]);
+    }
+}
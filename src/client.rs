@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+
+use pulse::{context::introspect::ClientInfo, proplist::properties};
+
+pub type Clients = HashMap<u32, ClientDatum>;
+
+#[derive(Debug, Clone)]
+pub struct ClientDatum {
+    /// The client's `application.name`, falling back to its connection name
+    pub name: String,
+    pub binary: Option<String>,
+    pub process_id: Option<String>,
+}
+
+impl ClientDatum {
+    pub fn from_info(info: &ClientInfo<'_>) -> Self {
+        ClientDatum {
+            name: info
+                .proplist
+                .get_str(properties::APPLICATION_NAME)
+                .or_else(|| info.name.as_ref().map(|name| name.to_string()))
+                .unwrap_or_else(|| "unknown".to_string()),
+            binary: info
+                .proplist
+                .get_str(properties::APPLICATION_PROCESS_BINARY),
+            process_id: info.proplist.get_str(properties::APPLICATION_PROCESS_ID),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use pulse::{
+    context::Context,
+    volume::{ChannelVolumes, Volume, VolumeDB},
+};
+use serde::Serialize;
+
+use crate::batch::Batch;
+use crate::lock::MainloopGuard;
+use crate::mainloop::Mainloop;
+use crate::output::{Event, EventKind, Output, StateTexts};
+use crate::{
+    callback, get_default_source_index, get_sources, report_changes, set_default_source,
+    set_source_mute, set_source_volume, subscribe_source_mute, CallbackComms, Command, Errors,
+    ListenerConfig, ListenerRequest, ListenerState, Report, SourceDatum, Sources, VolumeAction,
+    WaitCondition, Watch, CBRX, CBTX,
+};
+
+/// Exit codes of `status`, so it can be used in shell conditionals. Control commands share
+/// `EXIT_NO_SOURCE`.
+const EXIT_UNMUTED: i32 = 0;
+const EXIT_MUTED: i32 = 1;
+const EXIT_NO_SOURCE: i32 = 2;
+/// Any command that failed outright, e.g. losing the connection midway, so that can't be taken
+/// for one of the answers above
+pub const EXIT_FAILED: i32 = 3;
+/// As used by coreutils' `timeout`
+pub const EXIT_TIMEOUT: i32 = 124;
+
+/// The exit code for a command's outcome, saying what went wrong if it failed
+pub fn exit_code(result: Result<i32, Errors>) -> i32 {
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        EXIT_FAILED
+    })
+}
+
+/// Run a one-shot command, returning the process' exit code
+pub fn run(
+    command: Command,
+    texts: &StateTexts,
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+    tx: CBTX,
+    rx: CBRX,
+) -> Result<i32, Errors> {
+    match command {
+        Command::List { json } => list(json, mainloop, context).map(|_| 0),
+        Command::Status => status(texts, mainloop, context),
+        Command::Mute => set_mute(Some(true), mainloop, context),
+        Command::Unmute => set_mute(Some(false), mainloop, context),
+        Command::Toggle => set_mute(None, mainloop, context),
+        Command::SetDefault { source } => set_default(&source, mainloop, context),
+        Command::Volume {
+            action,
+            source,
+            max,
+        } => volume(action, source.as_deref(), max, mainloop, context),
+        Command::Info { json } => info(json, mainloop, context).map(|_| 0),
+        // Dispatched before connecting
+        Command::Doctor | Command::Simulate { .. } | Command::Health { .. } => {
+            unreachable!("runs before connecting")
+        }
+        Command::WaitFor { condition, timeout } => {
+            wait_for(condition, timeout, mainloop, context, tx, rx)
+        }
+    }
+}
+
+/// The default source and its index, if there is one
+fn default_source(
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+) -> Result<Option<(u32, SourceDatum)>, Errors> {
+    let mut sources = get_sources(context, mainloop)?;
+    let default_source_id = get_default_source_index(mainloop, context, &sources)?;
+    Ok(default_source_id.and_then(|idx| sources.remove(&idx).map(|src| (idx, src))))
+}
+
+#[derive(Serialize)]
+struct SourceRow<'a> {
+    index: u32,
+    name: &'a str,
+    description: Option<&'a str>,
+    muted: bool,
+    volume: u32,
+    default: bool,
+    hardware: bool,
+    network: bool,
+}
+
+fn list(json: bool, mainloop: &mut Mainloop, context: &mut Context) -> Result<(), Errors> {
+    let sources = get_sources(context, mainloop)?;
+    let default_source_id = get_default_source_index(mainloop, context, &sources)?;
+
+    let mut rows: Vec<SourceRow> = sources
+        .iter()
+        .map(|(index, src)| SourceRow {
+            index: *index,
+            name: &src.name,
+            description: src.description.as_deref(),
+            muted: src.mute,
+            volume: src.volume_percent(),
+            default: default_source_id == Some(*index),
+            hardware: src.is_hardware(),
+            network: src.is_network(),
+        })
+        .collect();
+    rows.sort_by_key(|row| row.index);
+
+    let mut stdout = io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut stdout, &rows).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+    } else {
+        write_table(&mut stdout, &rows)?;
+    }
+    Ok(())
+}
+
+fn status(
+    texts: &StateTexts,
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+) -> Result<i32, Errors> {
+    let (text, code) = match default_source(mainloop, context)? {
+        Some((_, src)) if src.mute => (&texts.mute, EXIT_MUTED),
+        Some(_) => (&texts.unmute, EXIT_UNMUTED),
+        None => (&texts.nosource, EXIT_NO_SOURCE),
+    };
+    println!("{}", text);
+    Ok(code)
+}
+
+/// Set the default source's mute state, or toggle it if `mute` is None
+fn set_mute(
+    mute: Option<bool>,
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+) -> Result<i32, Errors> {
+    let Some((idx, src)) = default_source(mainloop, context)? else {
+        eprintln!("No default source");
+        return Ok(EXIT_NO_SOURCE);
+    };
+
+    let mute = mute.unwrap_or(!src.mute);
+    set_source_mute(idx, mute, &Batch::default(), context, mainloop)?;
+    Ok(0)
+}
+
+/// Find the source the user meant: by index, then exact name, then a case-insensitive match
+/// against names and descriptions that has to be unambiguous.
+fn find_source<'a>(sources: &'a Sources, query: &str) -> Result<(u32, &'a SourceDatum), String> {
+    if let Some((idx, src)) = query
+        .parse::<u32>()
+        .ok()
+        .and_then(|idx| sources.get_key_value(&idx))
+    {
+        return Ok((*idx, src));
+    }
+    if let Some((idx, src)) = sources
+        .index_of(query)
+        .and_then(|idx| sources.get_key_value(&idx))
+    {
+        return Ok((*idx, src));
+    }
+
+    let query = query.to_lowercase();
+    let mut matches: Vec<(u32, &SourceDatum)> = sources
+        .iter()
+        .map(|(idx, src)| (*idx, src))
+        .filter(|(_, src)| {
+            src.name.to_lowercase().contains(&query)
+                || src
+                    .description
+                    .as_ref()
+                    .is_some_and(|desc| desc.to_lowercase().contains(&query))
+        })
+        .collect();
+    match matches.len() {
+        0 => Err(format!("No source matches '{}'", query)),
+        1 => Ok(matches.remove(0)),
+        _ => {
+            let mut names: Vec<&str> = matches.iter().map(|(_, src)| src.name.as_ref()).collect();
+            names.sort();
+            Err(format!(
+                "'{}' matches several sources: {}",
+                query,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+fn set_default(query: &str, mainloop: &mut Mainloop, context: &mut Context) -> Result<i32, Errors> {
+    let sources = get_sources(context, mainloop)?;
+    let src = match find_source(&sources, query) {
+        Ok((_, src)) => src,
+        Err(reason) => {
+            eprintln!("{}", reason);
+            return Ok(EXIT_NO_SOURCE);
+        }
+    };
+
+    set_default_source(&src.name, &Batch::default(), context, mainloop)?;
+    Ok(0)
+}
+
+fn volume(
+    action: VolumeAction,
+    query: Option<&str>,
+    max: u32,
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+) -> Result<i32, Errors> {
+    let target = match query {
+        None => default_source(mainloop, context)?.ok_or("No default source".to_string()),
+        Some(query) => {
+            let sources = get_sources(context, mainloop)?;
+            find_source(&sources, query).map(|(idx, src)| (idx, src.clone()))
+        }
+    };
+    let (idx, src) = match target {
+        Ok(target) => target,
+        Err(reason) => {
+            eprintln!("{}", reason);
+            return Ok(EXIT_NO_SOURCE);
+        }
+    };
+
+    let current = src.volume_percent();
+    let Some(percent) = target_percent(action, current, max) else {
+        println!(
+            "{}% ({:.2} dB)",
+            current,
+            VolumeDB::from(src.volume.avg()).0
+        );
+        return Ok(0);
+    };
+
+    let mut volume = src.volume;
+    scale_to_percent(&mut volume, percent);
+    set_source_volume(idx, &volume, &Batch::default(), context, mainloop)?;
+    Ok(0)
+}
+
+/// The volume `action` goes to from `current`, or `None` if it only asks. Setting and stepping
+/// up go no higher than `max`, or than `current` if it's already above it, while stepping down
+/// only ever takes off the step.
+fn target_percent(action: VolumeAction, current: u32, max: u32) -> Option<u32> {
+    match action {
+        VolumeAction::Get => None,
+        VolumeAction::Set { percent } => Some(percent.min(max)),
+        VolumeAction::Up { step } => Some(current.saturating_add(step).min(max.max(current))),
+        VolumeAction::Down { step } => Some(current.saturating_sub(step)),
+    }
+}
+
+/// Scale every channel so the average is `percent`, keeping the balance between channels
+fn scale_to_percent(volume: &mut ChannelVolumes, percent: u32) {
+    let target = percent as f64 * Volume::NORMAL.0 as f64 / 100.0;
+    let avg = volume.avg().0 as f64;
+    for channel in volume.get_mut() {
+        let scaled = match avg {
+            0.0 => target,
+            _ => channel.0 as f64 * target / avg,
+        };
+        *channel = Volume((scaled.round() as u32).min(Volume::MAX.0));
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ServerDetails {
+    server_name: Option<String>,
+    server_version: Option<String>,
+    user_name: Option<String>,
+    host_name: Option<String>,
+    /// e.g. `s16le 2ch 44100Hz`
+    sample_spec: String,
+    default_source: Option<String>,
+    default_sink: Option<String>,
+    /// Address of the server we're connected to, e.g. the path of its unix socket
+    server: Option<String>,
+    local: Option<bool>,
+    protocol_version: u32,
+    server_protocol_version: Option<u32>,
+}
+
+fn get_server_details(context: &Context, mainloop: &mut Mainloop) -> Result<ServerDetails, Errors> {
+    let guard = MainloopGuard::lock(mainloop);
+
+    let introspector = context.introspect();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    introspector.get_server_info(move |server_info| {
+        let owned = |value: &Option<Cow<str>>| value.as_ref().map(|v| v.to_string());
+        callback::reply(
+            &tx,
+            ServerDetails {
+                server_name: owned(&server_info.server_name),
+                server_version: owned(&server_info.server_version),
+                user_name: owned(&server_info.user_name),
+                host_name: owned(&server_info.host_name),
+                sample_spec: server_info.sample_spec.print(),
+                default_source: owned(&server_info.default_source_name),
+                default_sink: owned(&server_info.default_sink_name),
+                server: None,
+                local: None,
+                protocol_version: 0,
+                server_protocol_version: None,
+            },
+        );
+    });
+    let server = context.get_server();
+    let local = context.is_local();
+    let protocol_version = context.get_protocol_version();
+    let server_protocol_version = context.get_server_protocol_version();
+
+    drop(guard);
+    Ok(ServerDetails {
+        server,
+        local,
+        protocol_version,
+        server_protocol_version,
+        ..mainloop.recv(&rx)?
+    })
+}
+
+fn info(json: bool, mainloop: &mut Mainloop, context: &mut Context) -> Result<(), Errors> {
+    let details = get_server_details(context, mainloop)?;
+
+    let mut stdout = io::stdout().lock();
+    if json {
+        serde_json::to_writer(&mut stdout, &details).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    let unknown = "unknown".to_string();
+    let fields = [
+        (
+            "Server name",
+            details.server_name.unwrap_or(unknown.clone()),
+        ),
+        (
+            "Server version",
+            details.server_version.unwrap_or(unknown.clone()),
+        ),
+        ("User name", details.user_name.unwrap_or(unknown.clone())),
+        ("Host name", details.host_name.unwrap_or(unknown.clone())),
+        ("Sample spec", details.sample_spec),
+        (
+            "Default source",
+            details.default_source.unwrap_or(unknown.clone()),
+        ),
+        (
+            "Default sink",
+            details.default_sink.unwrap_or(unknown.clone()),
+        ),
+        ("Server address", details.server.unwrap_or(unknown.clone())),
+        (
+            "Connection",
+            match details.local {
+                Some(true) => "local".to_string(),
+                Some(false) => "remote".to_string(),
+                None => unknown.clone(),
+            },
+        ),
+        (
+            "Protocol version",
+            match details.server_protocol_version {
+                Some(server) => format!("{} (server {})", details.protocol_version, server),
+                None => details.protocol_version.to_string(),
+            },
+        ),
+    ];
+    for (label, value) in fields {
+        writeln!(stdout, "{:<17} {}", format!("{}:", label), value)?;
+    }
+    Ok(())
+}
+
+/// Swallows events, asking the listener loop to stop once one satisfies the condition
+struct WaitOutput {
+    condition: WaitCondition,
+    met: Rc<Cell<bool>>,
+    tx: CBTX,
+}
+
+impl Output for WaitOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let met = match (self.condition, &event.kind) {
+            (WaitCondition::Muted, EventKind::Mute { device, .. }) => device.muted,
+            (WaitCondition::Unmuted, EventKind::Mute { device, .. }) => !device.muted,
+            (WaitCondition::DefaultChange, EventKind::DefaultChanged { .. }) => true,
+            _ => false,
+        };
+        if met && !self.met.replace(true) {
+            // The loop only goes away once it has seen this
+            let _ = self
+                .tx
+                .send(CallbackComms::Request(ListenerRequest::Shutdown));
+        }
+        Ok(())
+    }
+}
+
+fn wait_for(
+    condition: WaitCondition,
+    timeout: Option<u64>,
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+    tx: CBTX,
+    rx: CBRX,
+) -> Result<i32, Errors> {
+    if let Some(secs) = timeout {
+        let tx = tx.clone();
+        thread::Builder::new()
+            .name("wait-for-timeout".to_string())
+            .spawn(move || {
+                thread::sleep(Duration::from_secs(secs));
+                let _ = tx.send(CallbackComms::Timeout);
+            })?;
+    }
+
+    let met = Rc::new(Cell::new(false));
+    let mut output = WaitOutput {
+        condition,
+        met: met.clone(),
+        tx: tx.clone(),
+    };
+    let config = ListenerConfig {
+        watch: Watch::Source,
+        reports: vec![Report::Mute, Report::Default],
+        ..Default::default()
+    };
+    let state = ListenerState::new(config, mainloop, context)?;
+    // The initial report covers a mute condition that already holds
+    report_changes(&state, None, &mut output)?;
+
+    match subscribe_source_mute(mainloop, context, state, &mut output, tx, &rx) {
+        Err(Errors::Shutdown) if met.get() => Ok(0),
+        Err(Errors::Timeout) => Ok(EXIT_TIMEOUT),
+        Err(err) => Err(err),
+        Ok(()) => Ok(0),
+    }
+}
+
+/// Aligned columns, with the default source marked by a `*`
+fn write_table(writer: &mut impl Write, rows: &[SourceRow]) -> io::Result<()> {
+    let name_width = rows
+        .iter()
+        .map(|row| row.name.len())
+        .chain(["NAME".len()])
+        .max()
+        .unwrap_or_default();
+    let desc_width = rows
+        .iter()
+        .map(|row| row.description.unwrap_or_default().len())
+        .chain(["DESCRIPTION".len()])
+        .max()
+        .unwrap_or_default();
+
+    writeln!(
+        writer,
+        "  {:>5}  {:<name_width$}  {:<desc_width$}  {:<5}  VOLUME",
+        "INDEX", "NAME", "DESCRIPTION", "MUTE"
+    )?;
+    for row in rows {
+        writeln!(
+            writer,
+            "{} {:>5}  {:<name_width$}  {:<desc_width$}  {:<5}  {}%",
+            if row.default { '*' } else { ' ' },
+            row.index,
+            row.name,
+            row.description.unwrap_or_default(),
+            if row.muted { "yes" } else { "no" },
+            row.volume,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_exits_apart_from_any_answer() {
+        assert_eq!(exit_code(Err(Errors::Disconnected)), EXIT_FAILED);
+        assert!(![EXIT_UNMUTED, EXIT_MUTED, EXIT_NO_SOURCE, EXIT_TIMEOUT].contains(&EXIT_FAILED));
+        assert_eq!(exit_code(Ok(EXIT_MUTED)), EXIT_MUTED);
+    }
+
+    #[test]
+    fn only_raising_the_volume_is_held_to_max() {
+        let up = VolumeAction::Up { step: 5 };
+        let down = VolumeAction::Down { step: 5 };
+        assert_eq!(target_percent(up, 98, 100), Some(100));
+        assert_eq!(target_percent(up, 150, 100), Some(150));
+        assert_eq!(target_percent(down, 150, 100), Some(145));
+        assert_eq!(target_percent(down, 3, 100), Some(0));
+        assert_eq!(
+            target_percent(VolumeAction::Set { percent: 120 }, 50, 100),
+            Some(100)
+        );
+        assert_eq!(target_percent(VolumeAction::Get, 50, 100), None);
+    }
+}
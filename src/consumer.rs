@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Condvar, Mutex};
+
+use clap::ValueEnum;
+use log::{info, warn};
+use tokio::sync::Notify;
+
+use crate::health::Health;
+
+/// Events that can wait on a network client by default
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// What to do for a network client whose queue of events is full
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Make room by dropping the oldest event still waiting
+    DropOldest,
+    /// Drop the new event, keeping those already waiting
+    DropNewest,
+    /// Hang up on the client
+    #[default]
+    Disconnect,
+}
+
+/// How much each network client may fall behind, and what happens when it does
+#[derive(Debug, Clone, Copy)]
+pub struct ConsumerPolicy {
+    pub drop: DropPolicy,
+    pub capacity: usize,
+}
+
+impl Default for ConsumerPolicy {
+    fn default() -> Self {
+        ConsumerPolicy {
+            drop: DropPolicy::default(),
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+struct Queued<T> {
+    items: VecDeque<T>,
+    /// Either end has gone
+    closed: bool,
+    dropped: u64,
+}
+
+struct Shared<T> {
+    queued: Mutex<Queued<T>>,
+    /// Wakes a thread waiting on the queue
+    ready: Condvar,
+    /// Wakes a task waiting on the queue, or the next one to wait if none is yet
+    woken: Notify,
+}
+
+impl<T> Shared<T> {
+    fn wake(&self) {
+        self.ready.notify_all();
+        self.woken.notify_one();
+    }
+
+    fn close(&self) {
+        self.queued.lock().unwrap().closed = true;
+        self.wake();
+    }
+}
+
+/// The output's end of one client's queue
+pub struct Publisher<T> {
+    shared: Arc<Shared<T>>,
+    policy: ConsumerPolicy,
+    /// Who the queue is for, in logs
+    client: String,
+    /// The listener's, which counts the events its clients miss
+    health: Health,
+}
+
+/// The end of a client's queue its own thread or task takes events from, to write out to it
+pub struct Subscription<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// A bounded queue of events for one client, so a client that stops reading only ever loses
+/// its own events rather than holding up the others
+pub fn queue<T>(
+    policy: ConsumerPolicy,
+    client: String,
+    health: &Health,
+) -> (Publisher<T>, Subscription<T>) {
+    let shared = Arc::new(Shared {
+        queued: Mutex::new(Queued {
+            items: VecDeque::new(),
+            closed: false,
+            dropped: 0,
+        }),
+        ready: Condvar::new(),
+        woken: Notify::new(),
+    });
+    (
+        Publisher {
+            shared: shared.clone(),
+            policy,
+            client,
+            health: health.clone(),
+        },
+        Subscription { shared },
+    )
+}
+
+impl<T> Publisher<T> {
+    /// Queue `item` for the client, returning whether it's still worth sending it more
+    pub fn send(&self, item: T) -> bool {
+        let mut queued = self.shared.queued.lock().unwrap();
+        if queued.closed {
+            return false;
+        }
+        if queued.items.len() >= self.policy.capacity {
+            if queued.dropped == 0 {
+                let action = match self.policy.drop {
+                    DropPolicy::DropOldest => "dropping its oldest events",
+                    DropPolicy::DropNewest => "dropping new events for it",
+                    DropPolicy::Disconnect => "hanging up on it",
+                };
+                warn!("{} is falling behind, {}", self.client, action);
+            }
+            match self.policy.drop {
+                DropPolicy::DropOldest => {
+                    queued.items.pop_front();
+                }
+                DropPolicy::DropNewest => {
+                    queued.dropped += 1;
+                    self.health.dropped(1);
+                    return true;
+                }
+                DropPolicy::Disconnect => {
+                    // Whatever was still waiting goes with it
+                    let dropped = queued.items.len() as u64 + 1;
+                    queued.dropped += dropped;
+                    self.health.dropped(dropped);
+                    queued.items.clear();
+                    queued.closed = true;
+                    self.shared.wake();
+                    return false;
+                }
+            }
+            queued.dropped += 1;
+            self.health.dropped(1);
+        }
+        queued.items.push_back(item);
+        self.shared.wake();
+        true
+    }
+}
+
+impl<T> Drop for Publisher<T> {
+    fn drop(&mut self) {
+        let dropped = self.shared.queued.lock().unwrap().dropped;
+        if dropped > 0 {
+            info!("{} missed {} events", self.client, dropped);
+        }
+        self.shared.close();
+    }
+}
+
+impl<T> Subscription<T> {
+    /// The next event, waiting for one, or `None` once the client was hung up on or the
+    /// listener is stopping
+    pub fn recv(&self) -> Option<T> {
+        let mut queued = self.shared.queued.lock().unwrap();
+        loop {
+            if let Some(item) = queued.items.pop_front() {
+                return Some(item);
+            }
+            if queued.closed {
+                return None;
+            }
+            queued = self.shared.ready.wait(queued).unwrap();
+        }
+    }
+
+    /// The next event, waiting for one without holding up the task's thread meanwhile, or
+    /// `None` once the client was hung up on or the listener is stopping
+    pub async fn recv_async(&self) -> Option<T> {
+        loop {
+            match self.try_recv() {
+                Ok(item) => return Some(item),
+                Err(TryRecvError::Disconnected) => return None,
+                // Anything queued from here on leaves a permit to wake straight back up with
+                Err(TryRecvError::Empty) => self.shared.woken.notified().await,
+            }
+        }
+    }
+
+    /// The next event, if one is waiting
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut queued = self.shared.queued.lock().unwrap();
+        match queued.items.pop_front() {
+            Some(item) => Ok(item),
+            None if queued.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_queue(drop: DropPolicy) -> (Publisher<u32>, Subscription<u32>, bool) {
+        let policy = ConsumerPolicy { drop, capacity: 2 };
+        let (publisher, subscription) = queue(policy, "test".to_string(), &Health::default());
+        assert!(publisher.send(1));
+        assert!(publisher.send(2));
+        let open = publisher.send(3);
+        (publisher, subscription, open)
+    }
+
+    fn taken(subscription: &Subscription<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| subscription.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn dropping_the_oldest_keeps_the_newest() {
+        let (_publisher, subscription, open) = full_queue(DropPolicy::DropOldest);
+        assert!(open);
+        assert_eq!(taken(&subscription), vec![2, 3]);
+    }
+
+    #[test]
+    fn dropping_the_newest_keeps_what_was_waiting() {
+        let (_publisher, subscription, open) = full_queue(DropPolicy::DropNewest);
+        assert!(open);
+        assert_eq!(taken(&subscription), vec![1, 2]);
+    }
+
+    #[test]
+    fn disconnecting_hangs_up_with_nothing_left() {
+        let (publisher, subscription, open) = full_queue(DropPolicy::Disconnect);
+        assert!(!open);
+        assert!(!publisher.send(4));
+        assert_eq!(subscription.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(subscription.recv(), None);
+    }
+
+    #[test]
+    fn tasks_are_woken_until_closed() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let health = Health::default();
+        let (publisher, subscription) =
+            queue(ConsumerPolicy::default(), "test".to_string(), &health);
+        let taken = runtime.block_on(async move {
+            let taken = tokio::spawn(async move {
+                let mut taken = vec![];
+                while let Some(item) = subscription.recv_async().await {
+                    taken.push(item);
+                }
+                taken
+            });
+            // Have the task waiting on an empty queue before anything's sent
+            tokio::task::yield_now().await;
+            for item in 1..=3 {
+                assert!(publisher.send(item));
+                tokio::task::yield_now().await;
+            }
+            drop(publisher);
+            taken.await.unwrap()
+        });
+        assert_eq!(taken, vec![1, 2, 3]);
+    }
+}
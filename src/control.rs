@@ -0,0 +1,76 @@
+use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::thread;
+
+use log::{debug, info};
+
+use crate::{ListenerRequest, RequestTx};
+
+/// Commands a running listener accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Flip the watched source's mute state
+    Toggle,
+    Mute,
+    Unmute,
+    /// Report the current state again
+    Status,
+    /// Report every tracked source
+    List,
+    Quit,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(command: &str) -> Result<Self, Self::Err> {
+        match command {
+            "toggle" => Ok(ControlCommand::Toggle),
+            "mute" => Ok(ControlCommand::Mute),
+            "unmute" => Ok(ControlCommand::Unmute),
+            "status" => Ok(ControlCommand::Status),
+            "list" => Ok(ControlCommand::List),
+            "quit" => Ok(ControlCommand::Quit),
+            _ => Err(format!("unknown command '{}'", command)),
+        }
+    }
+}
+
+/// Questions for the listener loop, answered with the events that make up the answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    /// The watched state, as reported on startup
+    Status,
+    /// Every tracked source
+    List,
+}
+
+/// Read a command per line from stdin, forwarding them to the main loop
+pub fn spawn_stdin_reader(tx: RequestTx) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("stdin-commands".to_string())
+        .spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        info!("Stopped reading commands: {}", err);
+                        return;
+                    }
+                };
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match line.parse() {
+                    Ok(command) => {
+                        if tx.send(ListenerRequest::Control(command, None)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => info!("Ignoring stdin: {}", err),
+                }
+            }
+            debug!("stdin closed, no more commands");
+        })
+}
@@ -0,0 +1,98 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use log::{debug, info};
+
+use crate::Errors;
+
+/// Process recorded in an existing pidfile, if it is still running
+fn running_pid(path: &Path) -> Option<libc::pid_t> {
+    let pid = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    // Signal 0 only checks the process is there, which it is if we just may not signal it
+    let alive = unsafe { libc::kill(pid, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    alive.then_some(pid)
+}
+
+fn fork() -> io::Result<libc::pid_t> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok(pid),
+    }
+}
+
+/// Records our pid for as long as we run, removing it on the way out
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Refuse to start a second copy, going by a pidfile left by the first
+    pub fn check(path: &Path) -> Result<(), Errors> {
+        match running_pid(path) {
+            Some(pid) => Err(Errors::ConfigError(format!(
+                "already running as pid {}, according to {}",
+                pid,
+                path.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn create(path: &Path) -> Result<Self, Errors> {
+        Self::check(path)?;
+        fs::write(path, format!("{}\n", process::id())).map_err(|err| {
+            Errors::ConfigError(format!("can't write pidfile {}: {}", path.display(), err))
+        })?;
+        Ok(Pidfile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            debug!("Failed to remove pidfile {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Carry on in the background: the parent exits, a new session leaves us without a controlling
+/// terminal, and the standard streams are swapped for /dev/null. The working directory stays,
+/// so relative paths from the command line still work.
+///
+/// Only the forking thread carries on in the child, so this has to happen before any others are
+/// started.
+pub fn daemonize(pidfile: Option<&Path>) -> Result<Option<Pidfile>, Errors> {
+    // While whoever started us can still see why not
+    if let Some(path) = pidfile {
+        Pidfile::check(path)?;
+    }
+    if fork()? != 0 {
+        process::exit(0);
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // No longer a session leader, so opening a terminal can't make it ours
+    if fork()? != 0 {
+        process::exit(0);
+    }
+    let pidfile = pidfile.map(Pidfile::create).transpose()?;
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    info!("Running in the background as pid {}", process::id());
+    Ok(pidfile)
+}
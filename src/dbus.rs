@@ -0,0 +1,187 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error};
+use zbus::blocking::{connection, object_server::InterfaceRef};
+use zbus::{fdo, interface, object_server::SignalEmitter};
+
+use crate::control::{ControlCommand, Query};
+use crate::output::{Event, EventKind, Output};
+use crate::{Errors, ListenerRequest, RequestTx};
+
+/// Well-known name the service is published under on the session bus
+pub const BUS_NAME: &str = "dev.martsa1.SourceListener";
+const OBJECT_PATH: &str = "/dev/martsa1/SourceListener";
+
+/// How long a method waits on the listener loop before giving up
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A source as `List` returns it: name, index, muted, volume and whether it's the watched one
+type SourceEntry = (String, u32, bool, u32, bool);
+
+/// The default source, as far as the service can tell from the events so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct ServiceState {
+    source: String,
+    muted: bool,
+}
+
+impl ServiceState {
+    /// Fold an event in, returning whether anything visible changed
+    fn update(&mut self, kind: &EventKind) -> bool {
+        let old = self.clone();
+        match kind {
+            EventKind::NoSource => *self = ServiceState::default(),
+            kind if kind.is_default() && !kind.is_sink() => {
+                if let Some(source) = kind.device() {
+                    self.source = source.to_string();
+                }
+                self.muted = kind.muted().unwrap_or(self.muted);
+            }
+            _ => {}
+        }
+        *self != old
+    }
+}
+
+struct Service {
+    state: Arc<Mutex<ServiceState>>,
+    /// Method calls are handed to the listener loop, which owns the connection to the server
+    tx: RequestTx,
+}
+
+#[interface(name = "dev.martsa1.SourceListener")]
+impl Service {
+    /// Flip the watched source's mute state
+    fn toggle(&self) -> fdo::Result<()> {
+        let (reply_tx, reply) = crossbeam_channel::bounded(1);
+        self.tx
+            .send(ListenerRequest::Control(
+                ControlCommand::Toggle,
+                Some(reply_tx),
+            ))
+            .map_err(|_| fdo::Error::Failed("the listener has stopped".to_string()))?;
+        reply
+            .recv_timeout(REPLY_TIMEOUT)
+            .map_err(|_| fdo::Error::Failed("the listener didn't answer".to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Every tracked source
+    fn list(&self) -> fdo::Result<Vec<SourceEntry>> {
+        let (reply_tx, reply) = crossbeam_channel::unbounded();
+        self.tx
+            .send(ListenerRequest::Query(Query::List, reply_tx))
+            .map_err(|_| fdo::Error::Failed("the listener has stopped".to_string()))?;
+        let events = reply
+            .recv_timeout(REPLY_TIMEOUT)
+            .map_err(|_| fdo::Error::Failed("the listener didn't answer".to_string()))?;
+        Ok(events
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                EventKind::SourceList { sources } => Some(sources),
+                _ => None,
+            })
+            .flatten()
+            .map(|src| {
+                (
+                    src.source.to_string(),
+                    src.index,
+                    src.muted,
+                    src.volume,
+                    src.default,
+                )
+            })
+            .collect())
+    }
+
+    /// Name of the watched source, empty if there's none
+    #[zbus(property)]
+    fn default_source(&self) -> String {
+        self.state.lock().unwrap().source.clone()
+    }
+
+    #[zbus(property)]
+    fn muted(&self) -> bool {
+        self.state.lock().unwrap().muted
+    }
+
+    /// The watched source or its mute state changed
+    #[zbus(signal)]
+    async fn state_changed(
+        emitter: &SignalEmitter<'_>,
+        source: &str,
+        muted: bool,
+    ) -> zbus::Result<()>;
+}
+
+/// Publishes the watched source's state on the session bus, taking `Toggle` and `List` calls
+/// for the listener loop. zbus serves the bus from its own thread, so this only updates the
+/// shared state and emits signals.
+pub struct DbusOutput {
+    state: Arc<Mutex<ServiceState>>,
+    iface: InterfaceRef<Service>,
+    // Dropping the connection releases the name
+    _connection: connection::Connection,
+}
+
+impl DbusOutput {
+    pub fn start(tx: RequestTx) -> Result<Self, Errors> {
+        let dbus_error = |err: zbus::Error| {
+            Errors::ConfigError(format!("failed to publish {} on D-Bus: {}", BUS_NAME, err))
+        };
+        let state = Arc::new(Mutex::new(ServiceState::default()));
+        let service = Service {
+            state: state.clone(),
+            tx,
+        };
+        let connection = connection::Builder::session()
+            .and_then(|builder| builder.name(BUS_NAME))
+            .and_then(|builder| builder.serve_at(OBJECT_PATH, service))
+            .and_then(|builder| builder.build())
+            .map_err(dbus_error)?;
+        let iface = connection
+            .object_server()
+            .interface::<_, Service>(OBJECT_PATH)
+            .map_err(dbus_error)?;
+        debug!("Published {} on the session bus", BUS_NAME);
+        Ok(DbusOutput {
+            state,
+            iface,
+            _connection: connection,
+        })
+    }
+
+    fn notify(&self, old: &ServiceState, new: &ServiceState) -> zbus::Result<()> {
+        let emitter = self.iface.signal_emitter();
+        let service = self.iface.get();
+        zbus::block_on(async {
+            if old.source != new.source {
+                service.default_source_changed(emitter).await?;
+            }
+            if old.muted != new.muted {
+                service.muted_changed(emitter).await?;
+            }
+            Service::state_changed(emitter, &new.source, new.muted).await
+        })
+    }
+}
+
+impl Output for DbusOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let (old, new) = {
+            let mut state = self.state.lock().unwrap();
+            let old = state.clone();
+            if !state.update(&event.kind) {
+                return Ok(());
+            }
+            (old, state.clone())
+        };
+        // Nobody listening on the bus is no reason to stop the listener
+        if let Err(err) = self.notify(&old, &new) {
+            error!("failed to signal D-Bus state change: {}", err);
+        }
+        Ok(())
+    }
+}
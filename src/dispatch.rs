@@ -0,0 +1,132 @@
+use std::io;
+use std::mem;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use log::{info, warn};
+use tracing::info_span;
+
+use crate::latency;
+use crate::output::{self, Event, Output};
+
+/// Events that can wait on an output before newer ones are dropped for it
+const QUEUE_CAPACITY: usize = 64;
+
+enum Worker {
+    Running {
+        queue: SyncSender<Event>,
+        thread: JoinHandle<Box<dyn Output + Send>>,
+    },
+    /// Back from its thread on the way out, for the last events, unless it never came back
+    Finished(Option<Box<dyn Output + Send>>),
+}
+
+/// Runs an output on a thread of its own, so one that blocks (e.g. writing into the pipe of a
+/// status bar that has died) holds up only its own events, not the event loop and with it the
+/// server's callbacks. Events it can't keep up with are dropped for it, and an error it runs
+/// into comes back from the next emit.
+pub struct DispatchedOutput {
+    name: &'static str,
+    worker: Worker,
+    failed: Arc<Mutex<Option<io::Error>>>,
+    /// Whether events are being dropped, so it's only warned about once per backlog
+    dropping: bool,
+}
+
+impl DispatchedOutput {
+    pub fn spawn(mut inner: Box<dyn Output + Send>) -> io::Result<Self> {
+        let name = inner.name();
+        let failed: Arc<Mutex<Option<io::Error>>> = Arc::default();
+        let (queue, events) = mpsc::sync_channel::<Event>(QUEUE_CAPACITY);
+        let thread = {
+            let failed = failed.clone();
+            thread::Builder::new()
+                .name(format!("output-{}", name))
+                .spawn(move || {
+                    for event in events {
+                        let _span = info_span!("output", output = name, event = event.kind.name())
+                            .entered();
+                        match inner.emit(&event) {
+                            Ok(()) => latency::written(name, &event),
+                            Err(err) => {
+                                failed.lock().unwrap().get_or_insert(err);
+                            }
+                        }
+                    }
+                    inner
+                })?
+        };
+        Ok(DispatchedOutput {
+            name,
+            worker: Worker::Running { queue, thread },
+            failed,
+            dropping: false,
+        })
+    }
+}
+
+impl Output for DispatchedOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if let Some(err) = self.failed.lock().unwrap().take() {
+            return Err(err);
+        }
+        let queue = match &mut self.worker {
+            Worker::Running { queue, .. } => queue,
+            Worker::Finished(Some(inner)) => return inner.emit(event),
+            Worker::Finished(None) => return Ok(()),
+        };
+        match queue.try_send(event.clone()) {
+            Ok(()) => {
+                if mem::take(&mut self.dropping) {
+                    info!("{} caught up again", self.name);
+                }
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                if !mem::replace(&mut self.dropping, true) {
+                    warn!("{} is falling behind, dropping events for it", self.name);
+                }
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("{} has stopped", self.name),
+            )),
+        }
+    }
+
+    fn finish(&mut self, deadline: Instant) -> io::Result<()> {
+        let thread = match mem::replace(&mut self.worker, Worker::Finished(None)) {
+            Worker::Running { queue, thread } => {
+                drop(queue);
+                thread
+            }
+            finished => {
+                self.worker = finished;
+                return Ok(());
+            }
+        };
+        match output::join_until(thread, deadline) {
+            Some(Ok(mut inner)) => {
+                let result = inner.finish(deadline);
+                self.worker = Worker::Finished(Some(inner));
+                result
+            }
+            Some(Err(_)) => Err(io::Error::other(format!("{} panicked", self.name))),
+            None => {
+                warn!("{} is still writing, leaving it behind", self.name);
+                Ok(())
+            }
+        }
+    }
+
+    fn writes_later(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
@@ -0,0 +1,180 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use log::debug;
+use pulse::context::{subscribe::InterestMaskSet, Context};
+
+use crate::lock::MainloopGuard;
+use crate::mainloop::Mainloop;
+use crate::{
+    callback, connect_to_server, get_default_source_index, get_sources, ConnectOptions, Errors,
+    CBRX, CBTX, COOKIE_LEN,
+};
+
+enum Finding {
+    Ok(String),
+    Warn(String, &'static str),
+    Fail(String, &'static str),
+}
+
+impl Finding {
+    fn print(&self) {
+        match self {
+            Finding::Ok(what) => println!("[ok]   {}", what),
+            Finding::Warn(what, advice) => println!("[warn] {}\n       -> {}", what, advice),
+            Finding::Fail(what, advice) => println!("[fail] {}\n       -> {}", what, advice),
+        }
+    }
+}
+
+fn check_socket() -> Finding {
+    if let Ok(server) = env::var("PULSE_SERVER") {
+        return Finding::Ok(format!("server set by PULSE_SERVER: {}", server));
+    }
+
+    let mut candidates = vec![];
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        candidates.push(PathBuf::from(runtime_dir).join("pulse/native"));
+    }
+    // System-wide daemons
+    candidates.push(PathBuf::from("/run/pulse/native"));
+    candidates.push(PathBuf::from("/var/run/pulse/native"));
+
+    match candidates.iter().find(|path| path.exists()) {
+        Some(path) => Finding::Ok(format!("found server socket at {}", path.display())),
+        None => Finding::Fail(
+            format!(
+                "no server socket at any of: {}",
+                candidates
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            "start PulseAudio (or pipewire-pulse), or point PULSE_SERVER at it",
+        ),
+    }
+}
+
+fn check_cookie() -> Finding {
+    let mut candidates = vec![];
+    if let Some(cookie) = env::var_os("PULSE_COOKIE") {
+        candidates.push(PathBuf::from(cookie));
+    }
+    if let Some(config_dir) = env::var_os("XDG_CONFIG_HOME") {
+        candidates.push(PathBuf::from(config_dir).join("pulse/cookie"));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        candidates.push(PathBuf::from(&home).join(".config/pulse/cookie"));
+        candidates.push(PathBuf::from(&home).join(".pulse-cookie"));
+    }
+
+    for path in &candidates {
+        match fs::metadata(path) {
+            Ok(meta) if meta.len() == COOKIE_LEN => {
+                return Finding::Ok(format!("auth cookie at {}", path.display()));
+            }
+            Ok(meta) => {
+                return Finding::Warn(
+                    format!(
+                        "auth cookie at {} is {} bytes, expected {}",
+                        path.display(),
+                        meta.len(),
+                        COOKIE_LEN
+                    ),
+                    "delete it and restart the server so a fresh one is written",
+                );
+            }
+            Err(err) => debug!("no cookie at {}: {}", path.display(), err),
+        }
+    }
+    // Local servers usually authenticate by socket credentials instead, so this isn't fatal
+    Finding::Warn(
+        "no auth cookie found".to_string(),
+        "only a problem for remote servers or if connecting fails with 'Access denied'",
+    )
+}
+
+fn check_subscribe(context: &mut Context, mainloop: &mut Mainloop) -> Result<Finding, Errors> {
+    let guard = MainloopGuard::lock(mainloop);
+    let (tx, rx) = crossbeam_channel::unbounded();
+    context.subscribe(
+        InterestMaskSet::SOURCE | InterestMaskSet::SERVER,
+        move |success| {
+            callback::reply(&tx, success);
+        },
+    );
+    drop(guard);
+
+    Ok(match mainloop.recv(&rx)? {
+        true => Finding::Ok("subscribed to source and server events".to_string()),
+        false => Finding::Fail(
+            "the server refused the event subscription".to_string(),
+            "the server may be a restricted (e.g. sandboxed) connection without introspection",
+        ),
+    })
+}
+
+fn check_default_source(context: &mut Context, mainloop: &mut Mainloop) -> Result<Finding, Errors> {
+    let sources = get_sources(context, mainloop)?;
+    let default_source =
+        get_default_source_index(mainloop, context, &sources)?.and_then(|idx| sources.get(&idx));
+
+    Ok(match default_source {
+        None if sources.is_empty() => Finding::Fail(
+            "the server has no sources at all".to_string(),
+            "check the microphone is plugged in and its card profile includes an input",
+        ),
+        None => Finding::Fail(
+            format!("none of the {} sources is the default", sources.len()),
+            "pick one with `pulse-source-listener set-default <name>`",
+        ),
+        Some(src) if src.monitor_of_sink.is_some() => Finding::Warn(
+            format!("the default source '{}' is a sink monitor", src.name),
+            "mute state will follow the monitor, not a microphone; set a real input as default",
+        ),
+        Some(src) => Finding::Ok(format!("default source is '{}'", src.name)),
+    })
+}
+
+/// Run every check, printing findings as we go. Exits with 1 if any failed, leaving
+/// `EXIT_FAILED` for checking itself having failed.
+pub fn doctor(
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    connect: &ConnectOptions,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<i32, Errors> {
+    let mut findings = vec![check_socket(), check_cookie()];
+    for finding in &findings {
+        finding.print();
+    }
+
+    let connected = match connect_to_server(context, mainloop, connect, tx, rx) {
+        Ok(()) => Finding::Ok("connected to the server".to_string()),
+        Err(Errors::Shutdown) => return Err(Errors::Shutdown),
+        Err(err) => Finding::Fail(
+            format!("could not connect: {} ({})", err, context.errno()),
+            "'Access denied' points at the cookie, 'Connection refused' at the socket",
+        ),
+    };
+    connected.print();
+    let reachable = matches!(connected, Finding::Ok(_));
+    findings.push(connected);
+
+    // Everything else needs a connection
+    if reachable {
+        for check in [check_subscribe, check_default_source] {
+            let finding = check(context, mainloop)?;
+            finding.print();
+            findings.push(finding);
+        }
+    }
+
+    let failed = findings
+        .iter()
+        .any(|finding| matches!(finding, Finding::Fail(..)));
+    Ok(i32::from(failed))
+}
@@ -0,0 +1,39 @@
+use regex::Regex;
+
+use crate::SourceDatum;
+
+/// Which sources the listener considers at all. Filtered out sources are never tracked, never
+/// resolved as the default, and never produce events.
+#[derive(Debug, Clone, Default)]
+pub struct SourceFilter {
+    /// If non-empty, sources have to match one of these
+    pub include: Vec<Regex>,
+    /// Sources matching any of these are dropped, even if included
+    pub exclude: Vec<Regex>,
+    /// Monitors of sinks aren't microphones, so they're ignored unless asked for
+    pub include_monitors: bool,
+    /// Drop virtual and network sources
+    pub only_hardware: bool,
+}
+
+impl SourceFilter {
+    /// Patterns are matched against both the name and description of sources
+    pub fn allows(&self, source: &SourceDatum) -> bool {
+        if source.monitor_of_sink.is_some() && !self.include_monitors {
+            return false;
+        }
+        if self.only_hardware && !source.is_hardware() {
+            return false;
+        }
+
+        let matches = |regex: &Regex| {
+            regex.is_match(&source.name)
+                || source
+                    .description
+                    .as_deref()
+                    .is_some_and(|desc| regex.is_match(desc))
+        };
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::Errors;
+
+/// Exit codes of `health`, for systemd's ExecStartPre= and container healthchecks
+const EXIT_HEALTHY: i32 = 0;
+const EXIT_UNHEALTHY: i32 = 1;
+/// Nothing answered on the socket, so there's no listener to be healthy
+const EXIT_UNREACHABLE: i32 = 2;
+
+/// How long `health` waits on the listener to answer
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the connection to one server is doing
+#[derive(Debug, Default)]
+struct ServerHealth {
+    connected: AtomicBool,
+    subscribed: AtomicBool,
+    /// When the state was last brought up to date
+    refreshed: Mutex<Option<Instant>>,
+}
+
+/// How one listener is doing, kept up to date by its loop. Clones share it, and report on the
+/// same server.
+#[derive(Debug, Clone)]
+pub struct Health {
+    server: Arc<ServerHealth>,
+    /// Every server the listener follows, this one included
+    servers: Arc<Mutex<Vec<Arc<ServerHealth>>>>,
+    /// Events network clients missed by falling behind
+    dropped: Arc<AtomicU64>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        let server = Arc::<ServerHealth>::default();
+        Health {
+            servers: Arc::new(Mutex::new(vec![server.clone()])),
+            server,
+            dropped: Arc::default(),
+        }
+    }
+}
+
+impl Health {
+    /// Report on one more server followed by the same listener
+    pub fn another_server(&self) -> Self {
+        let server = Arc::<ServerHealth>::default();
+        self.servers.lock().unwrap().push(server.clone());
+        Health {
+            server,
+            servers: self.servers.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    /// The connection to the server was made or lost, losing the subscription with it
+    pub fn connected(&self, connected: bool) {
+        self.server.connected.store(connected, Ordering::Relaxed);
+        if !connected {
+            self.server.subscribed.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn subscribed(&self) {
+        self.server.subscribed.store(true, Ordering::Relaxed);
+    }
+
+    /// A network client missed `count` events
+    pub fn dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn refreshed(&self) {
+        *self.server.refreshed.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// How the listener is doing, answered without involving its loop so a stuck loop can't
+    /// stop it being reported. It's only as healthy as the worst off of its servers.
+    pub fn report(&self) -> HealthReport {
+        let servers = self.servers.lock().unwrap();
+        let connected = servers
+            .iter()
+            .all(|server| server.connected.load(Ordering::Relaxed));
+        let subscribed = servers
+            .iter()
+            .all(|server| server.subscribed.load(Ordering::Relaxed));
+        let state_age = servers
+            .iter()
+            .map(|server| *server.refreshed.lock().unwrap())
+            .collect::<Option<Vec<_>>>()
+            .map(|refreshed| {
+                refreshed
+                    .iter()
+                    .map(|refreshed| refreshed.elapsed().as_secs_f64())
+                    .fold(0.0, f64::max)
+            });
+        HealthReport {
+            healthy: connected && subscribed,
+            connected,
+            subscribed,
+            state_age,
+            dropped_events: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How the listener is doing, as answered to `health`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Connected and subscribed
+    pub healthy: bool,
+    pub connected: bool,
+    pub subscribed: bool,
+    /// Seconds since the listener's state was last brought up to date, if it ever has been
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_age: Option<f64>,
+    /// Events network clients missed by falling behind, since the listener started
+    #[serde(default)]
+    pub dropped_events: u64,
+}
+
+/// What the listener sends back, of which only the report matters here
+#[derive(Deserialize)]
+struct Reply {
+    health: Option<HealthReport>,
+}
+
+fn ask(path: &Path) -> io::Result<HealthReport> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(b"health\n")?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let reply: Reply = serde_json::from_str(&line)?;
+    reply
+        .health
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no health report in reply"))
+}
+
+/// Ask the listener serving on `path` how it's doing, printing its report as JSON and returning
+/// the exit code. A state older than `max_age` seconds counts as unhealthy.
+pub fn check(path: &Path, max_age: Option<u64>) -> Result<i32, Errors> {
+    let report = match ask(path) {
+        Ok(report) => report,
+        Err(err) => {
+            error!("No answer from a listener on {}: {}", path.display(), err);
+            return Ok(EXIT_UNREACHABLE);
+        }
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&report).map_err(io::Error::from)?
+    );
+    let fresh = match (max_age, report.state_age) {
+        (None, _) => true,
+        (Some(max_age), Some(age)) => age <= max_age as f64,
+        (Some(_), None) => false,
+    };
+    Ok(if report.healthy && fresh {
+        EXIT_HEALTHY
+    } else {
+        EXIT_UNHEALTHY
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_server_has_to_be_healthy() {
+        let first = Health::default();
+        let second = first.another_server();
+        first.connected(true);
+        first.subscribed();
+        first.refreshed();
+        assert!(!first.report().healthy);
+        assert_eq!(first.report().state_age, None);
+
+        second.connected(true);
+        second.subscribed();
+        second.refreshed();
+        assert!(second.report().healthy);
+        assert!(first.report().state_age.is_some());
+
+        Health::default().dropped(2);
+        assert_eq!(first.report().dropped_events, 0);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use log::{info, trace};
+use pulse::mainloop::signal::Event as SignalEvent;
+
+use crate::output::{Event, Output, StateTexts};
+
+/// Signals the history can be dumped on
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpSignal {
+    Usr1,
+    Usr2,
+}
+
+impl DumpSignal {
+    fn number(self) -> i32 {
+        match self {
+            DumpSignal::Usr1 => 10,
+            DumpSignal::Usr2 => 12,
+        }
+    }
+}
+
+/// The most recent events, oldest first. Clones share the same buffer, so the signal handler can
+/// read what the listener records.
+#[derive(Debug, Clone)]
+pub struct History {
+    events: Arc<Mutex<VecDeque<Event>>>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Write one line per remembered event
+    pub fn dump(&self, writer: &mut impl Write, texts: &StateTexts) -> io::Result<()> {
+        let events = self.events.lock().unwrap();
+        for event in events.iter() {
+            writeln!(
+                writer,
+                "{} {} {}",
+                event.timestamp.to_rfc3339(),
+                event.seq,
+                texts.plain_line(&event.kind)
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+impl Output for History {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if self.capacity == 0 || event.snapshot {
+            return Ok(());
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        Ok(())
+    }
+}
+
+/// Dump the history to stderr, which unlike stdout isn't being consumed by a status bar, whenever
+/// `signal` is received
+pub fn bind_dump_signal(signal: DumpSignal, history: History, texts: StateTexts) -> SignalEvent {
+    trace!("configuring history dump on {:?}", signal);
+    SignalEvent::new(signal.number(), move |_| {
+        info!("Dumping event history");
+        if let Err(err) = history.dump(&mut io::stderr().lock(), &texts) {
+            info!("Failed to dump event history: {}", err);
+        }
+    })
+}
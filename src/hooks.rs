@@ -0,0 +1,539 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use log::{debug, error, info, trace};
+use tracing::{info_span, Span};
+
+use crate::output::{self, Event, EventKind, Output};
+use crate::{ListenerRequest, RequestTx};
+
+/// How often a running hook is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Period `max_rate` is counted over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Longest wait between retries, however many there have been
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Delay before retrying after `attempts` failures, doubling each time up to `MAX_BACKOFF`
+pub fn backoff(retry_delay: Duration, attempts: u32) -> Duration {
+    retry_delay
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+/// What to do when a hook fires while its previous invocation is still running
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overlap {
+    /// Run it once the previous one is done
+    #[default]
+    Queue,
+    /// Don't run it at all
+    Skip,
+    /// Kill the previous one and run it straight away
+    Kill,
+}
+
+/// How each hook's invocations are run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HookPolicy {
+    /// Give up waiting on a hook after this long
+    pub timeout: Option<Duration>,
+    /// Kill hooks that time out, rather than waiting for them to finish before the next runs
+    pub kill_on_timeout: bool,
+    pub overlap: Overlap,
+    /// How many times to retry a hook that fails or times out
+    pub retries: u32,
+    /// Delay before the first retry, doubling for each one after
+    pub retry_delay: Duration,
+    /// Only run a hook once its events have stopped coming for this long
+    pub debounce: Option<Duration>,
+    /// Most times a hook may run per minute
+    pub max_rate: Option<u32>,
+}
+
+/// A hook that kept failing, even after retries
+#[derive(Debug, Clone)]
+pub struct HookFailure {
+    pub command: String,
+    /// The event the hook was run for
+    pub event: &'static str,
+    pub attempts: u32,
+    pub reason: String,
+}
+
+/// Which events a hook runs for. The mute triggers follow the watched source, or every source
+/// combined when aggregating, rather than any extra ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Any mute change, muted or not
+    MuteChange,
+    Mute,
+    Unmute,
+    DefaultChange,
+    SourceNew,
+    SourceRemoved,
+}
+
+impl Trigger {
+    fn matches(self, kind: &EventKind) -> bool {
+        let muted = match kind {
+            EventKind::Mute {
+                device,
+                default: true,
+                ..
+            } => Some(device.muted),
+            EventKind::Aggregate { muted, .. } => Some(*muted),
+            _ => None,
+        };
+        match self {
+            Trigger::MuteChange => muted.is_some(),
+            Trigger::Mute => muted == Some(true),
+            Trigger::Unmute => muted == Some(false),
+            Trigger::DefaultChange => matches!(kind, EventKind::DefaultChanged { .. }),
+            Trigger::SourceNew => matches!(kind, EventKind::SourceAdded { .. }),
+            Trigger::SourceRemoved => matches!(kind, EventKind::SourceRemoved { .. }),
+        }
+    }
+}
+
+/// A hook command to run, with the event it's for described in its environment
+#[derive(Debug)]
+struct Job {
+    command: String,
+    event: &'static str,
+    env: Vec<(&'static str, String)>,
+    /// The whole event, as the JSON output would print it, for the hook's stdin
+    input: Vec<u8>,
+    /// Traces the job from being queued until its last attempt ends
+    span: Span,
+}
+
+impl Job {
+    fn new(command: &str, event: &Event) -> io::Result<Self> {
+        let kind = &event.kind;
+        let mut env = vec![("PSL_EVENT", kind.name().to_string())];
+        if let Some(device) = kind.device() {
+            env.push(("PSL_SOURCE_NAME", device.to_string()));
+        }
+        if let Some(index) = kind.index() {
+            env.push(("PSL_SOURCE_INDEX", index.to_string()));
+        }
+        if let Some(muted) = kind.muted() {
+            env.push(("PSL_MUTED", u8::from(muted).to_string()));
+        }
+        let mut input = serde_json::to_vec(event)?;
+        input.push(b'\n');
+        Ok(Job {
+            command: command.to_string(),
+            event: kind.name(),
+            env,
+            input,
+            span: info_span!("hook", command, event = kind.name()),
+        })
+    }
+
+    fn spawn(&self) -> io::Result<Child> {
+        debug!("running hook '{}'", self.command);
+        let mut child = spawn_shell(&self.command, &self.env, Stdio::piped())?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A hook that doesn't read it mustn't hold up its timeout, or the worker
+            let (command, input) = (self.command.clone(), self.input.clone());
+            thread::spawn(move || {
+                // Hooks that don't care about their input may exit without reading it
+                if let Err(err) = stdin.write_all(&input) {
+                    debug!("couldn't write event to hook '{}': {}", command, err);
+                }
+            });
+        }
+        Ok(child)
+    }
+}
+
+/// Start a shell command with extra environment variables
+pub fn spawn_shell(command: &str, env: &[(&str, String)], stdin: Stdio) -> io::Result<Child> {
+    // Commands mustn't write into whatever consumes stdout, e.g. a status bar
+    Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().cloned())
+        .stdin(stdin)
+        .stdout(io::stderr())
+        .spawn()
+}
+
+/// Wait on a command in the background, so it doesn't linger as a zombie once it exits
+pub fn reap(mut child: Child) {
+    thread::spawn(move || child.wait());
+}
+
+/// How an attempt at running a job ended
+enum Outcome {
+    Done,
+    Failed(String),
+    /// A newer job took over, with the `kill` overlap policy
+    Superseded,
+}
+
+/// Runs one hook's jobs as they come in, applying its policy
+struct Worker {
+    jobs: Receiver<Job>,
+    pending: VecDeque<Job>,
+    policy: HookPolicy,
+    /// Where to report hooks that keep failing, if anywhere
+    failures: Option<RequestTx>,
+    /// No more jobs are coming, though the ones in hand still get run
+    closed: bool,
+    /// The last attempt, still running after timing out, which the next waits for
+    timed_out: Option<Child>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        loop {
+            let job = match self.pending.pop_front() {
+                Some(job) => job,
+                None => match self.jobs.recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                },
+            };
+            let _span = job.span.enter();
+
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                match self.attempt(&job) {
+                    Outcome::Done | Outcome::Superseded => break,
+                    Outcome::Failed(reason) if attempts > self.policy.retries => {
+                        self.report_failure(&job, attempts, reason);
+                        break;
+                    }
+                    Outcome::Failed(reason) => {
+                        let delay = backoff(self.policy.retry_delay, attempts);
+                        info!(
+                            "hook '{}' failed ({}), retrying in {:?}",
+                            job.command, reason, delay
+                        );
+                        if self.receive_for(delay).is_some() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn attempt(&mut self, job: &Job) -> Outcome {
+        if let Some(outcome) = self.wait_for_timed_out(job) {
+            return outcome;
+        }
+        let mut child = match job.spawn() {
+            Ok(child) => child,
+            Err(err) => return Outcome::Failed(format!("couldn't start: {}", err)),
+        };
+
+        let started = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => return Outcome::Done,
+                Ok(Some(status)) => return Outcome::Failed(status.to_string()),
+                Ok(None) => {}
+                Err(err) => return Outcome::Failed(format!("couldn't wait on it: {}", err)),
+            }
+
+            if self
+                .policy
+                .timeout
+                .is_some_and(|timeout| started.elapsed() >= timeout)
+            {
+                if self.policy.kill_on_timeout {
+                    let _ = child.kill();
+                    reap(child);
+                } else {
+                    self.timed_out = Some(child);
+                }
+                return Outcome::Failed("timed out".to_string());
+            }
+
+            if let Some(outcome) = self.receive_for(POLL_INTERVAL) {
+                if let Outcome::Superseded = outcome {
+                    debug!("hook '{}' still running, killing it", job.command);
+                    let _ = child.kill();
+                }
+                reap(child);
+                return outcome;
+            }
+        }
+    }
+
+    /// Wait for an attempt that timed out to exit, so no two run at once, taking in jobs
+    /// meanwhile. Gives the outcome for `job` if a newer one supersedes it before then.
+    fn wait_for_timed_out(&mut self, job: &Job) -> Option<Outcome> {
+        let mut child = self.timed_out.take()?;
+        loop {
+            if !matches!(child.try_wait(), Ok(None)) {
+                return None;
+            }
+            if let Some(outcome) = self.receive_for(POLL_INTERVAL) {
+                if let Outcome::Superseded = outcome {
+                    debug!("hook '{}' still running, killing it", job.command);
+                    let _ = child.kill();
+                }
+                reap(child);
+                return Some(outcome);
+            }
+        }
+    }
+
+    /// Take in jobs arriving within `duration`, stopping early if one supersedes the current job
+    fn receive_for(&mut self, duration: Duration) -> Option<Outcome> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if self.closed {
+                thread::sleep(remaining);
+                return None;
+            }
+            match self.jobs.recv_timeout(remaining) {
+                Ok(next) => match self.policy.overlap {
+                    Overlap::Queue => self.pending.push_back(next),
+                    Overlap::Skip => debug!("hook '{}' busy, skipping", next.command),
+                    Overlap::Kill => {
+                        self.pending.clear();
+                        self.pending.push_back(next);
+                        return Some(Outcome::Superseded);
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => return None,
+                // The listener is shutting down, and waits on the jobs in hand up to a deadline
+                Err(RecvTimeoutError::Disconnected) => self.closed = true,
+            }
+        }
+    }
+
+    fn report_failure(&self, job: &Job, attempts: u32, reason: String) {
+        error!(
+            "hook '{}' for {} failed after {} attempt(s): {}",
+            job.command, job.event, attempts, reason
+        );
+        if let Some(failures) = &self.failures {
+            let failure = HookFailure {
+                command: job.command.clone(),
+                event: job.event,
+                attempts,
+                reason,
+            };
+            // Nobody to tell if the listener has stopped
+            let _ = failures.send(ListenerRequest::HookFailed(failure));
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Some(child) = self.timed_out.take() {
+            reap(child);
+        }
+    }
+}
+
+/// Hold jobs back until they settle and fit within the rate limit, passing on only the latest.
+/// Later jobs carry the newer state, so dropping earlier ones loses nothing that matters.
+fn throttle(input: Receiver<Job>, output: Sender<Job>, policy: HookPolicy) {
+    let mut recent_runs: VecDeque<Instant> = VecDeque::new();
+    while let Ok(mut job) = input.recv() {
+        if let Some(quiet) = policy.debounce {
+            // Also ends when shutting down, but the latest job still gets passed on
+            while let Ok(newer) = input.recv_timeout(quiet) {
+                trace!("debouncing hook '{}'", job.command);
+                job = newer;
+            }
+        }
+
+        if let Some(max_rate) = policy.max_rate {
+            loop {
+                let now = Instant::now();
+                while recent_runs
+                    .front()
+                    .is_some_and(|run| now.duration_since(*run) >= RATE_WINDOW)
+                {
+                    recent_runs.pop_front();
+                }
+                let Some(oldest) = recent_runs.front() else {
+                    break;
+                };
+                if recent_runs.len() < max_rate as usize {
+                    break;
+                }
+                let wait = RATE_WINDOW - now.duration_since(*oldest);
+                debug!("hook '{}' rate limited for {:?}", job.command, wait);
+                match input.recv_timeout(wait) {
+                    Ok(newer) => job = newer,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => thread::sleep(wait),
+                }
+            }
+            recent_runs.push_back(Instant::now());
+        }
+
+        if output.send(job).is_err() {
+            return;
+        }
+    }
+}
+
+/// A command run for every event matching its trigger. Each hook runs on its own thread, so a
+/// slow hook can delay its own later invocations but never the event loop or other hooks.
+struct Hook {
+    trigger: Trigger,
+    command: String,
+    jobs: Sender<Job>,
+    worker: JoinHandle<()>,
+}
+
+impl Hook {
+    fn new(
+        trigger: Trigger,
+        command: String,
+        policy: HookPolicy,
+        failures: Option<RequestTx>,
+    ) -> Self {
+        let (worker_jobs, rx) = mpsc::channel();
+        let worker = Worker {
+            jobs: rx,
+            pending: VecDeque::new(),
+            policy,
+            failures,
+            closed: false,
+            timed_out: None,
+        };
+        let worker = thread::spawn(move || worker.run());
+
+        let jobs = if policy.debounce.is_some() || policy.max_rate.is_some() {
+            let (jobs, rx) = mpsc::channel();
+            thread::spawn(move || throttle(rx, worker_jobs, policy));
+            jobs
+        } else {
+            worker_jobs
+        };
+        Hook {
+            trigger,
+            command,
+            jobs,
+            worker,
+        }
+    }
+}
+
+/// Runs hook commands for the events they're triggered by
+pub struct HookRunner {
+    hooks: Vec<Hook>,
+}
+
+impl HookRunner {
+    /// Failures are sent to `failures` to be reported as events, otherwise they're only logged
+    pub fn new(
+        hooks: Vec<(Trigger, String)>,
+        policy: HookPolicy,
+        failures: Option<RequestTx>,
+    ) -> Self {
+        HookRunner {
+            hooks: hooks
+                .into_iter()
+                .map(|(trigger, command)| Hook::new(trigger, command, policy, failures.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Output for HookRunner {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if event.snapshot {
+            return Ok(());
+        }
+        for hook in &self.hooks {
+            if !hook.trigger.matches(&event.kind) {
+                continue;
+            }
+            hook.jobs
+                .send(Job::new(&hook.command, event)?)
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "hook runner has stopped")
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Let every hook run what it has queued, leaving those still going at `deadline` behind
+    fn finish(&mut self, deadline: Instant) -> io::Result<()> {
+        for Hook {
+            command,
+            jobs,
+            worker,
+            ..
+        } in self.hooks.drain(..)
+        {
+            // Without more jobs coming, the worker stops once it's through the ones it has
+            drop(jobs);
+            if output::join_until(worker, deadline).is_none() {
+                info!("hook '{}' still running at shutdown, leaving it", command);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::DeviceState;
+
+    fn mute(muted: bool, default: bool) -> EventKind {
+        EventKind::Mute {
+            source: "mic".into(),
+            device: DeviceState {
+                index: 1,
+                muted,
+                volume: 100,
+            },
+            default,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let second = Duration::from_secs(1);
+        assert_eq!(backoff(second, 1), second);
+        assert_eq!(backoff(second, 3), 4 * second);
+        assert_eq!(backoff(second, 40), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::MAX, 2), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::ZERO, 40), Duration::ZERO);
+    }
+
+    #[test]
+    fn mute_triggers_follow_the_watched_source() {
+        assert!(Trigger::Mute.matches(&mute(true, true)));
+        assert!(!Trigger::Mute.matches(&mute(false, true)));
+        assert!(Trigger::Unmute.matches(&mute(false, true)));
+        assert!(!Trigger::MuteChange.matches(&mute(true, false)));
+    }
+
+    #[test]
+    fn mute_triggers_follow_the_aggregate() {
+        let aggregate = EventKind::Aggregate {
+            muted: true,
+            live: vec![],
+            sources: 2,
+        };
+        assert!(Trigger::MuteChange.matches(&aggregate));
+        assert!(Trigger::Mute.matches(&aggregate));
+        assert!(!Trigger::Unmute.matches(&aggregate));
+    }
+}
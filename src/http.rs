@@ -0,0 +1,232 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::consumer::{self, ConsumerPolicy, Publisher};
+use crate::control::Query;
+use crate::health::Health;
+use crate::output::{Event, Output};
+use crate::runtime::{self, AcceptTask};
+use crate::socket::Request;
+use crate::{Errors, RequestTx};
+
+/// How long an event stream gets to take an event before it's given up on, so a stuck client
+/// doesn't keep its task forever
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client gets to send its request before it's hung up on
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request or header line taken
+const MAX_LINE: usize = 8 * 1024;
+
+/// Most headers taken with a request
+const MAX_HEADERS: usize = 100;
+
+/// Each event stream's queue of frames, written out by its own task
+type Streams = Arc<Mutex<Vec<Publisher<Arc<str>>>>>;
+
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await
+}
+
+/// Read one line of a request into `line`, refusing any too long to be real
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    line: &mut String,
+) -> io::Result<usize> {
+    line.clear();
+    let read = reader.take(MAX_LINE as u64).read_line(line).await?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long",
+        ));
+    }
+    Ok(read)
+}
+
+/// Read a request's method and path. Headers don't change anything here, but have to be read
+/// before answering. A client that's slow to send them, or sends too many, is given up on.
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<(String, String)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    let mut header = String::new();
+    let read = async {
+        read_line(&mut reader, &mut request_line).await?;
+        for _ in 0..=MAX_HEADERS {
+            if read_line(&mut reader, &mut header).await? <= 2 {
+                return Ok(());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many headers",
+        ))
+    };
+    time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let mut parts = request_line.split_whitespace();
+    Ok((
+        parts.next().unwrap_or_default().to_string(),
+        parts.next().unwrap_or_default().to_string(),
+    ))
+}
+
+/// Answer one request. Event streams are fed from `streams` until the client goes, the rest are
+/// closed once answered.
+async fn serve_client(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    tx: RequestTx,
+    streams: Streams,
+    policy: ConsumerPolicy,
+    health: Health,
+) -> io::Result<()> {
+    let (method, path) = read_request(&mut stream).await?;
+    match (method.as_str(), path.as_str()) {
+        ("GET", "/events") => {
+            stream.write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+            ).await?;
+            let (publisher, frames) =
+                consumer::queue(policy, format!("event stream {}", peer), &health);
+            streams.lock().unwrap().push(publisher);
+            while let Some(frame) = frames.recv_async().await {
+                time::timeout(STREAM_TIMEOUT, stream.write_all(frame.as_bytes()))
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            }
+            Ok(())
+        }
+        ("GET", "/state") => {
+            let reply = runtime::answer(Request::Query(Query::Status), tx, health).await?;
+            let body = serde_json::to_vec(&reply)?;
+            let status = if reply.ok {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            respond(&mut stream, status, "application/json", &body).await
+        }
+        ("GET", "/healthz") => {
+            let report = health.report();
+            let body = serde_json::to_vec(&report)?;
+            let status = if report.healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            respond(&mut stream, status, "application/json", &body).await
+        }
+        ("GET", _) => respond(&mut stream, "404 Not Found", "text/plain", b"not found\n").await,
+        _ => {
+            respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                b"only GET is supported\n",
+            )
+            .await
+        }
+    }
+}
+
+/// Serves `GET /events`, streaming every event as a server-sent event named after its kind with
+/// the JSON output as data, `GET /state`, answering like `status` on `--socket`, and
+/// `GET /healthz`, answering like `health` with 503 when unhealthy
+pub struct HttpOutput {
+    streams: Streams,
+    _accepting: AcceptTask,
+}
+
+impl HttpOutput {
+    pub fn start(
+        addr: SocketAddr,
+        tx: RequestTx,
+        policy: ConsumerPolicy,
+        health: Health,
+    ) -> Result<Self, Errors> {
+        let (runtime, listener) = runtime::listen(addr)?;
+        let streams = Streams::default();
+        let accepted = streams.clone();
+        let accepting = runtime::serve(&runtime, listener, "HTTP", move |stream, peer| {
+            serve_client(
+                stream,
+                peer,
+                tx.clone(),
+                accepted.clone(),
+                policy,
+                health.clone(),
+            )
+        });
+        debug!("Serving HTTP on {}", addr);
+        Ok(HttpOutput {
+            streams,
+            _accepting: accepting,
+        })
+    }
+}
+
+impl Output for HttpOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let frame: Arc<str> = format!(
+            "event: {}\ndata: {}\n\n",
+            event.kind.name(),
+            serde_json::to_string(event)?
+        )
+        .into();
+        // Clients that have gone away, or were hung up on for falling behind, are dropped
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|stream| stream.send(frame.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(request: &[u8]) -> io::Result<(String, String)> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(read_request(&mut &request[..]))
+    }
+
+    #[test]
+    fn requests_are_bounded() {
+        let request = read(b"GET /state HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(request, ("GET".to_string(), "/state".to_string()));
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert!(read(long.as_bytes()).is_err());
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: y\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(read(many.as_bytes()).is_err());
+    }
+}
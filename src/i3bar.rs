@@ -0,0 +1,155 @@
+use std::io::{self, BufRead, Write};
+use std::thread;
+
+use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::output::{Event, Output, StateTexts};
+use crate::{DeviceKind, ListenerRequest, RequestTx};
+
+/// Block name we report under, and filter click events by
+const BLOCK_NAME: &str = "pulse-source-listener";
+
+#[derive(Serialize)]
+struct Header {
+    version: u32,
+    click_events: bool,
+}
+
+#[derive(Serialize)]
+struct Block<'a> {
+    name: &'a str,
+    instance: &'a str,
+    full_text: &'a str,
+    urgent: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClickEvent {
+    name: Option<String>,
+    instance: Option<String>,
+    button: u32,
+}
+
+/// Last known text and urgency of one of our blocks
+struct BlockState {
+    full_text: String,
+    urgent: bool,
+}
+
+/// Writes the i3bar protocol: a header, then an infinite JSON array of status lines.
+///
+/// Each status line carries a block per watched device, so the latest state of each is kept
+/// around to re-send alongside whichever one changed.
+pub struct I3barOutput<W: Write> {
+    writer: W,
+    started: bool,
+    texts: StateTexts,
+    source: Option<BlockState>,
+    sink: Option<BlockState>,
+}
+
+impl<W: Write> I3barOutput<W> {
+    pub fn new(writer: W, texts: StateTexts) -> Self {
+        I3barOutput {
+            writer,
+            started: false,
+            texts,
+            source: None,
+            sink: None,
+        }
+    }
+}
+
+impl<W: Write> Output for I3barOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if !event.kind.is_device_state() {
+            return Ok(());
+        }
+        if !self.started {
+            let header = Header {
+                version: 1,
+                click_events: true,
+            };
+            serde_json::to_writer(&mut self.writer, &header)?;
+            writeln!(self.writer, "\n[")?;
+            self.started = true;
+        } else {
+            write!(self.writer, ",")?;
+        }
+
+        let state = BlockState {
+            full_text: self.texts.for_event(&event.kind).to_string(),
+            urgent: event.kind.muted().is_none(),
+        };
+        match event.kind.is_sink() {
+            false => self.source = Some(state),
+            true => self.sink = Some(state),
+        }
+
+        let blocks: Vec<Block> = [("source", &self.source), ("sink", &self.sink)]
+            .into_iter()
+            .filter_map(|(instance, state)| {
+                state.as_ref().map(|state| Block {
+                    name: BLOCK_NAME,
+                    instance,
+                    full_text: &state.full_text,
+                    urgent: state.urgent,
+                })
+            })
+            .collect();
+        serde_json::to_writer(&mut self.writer, &blocks)?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+}
+
+/// Parse one line of the click event stream, which is itself an infinite JSON array, so lines
+/// look like `[`, `{...}` or `,{...}`.
+fn parse_click(line: &str) -> Option<ClickEvent> {
+    let line = line.trim().trim_start_matches(['[', ',']).trim();
+    if line.is_empty() {
+        return None;
+    }
+    match serde_json::from_str::<ClickEvent>(line) {
+        Ok(click) => Some(click),
+        Err(err) => {
+            debug!("Ignoring unparseable click event '{}': {}", line, err);
+            None
+        }
+    }
+}
+
+/// Read click events from stdin, forwarding clicks on our block to the main loop
+pub fn spawn_click_reader(tx: RequestTx) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("i3bar-clicks".to_string())
+        .spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        info!("Stopped reading click events: {}", err);
+                        return;
+                    }
+                };
+                if let Some(click) = parse_click(&line) {
+                    if click.name.as_deref() != Some(BLOCK_NAME) {
+                        continue;
+                    }
+                    trace!("click event: {:?}", click);
+                    let device = match click.instance.as_deref() {
+                        Some("sink") => DeviceKind::Sink,
+                        _ => DeviceKind::Source,
+                    };
+                    if tx
+                        .send(ListenerRequest::Click(device, click.button))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+            debug!("stdin closed, no more click events");
+        })
+}
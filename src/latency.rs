@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::output::Event;
+use crate::Errors;
+
+/// What's being measured, from the subscribe callback onwards
+const INTROSPECTION: &str = "introspection";
+
+type Samples = BTreeMap<&'static str, Vec<Duration>>;
+
+/// One listener's latencies since they were last reported, by what they're up to: the state
+/// being brought up to date, or each output having written an event. Clones share them.
+#[derive(Debug, Clone, Default)]
+pub struct Latency(Arc<Mutex<Samples>>);
+
+/// When the server told us about the change behind an event, and whose latency it counts to
+#[derive(Debug, Clone)]
+pub struct Received {
+    at: Instant,
+    latency: Latency,
+}
+
+thread_local! {
+    /// The change this thread is handling, if it's one being measured
+    static HANDLING: RefCell<Option<Received>> = const { RefCell::new(None) };
+}
+
+impl Latency {
+    /// Measure how long it takes from the server telling us about a change to the state having
+    /// caught up, and to each output having written the events it caused, reporting the p50
+    /// and p99 every `every` for as long as the measurements are kept
+    pub fn measure(every: Duration) -> Result<Self, Errors> {
+        let latency = Latency::default();
+        let samples = Arc::downgrade(&latency.0);
+        thread::Builder::new()
+            .name("latency".to_string())
+            .spawn(move || loop {
+                thread::sleep(every);
+                match samples.upgrade() {
+                    Some(samples) => Latency(samples).report(),
+                    None => return,
+                }
+            })?;
+        Ok(latency)
+    }
+
+    /// The change the server told us about at `received` is being handled, until this is
+    /// dropped. Events made meanwhile are measured from then.
+    pub fn handling(&self, received: Option<Instant>) -> Handling {
+        let received = received.map(|at| Received {
+            at,
+            latency: self.clone(),
+        });
+        Handling(HANDLING.with(|handling| handling.replace(received)))
+    }
+
+    /// The state has caught up with the change the server told us about at `received`
+    pub fn introspected(&self, received: Instant) {
+        self.record(INTROSPECTION, received.elapsed());
+    }
+
+    fn record(&self, stage: &'static str, took: Duration) {
+        self.0.lock().unwrap().entry(stage).or_default().push(took);
+    }
+
+    /// Log the latencies since last time, and start afresh
+    pub fn report(&self) {
+        let samples = std::mem::take(&mut *self.0.lock().unwrap());
+        if samples.is_empty() {
+            info!("Latency: no server changes since last reported");
+        }
+        for (stage, mut took) in samples {
+            took.sort_unstable();
+            info!(
+                "Latency to {}: p50 {:.1?}, p99 {:.1?}, max {:.1?} ({} measured)",
+                stage,
+                percentile(&took, 50),
+                percentile(&took, 99),
+                took[took.len() - 1],
+                took.len(),
+            );
+        }
+    }
+}
+
+/// A change being handled, until dropped
+pub struct Handling(Option<Received>);
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        let _ = HANDLING.try_with(|handling| handling.replace(previous));
+    }
+}
+
+/// The change behind events made now, if it's being measured
+pub fn received() -> Option<Received> {
+    HANDLING.with(|handling| handling.borrow().clone())
+}
+
+/// `output` has written `event`
+pub fn written(output: &'static str, event: &Event) {
+    if let Some(received) = &event.received {
+        received.latency.record(output, received.at.elapsed());
+    }
+}
+
+/// The nearest-rank `percentile` of sorted `samples`
+fn percentile(samples: &[Duration], percentile: usize) -> Duration {
+    let rank = (samples.len() * percentile).div_ceil(100).max(1);
+    samples[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let took: Vec<Duration> = (1..=200).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&took, 50), Duration::from_millis(100));
+        assert_eq!(percentile(&took, 99), Duration::from_millis(198));
+        assert_eq!(percentile(&took[..1], 99), Duration::from_millis(1));
+    }
+}
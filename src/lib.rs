@@ -0,0 +1,3871 @@
+//! Follows a PulseAudio server's sources (and sinks), reporting mute, volume and default device
+//! changes as they happen. The command line tool is [`cli`]; other programs can embed the
+//! listener itself with [`SourceListener`], set up through [`SourceListenerBuilder`].
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs, io};
+
+use pulse::error::{Code, PAErr};
+use std::io::Write;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use crossbeam_channel::{RecvError, RecvTimeoutError, SendError, Sender};
+use filter::SourceFilter;
+use glob::Pattern;
+use log::{debug, error, info, trace, warn};
+use pulse::{
+    context::{
+        introspect::SourceInfo,
+        subscribe::{Facility, InterestMaskSet, Operation},
+        Context, FlagSet, State,
+    },
+    def::{SourceFlagSet, SourceState},
+    mainloop::signal::Event as SignalEvent,
+    proplist::Proplist,
+    volume::{ChannelVolumes, Volume},
+};
+use tracing::{info_span, instrument, Span};
+
+#[cfg(test)]
+mod alloc_bench;
+mod batch;
+mod builder;
+mod callback;
+mod card;
+mod changes;
+mod client;
+mod commands;
+mod consumer;
+mod control;
+mod daemon;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod dispatch;
+mod doctor;
+mod filter;
+mod health;
+mod history;
+mod hooks;
+mod http;
+mod i3bar;
+mod latency;
+mod listener;
+mod lock;
+mod log_file;
+mod logging;
+mod mainloop;
+mod metrics;
+mod module;
+#[cfg(feature = "native-plugins")]
+mod native_plugin;
+mod output;
+#[cfg(feature = "wasm")]
+mod plugin;
+mod resolve;
+#[cfg(feature = "rules")]
+mod rules;
+mod runtime;
+#[cfg(test)]
+mod scale_bench;
+#[cfg(feature = "lua")]
+mod script;
+mod servers;
+mod simulate;
+mod sink;
+mod socket;
+mod source_output;
+mod sources;
+mod stall;
+mod state_file;
+mod statsd;
+#[cfg(feature = "async")]
+mod stream;
+mod systemd;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod template;
+mod theme;
+mod udp;
+mod varlink;
+#[cfg(feature = "webhook")]
+mod webhook;
+#[cfg(feature = "websocket")]
+mod ws;
+#[cfg(feature = "zmq")]
+mod zmq_pub;
+
+use batch::Batch;
+use builder::Backends;
+use card::Cards;
+use changes::{ChangeQueue, QueuePolicy};
+use client::{ClientDatum, Clients};
+use control::{ControlCommand, Query};
+use dispatch::DispatchedOutput;
+use health::Health;
+use history::{DumpSignal, History};
+use hooks::{HookFailure, HookPolicy, HookRunner, Overlap, Trigger};
+use i3bar::I3barOutput;
+use latency::Latency;
+use lock::MainloopGuard;
+use log_file::{LogFileConfig, Rotation};
+use logging::{LogConfig, LogFormat, LogTarget, SyslogConfig, SyslogFacility, SyslogServer};
+use mainloop::{LoopRx, LoopTx, Mainloop};
+use module::{ModuleDatum, Modules};
+use resolve::Resolution;
+use sink::{SinkDatum, Sinks};
+use source_output::{SourceOutputDatum, SourceOutputs};
+use sources::Sources;
+use statsd::{StatsdFormat, StatsdOutput};
+use template::{Template, TemplateOutput};
+use theme::{ColorChoice, Theme};
+
+use output::{
+    CsvOutput, EventLogOutput, FanoutOutput, JsonOutput, MsgpackOutput, Output, OutputFormat,
+    PlainOutput, PolybarOutput, PolybarStyle, Sequence, StateTexts, WaybarOutput,
+};
+
+pub use builder::SourceListenerBuilder;
+pub use changes::Backpressure;
+pub use consumer::DropPolicy;
+pub use listener::SourceListener;
+pub use mainloop::MainloopKind;
+pub use output::{DeviceState, Event, EventKind, ListedSource};
+#[cfg(feature = "async")]
+pub use stream::EventStream;
+#[cfg(feature = "webhook")]
+pub use webhook::WebhookPolicy;
+
+type CBTX = LoopTx<CallbackComms>;
+type CBRX = LoopRx<CallbackComms>;
+
+/// Which devices' mute state to follow
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Watch {
+    #[default]
+    /// The default source (microphone)
+    Source,
+    /// The default sink (speakers/headphones)
+    Sink,
+    Both,
+}
+
+impl Watch {
+    fn sources(self) -> bool {
+        matches!(self, Watch::Source | Watch::Both)
+    }
+
+    fn sinks(self) -> bool {
+        matches!(self, Watch::Sink | Watch::Both)
+    }
+}
+
+/// Kinds of change that get reported
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    Mute,
+    Volume,
+    /// The default device switched to a different one
+    Default,
+    /// The default source's active port changed, e.g. internal mic to headset mic
+    Port,
+    /// The default source started or stopped being recorded from
+    State,
+    /// Applications starting or stopping recording, from any source
+    Recording,
+    /// Cards switching profile, which adds and removes their sources
+    Profile,
+    /// The default source being suspended or resumed
+    Suspend,
+    /// Capture-related modules (echo-cancel, loopback, ...) being loaded or unloaded
+    Module,
+    /// Sources being added or removed
+    Devices,
+}
+
+impl Report {
+    /// What's reported unless told otherwise: everything about the watched device itself
+    pub const DEFAULTS: [Report; 5] = [
+        Report::Mute,
+        Report::Default,
+        Report::Port,
+        Report::State,
+        Report::Suspend,
+    ];
+}
+
+/// One-shot commands, run instead of listening for changes
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the available sources and exit
+    List {
+        /// Print a JSON array instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the default source's mute state and exit with 0 if unmuted, 1 if muted, 2 if
+    /// there is no default source or 3 if it couldn't be found out
+    Status,
+    /// Mute the default source
+    Mute,
+    /// Unmute the default source
+    Unmute,
+    /// Toggle the default source's mute state
+    Toggle,
+    /// Change the server's default source
+    SetDefault {
+        /// Index, name, or part of the name or description of the source
+        source: String,
+    },
+    /// Get or change a source's volume
+    Volume {
+        #[command(subcommand)]
+        action: VolumeAction,
+
+        /// Source to use instead of the default, matched as for set-default
+        #[arg(long, global = true)]
+        source: Option<String>,
+
+        /// Highest volume, in percent, that set/up will go to
+        #[arg(long, global = true, default_value_t = 100)]
+        max: u32,
+    },
+    /// Print details of the PulseAudio server and exit
+    Info {
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check for common setup problems and suggest fixes, exiting with 1 if any check failed or
+    /// 3 if checking did
+    Doctor,
+    /// Ask the listener serving --socket whether it's connected to the server and subscribed,
+    /// printing its answer as JSON. Exits with 0 if so, 1 if not, or 2 if nothing answered.
+    Health {
+        /// Also count as unhealthy when the listener's state is older than this many seconds
+        #[arg(long, value_name = "SECS")]
+        max_age: Option<u64>,
+    },
+    /// Emit fake events through the configured output without connecting to PulseAudio, for
+    /// testing status bar and hook setups
+    Simulate {
+        /// Script of steps to play instead of toggling on a timer, one per line: mute, unmute,
+        /// volume <pct>, default <name>, no-source, sleep <secs>
+        #[arg(long)]
+        script: Option<PathBuf>,
+
+        /// Seconds between fake events when not running a script
+        #[arg(long, value_name = "SECS", default_value = "2", value_parser = parse_positive_secs)]
+        interval: Duration,
+    },
+    /// Block until a condition holds, then exit with 0, or 124 on timeout
+    WaitFor {
+        #[arg(value_enum)]
+        condition: WaitCondition,
+
+        /// Give up after this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum WaitCondition {
+    /// The default source is muted
+    Muted,
+    /// The default source is unmuted
+    Unmuted,
+    /// The default source switches to a different one
+    DefaultChange,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+enum VolumeAction {
+    /// Print the volume in percent and dB
+    Get,
+    /// Set the volume to a percentage
+    Set { percent: u32 },
+    /// Raise the volume by a number of percentage points
+    Up {
+        #[arg(default_value_t = 5)]
+        step: u32,
+    },
+    /// Lower the volume by a number of percentage points
+    Down {
+        #[arg(default_value_t = 5)]
+        step: u32,
+    },
+}
+
+/// How to combine the mute state of every (filtered) source into one
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    /// UNMUTED as soon as any source is unmuted, so MUTED only while every source is
+    AnyUnmuted,
+    /// MUTED as soon as any source is muted, so UNMUTED only while every source is
+    AnyMuted,
+}
+
+impl Aggregate {
+    /// The combined state of sources muted as given. With no sources at all, nothing can hear
+    /// you, so that counts as muted.
+    fn muted(self, mut mutes: impl Iterator<Item = bool>) -> bool {
+        match self {
+            Aggregate::AnyUnmuted => mutes.all(|muted| muted),
+            Aggregate::AnyMuted => {
+                let mut mutes = mutes.peekable();
+                mutes.peek().is_none() || mutes.any(|muted| muted)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    Source,
+    Sink,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author = "Sam Martin-Brown", version, about)]
+/// Application configuration
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Watch this source, by name or glob pattern, instead of the server's default source
+    name: Option<String>,
+
+    /// Also watch this source, by name or glob pattern (repeatable). Without NAME, the first one
+    /// replaces the server's default source.
+    #[arg(long = "source", value_name = "SOURCE")]
+    sources: Vec<String>,
+
+    /// Follow the source with this index. If the server reuses the index for a different device,
+    /// a source_replaced event is emitted and the original device is followed again if it returns.
+    #[arg(long, value_name = "INDEX", conflicts_with_all = ["name", "sources"])]
+    index: Option<u32>,
+
+    /// Sources to fall back on, best first, when the server has no default source. Glob
+    /// patterns, matched against names and descriptions (comma separated or repeatable).
+    #[arg(long, value_name = "PATTERN", value_delimiter = ',')]
+    prefer: Vec<String>,
+
+    /// Only consider sources whose name or description matches this regex (repeatable)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Ignore sources whose name or description matches this regex (repeatable)
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Ignore monitor sources of sinks (the default)
+    #[arg(long, overrides_with = "include_monitors")]
+    no_monitors: bool,
+
+    /// Consider monitor sources of sinks like any other source
+    #[arg(long, overrides_with = "no_monitors")]
+    include_monitors: bool,
+
+    /// Name monitor sources after their sink in events, e.g. "Speakers (monitor)"
+    #[arg(long)]
+    monitor_sink_names: bool,
+
+    /// Ignore virtual (null, remap, echo-cancel, ...) and network sources
+    #[arg(long)]
+    only_hardware: bool,
+
+    /// whether to be verbose
+    #[arg(short = 'v')]
+    verbose: bool,
+
+    /// Fork to the background and detach from the terminal, e.g. when started from xinitrc or a
+    /// sway config. Logs only go to --log-target and --log-file from then on.
+    #[arg(long)]
+    daemon: bool,
+
+    /// Write our pid here, removing it on exit. With --daemon, defaults to
+    /// $XDG_RUNTIME_DIR/pulseaudio-sink-listener.pid
+    #[arg(long, value_name = "PATH")]
+    pidfile: Option<PathBuf>,
+
+    /// PulseAudio server to connect to, e.g. tcp:studio.local:4713 or unix:/path/to/socket,
+    /// rather than the local default. Repeat to listen to several servers at once, with each
+    /// event tagged with the server it came from.
+    #[arg(long, value_name = "ADDR")]
+    server: Vec<String>,
+
+    /// Start PulseAudio if it isn't running yet, as desktop clients do, rather than failing
+    #[arg(long)]
+    autospawn: bool,
+
+    /// Wait for the server to appear if it isn't there yet, rather than failing to connect
+    #[arg(long)]
+    nofail: bool,
+
+    /// Give up on a connection attempt the server hasn't answered within this many seconds
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+
+    /// Try connecting this many more times at startup before giving up, waiting longer between
+    /// each attempt
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    connect_retries: u32,
+
+    /// Keep trying to connect at startup until the server appears, for when started before it
+    #[arg(long, conflicts_with = "connect_retries")]
+    wait_for_server: bool,
+
+    /// Auth cookie to present to the server, e.g. a copy of its ~/.config/pulse/cookie
+    #[arg(long, value_name = "PATH")]
+    cookie: Option<PathBuf>,
+
+    /// Which of libpulse's mainloops to run the server's callbacks on: a thread of its own
+    /// (threaded), or ours, only while waiting on the server, which needs no locking and no
+    /// extra thread (standard)
+    #[arg(long, value_enum, default_value = "threaded")]
+    mainloop: MainloopKind,
+
+    /// Reconnect if the server hasn't answered a check within this many seconds, and exit (to
+    /// be restarted by the service manager) if handling one event takes that long
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    stall_timeout: Option<u64>,
+
+    /// Report the current state every this many seconds, even when nothing changed, for
+    /// consumers that want refreshing. These events are marked `"snapshot": true`, and don't run
+    /// hooks, webhooks or rules.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat: Option<u64>,
+
+    /// Log how long server changes take to be handled and written by each output, as p50 and
+    /// p99 latencies every this many seconds (60 if not given)
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    measure_latency: Option<u64>,
+
+    /// Exit when the connection to the server is lost, rather than reconnecting once it's back
+    #[arg(long)]
+    no_reconnect: bool,
+
+    /// What to do when server changes come in faster than they can be handled, e.g. from a
+    /// misbehaving driver flooding the server with them
+    #[arg(long, value_enum, default_value = "coalesce")]
+    backpressure: Backpressure,
+
+    /// How many server changes can wait to be handled before --backpressure applies
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = changes::DEFAULT_CAPACITY,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    change_queue_size: usize,
+
+    /// Gather server changes for this many milliseconds after the first of a burst, merging
+    /// those for the same device, before handling them. 0 handles each straight away.
+    #[arg(long, value_name = "MS", default_value_t = changes::DEFAULT_WINDOW_MS)]
+    coalesce_window: u64,
+
+    /// On the way out, how long to wait on running hooks and queued webhooks before leaving
+    /// them behind
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    shutdown_timeout: u64,
+
+    /// Where to send logs. The journal and syslog also get each event, with its details as
+    /// fields.
+    #[arg(long, value_enum, default_value_t = LogTarget::Stderr)]
+    log_target: LogTarget,
+
+    /// How to write logs to stderr and --log-file. JSON logs also get each event, with its
+    /// details as fields.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Syslog server for --log-target syslog: udp://HOST:PORT, tcp://HOST:PORT or a socket
+    /// path [default: /dev/log]
+    #[arg(long, value_name = "SERVER", value_parser = logging::parse_syslog_server)]
+    syslog_server: Option<SyslogServer>,
+
+    /// Syslog facility to log as
+    #[arg(long, value_enum, default_value_t = SyslogFacility::User)]
+    syslog_facility: SyslogFacility,
+
+    /// Syslog APP-NAME to log as
+    #[arg(long, value_name = "NAME", default_value = "pulse-source-listener")]
+    syslog_app_name: String,
+
+    /// Also log to this file, e.g. when running under a supervisor that doesn't keep stderr
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it would grow past this many bytes
+    #[arg(long, value_name = "BYTES", requires = "log_file")]
+    log_file_max_size: Option<u64>,
+
+    /// Rotate the log file when a new hour or day starts
+    #[arg(long, value_enum, default_value_t = Rotation::Never, requires = "log_file")]
+    log_file_rotate: Rotation,
+
+    /// How many rotated log files to keep, as PATH.1 (the newest) to PATH.N
+    #[arg(long, value_name = "N", default_value_t = 5, requires = "log_file")]
+    log_file_keep: usize,
+
+    /// Export tracing spans for each PulseAudio event, from its callback through to outputs and
+    /// hooks, to an OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces
+    #[cfg(feature = "otel")]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Text to emit when default source is muted
+    #[arg(long, short, default_value = "MUTED")]
+    mute_text: Option<String>,
+
+    /// Text to emit when default source is unmuted
+    #[arg(long, short, default_value = "UNMUTED")]
+    unmute_text: Option<String>,
+
+    /// Text to emit when default source is missing (no mic connected, etc.)
+    #[arg(long, short, default_value = "NO SOURCE")]
+    no_src_text: Option<String>,
+
+    /// Text to emit when default sink is muted
+    #[arg(long, default_value = "SINK_MUTED")]
+    sink_mute_text: String,
+
+    /// Text to emit when default sink is unmuted
+    #[arg(long, default_value = "SINK_UNMUTED")]
+    sink_unmute_text: String,
+
+    /// Text to emit when there is no default sink
+    #[arg(long, default_value = "NO SINK")]
+    no_sink_text: String,
+
+    /// Which default device(s) to watch
+    #[arg(long, value_enum, default_value = "source")]
+    watch: Watch,
+
+    /// Which changes of the watched device(s) to report, comma separated
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "mute,default,port,state,suspend"
+    )]
+    report: Vec<Report>,
+
+    /// Also report PulseAudio clients connecting and disconnecting
+    #[arg(long)]
+    watch_clients: bool,
+
+    /// Report one combined mute state across all sources instead of the default source's
+    #[arg(long, value_enum)]
+    aggregate: Option<Aggregate>,
+
+    /// Output format for emitted events
+    #[arg(long, value_enum, default_value = "plain")]
+    format: OutputFormat,
+
+    /// Render each event with a template instead of a preset format, e.g. '{state} {volume}%'.
+    /// Placeholders: {source_name} {muted} {index} {default} {volume} {state} {event} {timestamp}
+    /// {seq}
+    #[arg(long, conflicts_with = "format")]
+    template: Option<String>,
+
+    /// Hand every event to the on_event function of this Lua script, which prints its own output
+    /// with psl.emit and can call psl.mute, psl.unmute and psl.set_default
+    #[cfg(feature = "lua")]
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["format", "template"])]
+    lua_script: Option<PathBuf>,
+
+    /// Pass events through this WASM plugin before they're output (repeatable, run in order).
+    /// One that runs too long on an event is unloaded.
+    #[cfg(feature = "wasm")]
+    #[arg(long = "plugin", value_name = "FILE")]
+    plugins: Vec<PathBuf>,
+
+    /// Send events to this native output plugin (repeatable), as well as any found in
+    /// $XDG_DATA_HOME/pulse-source-listener/plugins
+    #[cfg(feature = "native-plugins")]
+    #[arg(long = "native-plugin", value_name = "FILE")]
+    native_plugins: Vec<PathBuf>,
+
+    /// Accept commands on stdin while listening, one per line: toggle, mute, unmute,
+    /// status, list, quit
+    #[arg(long)]
+    stdin_commands: bool,
+
+    /// Serve queries, commands and an event stream on a Unix socket at this path
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Serve the same protocol as --socket over TCP on this address, e.g. 0.0.0.0:7779.
+    /// Nothing authenticates clients, so only queries and subscribing are answered unless
+    /// --tcp-control is given.
+    #[arg(long, value_name = "ADDR")]
+    tcp_listen: Option<std::net::SocketAddr>,
+
+    /// Take toggle, mute and unmute over --tcp-listen too, from anyone who can reach it
+    #[arg(long, requires = "tcp_listen")]
+    tcp_control: bool,
+
+    /// What to do for a client of --socket, --tcp-listen, --ws-listen or --http-listen that
+    /// falls too far behind on events
+    #[arg(long, value_enum, default_value = "disconnect")]
+    consumer_drop_policy: DropPolicy,
+
+    /// How many events can wait on each of those clients before --consumer-drop-policy applies
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = consumer::DEFAULT_CAPACITY,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    consumer_queue_size: usize,
+
+    /// Send a JSON datagram with the default source's state to this host:port on every change
+    /// (repeatable)
+    #[arg(long = "udp-target", value_name = "HOST:PORT")]
+    udp_targets: Vec<String>,
+
+    /// Seconds between resending the current state to UDP targets
+    #[arg(long, value_name = "SECS", default_value = "5", value_parser = parse_positive_secs)]
+    udp_interval: Duration,
+
+    /// Serve the org.pulse_source_listener varlink interface on a Unix socket at this path
+    #[arg(long, value_name = "PATH")]
+    varlink: Option<PathBuf>,
+
+    /// Serve server-sent events at /events and the current state at /state over HTTP on this
+    /// address, e.g. 127.0.0.1:7778
+    #[arg(long, value_name = "ADDR")]
+    http_listen: Option<std::net::SocketAddr>,
+
+    /// POST every event as JSON to this URL (repeatable)
+    #[cfg(feature = "webhook")]
+    #[arg(long = "webhook", value_name = "URL")]
+    webhooks: Vec<String>,
+
+    /// Extra header sent with webhooks, as 'Name: value' (repeatable)
+    #[cfg(feature = "webhook")]
+    #[arg(long = "webhook-header", value_name = "HEADER", value_parser = webhook::parse_header)]
+    webhook_headers: Vec<(String, String)>,
+
+    /// Sign webhook bodies with HMAC-SHA256 using this secret, sent as
+    /// 'X-PSL-Signature: sha256=<hex>'
+    #[cfg(feature = "webhook")]
+    #[arg(long, value_name = "SECRET")]
+    webhook_secret: Option<String>,
+
+    /// Retry webhooks that fail to send or get a server error this many times, then report a
+    /// hook_failed event
+    #[cfg(feature = "webhook")]
+    #[arg(long, default_value_t = 0)]
+    webhook_retries: u32,
+
+    /// Seconds before retrying a failed webhook, doubling with each retry
+    #[cfg(feature = "webhook")]
+    #[arg(long, value_name = "SECS", default_value = "1", value_parser = parse_secs)]
+    webhook_retry_delay: Duration,
+
+    /// Publish every event on a ZeroMQ PUB socket bound to this endpoint, e.g. tcp://*:5556,
+    /// with the event type as topic
+    #[cfg(feature = "zmq")]
+    #[arg(long, value_name = "ENDPOINT")]
+    zmq_pub: Option<String>,
+
+    /// Send source mute and volume metrics to a StatsD or InfluxDB listener at this host:port,
+    /// over UDP
+    #[arg(long, value_name = "HOST:PORT")]
+    statsd: Option<String>,
+
+    /// Line format for --statsd
+    #[arg(long, value_enum, default_value = "statsd")]
+    statsd_format: StatsdFormat,
+
+    /// Serve Prometheus metrics of source states at /metrics over HTTP on this address, e.g.
+    /// 127.0.0.1:9477
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<std::net::SocketAddr>,
+
+    /// Push every event to WebSocket clients connecting to this address, e.g. 127.0.0.1:7777,
+    /// answering the same queries as --socket. Commands are refused unless --ws-control is
+    /// given, and browsers unless opened from a --ws-origin.
+    #[cfg(feature = "websocket")]
+    #[arg(long, value_name = "ADDR")]
+    ws_listen: Option<std::net::SocketAddr>,
+
+    /// Take toggle, mute and unmute over --ws-listen too, from anyone who can reach it
+    #[cfg(feature = "websocket")]
+    #[arg(long, requires = "ws_listen")]
+    ws_control: bool,
+
+    /// Let browsers connect to --ws-listen from pages at this origin, e.g.
+    /// http://localhost:8000 (repeatable)
+    #[cfg(feature = "websocket")]
+    #[arg(long = "ws-origin", value_name = "ORIGIN", requires = "ws_listen")]
+    ws_origins: Vec<String>,
+
+    /// Don't load native plugins from the plugin directory
+    #[cfg(feature = "native-plugins")]
+    #[arg(long)]
+    no_plugin_discovery: bool,
+
+    /// Rhai file of rules, each running a command once its condition on the default source has
+    /// held for a while
+    #[cfg(feature = "rules")]
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+
+    /// Publish the watched source on the session bus as dev.martsa1.SourceListener, with
+    /// DefaultSource and Muted properties, a StateChanged signal, and Toggle and List methods
+    #[cfg(feature = "dbus")]
+    #[arg(long)]
+    dbus: bool,
+
+    /// Keep this file holding the latest state, formatted as on stdout and replaced atomically
+    /// on every change, for status bars that poll
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+
+    /// Add an 'updated <unix seconds>' line to the state file, rewritten every 30 seconds so
+    /// a stale timestamp means the listener has stopped
+    #[arg(long, requires = "state_file")]
+    state_file_timestamp: bool,
+
+    /// Prefix plain output lines with an ISO8601 timestamp
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Prefix plain output lines with an incrementing sequence number
+    #[arg(long)]
+    seq: bool,
+
+    /// Color plain and template output by state
+    #[arg(long, value_enum, default_value = "auto")]
+    color: ColorChoice,
+
+    /// Append every event as a JSON line to this file, independent of stdout output
+    #[arg(long)]
+    event_log: Option<PathBuf>,
+
+    /// Run this shell command on every mute change of the watched source, or of all of them
+    /// combined with --aggregate, with PSL_EVENT, PSL_SOURCE_NAME, PSL_SOURCE_INDEX and PSL_MUTED
+    /// (1 or 0) set in its environment, and the event as a line of JSON on its stdin
+    #[arg(long, value_name = "CMD")]
+    exec: Option<String>,
+
+    /// Run this shell command, like --exec, when the watched source is muted
+    #[arg(long, value_name = "CMD")]
+    on_mute: Option<String>,
+
+    /// Run this shell command, like --exec, when the watched source is unmuted
+    #[arg(long, value_name = "CMD")]
+    on_unmute: Option<String>,
+
+    /// Run this shell command, like --exec, when the default source changes
+    #[arg(long, value_name = "CMD")]
+    on_default_change: Option<String>,
+
+    /// Run this shell command, like --exec, when a source is added
+    #[arg(long, value_name = "CMD")]
+    on_source_new: Option<String>,
+
+    /// Run this shell command, like --exec, when a source is removed
+    #[arg(long, value_name = "CMD")]
+    on_source_removed: Option<String>,
+
+    /// Count a hook as failed once it's been running this many seconds. Its next run still
+    /// waits for it to exit, unless it's killed.
+    #[arg(long, value_name = "SECS", value_parser = parse_positive_secs)]
+    hook_timeout: Option<Duration>,
+
+    /// Kill hooks that time out, instead of waiting for them to exit before running the next
+    #[arg(long, requires = "hook_timeout")]
+    hook_kill_on_timeout: bool,
+
+    /// What to do when a hook fires while it's still running from last time
+    #[arg(long, value_enum, default_value = "queue")]
+    hook_overlap: Overlap,
+
+    /// Retry hooks that fail or time out this many times, then report a hook_failed event
+    #[arg(long, default_value_t = 0)]
+    hook_retries: u32,
+
+    /// Seconds before retrying a failed hook, doubling with each retry
+    #[arg(long, value_name = "SECS", default_value = "1", value_parser = parse_secs)]
+    hook_retry_delay: Duration,
+
+    /// Wait for a hook's events to stop for this many milliseconds, then run it once for the
+    /// latest, so flapping mute state doesn't run it over and over
+    #[arg(long, value_name = "MS")]
+    hook_debounce: Option<u64>,
+
+    /// Run each hook at most this many times a minute, holding back the latest event until it
+    /// may run
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    hook_max_rate: Option<u32>,
+
+    /// Keep recent events in memory and print them to stderr when this signal is received
+    #[arg(long, value_enum)]
+    dump_history_on: Option<DumpSignal>,
+
+    /// Number of events to keep for --dump-history-on
+    #[arg(long, default_value_t = 100)]
+    history_size: usize,
+
+    /// Printed before the state in polybar output, may contain polybar format tags
+    #[arg(long, default_value = "")]
+    polybar_prefix: String,
+
+    /// Printed after the state in polybar output, may contain polybar format tags
+    #[arg(long, default_value = "")]
+    polybar_suffix: String,
+
+    /// Polybar foreground color (e.g. #ff5555) used while the default source is muted
+    #[arg(long)]
+    polybar_mute_color: Option<String>,
+
+    /// Polybar foreground color used while the default source is unmuted
+    #[arg(long)]
+    polybar_unmute_color: Option<String>,
+
+    /// Polybar foreground color used while there is no default source
+    #[arg(long)]
+    polybar_no_src_color: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct SourceDatum {
+    /// Shared with the events reporting the source, rather than copied into each
+    name: Arc<str>,
+    description: Option<String>,
+    mute: bool,
+    volume: ChannelVolumes,
+    active_port: Option<String>,
+    active_port_description: Option<String>,
+    state: SourceState,
+    /// Set if this source is the monitor of a sink rather than a capture device
+    monitor_of_sink: Option<u32>,
+    flags: SourceFlagSet,
+    /// What to call a monitor in events, if named after its sink
+    monitor_label: Option<Arc<str>>,
+}
+impl SourceDatum {
+    fn from_info(info: &SourceInfo<'_>) -> Self {
+        let name = match &info.name {
+            None => Arc::from("unknown"),
+            Some(name) => Arc::from(name.as_ref()),
+        };
+        let port = info.active_port.as_ref();
+
+        SourceDatum {
+            name,
+            description: info.description.as_ref().map(|desc| desc.to_string()),
+            mute: info.mute,
+            volume: info.volume,
+            active_port: port.and_then(|port| port.name.as_ref().map(|name| name.to_string())),
+            active_port_description: port
+                .and_then(|port| port.description.as_ref().map(|desc| desc.to_string())),
+            state: info.state,
+            monitor_of_sink: info.monitor_of_sink,
+            flags: info.flags,
+            monitor_label: None,
+        }
+    }
+
+    /// An idle hardware source, going by `name`
+    #[cfg(test)]
+    fn named(name: &str) -> Self {
+        SourceDatum {
+            name: Arc::from(name),
+            description: None,
+            mute: false,
+            volume: ChannelVolumes::default(),
+            active_port: None,
+            active_port_description: None,
+            state: SourceState::Idle,
+            monitor_of_sink: None,
+            flags: SourceFlagSet::HARDWARE,
+            monitor_label: None,
+        }
+    }
+
+    /// The name to report the source by
+    fn display_name(&self) -> &Arc<str> {
+        self.monitor_label.as_ref().unwrap_or(&self.name)
+    }
+
+    fn volume_percent(&self) -> u32 {
+        volume_percent(&self.volume)
+    }
+
+    /// Backed by a hardware device, rather than virtual (null, remap, echo-cancel...) or network
+    fn is_hardware(&self) -> bool {
+        self.flags.contains(SourceFlagSet::HARDWARE)
+    }
+
+    fn is_network(&self) -> bool {
+        self.flags.contains(SourceFlagSet::NETWORK)
+    }
+
+    /// Whether something is currently recording from the source
+    fn running(&self) -> bool {
+        self.state == SourceState::Running
+    }
+
+    fn suspended(&self) -> bool {
+        self.state == SourceState::Suspended
+    }
+
+    fn state_name(&self) -> &'static str {
+        match self.state {
+            SourceState::Running => "running",
+            SourceState::Idle => "idle",
+            SourceState::Suspended => "suspended",
+            SourceState::Invalid => "invalid",
+        }
+    }
+}
+
+/// Name a monitor source after the sink it monitors, as raw `.monitor` names mean little in a
+/// status bar
+fn label_monitor(src: &mut SourceDatum, sinks: &Sinks) {
+    let Some(sink_idx) = src.monitor_of_sink else {
+        return;
+    };
+    match sinks.get(&sink_idx) {
+        Some(sink) => {
+            let sink_name = sink.description.as_ref().unwrap_or(&sink.name);
+            src.monitor_label = Some(format!("{} (monitor)", sink_name).into());
+        }
+        None => debug!("Couldn't find sink {} monitored by {}", sink_idx, src.name),
+    }
+}
+
+/// Name a source fetched on its own after the sink it monitors, asking for just that sink
+#[instrument(skip_all, fields(source = %src.name))]
+fn fetch_monitor_label(
+    src: &mut SourceDatum,
+    batch: &Batch,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+) -> Result<(), Errors> {
+    let Some(sink_idx) = src.monitor_of_sink else {
+        return Ok(());
+    };
+    let sinks = match batch.ask(&[batch::Query::SinkByIndex(sink_idx)], context, mainloop) {
+        Ok(answers) => answers.sinks,
+        Err(Errors::SinkListError) => Sinks::new(),
+        Err(err) => return Err(err),
+    };
+    label_monitor(src, &sinks);
+    Ok(())
+}
+
+/// Average volume across channels, as a percentage of PA's "normal" (100%) volume
+fn volume_percent(volume: &ChannelVolumes) -> u32 {
+    (volume.avg().0 as f64 * 100.0 / Volume::NORMAL.0 as f64).round() as u32
+}
+
+/// Seconds given on the command line, which may be fractional
+fn parse_secs(secs: &str) -> Result<Duration, String> {
+    let secs: f64 = secs
+        .parse()
+        .map_err(|_| format!("'{}' isn't a number of seconds", secs))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("{} seconds is out of range", secs))
+}
+
+/// Seconds given on the command line, which must be more than 0
+fn parse_positive_secs(secs: &str) -> Result<Duration, String> {
+    match parse_secs(secs)? {
+        duration if duration.is_zero() => Err("expected more than 0 seconds".to_string()),
+        duration => Ok(duration),
+    }
+}
+
+/// What went wrong
+#[derive(Debug)]
+pub enum Errors {
+    Shutdown,
+    Timeout,
+    /// The connection to the server was lost
+    Disconnected,
+    /// The server stopped answering, though still connected
+    Stalled,
+    /// What the server said doesn't fit the state as it's known, which needs fetching again
+    Inconsistent(String),
+    SrcListError,
+    SinkListError,
+    SourceOutputListError,
+    CardListError,
+    ClientListError,
+    ModuleListError,
+    ContextError(String),
+    /// Connecting to the server failed, with why in terms of what to do about it
+    ConnectError(PAErr, String),
+    ConfigError(String),
+    PAError(PAErr),
+    RecvError(RecvError),
+    IOError(io::Error),
+}
+
+impl Errors {
+    /// Whether asking the server about something failed, which leaves the state out of date
+    /// but the connection usable
+    fn is_introspection_failure(&self) -> bool {
+        matches!(
+            self,
+            Errors::SrcListError
+                | Errors::SinkListError
+                | Errors::SourceOutputListError
+                | Errors::CardListError
+                | Errors::ClientListError
+                | Errors::ModuleListError
+                | Errors::Inconsistent(_)
+                | Errors::PAError(_)
+                | Errors::RecvError(_)
+        )
+    }
+}
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::Shutdown => write!(f, "Shutting down"),
+            Errors::Timeout => write!(f, "Timed out"),
+            Errors::Disconnected => write!(f, "Lost the connection to the server"),
+            Errors::Stalled => write!(f, "The server stopped answering"),
+            Errors::Inconsistent(what) => write!(f, "Inconsistent server state: {}", what),
+            Errors::SrcListError => write!(f, "Error receiving sources from pulseaudio"),
+            Errors::SinkListError => write!(f, "Error receiving sinks from pulseaudio"),
+            Errors::SourceOutputListError => {
+                write!(f, "Error receiving source outputs from pulseaudio")
+            }
+            Errors::CardListError => write!(f, "Error receiving cards from pulseaudio"),
+            Errors::ClientListError => write!(f, "Error receiving clients from pulseaudio"),
+            Errors::ModuleListError => write!(f, "Error receiving modules from pulseaudio"),
+            Errors::ContextError(context) => write!(f, "Context error: {}", context),
+            Errors::ConnectError(_, reason) => write!(f, "Connection error: {}", reason),
+            Errors::ConfigError(config) => write!(f, "Configuration error: {}", config),
+            Errors::PAError(pa_err) => write!(f, "PAError: {}", pa_err),
+            Errors::RecvError(recv_err) => write!(f, "RecvError: {}", recv_err),
+            Errors::IOError(io_err) => write!(f, "IOError: {}", io_err),
+        }
+    }
+}
+impl Error for Errors {}
+
+impl From<PAErr> for Errors {
+    fn from(value: PAErr) -> Self {
+        Self::PAError(value)
+    }
+}
+
+impl From<RecvError> for Errors {
+    fn from(value: RecvError) -> Self {
+        Self::RecvError(value)
+    }
+}
+
+impl From<io::Error> for Errors {
+    fn from(value: io::Error) -> Self {
+        Self::IOError(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PulseChange {
+    SourceChange(u32),
+    SourceNew(u32),
+    SourceDrop(u32),
+    SinkChange(u32),
+    SinkNew(u32),
+    SinkDrop(u32),
+    SourceOutputChange(u32),
+    SourceOutputNew(u32),
+    SourceOutputDrop(u32),
+    CardChange(u32),
+    CardNew(u32),
+    CardDrop(u32),
+    ClientChange(u32),
+    ClientNew(u32),
+    ClientDrop(u32),
+    ModuleNew(u32),
+    ModuleDrop(u32),
+    Server,
+}
+
+/// Asked of the running listener from outside its loop: by a signal, a client, a hook, a script
+/// or the status bar
+#[derive(Debug, Clone)]
+enum ListenerRequest {
+    Shutdown,
+    /// Mouse button clicked on one of our status bar blocks
+    Click(DeviceKind, u32),
+    /// A hook kept failing
+    HookFailed(HookFailure),
+    /// A command for the running listener, with where to say whether it worked if anyone's
+    /// waiting to hear
+    Control(ControlCommand, Option<Sender<Result<(), String>>>),
+    /// A script asked for something to be done
+    #[cfg(feature = "lua")]
+    Action(script::Action),
+    /// Something outside the loop wants to know about the current state
+    Query(Query, Sender<Vec<Event>>),
+}
+
+/// What the loop on a connection to the server hears about: requests from outside, and what the
+/// server's callbacks pass on
+#[derive(Debug, Clone)]
+enum CallbackComms {
+    Request(ListenerRequest),
+    /// A deadline set by a one-shot command passed
+    Timeout,
+    CallbackDone,
+    /// The connection to the server changed state, once it's been made
+    ContextState,
+    /// The server answered a check that it's still there
+    Pong,
+    /// Server changes are waiting in the change queue
+    Changes,
+}
+
+/// What the event bus in front of several servers' listeners hears about
+#[derive(Debug)]
+enum BusComms {
+    /// For every server's listener
+    Request(ListenerRequest),
+    /// An event from one of the servers, already tagged with its name
+    ServerEvent(Event),
+    /// The listener for the named server stopped
+    ServerDone(String),
+}
+
+/// Passes requests on to whichever loop takes them: the listener's own, or the event bus in
+/// front of several
+#[derive(Debug, Clone)]
+enum RequestTx {
+    Listener(CBTX),
+    Bus(LoopTx<BusComms>),
+}
+
+impl RequestTx {
+    /// Fails once the loop has stopped
+    fn send(&self, request: ListenerRequest) -> Result<(), SendError<()>> {
+        let sent = match self {
+            RequestTx::Listener(tx) => tx.send(CallbackComms::Request(request)).is_ok(),
+            RequestTx::Bus(tx) => tx.send(BusComms::Request(request)).is_ok(),
+        };
+        sent.then_some(()).ok_or(SendError(()))
+    }
+}
+
+#[derive(Debug)]
+struct ListenerState {
+    // Use Pulseaudio's source index as key to source data (which is just name and mute-status)
+    sources: Sources,
+    /// The watched source, which is the server's default unless a name was given. Looked up
+    /// again whenever sources come and go.
+    watched_source: Resolution,
+    /// The first pattern picks the watched source instead of the server's default, any others
+    /// pick extra sources to watch
+    source_patterns: Vec<Pattern>,
+    /// Extra watched sources, one per pattern after the first
+    extra_source_ids: Vec<Option<u32>>,
+    /// Set when following a source by index, instead of by pattern or the server's default
+    index_binding: Option<IndexBinding>,
+    /// Fallbacks, best first, for when the server has no default source
+    preferred: Vec<Pattern>,
+    source_filter: SourceFilter,
+
+    // Only populated when watching sinks
+    sinks: Sinks,
+    default_sink_id: Option<u32>,
+    /// The default device names as the server last gave them, so server changes that leave
+    /// them be can be skipped
+    server_defaults: ServerDefaults,
+    /// Asks the server about things, keeping its channel from one question to the next
+    batch: Batch,
+    /// Only tracked when recording is reported
+    source_outputs: SourceOutputs,
+    /// Only tracked when profile changes are reported
+    cards: Cards,
+    /// Only tracked with `--watch-clients`
+    clients: Clients,
+    /// Only tracked when module changes are reported, and only capture-related modules
+    modules: Modules,
+
+    watch: Watch,
+    reports: Vec<Report>,
+    watch_clients: bool,
+    aggregate: Option<Aggregate>,
+    monitor_sink_names: bool,
+    /// How long the server and the loop itself may take before they count as stuck
+    stall_timeout: Option<Duration>,
+    /// Told from another thread when the loop itself is stuck
+    on_stall: Option<fn(Duration)>,
+    change_queue: QueuePolicy,
+    /// How often to report the state when nothing changed, if at all
+    heartbeat: Option<Duration>,
+    health: Health,
+    latency: Option<Latency>,
+    /// Only the CLI speaks for the process to systemd
+    notify_systemd: bool,
+}
+
+/// What the default devices looked like before an event, so only changes get reported
+#[derive(Debug, Clone, Default)]
+struct Snapshot {
+    source_id: Option<u32>,
+    source_mute: Option<bool>,
+    source_volume: Option<u32>,
+    source_port: Option<String>,
+    source_running: Option<bool>,
+    source_suspended: Option<bool>,
+    sink_id: Option<u32>,
+    sink_mute: Option<bool>,
+    sink_volume: Option<u32>,
+    extra_sources: Vec<SourceSnapshot>,
+    aggregate_muted: Option<bool>,
+}
+
+/// Previous state of an extra watched source
+#[derive(Debug, Clone, Default)]
+struct SourceSnapshot {
+    id: Option<u32>,
+    mute: Option<bool>,
+    volume: Option<u32>,
+}
+
+/// A source followed by index, remembering which device was first seen there so index reuse can
+/// be told apart from the same device changing
+#[derive(Debug, Clone)]
+struct IndexBinding {
+    index: u32,
+    name: Option<String>,
+}
+
+impl IndexBinding {
+    /// Bind to whatever is at the index, or once bound, follow the device wherever it is
+    fn resolve(&mut self, sources: &Sources) -> Option<u32> {
+        match &self.name {
+            None => {
+                let src = sources.get(&self.index)?;
+                debug!("Following source {} ({})", self.index, src.name);
+                self.name = Some(src.name.to_string());
+                Some(self.index)
+            }
+            Some(name) => {
+                let idx = sources.index_of(name)?;
+                self.index = idx;
+                Some(idx)
+            }
+        }
+    }
+
+    /// Keep following the device at `idx` under the name it's taken on
+    fn rename(&mut self, idx: u32, name: &str) {
+        if idx == self.index && self.name.is_some() {
+            self.name = Some(name.to_string());
+        }
+    }
+
+    /// Name of the followed device, if `src` has taken over its index
+    fn replaced_by(&self, idx: u32, src: &SourceDatum) -> Option<&str> {
+        if idx != self.index {
+            return None;
+        }
+        self.name.as_deref().filter(|name| **name != *src.name)
+    }
+}
+
+/// How to reach the server, from the command line
+#[derive(Debug, Clone, Default)]
+struct ConnectOptions {
+    /// Rather than the default server, e.g. `tcp:studio.local:4713`
+    server: Option<String>,
+    autospawn: bool,
+    nofail: bool,
+    /// How long an attempt may take
+    timeout: Option<Duration>,
+    /// Further attempts at startup, or `None` to keep trying until the server appears
+    retries: Option<u32>,
+    /// Each server gets a mainloop of its own, of this kind
+    mainloop: MainloopKind,
+}
+
+impl ConnectOptions {
+    fn flags(&self) -> FlagSet {
+        let mut flags = FlagSet::NOFLAGS;
+        if !self.autospawn {
+            flags |= FlagSet::NOAUTOSPAWN;
+        }
+        if self.nofail {
+            flags |= FlagSet::NOFAIL;
+        }
+        flags
+    }
+}
+
+/// What the listener should follow, from the command line
+#[derive(Debug, Clone, Default)]
+struct ListenerConfig {
+    watch: Watch,
+    reports: Vec<Report>,
+    watch_clients: bool,
+    aggregate: Option<Aggregate>,
+    source_patterns: Vec<Pattern>,
+    source_index: Option<u32>,
+    preferred: Vec<Pattern>,
+    source_filter: SourceFilter,
+    monitor_sink_names: bool,
+    stall_timeout: Option<Duration>,
+    on_stall: Option<fn(Duration)>,
+    change_queue: QueuePolicy,
+    heartbeat: Option<Duration>,
+    health: Health,
+    latency: Option<Latency>,
+    sequence: Sequence,
+    notify_systemd: bool,
+}
+
+impl ListenerState {
+    fn new(
+        config: ListenerConfig,
+        mainloop: &mut Mainloop,
+        context: &mut Context,
+    ) -> Result<Self, Errors> {
+        let ListenerConfig {
+            watch,
+            reports,
+            watch_clients,
+            aggregate,
+            source_patterns,
+            source_index,
+            preferred,
+            source_filter,
+            monitor_sink_names,
+            stall_timeout,
+            on_stall,
+            change_queue,
+            heartbeat,
+            health,
+            latency,
+            sequence: _,
+            notify_systemd,
+        } = config;
+
+        let mut state = Self {
+            sources: Sources::default(),
+            watched_source: Resolution::Unknown,
+            source_patterns,
+            extra_source_ids: vec![],
+            index_binding: source_index.map(|index| IndexBinding { index, name: None }),
+            preferred,
+            source_filter,
+            sinks: HashMap::new(),
+            default_sink_id: None,
+            server_defaults: ServerDefaults::default(),
+            batch: Batch::default(),
+            source_outputs: HashMap::new(),
+            cards: HashMap::new(),
+            clients: HashMap::new(),
+            modules: HashMap::new(),
+            watch,
+            reports,
+            watch_clients,
+            aggregate,
+            monitor_sink_names,
+            stall_timeout,
+            on_stall,
+            change_queue,
+            heartbeat,
+            health,
+            latency,
+            notify_systemd,
+        };
+        state.refresh(mainloop, context)?;
+        Ok(state)
+    }
+
+    /// Fetch everything being followed from the server, and work out which devices are watched
+    #[instrument(skip_all)]
+    fn refresh(&mut self, mainloop: &mut Mainloop, context: &mut Context) -> Result<(), Errors> {
+        // The devices and defaults, all asked for at once
+        let mut queries = vec![];
+        if self.watch.sources() || self.watch.sinks() {
+            queries.push(batch::Query::Defaults);
+        }
+        if self.watch.sources() {
+            queries.push(batch::Query::Sources);
+        }
+        // Monitors are named after their sinks
+        if self.watch.sinks() || (self.watch.sources() && self.monitor_sink_names) {
+            queries.push(batch::Query::Sinks);
+        }
+        if self.reports(Report::Recording) {
+            queries.push(batch::Query::SourceOutputs);
+        }
+        if self.reports(Report::Profile) {
+            queries.push(batch::Query::Cards);
+        }
+        if self.reports(Report::Module) {
+            queries.push(batch::Query::Modules);
+        }
+        if self.watch_clients {
+            queries.push(batch::Query::Clients);
+        }
+        let mut answers = self.batch.ask(&queries, context, mainloop)?;
+        self.server_defaults = answers.defaults;
+
+        let mut sources = answers.sources;
+        sources.retain(|_, src| self.source_filter.allows(src));
+        if self.monitor_sink_names {
+            for src in sources.values_mut() {
+                label_monitor(src, &answers.sinks);
+            }
+        }
+        self.sources = sources.into_iter().collect();
+
+        if self.watch.sinks() {
+            self.sinks = answers.sinks;
+        }
+        self.default_sink_id = self
+            .server_defaults
+            .sink
+            .as_deref()
+            .and_then(|name| sink::find_sink_by_name(&self.sinks, name));
+
+        self.source_outputs = answers.source_outputs;
+        self.cards = answers.cards;
+        answers.modules.retain(|_, module| module.affects_capture());
+        self.modules = answers.modules;
+        for (idx, client) in &answers.clients {
+            debug!("Client {} already connected: {}", idx, client.name);
+        }
+        self.clients = answers.clients;
+
+        if self.watch.sources() {
+            self.resolve_sources_from(self.server_defaults.source.clone());
+        }
+        Ok(())
+    }
+
+    /// Work out which sources are being watched, from the patterns or the server's default
+    #[instrument(skip_all)]
+    fn resolve_sources(
+        &mut self,
+        mainloop: &mut Mainloop,
+        context: &mut Context,
+    ) -> Result<(), Errors> {
+        // Only the server's default needs asking the server about
+        if self.index_binding.is_none() && self.source_patterns.is_empty() {
+            self.server_defaults.source = self
+                .batch
+                .ask(&[batch::Query::Defaults], context, mainloop)?
+                .defaults
+                .source;
+        }
+        self.resolve_sources_from(self.server_defaults.source.clone());
+        Ok(())
+    }
+
+    /// Work out which sources are being watched, given the server's default source
+    fn resolve_sources_from(&mut self, default: Option<String>) {
+        let name_of = |idx: u32| self.sources.get(&idx).map(|src| src.name.to_string());
+        let name = match &mut self.index_binding {
+            Some(binding) => binding.resolve(&self.sources).and_then(name_of),
+            None => match self.source_patterns.first() {
+                Some(pattern) => find_matching_source(&self.sources, pattern).and_then(name_of),
+                None => {
+                    match default
+                        .as_deref()
+                        .and_then(|name| self.sources.index_of(name))
+                    {
+                        Some(_) => default,
+                        // The server's default is kept to, should it turn up after all
+                        None => find_preferred_source(&self.sources, &self.preferred)
+                            .and_then(name_of)
+                            .or(default),
+                    }
+                }
+            },
+        };
+        self.watched_source = Resolution::by_name(name, |name| self.sources.index_of(name));
+        debug!("Watching {:?}", self.watched_source);
+        self.extra_source_ids = self
+            .source_patterns
+            .iter()
+            .skip(1)
+            .map(|pattern| find_matching_source(&self.sources, pattern))
+            .collect();
+    }
+
+    /// Fetch a source the server named that isn't known yet, rather than every source again.
+    /// A source the server doesn't know either means the state is out of date.
+    fn fetch_named_source(
+        &mut self,
+        name: &str,
+        context: &mut Context,
+        mainloop: &mut Mainloop,
+    ) -> Result<(), Errors> {
+        if self.sources.index_of(name).is_some() {
+            return Ok(());
+        }
+        let found = self
+            .batch
+            .ask(
+                &[batch::Query::SourceByName(name.to_string())],
+                context,
+                mainloop,
+            )?
+            .lookups
+            .pop()
+            .flatten();
+        match found {
+            None => Err(Errors::Inconsistent(format!(
+                "default source {} doesn't exist",
+                name
+            ))),
+            Some((idx, src)) if !self.source_filter.allows(&src) => {
+                trace!("Ignoring filtered out source {} ({})", idx, src.name);
+                Ok(())
+            }
+            Some((idx, mut src)) => {
+                if self.monitor_sink_names {
+                    fetch_monitor_label(&mut src, &self.batch, context, mainloop)?;
+                }
+                match self.sources.get(&idx) {
+                    Some(stale) => debug!("Source {} was {}, now {}", idx, stale.name, src.name),
+                    None => debug!("Fetched source {} ({})", idx, src.name),
+                }
+                self.sources.insert(idx, src);
+                Ok(())
+            }
+        }
+    }
+
+    /// Look the watched source's index up again from its name, after sources came or went
+    fn rebind_default_source(&mut self) {
+        let before = self.watched_source.index();
+        match &mut self.index_binding {
+            // The binding keeps its own index up to date, to tell reuse apart from a change
+            Some(binding) => {
+                binding.resolve(&self.sources);
+                self.watched_source =
+                    Resolution::by_name(binding.name.clone(), |name| self.sources.index_of(name));
+            }
+            None => self
+                .watched_source
+                .retry(|name| self.sources.index_of(name)),
+        }
+        if self.watched_source.index() != before {
+            debug!("Watched source now {:?}", self.watched_source);
+        }
+    }
+
+    /// Index of the watched source, if it's there
+    fn default_source_id(&self) -> Option<u32> {
+        self.watched_source.index()
+    }
+
+    /// Whether the listener picks the watched source itself, so losing it means finding another
+    /// rather than waiting for the server to announce a new default
+    fn picks_own_source(&self) -> bool {
+        !self.source_patterns.is_empty()
+            || self.index_binding.is_some()
+            || !self.preferred.is_empty()
+    }
+
+    /// Whether any watched source is still missing, and might be found after a change
+    fn has_unresolved_sources(&self) -> bool {
+        self.default_source_id().is_none() || self.extra_source_ids.contains(&None)
+    }
+
+    /// Combined mute state of every tracked source, if aggregating
+    fn aggregate_muted(&self) -> Option<bool> {
+        self.aggregate
+            .map(|aggregate| aggregate.muted(self.sources.values().map(|src| src.mute)))
+    }
+
+    /// Whether the source is one of the watched ones
+    fn is_watched_source(&self, idx: u32) -> bool {
+        self.default_source_id() == Some(idx) || self.extra_source_ids.contains(&Some(idx))
+    }
+
+    /// Every tracked source, by index
+    fn source_list(&self) -> EventKind {
+        let mut sources: Vec<ListedSource> = self
+            .sources
+            .iter()
+            .map(|(idx, src)| ListedSource {
+                source: src.display_name().clone(),
+                index: *idx,
+                muted: src.mute,
+                volume: src.volume_percent(),
+                default: self.default_source_id() == Some(*idx),
+            })
+            .collect();
+        sources.sort_by_key(|src| src.index);
+        EventKind::SourceList { sources }
+    }
+
+    fn reports(&self, report: Report) -> bool {
+        self.reports.contains(&report)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            source_id: self.default_source_id(),
+            source_mute: self.default_source().map(|src| src.mute),
+            source_volume: self.default_source().map(|src| src.volume_percent()),
+            source_port: self
+                .default_source()
+                .and_then(|src| src.active_port.clone()),
+            source_running: self.default_source().map(|src| src.running()),
+            source_suspended: self.default_source().map(|src| src.suspended()),
+            sink_id: self.default_sink_id,
+            sink_mute: self.default_sink().map(|sink| sink.mute),
+            sink_volume: self.default_sink().map(|sink| sink.volume_percent()),
+            extra_sources: self
+                .extra_source_ids
+                .iter()
+                .map(|id| {
+                    let src = id.and_then(|id| self.sources.get(&id));
+                    SourceSnapshot {
+                        id: *id,
+                        mute: src.map(|src| src.mute),
+                        volume: src.map(|src| src.volume_percent()),
+                    }
+                })
+                .collect(),
+            aggregate_muted: self.aggregate_muted(),
+        }
+    }
+
+    /// The state has caught up with the server, as the health check and systemd get told
+    fn refreshed(&self) {
+        self.health.refreshed();
+        if self.notify_systemd {
+            systemd::status(&self.status());
+        }
+    }
+
+    /// One line on the watched device, for `systemctl status`
+    fn status(&self) -> String {
+        let describe = |name: &str, muted: bool, volume: u32| {
+            let mute = if muted { "muted" } else { "unmuted" };
+            format!("{}: {} at {}%", name, mute, volume)
+        };
+        if self.watch.sources() {
+            match self.default_source() {
+                Some(src) => describe(src.display_name(), src.mute, src.volume_percent()),
+                None => "No source to watch".to_string(),
+            }
+        } else {
+            match self.default_sink() {
+                Some(sink) => describe(&sink.name, sink.mute, sink.volume_percent()),
+                None => "No sink to watch".to_string(),
+            }
+        }
+    }
+
+    fn default_sink(&self) -> Option<&SinkDatum> {
+        self.default_sink_id.and_then(|idx| self.sinks.get(&idx))
+    }
+
+    fn default_source<'a>(&'a self) -> Option<&'a SourceDatum> {
+        if let Some(src_id) = self.default_source_id() {
+            return self.sources.get(&src_id);
+        };
+        None
+    }
+}
+
+fn bind_signals(mainloop: &mut Mainloop, sig_tx: RequestTx) -> Result<Vec<SignalEvent>, Errors> {
+    let mut signals = vec![];
+    for sig_id in &[1, 2, 15] {
+        let sig_tx = sig_tx.clone();
+
+        signals.push(SignalEvent::new(*sig_id, move |sig_num| {
+            // TODO: can I translate from i32 to human-readable name..?
+            info!("Received a signal, num {}", sig_num);
+            if sig_tx.send(ListenerRequest::Shutdown).is_err() {
+                debug!("Nothing left listening to shut down");
+            }
+        }));
+        trace!("configuring signal handler for {}", sig_id);
+    }
+
+    mainloop.init_signals()?;
+    Ok(signals)
+}
+
+/// Run as the command line tool, with the process' arguments
+pub fn cli() -> Result<(), Errors> {
+    let args = Args::parse();
+    let daemon = args.daemon;
+    let result = run(args);
+    // Nobody sees stderr once we're in the background
+    if let (true, Err(err)) = (daemon, &result) {
+        error!("{}", err);
+    }
+    result
+}
+
+fn run(mut args: Args) -> Result<(), Errors> {
+    // Setting the environment races any thread reading it, so it goes before anything starts one
+    if let Some(cookie) = &args.cookie {
+        use_cookie(cookie)?;
+    }
+    logging::setup(args.log_config())?;
+    // Before anything starts a thread, as only the forking one would carry on
+    let pidfile = if args.daemon {
+        if args.command.is_some() {
+            return Err(Errors::ConfigError(
+                "--daemon only applies when listening".to_string(),
+            ));
+        }
+        if args.log_target == LogTarget::Stderr && args.log_file.is_none() {
+            warn!(
+                "Logs will be discarded in the background, --log-target or --log-file keeps them"
+            );
+        }
+        daemon::daemonize(args.pidfile().as_deref())?
+    } else {
+        args.pidfile()
+            .as_deref()
+            .map(daemon::Pidfile::create)
+            .transpose()?
+    };
+    #[cfg(feature = "otel")]
+    let _telemetry = args
+        .otlp_endpoint
+        .as_deref()
+        .map(telemetry::Telemetry::export)
+        .transpose()?;
+
+    let mut builder = args.builder().notify_systemd();
+    // Simulating needs no server at all
+    if let Some(Command::Simulate { script, interval }) = args.take_if_simulate() {
+        let health = Health::default();
+        let mut output = build_output(args, builder.backends(), None, None, &health)?;
+        return simulate::simulate(script.as_deref(), interval, output.as_mut());
+    }
+
+    // Asks the running listener, which has the connection
+    if let Some(Command::Health { max_age }) = args.command {
+        let socket = args.socket.as_deref().ok_or(Errors::ConfigError(
+            "health asks the listener over its --socket, so needs the path".to_string(),
+        ))?;
+        std::process::exit(health::check(socket, max_age)?);
+    }
+
+    let servers = builder.connect_options()?;
+    if servers.len() > 1 && args.command.is_some() {
+        return Err(Errors::ConfigError(
+            "--server can only be given once, except when listening".to_string(),
+        ));
+    }
+    // Commands and the doctor only ever have the one
+    let connect = &servers[0];
+
+    let (tx, rx) = mainloop::channel();
+    // Several servers' listeners have an event bus in front, which takes the requests instead
+    let (bus_tx, bus) = mainloop::channel();
+    let requests = match servers.len() {
+        1 => RequestTx::Listener(tx.clone()),
+        _ => RequestTx::Bus(bus_tx.clone()),
+    };
+    let mut mainloop = Mainloop::new(connect.mainloop)
+        .ok_or(Errors::ContextError("mainloop new failed".to_string()))?;
+    let mut sig_events = bind_signals(&mut mainloop, requests.clone())?;
+    // Only connected when there's a single server, as each of several gets its own
+    let mut context = new_context(&mainloop)?;
+
+    // The doctor checks connecting itself, so it has to run before we do
+    if let Some(Command::Doctor) = args.command {
+        let result = doctor::doctor(&mut context, &mut mainloop, connect, tx.clone(), &rx);
+        terminate(mainloop, context, sig_events);
+        std::process::exit(commands::exit_code(result));
+    }
+
+    if servers.len() > 1 {
+        // Still needed for the signal handlers
+        mainloop.start()?;
+    } else {
+        info!("Connecting to daemon");
+        match connect_at_startup(&mut context, &mut mainloop, connect, tx.clone(), &rx) {
+            Ok(()) => {}
+            Err(Errors::Shutdown) => return Ok(()),
+            Err(err) => {
+                error!("{}", err);
+                // Exiting skips the usual cleanup
+                drop(pidfile);
+                std::process::exit(startup_exit_code(&err));
+            }
+        }
+    }
+
+    if let Some(command) = args.command.take() {
+        let result = commands::run(
+            command,
+            &args.state_texts(),
+            &mut mainloop,
+            &mut context,
+            tx.clone(),
+            rx,
+        );
+        terminate(mainloop, context, sig_events);
+        // Commands report their outcome through the exit code
+        std::process::exit(commands::exit_code(result));
+    }
+
+    if args.format == OutputFormat::I3bar {
+        if args.stdin_commands {
+            return Err(Errors::ConfigError(
+                "--stdin-commands can't be used with i3bar output, which reads clicks from stdin"
+                    .to_string(),
+            ));
+        }
+        i3bar::spawn_click_reader(requests.clone())?;
+    }
+    if args.stdin_commands {
+        control::spawn_stdin_reader(requests.clone())?;
+    }
+    if let Some(every) = args.measure_latency {
+        builder = builder.measure_latency(Latency::measure(Duration::from_secs(every))?);
+    }
+    let config = builder.listener_config()?;
+    let history = args.dump_history_on.map(|signal| {
+        let history = History::new(args.history_size);
+        sig_events.push(history::bind_dump_signal(
+            signal,
+            history.clone(),
+            args.state_texts(),
+        ));
+        history
+    });
+    let reconnects = builder.reconnects();
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+    let mut output = build_output(
+        args,
+        builder.backends(),
+        history,
+        Some(requests),
+        &config.health,
+    )?;
+    let subscribe_result = if servers.len() > 1 {
+        servers::listen_to_servers(
+            servers,
+            &config,
+            output.as_mut(),
+            reconnects,
+            &mut mainloop,
+            bus_tx,
+            &bus,
+        )
+    } else {
+        listen(
+            &mut mainloop,
+            &mut context,
+            connect,
+            &config,
+            output.as_mut(),
+            reconnects,
+            tx.clone(),
+            &rx,
+        )
+    };
+    info!("shutting down");
+    // Nothing more is taken in, but what's already on its way out gets a chance to finish
+    if let Err(err) = output.finish(Instant::now() + shutdown_timeout) {
+        debug!("Failed to finish off output: {}", err);
+    }
+    // The last stretch, since the latency was last reported
+    if let Some(latency) = &config.latency {
+        latency.report();
+    }
+    if let Err(err) = output.emit(&Event::new(EventKind::Shutdown)) {
+        debug!("Failed to report shutting down: {}", err);
+    }
+    terminate(mainloop, context, sig_events);
+
+    if let Err(Errors::Shutdown) = subscribe_result {
+        return Ok(());
+    }
+    return subscribe_result;
+}
+
+/// Follow the server until told to stop, reconnecting whenever the connection is lost
+#[allow(clippy::too_many_arguments)]
+fn listen(
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+    connect: &ConnectOptions,
+    config: &ListenerConfig,
+    output: &mut dyn Output,
+    reconnects: bool,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    output::number_events(&config.sequence);
+    let mut reconnected = false;
+    loop {
+        let state = ListenerState::new(config.clone(), mainloop, context)?;
+        config.health.connected(true);
+        if reconnected {
+            output.emit(&Event::new(EventKind::Reconnected))?;
+        }
+        report_changes(&state, None, output)?;
+        let subscribed = subscribe_source_mute(mainloop, context, state, output, tx.clone(), rx);
+        config.health.connected(false);
+        match subscribed {
+            Err(Errors::Disconnected) if reconnects => {
+                info!("Lost the connection to the daemon");
+            }
+            Err(Errors::Stalled) if reconnects => {
+                info!("The daemon stopped answering");
+            }
+            result => return result,
+        }
+        reconnect(context, mainloop, connect, None, tx.clone(), rx)?;
+        reconnected = true;
+    }
+}
+
+impl Args {
+    fn log_config(&self) -> LogConfig {
+        LogConfig {
+            verbose: self.verbose,
+            target: self.log_target,
+            format: self.log_format,
+            syslog: SyslogConfig {
+                server: self.syslog_server.clone().unwrap_or_default(),
+                facility: self.syslog_facility,
+                app_name: self.syslog_app_name.clone(),
+            },
+            file: self.log_file.clone().map(|path| LogFileConfig {
+                path,
+                max_size: self.log_file_max_size,
+                rotation: self.log_file_rotate,
+                keep: self.log_file_keep,
+            }),
+        }
+    }
+
+    fn pidfile(&self) -> Option<PathBuf> {
+        self.pidfile.clone().or_else(|| {
+            let runtime_dir = env::var_os("XDG_RUNTIME_DIR").filter(|_| self.daemon)?;
+            Some(PathBuf::from(runtime_dir).join(concat!(env!("CARGO_PKG_NAME"), ".pid")))
+        })
+    }
+
+    /// The options on connecting, on what to follow and on serving it to other programs, as a
+    /// library user would give them
+    fn builder(&self) -> SourceListenerBuilder {
+        let mut builder = SourceListenerBuilder::default()
+            .autospawn(self.autospawn)
+            .nofail(self.nofail)
+            .connect_retries(self.connect_retries)
+            .mainloop(self.mainloop)
+            .reconnect(!self.no_reconnect)
+            .watch(self.watch)
+            .watch_clients(self.watch_clients)
+            // Whichever of the two came last wins, clearing the other
+            .include_monitors(self.include_monitors && !self.no_monitors)
+            .only_hardware(self.only_hardware)
+            .monitor_sink_names(self.monitor_sink_names)
+            .backpressure(self.backpressure)
+            .change_queue_size(self.change_queue_size)
+            .debounce(Duration::from_millis(self.coalesce_window));
+        for server in &self.server {
+            builder = builder.server(server);
+        }
+        if let Some(secs) = self.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        if self.wait_for_server {
+            builder = builder.wait_for_server();
+        }
+        for source in self.name.iter().chain(&self.sources) {
+            builder = builder.source(source);
+        }
+        if let Some(index) = self.index {
+            builder = builder.index(index);
+        }
+        for pattern in &self.prefer {
+            builder = builder.prefer(pattern);
+        }
+        for regex in &self.include {
+            builder = builder.include(regex);
+        }
+        for regex in &self.exclude {
+            builder = builder.exclude(regex);
+        }
+        if let Some(aggregate) = self.aggregate {
+            builder = builder.aggregate(aggregate);
+        }
+        if let Some(secs) = self.stall_timeout {
+            builder = builder
+                .stall_timeout(Duration::from_secs(secs))
+                .on_stall(exit_stalled);
+        }
+        if let Some(secs) = self.heartbeat {
+            builder = builder.heartbeat(Duration::from_secs(secs));
+        }
+        builder = builder
+            .consumer_drop_policy(self.consumer_drop_policy)
+            .consumer_queue_size(self.consumer_queue_size);
+        if let Some(path) = &self.socket {
+            builder = builder.socket(path);
+        }
+        if let Some(addr) = self.tcp_listen {
+            builder = builder.tcp_listen(addr).tcp_control(self.tcp_control);
+        }
+        if let Some(addr) = self.http_listen {
+            builder = builder.http_listen(addr);
+        }
+        if let Some(addr) = self.metrics_listen {
+            builder = builder.metrics_listen(addr);
+        }
+        #[cfg(feature = "websocket")]
+        if let Some(addr) = self.ws_listen {
+            builder = builder.ws_listen(addr).ws_control(self.ws_control);
+            for origin in &self.ws_origins {
+                builder = builder.ws_origin(origin);
+            }
+        }
+        #[cfg(feature = "webhook")]
+        {
+            for url in &self.webhooks {
+                builder = builder.webhook(url);
+            }
+            builder = builder.webhook_policy(WebhookPolicy {
+                headers: self.webhook_headers.clone(),
+                secret: self.webhook_secret.clone(),
+                retries: self.webhook_retries,
+                retry_delay: self.webhook_retry_delay,
+            });
+        }
+
+        // Hooks can only fire for changes that get reported
+        let mut reports = self.report.clone();
+        let hooked = [
+            (Report::Default, &self.on_default_change),
+            (Report::Devices, &self.on_source_new),
+            (Report::Devices, &self.on_source_removed),
+        ];
+        for (report, command) in hooked {
+            if command.is_some() && !reports.contains(&report) {
+                reports.push(report);
+            }
+        }
+        // Waybar's percentage is the volume, which has to be kept up with too
+        if self.format == OutputFormat::Waybar && !reports.contains(&Report::Volume) {
+            reports.push(Report::Volume);
+        }
+        builder.reports(reports)
+    }
+
+    /// Take the command out of the args, but only if it's `simulate`
+    fn take_if_simulate(&mut self) -> Option<Command> {
+        match self.command {
+            Some(Command::Simulate { .. }) => self.command.take(),
+            _ => None,
+        }
+    }
+
+    /// Hook commands, paired with what triggers them
+    fn hooks(&self) -> Vec<(Trigger, String)> {
+        [
+            (Trigger::MuteChange, &self.exec),
+            (Trigger::Mute, &self.on_mute),
+            (Trigger::Unmute, &self.on_unmute),
+            (Trigger::DefaultChange, &self.on_default_change),
+            (Trigger::SourceNew, &self.on_source_new),
+            (Trigger::SourceRemoved, &self.on_source_removed),
+        ]
+        .into_iter()
+        .filter_map(|(trigger, command)| Some((trigger, command.clone()?)))
+        .collect()
+    }
+
+    fn state_texts(&self) -> StateTexts {
+        StateTexts {
+            mute: self.mute_text.clone().unwrap(),
+            unmute: self.unmute_text.clone().unwrap(),
+            nosource: self.no_src_text.clone().unwrap(),
+            sink_mute: self.sink_mute_text.clone(),
+            sink_unmute: self.sink_unmute_text.clone(),
+            nosink: self.no_sink_text.clone(),
+        }
+    }
+}
+
+/// Build the outputs events are sent to, `backends` among them. Requests, hook failures and
+/// script actions are sent to `tx`, if given, for the listener loop to handle.
+fn build_output(
+    args: Args,
+    backends: &Backends,
+    history: Option<History>,
+    tx: Option<RequestTx>,
+    health: &Health,
+) -> Result<Box<dyn Output>, Errors> {
+    // Plugins go in front of everything else, so they can transform events for every output
+    #[cfg(feature = "wasm")]
+    if !args.plugins.is_empty() {
+        let mut args = args;
+        let plugins = plugin::load_plugins(&std::mem::take(&mut args.plugins))?;
+        let next = build_output(args, backends, history, tx, health)?;
+        return Ok(Box::new(plugin::PluginOutput::new(
+            plugins,
+            next,
+            io::stdout(),
+        )));
+    }
+
+    let mut outputs: Vec<Box<dyn Output>> = vec![];
+
+    if let Some(history) = history {
+        outputs.push(Box::new(history));
+    }
+
+    if let Some(path) = &args.event_log {
+        outputs.push(dispatched(EventLogOutput::open(path)?)?);
+    }
+
+    #[cfg(feature = "native-plugins")]
+    {
+        let mut paths = args.native_plugins.clone();
+        if !args.no_plugin_discovery {
+            paths.extend(native_plugin::discover_plugins());
+        }
+        if !paths.is_empty() {
+            outputs.push(Box::new(native_plugin::NativePluginOutput::load(&paths)?));
+        }
+    }
+
+    #[cfg(feature = "rules")]
+    if let Some(path) = &args.rules {
+        outputs.push(Box::new(rules::RulesOutput::load(path.clone())?));
+    }
+
+    outputs.extend(backends.start(tx.as_ref(), health)?);
+
+    #[cfg(feature = "zmq")]
+    if let Some(endpoint) = &args.zmq_pub {
+        outputs.push(Box::new(zmq_pub::ZmqOutput::bind(endpoint)?));
+    }
+
+    if !args.udp_targets.is_empty() {
+        outputs.push(Box::new(udp::UdpOutput::start(
+            &args.udp_targets,
+            args.udp_interval,
+        )?));
+    }
+
+    if let Some(target) = &args.statsd {
+        outputs.push(dispatched(StatsdOutput::new(target, args.statsd_format)?)?);
+    }
+
+    if let Some(path) = &args.varlink {
+        match &tx {
+            Some(tx) => outputs.push(dispatched(varlink::VarlinkOutput::start(
+                path.clone(),
+                tx.clone(),
+            )?)?),
+            None => info!(
+                "Not serving {}, there's no server to act on",
+                path.display()
+            ),
+        }
+    }
+
+    #[cfg(feature = "dbus")]
+    if args.dbus {
+        match &tx {
+            Some(tx) => outputs.push(dispatched(dbus::DbusOutput::start(tx.clone())?)?),
+            None => info!("Not publishing on D-Bus, there's no server to act on"),
+        }
+    }
+
+    if args.log_target != LogTarget::Stderr || args.log_format == LogFormat::Json {
+        outputs.push(Box::new(logging::LogOutput));
+    }
+
+    let hooks = args.hooks();
+    if !hooks.is_empty() {
+        let policy = HookPolicy {
+            timeout: args.hook_timeout,
+            kill_on_timeout: args.hook_kill_on_timeout,
+            overlap: args.hook_overlap,
+            retries: args.hook_retries,
+            retry_delay: args.hook_retry_delay,
+            debounce: args.hook_debounce.map(Duration::from_millis),
+            max_rate: args.hook_max_rate,
+        };
+        outputs.push(Box::new(HookRunner::new(hooks, policy, tx.clone())));
+    }
+
+    if let Some(path) = &args.state_file {
+        let buffer = state_file::SharedBuffer::default();
+        let formatter = format_output(&args, buffer.clone(), None)?;
+        outputs.push(dispatched(state_file::StateFileOutput::new(
+            path.clone(),
+            formatter,
+            buffer,
+            args.state_file_timestamp,
+        )?)?);
+    }
+
+    #[cfg(feature = "lua")]
+    if let Some(path) = &args.lua_script {
+        outputs.push(Box::new(script::ScriptOutput::load(
+            path,
+            io::stdout(),
+            tx.clone(),
+        )?));
+        return Ok(Box::new(FanoutOutput::new(outputs)));
+    }
+
+    let stdout = format_output(&args, io::stdout(), Theme::for_stdout(args.color))?;
+    outputs.push(Box::new(DispatchedOutput::spawn(stdout)?));
+    Ok(Box::new(FanoutOutput::new(outputs)))
+}
+
+/// Run `output` on a thread of its own, for outputs that write as they go and could block.
+/// Those that already hand events on to threads of their own go without.
+fn dispatched(output: impl Output + Send + 'static) -> Result<Box<dyn Output>, Errors> {
+    Ok(Box::new(DispatchedOutput::spawn(Box::new(output))?))
+}
+
+/// The chosen template or format, writing to `writer`
+fn format_output<W: Write + Send + 'static>(
+    args: &Args,
+    writer: W,
+    theme: Option<Theme>,
+) -> Result<Box<dyn Output + Send>, Errors> {
+    let texts = args.state_texts();
+    if let Some(template) = &args.template {
+        let template = Template::parse(template)
+            .map_err(|err| Errors::ConfigError(format!("invalid --template: {}", err)))?;
+        return Ok(Box::new(TemplateOutput::new(
+            writer, template, texts, theme,
+        )));
+    }
+
+    Ok(match args.format {
+        OutputFormat::Plain => Box::new(
+            PlainOutput::new(writer, texts)
+                .with_timestamps(args.timestamps)
+                .with_seq(args.seq)
+                .with_theme(theme),
+        ),
+        OutputFormat::Json => Box::new(JsonOutput::new(writer)),
+        OutputFormat::Waybar => Box::new(WaybarOutput::new(writer, texts)),
+        OutputFormat::I3bar => Box::new(I3barOutput::new(writer, texts)),
+        OutputFormat::Csv => Box::new(CsvOutput::new(writer)),
+        OutputFormat::Msgpack => Box::new(MsgpackOutput::new(writer)),
+        OutputFormat::Polybar => Box::new(PolybarOutput::new(
+            writer,
+            texts,
+            PolybarStyle {
+                prefix: args.polybar_prefix.clone(),
+                suffix: args.polybar_suffix.clone(),
+                mute_color: args.polybar_mute_color.clone(),
+                unmute_color: args.polybar_unmute_color.clone(),
+                nosource_color: args.polybar_no_src_color.clone(),
+            },
+        )),
+    })
+}
+
+fn new_context(mainloop: &Mainloop) -> Result<Context, Errors> {
+    let proplist = Proplist::new().ok_or(Errors::ContextError("proplist failed".to_string()))?;
+    mainloop
+        .new_context("source-listener", &proplist)
+        .ok_or(Errors::ContextError(
+            "context::new_with_proplist failed".to_string(),
+        ))
+}
+
+/// Event reporting a hook that kept failing
+fn hook_failed(failure: HookFailure) -> Event {
+    Event::new(EventKind::HookFailed {
+        hook: failure.command,
+        triggered_by: failure.event.to_string(),
+        attempts: failure.attempts,
+        reason: failure.reason,
+    })
+}
+
+fn terminate(mut mainloop: Mainloop, mut context: Context, sig_events: Vec<SignalEvent>) {
+    trace!("Disconnecting context");
+    let guard = MainloopGuard::lock(&mut mainloop);
+    context.disconnect();
+    drop(guard);
+    trace!("Stopping mainloop");
+    mainloop.stop();
+    trace!("dropping signal handlers");
+    drop(sig_events);
+    // Seems to cause crashes... unsure why
+    // mainloop.signals_done();
+    trace!("Termination complete");
+}
+
+fn set_source_mute(
+    idx: u32,
+    mute: bool,
+    batch: &Batch,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+) -> Result<(), Errors> {
+    batch.ask(&[batch::Query::SetSourceMute(idx, mute)], context, mainloop)?;
+    debug!("Set mute to {} for source {}", mute, idx);
+    Ok(())
+}
+
+/// A change the server refused, e.g. to a source that's just gone, is no reason to stop
+/// listening: it's logged, with the reason handed back. Anything else is passed on.
+fn tolerate_refusal(result: Result<(), Errors>) -> Result<Result<(), String>, Errors> {
+    match result {
+        Ok(()) => Ok(Ok(())),
+        Err(Errors::ContextError(err)) => {
+            warn!("Carrying on after the server refused: {}", err);
+            Ok(Err(err))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn set_source_volume(
+    idx: u32,
+    volume: &ChannelVolumes,
+    batch: &Batch,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+) -> Result<(), Errors> {
+    batch.ask(
+        &[batch::Query::SetSourceVolume(idx, *volume)],
+        context,
+        mainloop,
+    )?;
+    debug!(
+        "Set volume to {} for source {}",
+        volume_percent(volume),
+        idx
+    );
+    Ok(())
+}
+
+fn set_default_source(
+    name: &str,
+    batch: &Batch,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+) -> Result<(), Errors> {
+    batch.ask(
+        &[batch::Query::SetDefaultSource(name.to_string())],
+        context,
+        mainloop,
+    )?;
+    debug!("Set default source to {}", name);
+    Ok(())
+}
+
+fn get_sources(context: &mut Context, mainloop: &mut Mainloop) -> Result<Sources, Errors> {
+    Ok(Batch::default()
+        .ask(&[batch::Query::Sources], context, mainloop)?
+        .sources
+        .into_iter()
+        .collect())
+}
+
+/// The server's default device names
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct ServerDefaults {
+    source: Option<String>,
+    sink: Option<String>,
+}
+
+fn get_default_source_index(
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+    sources: &Sources,
+) -> Result<Option<u32>, Errors> {
+    let default_source = Batch::default()
+        .ask(&[batch::Query::Defaults], context, mainloop)?
+        .defaults
+        .source;
+
+    if let Some(index) = default_source.and_then(|name| sources.index_of(&name)) {
+        debug!("Default source is index {}", index);
+        return Ok(Some(index));
+    }
+
+    info!("no default source available");
+    Ok(None)
+}
+
+/// The first source (by index) whose name matches `pattern`
+fn find_matching_source(sources: &Sources, pattern: &Pattern) -> Option<u32> {
+    let found = sources
+        .iter()
+        .filter(|(_, source)| pattern.matches(&source.name))
+        .map(|(index, _)| *index)
+        .min();
+    match found {
+        Some(index) => debug!("Watching source {} matching '{}'", index, pattern),
+        None => info!("no source matches '{}'", pattern),
+    }
+    found
+}
+
+/// The best available source by `preferred`, for when there's no default. Within a pattern,
+/// the lowest index wins.
+fn find_preferred_source(sources: &Sources, preferred: &[Pattern]) -> Option<u32> {
+    let found = preferred.iter().find_map(|pattern| {
+        sources
+            .iter()
+            .filter(|(_, source)| {
+                pattern.matches(&source.name)
+                    || source
+                        .description
+                        .as_deref()
+                        .is_some_and(|desc| pattern.matches(desc))
+            })
+            .map(|(index, _)| *index)
+            .min()
+    });
+    if let Some(index) = found {
+        info!("Falling back to preferred source {}", index);
+    }
+    found
+}
+
+/// How often the loop checks on the connection and subscription itself, in case a callback that
+/// should have told it about a problem never came
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Catch the connection or the subscription having gone wrong without a callback saying so,
+/// which would otherwise leave the loop waiting on events that never come
+fn check_liveness(context: &Context, subscribed: &AtomicBool) -> Result<(), Errors> {
+    match context.get_state() {
+        State::Ready => {}
+        state => {
+            warn!(
+                "Connection is {:?}, though nothing said so, reconnecting",
+                state
+            );
+            return Err(Errors::Disconnected);
+        }
+    }
+    if !subscribed.load(Ordering::Relaxed) {
+        warn!("Subscribing to the server's events never went through, reconnecting");
+        return Err(Errors::Disconnected);
+    }
+    trace!("Connection and subscription still fine");
+    Ok(())
+}
+
+/// How long after losing track of the server's state everything is fetched again, giving
+/// whatever went wrong a moment to settle
+const RESYNC_DELAY: Duration = Duration::from_secs(1);
+
+fn subscribe_source_mute(
+    mainloop: &mut Mainloop,
+    context: &mut Context,
+    mut state: ListenerState,
+    output: &mut dyn Output,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    // Sources toggle their mute state, default source changes Server state
+    let mut source_mask = InterestMaskSet::SERVER;
+    if state.watch.sources() {
+        source_mask |= InterestMaskSet::SOURCE;
+    }
+    if state.watch.sinks() {
+        source_mask |= InterestMaskSet::SINK;
+    }
+    if state.reports(Report::Recording) {
+        source_mask |= InterestMaskSet::SOURCE_OUTPUT;
+    }
+    if state.reports(Report::Profile) {
+        source_mask |= InterestMaskSet::CARD;
+    }
+    if state.watch_clients {
+        source_mask |= InterestMaskSet::CLIENT;
+    }
+    if state.reports(Report::Module) {
+        source_mask |= InterestMaskSet::MODULE;
+    }
+
+    trace!("Configuring context subscriber");
+
+    // Block pulseaudio from invoking callbacks
+    let guard = MainloopGuard::lock(mainloop);
+
+    let changes = Arc::new(ChangeQueue::new(state.change_queue));
+
+    // tell pulseaudio to notify us about Source & Server changes
+    {
+        // set callback that reacts to subscription changes
+        let tx = tx.clone();
+        let changes = changes.clone();
+        context.set_subscribe_callback(Some(Box::new(
+            move |facility: Option<Facility>, operation: Option<Operation>, idx| {
+                let (Some(facility), Some(operation)) = (facility, operation) else {
+                    debug!("Subscribe callback for an unknown facility or operation");
+                    return;
+                };
+                debug!(
+                    "Subcribe callback: {:?}, {:?}, {:?}",
+                    facility, operation, idx
+                );
+                // Covers the wait for the loop too, so time spent queued shows up
+                let span = info_span!(
+                    "pulse_event",
+                    facility = ?facility,
+                    operation = ?operation,
+                    index = idx
+                );
+
+                let change = match facility {
+                    Facility::Source => match operation {
+                        Operation::Changed => PulseChange::SourceChange(idx),
+                        Operation::New => PulseChange::SourceNew(idx),
+                        Operation::Removed => PulseChange::SourceDrop(idx),
+                    },
+                    Facility::Sink => match operation {
+                        Operation::Changed => PulseChange::SinkChange(idx),
+                        Operation::New => PulseChange::SinkNew(idx),
+                        Operation::Removed => PulseChange::SinkDrop(idx),
+                    },
+                    Facility::SourceOutput => match operation {
+                        Operation::Changed => PulseChange::SourceOutputChange(idx),
+                        Operation::New => PulseChange::SourceOutputNew(idx),
+                        Operation::Removed => PulseChange::SourceOutputDrop(idx),
+                    },
+                    Facility::Card => match operation {
+                        Operation::Changed => PulseChange::CardChange(idx),
+                        Operation::New => PulseChange::CardNew(idx),
+                        Operation::Removed => PulseChange::CardDrop(idx),
+                    },
+                    Facility::Client => match operation {
+                        Operation::Changed => PulseChange::ClientChange(idx),
+                        Operation::New => PulseChange::ClientNew(idx),
+                        Operation::Removed => PulseChange::ClientDrop(idx),
+                    },
+                    Facility::Module => match operation {
+                        Operation::New => PulseChange::ModuleNew(idx),
+                        Operation::Removed => PulseChange::ModuleDrop(idx),
+                        // Module changes are only proplist updates
+                        Operation::Changed => return,
+                    },
+                    Facility::Server => PulseChange::Server,
+                    _ => {
+                        debug!("Unrelated event: {:?}", facility);
+                        return;
+                    }
+                };
+                // The loop can't update anything from here, as we're already inside a callback,
+                // so the change is queued for it
+                changes.push(change, span, &tx);
+            },
+        )));
+    }
+
+    let subscribed = Arc::new(AtomicBool::new(false));
+    let confirmed = subscribed.clone();
+    let health = state.health.clone();
+    let notify_systemd = state.notify_systemd;
+    context.subscribe(source_mask, move |sub_success| {
+        debug!(
+            "Subscribing to source changes {}",
+            match sub_success {
+                true => "succeeded",
+                false => "failed",
+            }
+        );
+        if sub_success {
+            confirmed.store(true, Ordering::Relaxed);
+            health.subscribed();
+            if notify_systemd {
+                systemd::ready();
+            }
+        }
+    });
+    state.refreshed();
+    let mut watchdog = state
+        .notify_systemd
+        .then(systemd::Watchdog::from_env)
+        .flatten();
+    let mut probe = state.stall_timeout.map(stall::Probe::new);
+    let supervisor = state
+        .stall_timeout
+        .map(|timeout| stall::Supervisor::spawn(timeout, state.on_stall))
+        .transpose()?;
+    // When to fetch everything again, after losing track of the server's state
+    let mut resync: Option<Instant> = None;
+    let mut checked = Instant::now();
+    let mut heartbeat = state.heartbeat.map(|every| Instant::now() + every);
+
+    trace!("Starting subscribe mainloop");
+    // Allow pulseaudio to process callbacks again
+    drop(guard);
+    loop {
+        // When we receive data via channel here, it means, we should update sources, and then
+        // print if the mute state of the default source, changed.
+
+        if resync.is_some_and(|at| Instant::now() >= at) {
+            let before = state.snapshot();
+            match state.refresh(mainloop, context) {
+                Ok(()) => {
+                    info!("Resynced with the server");
+                    resync = None;
+                    report_changes(&state, Some(before), output)?;
+                    state.refreshed();
+                }
+                Err(err) if err.is_introspection_failure() => {
+                    warn!("Resyncing failed ({}), trying again", err);
+                    resync = Some(Instant::now() + RESYNC_DELAY);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        changes.release(&tx);
+
+        let old = state.snapshot();
+
+        if let Some(watchdog) = &mut watchdog {
+            watchdog.feed();
+        }
+        if let Some(probe) = &mut probe {
+            probe.check(context, mainloop, &tx)?;
+        }
+        if checked.elapsed() >= LIVENESS_INTERVAL {
+            check_liveness(context, &subscribed)?;
+            checked = Instant::now();
+        }
+        if let (Some(at), Some(every)) = (heartbeat, state.heartbeat) {
+            if Instant::now() >= at {
+                report_state(&state, output)?;
+                heartbeat = Some(Instant::now() + every);
+            }
+        }
+        // Only as long as the liveness check, the watchdog, the probe, a pending resync, the
+        // heartbeat and held changes can wait
+        let wait = [
+            watchdog.as_ref().map(systemd::Watchdog::timeout),
+            probe.as_ref().map(stall::Probe::wait),
+            resync.map(|at| at.saturating_duration_since(Instant::now())),
+            heartbeat.map(|at| at.saturating_duration_since(Instant::now())),
+            changes.held_for(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(
+            LIVENESS_INTERVAL.saturating_sub(checked.elapsed()),
+            Duration::min,
+        );
+        if let Some(supervisor) = &supervisor {
+            supervisor.idle()?;
+        }
+        let event = match mainloop.recv_timeout(rx, wait) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Err(Errors::RecvError(RecvError)),
+        };
+        if let Some(supervisor) = &supervisor {
+            supervisor.busy();
+        }
+        if changes.take_overflowed() {
+            resync.get_or_insert_with(Instant::now);
+        }
+        let dequeued = match event {
+            CallbackComms::Request(request) => Dequeued::Request(request),
+            CallbackComms::Changes => match changes.pop(&tx) {
+                Some((change, span, received)) => Dequeued::Change(change, span, received),
+                // Dropped for a resync, or still held back
+                None => continue,
+            },
+            CallbackComms::Timeout => {
+                return Err(Errors::Timeout);
+            }
+            // A state change left over from connecting is as good a reason to check as any
+            CallbackComms::ContextState | CallbackComms::CallbackDone => {
+                match context.get_state() {
+                    State::Failed | State::Terminated => return Err(Errors::Disconnected),
+                    _ => continue,
+                }
+            }
+            CallbackComms::Pong => {
+                if let Some(probe) = &mut probe {
+                    probe.answered();
+                }
+                continue;
+            }
+        };
+        let (span, received) = match &dequeued {
+            Dequeued::Change(_, span, received) => (span.clone(), Some(*received)),
+            Dequeued::Request(_) => (Span::none(), None),
+        };
+        let _entered = span.enter();
+        // Events made from here on are measured from when the server told us about the change
+        let _handling = state
+            .latency
+            .as_ref()
+            .map(|latency| latency.handling(received));
+        tracing::debug!("dequeued");
+        match dequeued {
+            Dequeued::Request(request) => act_on(request, &state, &old, context, mainloop, output)?,
+            Dequeued::Change(change, _, received) => {
+                let followed = follow_change(change, &mut state, context, mainloop, output);
+                if let Some(latency) = &state.latency {
+                    latency.introspected(received);
+                }
+                if let Err(err) = followed {
+                    if !err.is_introspection_failure() {
+                        return Err(err);
+                    }
+                    // Whatever the event was about can't be known now, so rather than give up
+                    // on the server, everything gets fetched again shortly
+                    warn!("Lost track of the server's state ({}), resyncing", err);
+                    resync.get_or_insert_with(|| Instant::now() + RESYNC_DELAY);
+                }
+            }
+        }
+
+        report_changes(&state, Some(old), output)?;
+        // A stale state isn't fresh until it's been resynced
+        if resync.is_none() {
+            state.refreshed();
+        } else if state.notify_systemd {
+            systemd::status(&state.status());
+        }
+    }
+}
+
+/// What the listener loop acts on, once it has dealt with its own messages
+enum Dequeued {
+    Request(ListenerRequest),
+    /// With the span its handling is traced under, opened as the callback arrived
+    Change(PulseChange, Span, Instant),
+}
+
+/// Do as asked from outside the loop, with `old` the state before
+fn act_on(
+    request: ListenerRequest,
+    state: &ListenerState,
+    old: &Snapshot,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    match request {
+        ListenerRequest::Shutdown => return Err(Errors::Shutdown),
+        ListenerRequest::HookFailed(failure) => output.emit(&hook_failed(failure))?,
+        #[cfg(feature = "lua")]
+        ListenerRequest::Action(action) => {
+            script::perform(action, state, context, mainloop)?;
+        }
+        ListenerRequest::Query(query, reply) => {
+            let mut events = vec![];
+            match query {
+                Query::Status => report_state(state, &mut events)?,
+                Query::List => events.push(Event::new(state.source_list())),
+            }
+            // Whoever asked may have given up waiting already
+            let _ = reply.send(events);
+        }
+        ListenerRequest::Control(command, reply) => {
+            let done = match command {
+                ControlCommand::Toggle => match (state.default_source_id(), old.source_mute) {
+                    (Some(idx), Some(mute)) => {
+                        set_source_mute(idx, !mute, &state.batch, context, mainloop)
+                    }
+                    _ => Ok(()),
+                },
+                ControlCommand::Mute | ControlCommand::Unmute => match state.default_source_id() {
+                    Some(idx) => {
+                        let mute = command == ControlCommand::Mute;
+                        set_source_mute(idx, mute, &state.batch, context, mainloop)
+                    }
+                    None => Ok(()),
+                },
+                ControlCommand::Status => report_state(state, output),
+                ControlCommand::List => output
+                    .emit(&Event::new(state.source_list()))
+                    .map_err(Errors::from),
+                ControlCommand::Quit => {
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Ok(()));
+                    }
+                    return Err(Errors::Shutdown);
+                }
+            };
+            // The server refusing, e.g. as the source has just gone, is only the sender's
+            // to hear about
+            let done = tolerate_refusal(done)?;
+            if let Some(reply) = reply {
+                let _ = reply.send(done);
+            }
+        }
+        ListenerRequest::Click(device, button) => {
+            // Left click toggles the clicked device, everything else is ignored
+            let toggled = match (device, button) {
+                (DeviceKind::Source, 1) => match (state.default_source_id(), old.source_mute) {
+                    (Some(idx), Some(mute)) => {
+                        set_source_mute(idx, !mute, &state.batch, context, mainloop)
+                    }
+                    _ => Ok(()),
+                },
+                (DeviceKind::Sink, 1) => match (state.default_sink_id, old.sink_mute) {
+                    (Some(idx), Some(mute)) => {
+                        sink::set_sink_mute(idx, !mute, &state.batch, context, mainloop)
+                    }
+                    _ => Ok(()),
+                },
+                _ => Ok(()),
+            };
+            // Nobody's waiting to hear it failed, the bar shows whatever state there is
+            let _ = tolerate_refusal(toggled)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bring the state up to date with a change the server told us about
+fn follow_change(
+    change: PulseChange,
+    state: &mut ListenerState,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    match change {
+        // Most server changes are to things other than the defaults, so the devices are only
+        // looked at again when a default's name changed
+        PulseChange::Server => {
+            let defaults = state
+                .batch
+                .ask(&[batch::Query::Defaults], context, mainloop)?
+                .defaults;
+            if defaults == state.server_defaults {
+                trace!("Server change left the defaults as they were");
+                return Ok(());
+            }
+            if state.watch.sources() && defaults.source != state.server_defaults.source {
+                debug!("Updating default source after server config change");
+                if let Some(name) = &defaults.source {
+                    state.fetch_named_source(name, context, mainloop)?;
+                }
+                state.resolve_sources_from(defaults.source.clone());
+
+                if let Some(src) = state.default_source() {
+                    info!("Default source is now: {}", src.name);
+                }
+            }
+            if state.watch.sinks() && defaults.sink != state.server_defaults.sink {
+                debug!("Updating default sink after server config change");
+                state.default_sink_id = match &defaults.sink {
+                    None => None,
+                    Some(name) => match sink::find_sink_by_name(&state.sinks, name) {
+                        Some(idx) => Some(idx),
+                        None => {
+                            return Err(Errors::Inconsistent(format!(
+                                "default sink {} isn't known",
+                                name
+                            )))
+                        }
+                    },
+                };
+
+                if let Some(sink) = state.default_sink() {
+                    info!("Default sink is now: {}", sink.name);
+                }
+            }
+            state.server_defaults = defaults;
+        }
+        PulseChange::SourceOutputNew(idx) | PulseChange::SourceOutputChange(idx) => {
+            let updated =
+                match state
+                    .batch
+                    .ask(&[batch::Query::SourceOutputByIndex(idx)], context, mainloop)
+                {
+                    Ok(mut answers) => answers.source_outputs.remove(&idx),
+                    Err(Errors::SourceOutputListError) => {
+                        info!("failed to retrieve source output {}, has it gone?", idx);
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+            let Some(updated) = updated else {
+                return Ok(());
+            };
+            // Changes are mostly volume/cork updates, only a move to another source
+            // is a change in what's being recorded
+            match state.source_outputs.get(&idx) {
+                Some(known) if known.source == updated.source => {}
+                Some(known) => {
+                    report_recording(state, idx, known, false, output)?;
+                    report_recording(state, idx, &updated, true, output)?;
+                }
+                None => report_recording(state, idx, &updated, true, output)?,
+            }
+            state.source_outputs.insert(idx, updated);
+        }
+        PulseChange::SourceOutputDrop(idx) => {
+            if let Some(gone) = state.source_outputs.remove(&idx) {
+                report_recording(state, idx, &gone, false, output)?;
+            }
+        }
+        PulseChange::CardNew(idx) | PulseChange::CardChange(idx) => {
+            let updated =
+                match state
+                    .batch
+                    .ask(&[batch::Query::CardByIndex(idx)], context, mainloop)
+                {
+                    Ok(mut answers) => answers.cards.remove(&idx),
+                    Err(Errors::CardListError) => {
+                        info!("failed to retrieve card {}, has it gone?", idx);
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+            let Some(updated) = updated else {
+                return Ok(());
+            };
+            // A new card has no profile to change from
+            if let Some(known) = state.cards.get(&idx) {
+                if known.active_profile != updated.active_profile {
+                    output.emit(&Event::new(EventKind::ProfileChanged {
+                        card: updated.name.clone(),
+                        index: idx,
+                        profile: updated.active_profile.clone(),
+                        profile_description: updated.active_profile_description.clone(),
+                        previous_profile: known.active_profile.clone(),
+                    }))?;
+                }
+            }
+            state.cards.insert(idx, updated);
+        }
+        PulseChange::CardDrop(idx) => {
+            if let Some(old_card) = state.cards.remove(&idx) {
+                trace!("Removing card {} from state ({})", &idx, &old_card.name);
+            }
+        }
+        PulseChange::ClientNew(idx) | PulseChange::ClientChange(idx) => {
+            let updated =
+                match state
+                    .batch
+                    .ask(&[batch::Query::ClientByIndex(idx)], context, mainloop)
+                {
+                    Ok(mut answers) => answers.clients.remove(&idx),
+                    Err(Errors::ClientListError) => {
+                        info!("failed to retrieve client {}, has it gone?", idx);
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+            let Some(updated) = updated else {
+                return Ok(());
+            };
+            if !state.clients.contains_key(&idx) {
+                info!("Client {} connected: {}", idx, updated.name);
+                report_client(idx, &updated, true, output)?;
+            }
+            state.clients.insert(idx, updated);
+        }
+        PulseChange::ClientDrop(idx) => {
+            if let Some(gone) = state.clients.remove(&idx) {
+                info!("Client {} disconnected: {}", idx, gone.name);
+                report_client(idx, &gone, false, output)?;
+            }
+        }
+        PulseChange::ModuleNew(idx) => {
+            let loaded =
+                match state
+                    .batch
+                    .ask(&[batch::Query::ModuleByIndex(idx)], context, mainloop)
+                {
+                    Ok(mut answers) => answers.modules.remove(&idx),
+                    Err(Errors::ModuleListError) => {
+                        info!("failed to retrieve module {}, has it gone?", idx);
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+            if let Some(loaded) = loaded.filter(|module| module.affects_capture()) {
+                info!("Module {} loaded: {}", idx, loaded.name);
+                report_module(idx, &loaded, true, output)?;
+                state.modules.insert(idx, loaded);
+            }
+        }
+        PulseChange::ModuleDrop(idx) => {
+            // Unloaded modules can't be introspected, so only the ones we already
+            // know about can be reported
+            if let Some(gone) = state.modules.remove(&idx) {
+                info!("Module {} unloaded: {}", idx, gone.name);
+                report_module(idx, &gone, false, output)?;
+            }
+        }
+        PulseChange::SinkNew(_) => {
+            // As with sources, a Change always follows a New.
+        }
+        PulseChange::SinkChange(idx) => {
+            let updated_sink =
+                match state
+                    .batch
+                    .ask(&[batch::Query::SinkByIndex(idx)], context, mainloop)
+                {
+                    Ok(mut answers) => answers.sinks.remove(&idx),
+                    Err(Errors::SinkListError) => {
+                        info!("failed to retrieve sink {}, has it gone?", idx);
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+            match updated_sink {
+                Some(updated) => {
+                    state.sinks.insert(idx, updated);
+
+                    // The server names its default before the sink may have been seen
+                    if state.default_sink_id.is_none() {
+                        state.default_sink_id = state
+                            .server_defaults
+                            .sink
+                            .as_deref()
+                            .and_then(|name| sink::find_sink_by_name(&state.sinks, name));
+                    }
+                }
+                None => {
+                    info!("failed to retrieve updated sink details for {}", &idx);
+                    return Err(Errors::SinkListError);
+                }
+            }
+        }
+        PulseChange::SinkDrop(idx) => {
+            if let Some(old_sink) = state.sinks.remove(&idx) {
+                trace!("Removing sink {} from state ({})", &idx, &old_sink.name);
+            }
+        }
+        // A new source may be the watched one coming back at another index, so it's
+        // looked up straight away rather than waiting on the change that follows
+        PulseChange::SourceNew(idx) | PulseChange::SourceChange(idx) => {
+            let updated_source =
+                match state
+                    .batch
+                    .ask(&[batch::Query::SourceByIndex(idx)], context, mainloop)
+                {
+                    Ok(mut answers) => answers.lookups.pop().flatten().map(|(_, src)| src),
+                    Err(err) => match err {
+                        Errors::SrcListError => {
+                            info!("failed to retrieve source {}, has it gone?", idx);
+                            return Ok(());
+                        }
+                        _ => return Err(err),
+                    },
+                };
+            match updated_source {
+                Some(src) if !state.source_filter.allows(&src) => {
+                    trace!("Ignoring filtered out source {} ({})", idx, src.name);
+                    state.sources.remove(&idx);
+                    state.rebind_default_source();
+                }
+                Some(mut src) => {
+                    if state.monitor_sink_names {
+                        fetch_monitor_label(&mut src, &state.batch, context, mainloop)?;
+                    }
+                    // Cards switching profile can re-announce a source under a new name, which
+                    // is still the same device rather than a replacement
+                    let renamed = state
+                        .sources
+                        .get(&idx)
+                        .filter(|old| old.name != src.name)
+                        .map(|old| old.name.clone());
+                    if let Some(renamed) = renamed {
+                        info!("Source {} renamed from {} to {}", idx, renamed, src.name);
+                        state.watched_source.rename(idx, &src.name);
+                        if let Some(binding) = &mut state.index_binding {
+                            binding.rename(idx, &src.name);
+                        }
+                        output.emit(&Event::new(EventKind::SourceRenamed {
+                            source: src.name.clone(),
+                            index: idx,
+                            previous: renamed,
+                        }))?;
+                    }
+                    let previous = state
+                        .index_binding
+                        .as_ref()
+                        .and_then(|binding| binding.replaced_by(idx, &src))
+                        .map(Arc::<str>::from);
+                    if let Some(previous) = &previous {
+                        info!(
+                            "Source index {} reused by {}, was {}",
+                            idx, src.name, previous
+                        );
+                        output.emit(&Event::new(EventKind::SourceReplaced {
+                            source: src.name.clone(),
+                            index: idx,
+                            previous: previous.clone(),
+                        }))?;
+                    }
+                    let added = (state.reports(Report::Devices)
+                        && !state.sources.contains_key(&idx))
+                    .then(|| EventKind::SourceAdded {
+                        source: src.display_name().clone(),
+                        index: idx,
+                    });
+                    state.sources.insert(idx, src);
+                    state.rebind_default_source();
+                    if let Some(added) = added {
+                        output.emit(&Event::new(added))?;
+                    }
+
+                    // If there's no current default source, or the followed one was
+                    // replaced, see if the recent change lets us resolve one...
+                    if previous.is_some() || state.has_unresolved_sources() {
+                        state.resolve_sources(mainloop, context)?;
+                    }
+                }
+                None => {
+                    info!("failed to retrieve updated source details for src {}", &idx);
+                    return Err(Errors::SrcListError);
+                }
+            }
+        }
+        PulseChange::SourceDrop(idx) => {
+            let watched = state.is_watched_source(idx);
+            let old_src = state.sources.remove(&idx);
+            state.rebind_default_source();
+            match old_src {
+                None => {
+                    info!(
+                        "Tried to drop source at idx {} but it was already missing",
+                        &idx,
+                    );
+                }
+                Some(src) => {
+                    trace!("Removing source {} from state ({})", &idx, &src.name);
+                    if state.reports(Report::Devices) {
+                        output.emit(&Event::new(EventKind::SourceRemoved {
+                            source: src.display_name().clone(),
+                            index: idx,
+                        }))?;
+                    }
+                }
+            }
+            // A named or preferred source can be replaced by another match, whereas a
+            // new default gets announced by the server
+            if state.picks_own_source() && watched {
+                state.resolve_sources(mainloop, context)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Passes events on as snapshots of the state, rather than changes
+struct SnapshotOutput<'a>(&'a mut dyn Output);
+
+impl Output for SnapshotOutput<'_> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let mut event = event.clone();
+        event.snapshot = true;
+        self.0.emit(&event)
+    }
+}
+
+/// Emit the current state again, as snapshots, without it counting as changed
+fn report_state(state: &ListenerState, output: &mut dyn Output) -> Result<(), Errors> {
+    report_changes(state, None, &mut SnapshotOutput(output))
+}
+
+/// Emit events for whatever changed since `old`, or the current state if this is the first
+/// report (`old` is None).
+#[instrument(name = "diff", level = "info", skip_all)]
+fn report_changes(
+    state: &ListenerState,
+    old: Option<Snapshot>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    let first = old.is_none();
+    // Nothing has "changed" on startup, so only report that once we have something to compare
+    let report_default = state.reports(Report::Default) && !first;
+    // Streams are reported as they come and go, so only the ones already running need reporting
+    // here
+    if old.is_none() && state.reports(Report::Recording) {
+        for (idx, source_output) in &state.source_outputs {
+            report_recording(state, *idx, source_output, true, output)?;
+        }
+    }
+    let old = old.unwrap_or_default();
+
+    if state.watch.sources() {
+        if report_default && state.default_source_id() != old.source_id {
+            report_default_change(state, output)?;
+        }
+        if state.reports(Report::Mute) {
+            match state.aggregate_muted() {
+                Some(muted) => report_aggregate_change(state, muted, old.aggregate_muted, output)?,
+                None => report_mute_change(state, old.source_mute, first, output)?,
+            }
+        }
+        if state.reports(Report::Volume) {
+            report_volume_change(state, old.source_volume, output)?;
+        }
+        // A new default source has its own port, which isn't a change of port
+        if state.reports(Report::Port) && state.default_source_id() == old.source_id {
+            report_port_change(state, &old.source_port, output)?;
+        }
+        if state.reports(Report::State) {
+            report_state_change(state, old.source_running, output)?;
+        }
+        report_extra_source_changes(state, &old.extra_sources, output)?;
+        // Sources aren't suspended "from" anything on startup, or when switching default
+        if state.reports(Report::Suspend)
+            && old.source_id.is_some()
+            && state.default_source_id() == old.source_id
+        {
+            report_suspend_change(state, old.source_suspended, output)?;
+        }
+    }
+    if state.watch.sinks() {
+        if report_default && state.default_sink_id != old.sink_id {
+            report_default_sink_change(state, output)?;
+        }
+        if state.reports(Report::Mute) {
+            report_sink_mute_change(state, old.sink_mute, first, output)?;
+        }
+        if state.reports(Report::Volume) {
+            report_sink_volume_change(state, old.sink_volume, output)?;
+        }
+    }
+    Ok(())
+}
+
+/// The default source's mute state if it changed, or that there's no default source if there
+/// wasn't one already (or this is the first report)
+fn report_mute_change(
+    state: &ListenerState,
+    old_default_mute: Option<bool>,
+    first: bool,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    match (state.default_source_id(), state.default_source()) {
+        (Some(index), Some(new_src)) if Some(new_src.mute) != old_default_mute => {
+            output.emit(&Event::new(EventKind::Mute {
+                source: new_src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: new_src.mute,
+                    volume: new_src.volume_percent(),
+                },
+                default: true,
+            }))?;
+        }
+        (Some(_), Some(_)) => {}
+        // Only once, rather than with every event while there's still no default
+        _ if first || old_default_mute.is_some() => {
+            output.emit(&Event::new(EventKind::NoSource))?
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn report_default_change(state: &ListenerState, output: &mut dyn Output) -> Result<(), Errors> {
+    // Losing the default source entirely is reported as NoSource by the mute reporting
+    if let (Some(index), Some(src)) = (state.default_source_id(), state.default_source()) {
+        output.emit(&Event::new(EventKind::DefaultChanged {
+            source: src.display_name().clone(),
+            device: DeviceState {
+                index,
+                muted: src.mute,
+                volume: src.volume_percent(),
+            },
+        }))?;
+    }
+    Ok(())
+}
+
+fn report_default_sink_change(
+    state: &ListenerState,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    if let (Some(index), Some(sink)) = (state.default_sink_id, state.default_sink()) {
+        output.emit(&Event::new(EventKind::SinkDefaultChanged {
+            sink: sink.name.clone(),
+            device: DeviceState {
+                index,
+                muted: sink.mute,
+                volume: sink.volume_percent(),
+            },
+        }))?;
+    }
+    Ok(())
+}
+
+fn report_aggregate_change(
+    state: &ListenerState,
+    muted: bool,
+    old_muted: Option<bool>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    if Some(muted) != old_muted {
+        let mut live: Vec<Arc<str>> = state
+            .sources
+            .values()
+            .filter(|src| !src.mute)
+            .map(|src| src.display_name().clone())
+            .collect();
+        live.sort();
+        output.emit(&Event::new(EventKind::Aggregate {
+            muted,
+            live,
+            sources: state.sources.len(),
+        }))?;
+    }
+    Ok(())
+}
+
+/// Mute and volume changes of the extra watched sources, each reported under its own name
+fn report_extra_source_changes(
+    state: &ListenerState,
+    old_extras: &[SourceSnapshot],
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    for (i, id) in state.extra_source_ids.iter().enumerate() {
+        let (Some(index), Some(src)) = (*id, id.and_then(|id| state.sources.get(&id))) else {
+            continue;
+        };
+        let old = old_extras.get(i).cloned().unwrap_or_default();
+        let replaced = old.id != Some(index);
+
+        if state.reports(Report::Mute) && (replaced || old.mute != Some(src.mute)) {
+            output.emit(&Event::new(EventKind::Mute {
+                source: src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: src.mute,
+                    volume: src.volume_percent(),
+                },
+                default: false,
+            }))?;
+        }
+        if state.reports(Report::Volume) && (replaced || old.volume != Some(src.volume_percent())) {
+            output.emit(&Event::new(EventKind::Volume {
+                source: src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: src.mute,
+                    volume: src.volume_percent(),
+                },
+                default: false,
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+fn report_port_change(
+    state: &ListenerState,
+    old_port: &Option<String>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    if let (Some(index), Some(src)) = (state.default_source_id(), state.default_source()) {
+        if src.active_port != *old_port {
+            output.emit(&Event::new(EventKind::PortChanged {
+                source: src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: src.mute,
+                    volume: src.volume_percent(),
+                },
+                port: src.active_port.clone(),
+                port_description: src.active_port_description.clone(),
+                previous_port: old_port.clone(),
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+fn report_recording(
+    state: &ListenerState,
+    output_index: u32,
+    source_output: &SourceOutputDatum,
+    recording: bool,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    // Sources are only tracked when watched, so the name may not be known
+    let source = state
+        .sources
+        .get(&source_output.source)
+        .map(|src| src.display_name().clone())
+        .unwrap_or_else(|| Arc::from("unknown"));
+
+    output.emit(&Event::new(EventKind::Recording {
+        application: source_output.application.clone(),
+        source,
+        index: source_output.source,
+        output_index,
+        default: state.default_source_id() == Some(source_output.source),
+        recording,
+    }))?;
+    Ok(())
+}
+
+fn report_module(
+    index: u32,
+    module: &ModuleDatum,
+    loaded: bool,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    output.emit(&Event::new(EventKind::Module {
+        module: module.name.clone(),
+        index,
+        argument: module.argument.clone(),
+        loaded,
+    }))?;
+    Ok(())
+}
+
+fn report_client(
+    index: u32,
+    client: &ClientDatum,
+    connected: bool,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    output.emit(&Event::new(EventKind::Client {
+        client: client.name.clone(),
+        index,
+        binary: client.binary.clone(),
+        process_id: client.process_id.clone(),
+        connected,
+    }))?;
+    Ok(())
+}
+
+fn report_suspend_change(
+    state: &ListenerState,
+    old_suspended: Option<bool>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    if let (Some(index), Some(src)) = (state.default_source_id(), state.default_source()) {
+        if Some(src.suspended()) != old_suspended {
+            output.emit(&Event::new(EventKind::Suspended {
+                source: src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: src.mute,
+                    volume: src.volume_percent(),
+                },
+                suspended: src.suspended(),
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+fn report_state_change(
+    state: &ListenerState,
+    old_running: Option<bool>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    // Only transitions in and out of RUNNING matter, idle <-> suspended is PA housekeeping
+    if let (Some(index), Some(src)) = (state.default_source_id(), state.default_source()) {
+        if Some(src.running()) != old_running {
+            output.emit(&Event::new(EventKind::State {
+                source: src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: src.mute,
+                    volume: src.volume_percent(),
+                },
+                running: src.running(),
+                state: src.state_name().to_string(),
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+fn report_volume_change(
+    state: &ListenerState,
+    old_default_volume: Option<u32>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    if let (Some(index), Some(src)) = (state.default_source_id(), state.default_source()) {
+        let volume = src.volume_percent();
+        if Some(volume) != old_default_volume {
+            output.emit(&Event::new(EventKind::Volume {
+                source: src.display_name().clone(),
+                device: DeviceState {
+                    index,
+                    muted: src.mute,
+                    volume,
+                },
+                default: true,
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+fn report_sink_volume_change(
+    state: &ListenerState,
+    old_default_volume: Option<u32>,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    if let (Some(index), Some(sink)) = (state.default_sink_id, state.default_sink()) {
+        let volume = sink.volume_percent();
+        if Some(volume) != old_default_volume {
+            output.emit(&Event::new(EventKind::SinkVolume {
+                sink: sink.name.clone(),
+                device: DeviceState {
+                    index,
+                    muted: sink.mute,
+                    volume,
+                },
+                default: true,
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+fn report_sink_mute_change(
+    state: &ListenerState,
+    old_default_mute: Option<bool>,
+    first: bool,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    match (state.default_sink_id, state.default_sink()) {
+        (Some(index), Some(new_sink)) if Some(new_sink.mute) != old_default_mute => {
+            output.emit(&Event::new(EventKind::SinkMute {
+                sink: new_sink.name.clone(),
+                device: DeviceState {
+                    index,
+                    muted: new_sink.mute,
+                    volume: new_sink.volume_percent(),
+                },
+                default: true,
+            }))?;
+        }
+        (Some(_), Some(_)) => {}
+        _ if first || old_default_mute.is_some() => output.emit(&Event::new(EventKind::NoSink))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Size of a valid PulseAudio auth cookie
+const COOKIE_LEN: u64 = 256;
+
+/// Have libpulse present `path` as the auth cookie, checking first that it is one. It's set
+/// through the environment, so this is for the command line tool alone, called before it
+/// starts any threads: a library can't know what else in the process is reading it.
+fn use_cookie(path: &Path) -> Result<(), Errors> {
+    let len = fs::metadata(path)
+        .map_err(|err| {
+            Errors::ConfigError(format!("can't read cookie {}: {}", path.display(), err))
+        })?
+        .len();
+    if len != COOKIE_LEN {
+        return Err(Errors::ConfigError(format!(
+            "cookie {} is {} bytes, expected {}",
+            path.display(),
+            len,
+            COOKIE_LEN
+        )));
+    }
+    // libpulse only takes the cookie from its config or the environment
+    env::set_var("PULSE_COOKIE", path);
+    Ok(())
+}
+
+/// Why connecting failed, in terms of what to do about it
+fn connect_failure(err: PAErr, options: &ConnectOptions) -> String {
+    let server = options.server.as_deref().unwrap_or("the local server");
+    if err == Code::Access.into() || err == Code::AuthKey.into() {
+        format!(
+            "{} refused us access, check --cookie is a copy of its auth cookie",
+            server
+        )
+    } else if err == Code::ConnectionRefused.into() {
+        match options.server {
+            Some(_) => format!(
+                "{} refused the connection, is it running and (if remote) loading \
+                 module-native-protocol-tcp?",
+                server
+            ),
+            None if !options.autospawn => format!(
+                "{} refused the connection, is it running? --autospawn starts it, or --nofail \
+                 waits for it",
+                server
+            ),
+            None => format!("{} refused the connection, and couldn't be started", server),
+        }
+    } else if err == Code::InvalidServer.into() {
+        format!("'{}' isn't a valid server address", server)
+    } else {
+        format!(
+            "couldn't connect to {}: {}",
+            server,
+            err.to_string()
+                .unwrap_or_else(|| format!("error {}", err.0))
+        )
+    }
+}
+
+fn connect_to_server(
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    options: &ConnectOptions,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    mainloop.start()?;
+    start_connecting(context, mainloop, options, tx.clone())?;
+    finish_connecting(context, mainloop, options, tx, rx)
+}
+
+/// Exit codes for failing to connect at startup, from sysexits.h
+const EXIT_UNAVAILABLE: i32 = 69;
+const EXIT_NOPERM: i32 = 77;
+
+/// Connect for the first time, trying again as often as the options allow
+fn connect_at_startup(
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    options: &ConnectOptions,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    match connect_to_server(context, mainloop, options, tx.clone(), rx) {
+        Err(Errors::Shutdown) => Err(Errors::Shutdown),
+        Err(err) if options.retries != Some(0) => {
+            info!("Connecting failed ({}), trying again", err);
+            reconnect(context, mainloop, options, options.retries, tx, rx)
+        }
+        result => result,
+    }
+}
+
+/// Exit code when the event loop gets stuck, from sysexits.h
+const EXIT_STALLED: i32 = 70;
+
+/// End the process for the service manager to start afresh, as the stuck loop can't be
+/// interrupted
+fn exit_stalled(_busy: Duration) {
+    error!("Exiting to be restarted");
+    std::process::exit(EXIT_STALLED);
+}
+
+/// Why the process exits when it couldn't connect at startup
+fn startup_exit_code(err: &Errors) -> i32 {
+    match err {
+        Errors::Timeout => commands::EXIT_TIMEOUT,
+        Errors::ConnectError(err, _) if *err == Code::Access.into() => EXIT_NOPERM,
+        Errors::ConnectError(err, _) if *err == Code::AuthKey.into() => EXIT_NOPERM,
+        _ => EXIT_UNAVAILABLE,
+    }
+}
+
+/// Ask the context to connect, with its state callback letting us know how that goes
+fn start_connecting(
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    options: &ConnectOptions,
+    tx: CBTX,
+) -> Result<(), Errors> {
+    trace!("Calling context.connect");
+    let guard = MainloopGuard::lock(mainloop);
+
+    {
+        // Context state boxed-callback setup
+        trace!("Registering context state callback");
+        context.set_state_callback(Some(Box::new(move || {
+            trace!("context state changed");
+            let _ = tx.send(CallbackComms::CallbackDone);
+        })));
+    }
+
+    let connected = context.connect(options.server.as_deref(), options.flags(), None);
+
+    drop(guard);
+    connected.map_err(|err| Errors::ConnectError(err, connect_failure(err, options)))
+}
+
+/// Wait for the context to be ready. From then on, its state changes are sent to the listener
+/// loop, so it hears about losing the server.
+fn finish_connecting(
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    options: &ConnectOptions,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // Wait for signal from callback, checking on the context now and then in case one went
+        // missing
+        let wait = deadline.map_or(LIVENESS_INTERVAL, |deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .min(LIVENESS_INTERVAL)
+        });
+        match mainloop.recv_timeout(rx, wait) {
+            Ok(CallbackComms::CallbackDone) => {
+                // Continue once callback is received.
+            }
+            Ok(CallbackComms::Request(ListenerRequest::Shutdown)) => {
+                return Err(Errors::Shutdown);
+            }
+            // Commands and queries can arrive while reconnecting, and can't be acted on yet
+            Ok(event) => {
+                debug!("Ignoring {:?} until connected", event);
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout)
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+            {
+                return Err(Errors::Timeout);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                info!("Still connecting, context is {:?}", context.get_state());
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(Errors::RecvError(RecvError)),
+        }
+
+        let state = context.get_state();
+        match state {
+            State::Unconnected | State::Connecting | State::Authorizing | State::SettingName => {
+                debug!("Context state: {:?}", state);
+                continue; // Use channel for synchronisation
+            }
+            State::Ready => {
+                debug!("Context state: {:?}", state);
+                break;
+            }
+            State::Failed => {
+                debug!("Context state: {:?}", state);
+                let err = context.errno();
+                return Err(Errors::ConnectError(err, connect_failure(err, options)));
+            }
+            State::Terminated => {
+                debug!("Context state: {:?}", state);
+                return Err(Errors::ContextError("Context terminated".into()));
+            }
+        }
+    }
+    let guard = MainloopGuard::lock(mainloop);
+    context.set_state_callback(Some(Box::new(move || {
+        callback::notify(&tx, CallbackComms::ContextState);
+    })));
+    drop(guard);
+
+    Ok(())
+}
+
+/// First delay before reconnecting, doubled after each failed attempt
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between reconnection attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Wait out `delay`, unless asked to shut down in the meantime
+fn wait_before_reconnecting(
+    delay: Duration,
+    mainloop: &mut Mainloop,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    let deadline = Instant::now() + delay;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match mainloop.recv_timeout(rx, left) {
+            Ok(CallbackComms::Request(ListenerRequest::Shutdown)) => return Err(Errors::Shutdown),
+            Ok(event) => debug!("Ignoring {:?} until reconnected", event),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => return Err(Errors::RecvError(RecvError)),
+        }
+    }
+    Ok(())
+}
+
+/// Replace a context that lost its server with a new one, once the server is back. Gives up
+/// after `attempts`, if limited.
+fn reconnect(
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+    options: &ConnectOptions,
+    attempts: Option<u32>,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(), Errors> {
+    let mut delay = RECONNECT_MIN_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        wait_before_reconnecting(delay, mainloop, rx)?;
+        info!("Reconnecting to daemon");
+
+        let guard = MainloopGuard::lock(mainloop);
+        // The old context goes while the mainloop is locked, as its callbacks run on it
+        let replaced = new_context(&guard).map(|fresh| {
+            context.set_state_callback(None);
+            context.disconnect();
+            *context = fresh;
+        });
+        drop(guard);
+        replaced?;
+
+        let connected = start_connecting(context, mainloop, options, tx.clone())
+            .and_then(|()| finish_connecting(context, mainloop, options, tx.clone(), rx));
+        match connected {
+            Ok(()) => return Ok(()),
+            Err(Errors::Shutdown) => return Err(Errors::Shutdown),
+            Err(err) if attempts.is_some_and(|attempts| attempt >= attempts) => return Err(err),
+            Err(err) => {
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                info!("Reconnecting failed ({}), retrying in {:?}", err, delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secs_must_be_finite_and_not_negative() {
+        assert_eq!(parse_secs("0"), Ok(Duration::ZERO));
+        for secs in ["-1", "NaN", "inf", "1e30", "soon"] {
+            assert!(parse_secs(secs).is_err(), "{}", secs);
+        }
+    }
+
+    #[test]
+    fn positive_secs_must_be_finite_and_above_zero() {
+        assert_eq!(parse_positive_secs("1.5"), Ok(Duration::from_millis(1500)));
+        for secs in ["0", "-1", "NaN", "inf", "1e30", "soon"] {
+            assert!(parse_positive_secs(secs).is_err(), "{}", secs);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::ffi::OsString;
+use std::io;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use crossbeam_channel::{self as channel, Sender};
+use log::debug;
+use pulse::context::Context;
+
+use crate::consumer::{self, Publisher, Subscription};
+use crate::control::Query;
+use crate::mainloop::{self, Mainloop};
+use crate::output::{Event, FanoutOutput, Output};
+use crate::socket::QUERY_TIMEOUT;
+#[cfg(feature = "async")]
+use crate::stream::EventStream;
+use crate::{
+    connect_at_startup, listen, new_context, terminate, Args, ConnectOptions, Errors,
+    ListenerConfig, ListenerRequest, RequestTx, SourceListenerBuilder, CBRX, CBTX,
+};
+
+/// How long events already on their way out to backends get once the listener stops
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tasks waiting on the listener's next event
+#[derive(Debug, Default)]
+pub struct Waiting {
+    wakers: Vec<Waker>,
+    /// Nothing more is coming, the listener having stopped
+    closed: bool,
+}
+
+impl Waiting {
+    /// Have `waker` woken by the next event, unless there won't be one
+    #[cfg(feature = "async")]
+    pub fn register(&mut self, waker: &Waker) -> bool {
+        if !self.wakers.iter().any(|known| known.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+        self.closed
+    }
+
+    fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Hands the listener's events over to whoever embeds it, who like a network client only
+/// holds up its own events by not taking them
+struct ChannelOutput {
+    events: Publisher<Event>,
+    waiting: Arc<Mutex<Waiting>>,
+}
+
+impl Output for ChannelOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        // Once hung up on, the listener carries on for its other outputs
+        if self.events.send(event.clone()) {
+            self.waiting.lock().unwrap().wake();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChannelOutput {
+    fn drop(&mut self) {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.closed = true;
+        waiting.wake();
+    }
+}
+
+/// Follows a PulseAudio server from another program, as the command line tool does: connects,
+/// keeps up with the server on a thread of its own, reconnecting if it's lost, and hands over
+/// the events the tool would write out.
+///
+/// ```no_run
+/// use pulseaudio_sink_listener::{EventKind, SourceListener};
+///
+/// let listener = SourceListener::connect_with(["--watch", "both"])?;
+/// println!("{:?}", listener.current_state());
+/// for event in listener.events() {
+///     if let EventKind::Mute { source, device, .. } = event.kind {
+///         println!("{} muted: {}", source, device.muted);
+///     }
+/// }
+/// # Ok::<(), pulseaudio_sink_listener::Errors>(())
+/// ```
+///
+/// Dropping the listener disconnects it.
+pub struct SourceListener {
+    requests: RequestTx,
+    events: Arc<Subscription<Event>>,
+    #[cfg(feature = "async")]
+    waiting: Arc<Mutex<Waiting>>,
+    thread: Option<JoinHandle<Result<(), Errors>>>,
+}
+
+impl SourceListener {
+    /// Connect to the default server, following its default source
+    pub fn connect() -> Result<Self, Errors> {
+        Self::builder().connect()
+    }
+
+    /// Set up what to connect to and follow, before connecting
+    pub fn builder() -> SourceListenerBuilder {
+        SourceListenerBuilder::default()
+    }
+
+    /// Connect as the command line tool would when given `args`, e.g. `["--server",
+    /// "tcp:studio.local:4713", "--report", "volume"]`. Only the options on connecting, on
+    /// what to follow and on serving it to other programs apply, as events are handed over
+    /// rather than written out.
+    pub fn connect_with<I, T>(args: I) -> Result<Self, Errors>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args = Args::try_parse_from(
+            iter::once(OsString::from(env!("CARGO_PKG_NAME")))
+                .chain(args.into_iter().map(Into::into)),
+        )
+        .map_err(|err| Errors::ConfigError(err.to_string()))?;
+        if args.command.is_some() {
+            return Err(Errors::ConfigError(
+                "commands are for the command line tool, a listener only listens".to_string(),
+            ));
+        }
+        // libpulse only takes it from the environment, which is the whole process'
+        if args.cookie.is_some() {
+            return Err(Errors::ConfigError(
+                "--cookie is for the command line tool, set PULSE_COOKIE before starting \
+                 any threads instead"
+                    .to_string(),
+            ));
+        }
+        args.builder().connect()
+    }
+
+    /// Connect as `builder` says, then follow the server on a thread of its own
+    pub(crate) fn start(builder: SourceListenerBuilder) -> Result<Self, Errors> {
+        let mut servers = builder.connect_options()?;
+        if servers.len() > 1 {
+            return Err(Errors::ConfigError(
+                "a listener follows one server, each of several needs its own".to_string(),
+            ));
+        }
+        let connect = servers.remove(0);
+        let config = builder.listener_config()?;
+        let reconnects = builder.reconnects();
+        let backends = builder.backends().clone();
+
+        let (tx, rx) = mainloop::channel();
+        let (events_tx, events) = consumer::queue(
+            builder.consumers(),
+            "The program taking the listener's events".to_string(),
+            &config.health,
+        );
+        let (connected_tx, connected) = channel::bounded(1);
+        let waiting = Arc::new(Mutex::new(Waiting::default()));
+        let thread = thread::Builder::new()
+            .name("source-listener".to_string())
+            .spawn({
+                let tx = tx.clone();
+                let waiting = waiting.clone();
+                move || {
+                    let requests = RequestTx::Listener(tx.clone());
+                    let mut outputs = match backends.start(Some(&requests), &config.health) {
+                        Ok(outputs) => outputs,
+                        Err(err) => {
+                            let _ = connected_tx.send(Err(err));
+                            return Ok(());
+                        }
+                    };
+                    outputs.push(Box::new(ChannelOutput {
+                        events: events_tx,
+                        waiting,
+                    }));
+                    let output = FanoutOutput::new(outputs);
+                    follow(&connect, &config, reconnects, output, connected_tx, tx, rx)
+                }
+            })?;
+        // Failing to connect is the caller's to hear about, rather than something to retry
+        match connected.recv() {
+            Ok(Ok(())) => Ok(SourceListener {
+                requests: RequestTx::Listener(tx),
+                events: Arc::new(events),
+                #[cfg(feature = "async")]
+                waiting,
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => Err(Errors::ContextError(
+                "listener stopped before connecting".to_string(),
+            )),
+        }
+    }
+
+    /// The state as it is now, as the events reporting it: the watched source's mute state, the
+    /// default source, and whatever else is reported. Empty while reconnecting.
+    pub fn current_state(&self) -> Vec<Event> {
+        let (reply_tx, reply) = channel::bounded(1);
+        if self
+            .requests
+            .send(ListenerRequest::Query(Query::Status, reply_tx))
+            .is_err()
+        {
+            return vec![];
+        }
+        // A loop that's reconnecting drops the question unanswered, and one that's stuck
+        // mustn't hold up the caller
+        reply.recv_timeout(QUERY_TIMEOUT).unwrap_or_default()
+    }
+
+    /// Events as they happen, waiting for each, until the listener stops. Events wait to be
+    /// taken for as long as the listener is around, as many as the consumer queue size, with
+    /// the consumer drop policy applying to any more.
+    pub fn events(&self) -> impl Iterator<Item = Event> + '_ {
+        iter::from_fn(|| self.events.recv())
+    }
+
+    /// Events as they happen, as a [`Stream`](futures_core::Stream) for async programs to wait
+    /// on alongside their other IO. It carries on after the listener's dropped, ending once
+    /// the listener has stopped.
+    #[cfg(feature = "async")]
+    pub fn event_stream(&self) -> EventStream {
+        EventStream::new(self.events.clone(), self.waiting.clone())
+    }
+
+    /// Stop listening, with why it had already stopped, if it had
+    pub fn stop(mut self) -> Result<(), Errors> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<(), Errors> {
+        let _ = self.requests.send(ListenerRequest::Shutdown);
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        match thread.join() {
+            Ok(Err(Errors::Shutdown)) => Ok(()),
+            Ok(result) => result,
+            Err(_) => Err(Errors::ContextError("listener panicked".to_string())),
+        }
+    }
+}
+
+impl Drop for SourceListener {
+    fn drop(&mut self) {
+        if let Err(err) = self.shut_down() {
+            debug!("Listener stopped: {}", err);
+        }
+    }
+}
+
+/// Connect to the server on the calling thread, which the mainloop and context are tied to
+fn connect_here(
+    connect: &ConnectOptions,
+    tx: CBTX,
+    rx: &CBRX,
+) -> Result<(Mainloop, Context), Errors> {
+    let mut mainloop = Mainloop::new(connect.mainloop)
+        .ok_or(Errors::ContextError("mainloop new failed".to_string()))?;
+    let mut context = new_context(&mainloop)?;
+    match connect_at_startup(&mut context, &mut mainloop, connect, tx, rx) {
+        Ok(()) => Ok((mainloop, context)),
+        Err(err) => {
+            terminate(mainloop, context, vec![]);
+            Err(err)
+        }
+    }
+}
+
+/// Connect, say whether that worked, then listen until stopped
+fn follow(
+    connect: &ConnectOptions,
+    config: &ListenerConfig,
+    reconnects: bool,
+    mut output: FanoutOutput,
+    connected: Sender<Result<(), Errors>>,
+    tx: CBTX,
+    rx: CBRX,
+) -> Result<(), Errors> {
+    let (mut mainloop, mut context) = match connect_here(connect, tx.clone(), &rx) {
+        Ok(connection) => connection,
+        Err(err) => {
+            let _ = connected.send(Err(err));
+            return Ok(());
+        }
+    };
+    let _ = connected.send(Ok(()));
+    let result = listen(
+        &mut mainloop,
+        &mut context,
+        connect,
+        config,
+        &mut output,
+        reconnects,
+        tx,
+        &rx,
+    );
+    if let Err(err) = output.finish(Instant::now() + FINISH_TIMEOUT) {
+        debug!("Failed to finish off output: {}", err);
+    }
+    terminate(mainloop, context, vec![]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused(args: &[&str]) -> String {
+        match SourceListener::connect_with(args) {
+            Err(Errors::ConfigError(reason)) => reason,
+            Err(err) => panic!("expected a config error, got {}", err),
+            Ok(_) => panic!("expected a config error, but connected"),
+        }
+    }
+
+    #[test]
+    fn options_are_checked_before_connecting() {
+        assert!(refused(&["--no-such-option"]).contains("--no-such-option"));
+        assert!(refused(&["--include", "("]).contains("--include regex"));
+    }
+
+    #[test]
+    fn only_listens() {
+        assert!(refused(&["status"]).contains("command"));
+    }
+
+    #[test]
+    fn leaves_the_cookie_to_the_tool() {
+        assert!(refused(&["--cookie", "/nonexistent"]).contains("PULSE_COOKIE"));
+    }
+
+    #[test]
+    fn follows_one_server() {
+        assert!(refused(&["--server", "tcp:a", "--server", "tcp:b"]).contains("one server"));
+    }
+}
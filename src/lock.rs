@@ -0,0 +1,36 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::mainloop::Mainloop;
+
+/// Holds the mainloop's lock, keeping pulseaudio from running callbacks while they're set up,
+/// until dropped. Early returns and `?` can't leave the mainloop locked, which would stall it.
+pub struct MainloopGuard<'a> {
+    mainloop: &'a mut Mainloop,
+}
+
+impl<'a> MainloopGuard<'a> {
+    pub fn lock(mainloop: &'a mut Mainloop) -> Self {
+        mainloop.lock();
+        MainloopGuard { mainloop }
+    }
+}
+
+impl Deref for MainloopGuard<'_> {
+    type Target = Mainloop;
+
+    fn deref(&self) -> &Mainloop {
+        self.mainloop
+    }
+}
+
+impl DerefMut for MainloopGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Mainloop {
+        self.mainloop
+    }
+}
+
+impl Drop for MainloopGuard<'_> {
+    fn drop(&mut self) {
+        self.mainloop.unlock();
+    }
+}
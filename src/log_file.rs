@@ -0,0 +1,138 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::Record;
+
+use crate::logging::{LogFormat, Sink};
+use crate::Errors;
+
+/// When the log file is started afresh, regardless of its size
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Which period `time` falls in, so a change of period means it's time to rotate
+    fn period(self, time: DateTime<Local>) -> Option<String> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(time.format("%Y%m%d%H").to_string()),
+            Rotation::Daily => Some(time.format("%Y%m%d").to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Rotate once the file would grow past this many bytes
+    pub max_size: Option<u64>,
+    pub rotation: Rotation,
+    /// How many rotated files to keep, as `PATH.1` (the newest) to `PATH.<keep>`
+    pub keep: usize,
+}
+
+/// The file currently being written
+struct Current {
+    file: File,
+    size: u64,
+    period: Option<String>,
+}
+
+/// `path` with `.n` on the end
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Ignores a file not being there, as rotated files won't be until there have been enough
+/// rotations
+fn missing_ok(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Appends log lines to a file, moving it aside to start a new one when it gets too big or a new
+/// hour or day starts, and removing the oldest rotated files beyond what's kept
+pub struct LogFile {
+    config: LogFileConfig,
+    format: LogFormat,
+    current: Mutex<Current>,
+}
+
+impl LogFile {
+    pub fn open(config: LogFileConfig, format: LogFormat) -> Result<Self, Errors> {
+        let current = Self::open_current(&config).map_err(|err| {
+            Errors::ConfigError(format!("can't log to {}: {}", config.path.display(), err))
+        })?;
+        Ok(LogFile {
+            config,
+            format,
+            current: Mutex::new(current),
+        })
+    }
+
+    fn open_current(config: &LogFileConfig) -> io::Result<Current> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        // A file carried over from an earlier run belongs to the period it was last written in
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(Current {
+            file,
+            size: metadata.len(),
+            period: config.rotation.period(modified),
+        })
+    }
+
+    fn rotate(&self) -> io::Result<Current> {
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            missing_ok(fs::remove_file(path))?;
+        } else {
+            missing_ok(fs::remove_file(rotated(path, self.config.keep)))?;
+            for n in (1..self.config.keep).rev() {
+                missing_ok(fs::rename(rotated(path, n), rotated(path, n + 1)))?;
+            }
+            fs::rename(path, rotated(path, 1))?;
+        }
+        Self::open_current(&self.config)
+    }
+}
+
+impl Sink for LogFile {
+    fn write(&self, record: &Record) -> io::Result<()> {
+        let mut line = self.format.line(record);
+        line.push('\n');
+        let mut current = self.current.lock().unwrap();
+        let too_big = self
+            .config
+            .max_size
+            .is_some_and(|max_size| current.size + line.len() as u64 > max_size);
+        let period = self.config.rotation.period(Local::now());
+        if current.size > 0 && (too_big || period != current.period) {
+            *current = self.rotate()?;
+        }
+        current.period = period;
+        current.file.write_all(line.as_bytes())?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,576 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, SecondsFormat};
+use clap::ValueEnum;
+use env_logger::Env;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use serde_json::json;
+
+use crate::log_file::{LogFile, LogFileConfig};
+use crate::output::{Event, Output};
+use crate::Errors;
+
+/// What journal entries are tagged with, for `journalctl -t`
+const IDENTIFIER: &str = "pulse-source-listener";
+
+/// Where journald takes native protocol messages
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The local syslog socket
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The documentation enterprise number, for our structured data ID
+const SD_ID: &str = "fields@32473";
+
+/// How long connecting to a syslog server over TCP, or sending it a record, may take
+const SYSLOG_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to drop records after failing to reach syslog, before trying it again
+const SYSLOG_RETRY: Duration = Duration::from_secs(5);
+
+/// Enough for most text lines in one go, rather than growing into them
+const LINE_CAPACITY: usize = 256;
+
+thread_local! {
+    /// What text lines call the thread logging, worked out once per thread rather than per line
+    static THREAD_LABEL: String = {
+        let thread = std::thread::current();
+        match thread.name() {
+            Some(name) => name.to_string(),
+            None => format!("{:?}", thread.id()),
+        }
+    };
+}
+
+/// Where log records go
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Formatted lines on stderr
+    #[default]
+    Stderr,
+    /// The systemd journal, with each event's details as fields
+    Journald,
+    /// RFC 5424 syslog, with each event's details as structured data
+    Syslog,
+}
+
+/// How records are written to stderr and log files
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Lines with the time, thread, source location and level before the message
+    #[default]
+    Text,
+    /// One JSON object per record, with its timestamp, level, target, message and fields
+    Json,
+}
+
+impl LogFormat {
+    /// A record as one line, without the line ending
+    pub fn line(self, record: &Record) -> String {
+        match self {
+            LogFormat::Text => THREAD_LABEL.with(|thread| {
+                let mut line = String::with_capacity(LINE_CAPACITY);
+                let _ = write!(
+                    line,
+                    "{} [{}:{}:{}] ({}): {}",
+                    Local::now().format("%Y-%m-%dT%H:%M:%S%.6f%z"),
+                    thread,
+                    record.file().unwrap_or("unknown"),
+                    record.line().unwrap_or(0),
+                    record.level(),
+                    record.args(),
+                );
+                line
+            }),
+            LogFormat::Json => {
+                let mut fields = JsonFields(serde_json::Map::new());
+                let _ = record.key_values().visit(&mut fields);
+                json!({
+                    "timestamp": Local::now().to_rfc3339_opts(SecondsFormat::Micros, false),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                    "fields": fields.0,
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+/// Collects a record's key-values for a JSON line
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .insert(key.as_str().to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+/// Syslog facilities it makes sense for us to log as
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyslogFacility {
+    #[default]
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    fn code(self) -> u8 {
+        match self {
+            SyslogFacility::User => 1,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+/// Where syslog messages are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogServer {
+    Unix(PathBuf),
+    Udp(String),
+    Tcp(String),
+}
+
+impl Default for SyslogServer {
+    fn default() -> Self {
+        SyslogServer::Unix(PathBuf::from(SYSLOG_SOCKET))
+    }
+}
+
+/// Parse `udp://HOST:PORT`, `tcp://HOST:PORT` or a socket path, as given on the command line
+pub fn parse_syslog_server(server: &str) -> Result<SyslogServer, String> {
+    if let Some(addr) = server.strip_prefix("udp://") {
+        Ok(SyslogServer::Udp(addr.to_string()))
+    } else if let Some(addr) = server.strip_prefix("tcp://") {
+        Ok(SyslogServer::Tcp(addr.to_string()))
+    } else if server.starts_with('/') {
+        Ok(SyslogServer::Unix(PathBuf::from(server)))
+    } else {
+        Err(format!(
+            "expected udp://HOST:PORT, tcp://HOST:PORT or a socket path, got '{}'",
+            server
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyslogConfig {
+    pub server: SyslogServer,
+    pub facility: SyslogFacility,
+    pub app_name: String,
+}
+
+/// How logging is set up
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub verbose: bool,
+    pub target: LogTarget,
+    pub format: LogFormat,
+    pub syslog: SyslogConfig,
+    /// Also log to a file
+    pub file: Option<LogFileConfig>,
+}
+
+/// Somewhere log records are written
+pub trait Sink: Send + Sync {
+    fn write(&self, record: &Record) -> io::Result<()>;
+}
+
+struct StderrSink(LogFormat);
+
+impl Sink for StderrSink {
+    fn write(&self, record: &Record) -> io::Result<()> {
+        writeln!(io::stderr().lock(), "{}", self.0.line(record))
+    }
+}
+
+/// Decides what gets logged, from RUST_LOG. Only env_logger's filtering is used, each sink
+/// formats records itself.
+fn filter(verbose: bool) -> env_logger::Logger {
+    let log_env = if verbose {
+        Env::default().default_filter_or("debug")
+    } else {
+        Env::default().default_filter_or("info")
+    };
+    env_logger::Builder::from_env(log_env).build()
+}
+
+/// Hands each record the filter lets through to every sink
+struct Logger {
+    filter: env_logger::Logger,
+    sinks: Vec<Box<dyn Sink>>,
+    /// Takes records a sink couldn't, unless stderr is already one of the sinks
+    fallback: Option<StderrSink>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut lost = false;
+        for sink in &self.sinks {
+            lost |= sink.write(record).is_err();
+        }
+        // A sink that's gone away is no reason to lose the record altogether
+        if let Some(fallback) = self.fallback.as_ref().filter(|_| lost) {
+            let _ = fallback.write(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send log records where `config` says
+pub fn setup(config: LogConfig) -> Result<(), Errors> {
+    let filter = filter(config.verbose);
+    let max_level = filter.filter();
+    let mut sinks: Vec<Box<dyn Sink>> = vec![match config.target {
+        LogTarget::Stderr => Box::new(StderrSink(config.format)),
+        LogTarget::Journald => Box::new(JournaldSink::connect()?),
+        LogTarget::Syslog => Box::new(SyslogSink::connect(config.syslog)?),
+    }];
+    if let Some(file) = config.file {
+        sinks.push(Box::new(LogFile::open(file, config.format)?));
+    }
+    let logger = Logger {
+        filter,
+        sinks,
+        fallback: (config.target != LogTarget::Stderr).then_some(StderrSink(config.format)),
+    };
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|err| Errors::ConfigError(format!("can't set up logging: {}", err)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Journal field names are upper case letters, digits and underscores
+fn field_name(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Append a field in journald's native protocol. Values with newlines have to be sent with
+/// their length instead.
+fn push_field(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+/// Collects a record's key-values as journal fields
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        push_field(self.0, &field_name(key.as_str()), &value.to_string());
+        Ok(())
+    }
+}
+
+/// Logs to the systemd journal over its native protocol, so key-values on a record end up as
+/// fields that can be matched on, e.g. `journalctl -t pulse-source-listener MUTED=1`
+struct JournaldSink {
+    socket: UnixDatagram,
+}
+
+impl JournaldSink {
+    fn connect() -> Result<Self, Errors> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET).map_err(|err| {
+            Errors::ConfigError(format!(
+                "can't reach the journal at {}: {}",
+                JOURNAL_SOCKET, err
+            ))
+        })?;
+        Ok(JournaldSink { socket })
+    }
+}
+
+impl Sink for JournaldSink {
+    fn write(&self, record: &Record) -> io::Result<()> {
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+        let mut message = vec![];
+        push_field(&mut message, "MESSAGE", &record.args().to_string());
+        push_field(&mut message, "PRIORITY", priority);
+        push_field(&mut message, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        push_field(&mut message, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            push_field(&mut message, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            push_field(&mut message, "CODE_LINE", &line.to_string());
+        }
+        let _ = record.key_values().visit(&mut Fields(&mut message));
+        self.socket.send(&message).map(|_| ())
+    }
+}
+
+/// Connect to the first of `addr`'s addresses that answers in time, which then gets as long to
+/// take each record
+fn connect_tcp(addr: &str) -> io::Result<TcpStream> {
+    let mut failure = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, SYSLOG_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(SYSLOG_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(err) => failure = err,
+        }
+    }
+    Err(failure)
+}
+
+/// An open connection to a syslog server
+enum SyslogTransport {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl SyslogTransport {
+    fn connect(server: &SyslogServer) -> io::Result<Self> {
+        Ok(match server {
+            SyslogServer::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                SyslogTransport::Unix(socket)
+            }
+            SyslogServer::Udp(addr) => {
+                let socket = UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+                socket.connect(addr)?;
+                SyslogTransport::Udp(socket)
+            }
+            SyslogServer::Tcp(addr) => SyslogTransport::Tcp(connect_tcp(addr)?),
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            SyslogTransport::Unix(socket) => socket.send(message).map(|_| ()),
+            SyslogTransport::Udp(socket) => socket.send(message).map(|_| ()),
+            // A stream needs framing, so messages are prefixed with their length (RFC 6587)
+            SyslogTransport::Tcp(stream) => {
+                let mut framed = format!("{} ", message.len()).into_bytes();
+                framed.extend_from_slice(message);
+                stream.write_all(&framed)
+            }
+        }
+    }
+}
+
+/// Structured data parameter values escape quotes, backslashes and closing brackets
+fn sd_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Collects a record's key-values as structured data parameters
+struct SdParams(String);
+
+impl<'kvs> VisitSource<'kvs> for SdParams {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let name: String = key
+            .as_str()
+            .chars()
+            .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+            .take(32)
+            .collect();
+        self.0
+            .push_str(&format!(" {}=\"{}\"", name, sd_value(&value.to_string())));
+        Ok(())
+    }
+}
+
+/// How a syslog sink is getting on with its server
+struct SyslogLink {
+    /// Dropped when sending fails, to reconnect on a later record
+    transport: Option<SyslogTransport>,
+    /// When reaching the server last failed, so a server that's gone doesn't hold up every
+    /// record
+    failed: Option<Instant>,
+}
+
+/// Logs RFC 5424 messages to a syslog server, locally or over the network
+struct SyslogSink {
+    config: SyslogConfig,
+    hostname: String,
+    link: Mutex<SyslogLink>,
+}
+
+impl SyslogSink {
+    fn connect(config: SyslogConfig) -> Result<Self, Errors> {
+        let transport = SyslogTransport::connect(&config.server).map_err(|err| {
+            Errors::ConfigError(format!(
+                "can't reach syslog at {:?}: {}",
+                config.server, err
+            ))
+        })?;
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_else(|_| "-".to_string());
+        Ok(SyslogSink {
+            config,
+            hostname,
+            link: Mutex::new(SyslogLink {
+                transport: Some(transport),
+                failed: None,
+            }),
+        })
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let mut params = SdParams(String::new());
+        let _ = record.key_values().visit(&mut params);
+        let data = if params.0.is_empty() {
+            "-".to_string()
+        } else {
+            format!("[{}{}]", SD_ID, params.0)
+        };
+        format!(
+            "<{}>1 {} {} {} {} - {} {}",
+            self.config.facility.code() * 8 + severity,
+            Local::now().to_rfc3339_opts(SecondsFormat::Micros, false),
+            self.hostname,
+            self.config.app_name,
+            std::process::id(),
+            data,
+            record.args(),
+        )
+    }
+}
+
+impl Sink for SyslogSink {
+    fn write(&self, record: &Record) -> io::Result<()> {
+        let message = self.format(record);
+        let mut link = self.link.lock().unwrap();
+        let mut connection = match link.transport.take() {
+            Some(connection) => connection,
+            None if link
+                .failed
+                .is_some_and(|failed| failed.elapsed() < SYSLOG_RETRY) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "syslog was unreachable",
+                ));
+            }
+            None => match SyslogTransport::connect(&self.config.server) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    link.failed = Some(Instant::now());
+                    return Err(err);
+                }
+            },
+        };
+        let sent = connection.send(message.as_bytes());
+        match sent {
+            Ok(()) => link.transport = Some(connection),
+            Err(_) => link.failed = Some(Instant::now()),
+        }
+        sent
+    }
+}
+
+/// Logs each event, with what it's about as key-values, so log targets that keep fields can be
+/// searched by them
+pub struct LogOutput;
+
+impl Output for LogOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let kind = &event.kind;
+        let mut fields = vec![("event", kind.name().to_string())];
+        if let Some(source) = kind.device() {
+            fields.push(("source_name", source.to_string()));
+        }
+        if let Some(index) = kind.index() {
+            fields.push(("source_index", index.to_string()));
+        }
+        if let Some(muted) = kind.muted() {
+            fields.push(("muted", u8::from(muted).to_string()));
+        }
+        if let Some(volume) = kind.volume() {
+            fields.push(("volume", volume.to_string()));
+        }
+        if let Some(server) = &event.server {
+            fields.push(("server", server.clone()));
+        }
+        let metadata = Metadata::builder()
+            .level(Level::Info)
+            .target(module_path!())
+            .build();
+        let logger = log::logger();
+        if !logger.enabled(&metadata) {
+            return Ok(());
+        }
+        logger.log(
+            &Record::builder()
+                .metadata(metadata)
+                .file(Some(file!()))
+                .line(Some(line!()))
+                .key_values(&fields)
+                .args(format_args!("{} event", kind.name()))
+                .build(),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use crossbeam_channel::{Receiver, RecvError, RecvTimeoutError, SendError, Sender, TryRecvError};
+use log::error;
+use pulse::{
+    context::Context,
+    def::Retval,
+    error::PAErr,
+    mainloop::{
+        api::{self, Mainloop as _},
+        events::io::{FlagSet as IoFlags, IoEvent},
+        signal::MainloopSignals,
+        standard, threaded,
+    },
+    proplist::Proplist,
+    time::MicroSeconds,
+};
+
+/// Which of libpulse's mainloops calls us back
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MainloopKind {
+    /// libpulse's own thread, which has to be locked out while callbacks are set up
+    #[default]
+    Threaded,
+    /// Our thread, only while we wait on something, so there's nothing to lock and no other
+    /// thread running
+    Standard,
+}
+
+/// Wakes a standard mainloop blocked in another thread, through an eventfd it watches
+#[derive(Debug, Clone)]
+pub struct Waker(Arc<File>);
+
+impl Waker {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Waker(Arc::new(File::from(unsafe {
+            OwnedFd::from_raw_fd(fd)
+        }))))
+    }
+
+    fn wake(&self) {
+        // Only fails when the count is already as high as it goes, which is awake enough
+        let _ = (&*self.0).write(&1u64.to_ne_bytes());
+    }
+
+    /// Take the wakeups so far, so the mainloop can block again
+    fn clear(&self) {
+        let _ = (&*self.0).read(&mut [0; 8]);
+    }
+}
+
+/// Sends to a loop, waking its mainloop if it's a standard one waiting on the channel
+#[derive(Debug)]
+pub struct LoopTx<T> {
+    tx: Sender<T>,
+    waker: Arc<OnceLock<Waker>>,
+}
+
+impl<T> Clone for LoopTx<T> {
+    fn clone(&self) -> Self {
+        LoopTx {
+            tx: self.tx.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+impl<T> LoopTx<T> {
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        self.tx.send(message)?;
+        if let Some(waker) = self.waker.get() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// Where a loop takes its messages from, from other threads as much as from callbacks. Only
+/// one mainloop waits on it.
+#[derive(Debug)]
+pub struct LoopRx<T> {
+    rx: Receiver<T>,
+    waker: Arc<OnceLock<Waker>>,
+}
+
+/// A channel for a loop to wait on in its mainloop
+pub fn channel<T>() -> (LoopTx<T>, LoopRx<T>) {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let waker = Arc::default();
+    (
+        LoopTx {
+            tx,
+            waker: Arc::clone(&waker),
+        },
+        LoopRx { rx, waker },
+    )
+}
+
+/// What a mainloop can wait on. Plain channels are only for callbacks' answers, which the
+/// mainloop runs itself, so it needn't be woken for them.
+pub trait Inbox {
+    type Message;
+
+    fn receiver(&self) -> &Receiver<Self::Message>;
+
+    /// Where senders look for the waker of the mainloop waiting on it
+    fn waker(&self) -> Option<&OnceLock<Waker>> {
+        None
+    }
+}
+
+impl<T> Inbox for Receiver<T> {
+    type Message = T;
+
+    fn receiver(&self) -> &Receiver<T> {
+        self
+    }
+}
+
+impl<T> Inbox for LoopRx<T> {
+    type Message = T;
+
+    fn receiver(&self) -> &Receiver<T> {
+        &self.rx
+    }
+
+    fn waker(&self) -> Option<&OnceLock<Waker>> {
+        Some(&self.waker)
+    }
+}
+
+/// libpulse's standard mainloop, blocking until there's IO, a timeout or a wakeup
+pub struct Standard {
+    /// Dropped before the mainloop it's registered with
+    _woken: IoEvent<<standard::Mainloop as api::Mainloop>::MI>,
+    waker: Waker,
+    mainloop: standard::Mainloop,
+}
+
+impl Standard {
+    fn new() -> Option<Self> {
+        let mut mainloop = standard::Mainloop::new()?;
+        let waker = Waker::new()
+            .map_err(|err| error!("Can't make an eventfd to wake the mainloop: {}", err))
+            .ok()?;
+        let woken = mainloop.new_io_event(
+            waker.0.as_raw_fd(),
+            IoFlags::INPUT,
+            Box::new({
+                let waker = waker.clone();
+                move |_, _, _| waker.clear()
+            }),
+        )?;
+        Some(Standard {
+            _woken: woken,
+            waker,
+            mainloop,
+        })
+    }
+}
+
+/// Either of libpulse's mainloops. Waiting on callbacks goes through it, as the standard one
+/// only runs them while it's being run itself.
+pub enum Mainloop {
+    Threaded(threaded::Mainloop),
+    Standard(Standard),
+}
+
+impl Mainloop {
+    pub fn new(kind: MainloopKind) -> Option<Self> {
+        Some(match kind {
+            MainloopKind::Threaded => Mainloop::Threaded(threaded::Mainloop::new()?),
+            MainloopKind::Standard => Mainloop::Standard(Standard::new()?),
+        })
+    }
+
+    pub fn new_context(&self, name: &str, proplist: &Proplist) -> Option<Context> {
+        match self {
+            Mainloop::Threaded(mainloop) => Context::new_with_proplist(mainloop, name, proplist),
+            Mainloop::Standard(standard) => {
+                Context::new_with_proplist(&standard.mainloop, name, proplist)
+            }
+        }
+    }
+
+    pub fn init_signals(&mut self) -> Result<(), PAErr> {
+        match self {
+            Mainloop::Threaded(mainloop) => mainloop.init_signals(),
+            Mainloop::Standard(standard) => standard.mainloop.init_signals(),
+        }
+    }
+
+    /// Keep callbacks from running until unlocked. The standard mainloop can't run them
+    /// meanwhile anyway.
+    pub fn lock(&mut self) {
+        if let Mainloop::Threaded(mainloop) = self {
+            mainloop.lock();
+        }
+    }
+
+    pub fn unlock(&mut self) {
+        if let Mainloop::Threaded(mainloop) = self {
+            mainloop.unlock();
+        }
+    }
+
+    /// Have callbacks run from now on. The standard mainloop runs them whenever we wait.
+    pub fn start(&mut self) -> Result<(), PAErr> {
+        match self {
+            Mainloop::Threaded(mainloop) => mainloop.start(),
+            Mainloop::Standard(_) => Ok(()),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        match self {
+            Mainloop::Threaded(mainloop) => mainloop.stop(),
+            Mainloop::Standard(standard) => standard.mainloop.quit(Retval(0)),
+        }
+    }
+
+    /// Wait up to `timeout` for something in `inbox`, running callbacks meanwhile when the
+    /// mainloop is ours to run
+    pub fn recv_timeout<I: Inbox>(
+        &mut self,
+        inbox: &I,
+        timeout: Duration,
+    ) -> Result<I::Message, RecvTimeoutError> {
+        match self {
+            Mainloop::Threaded(_) => inbox.receiver().recv_timeout(timeout),
+            Mainloop::Standard(standard) => standard.wait(inbox, Some(Instant::now() + timeout)),
+        }
+    }
+
+    /// Wait for something in `inbox`, running callbacks meanwhile when the mainloop is ours
+    /// to run
+    pub fn recv<I: Inbox>(&mut self, inbox: &I) -> Result<I::Message, RecvError> {
+        match self {
+            Mainloop::Threaded(_) => inbox.receiver().recv(),
+            Mainloop::Standard(standard) => standard.wait(inbox, None).map_err(|_| RecvError),
+        }
+    }
+}
+
+impl Standard {
+    /// Run the mainloop until something's in `inbox`, or the deadline's passed. Blocks in
+    /// between, as senders on other threads wake it.
+    fn wait<I: Inbox>(
+        &mut self,
+        inbox: &I,
+        deadline: Option<Instant>,
+    ) -> Result<I::Message, RecvTimeoutError> {
+        if let Some(waker) = inbox.waker() {
+            waker.get_or_init(|| self.waker.clone());
+        }
+        loop {
+            match inbox.receiver().try_recv() {
+                Ok(received) => return Ok(received),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    Some(MicroSeconds(left.as_micros() as u64))
+                }
+                None => None,
+            };
+            let mainloop = &mut self.mainloop;
+            let ran = mainloop
+                .prepare(timeout)
+                .and_then(|()| mainloop.poll())
+                .and_then(|_| mainloop.dispatch());
+            if let Err(err) = ran {
+                // Nothing will call back any more
+                error!("Running the mainloop failed: {}", err);
+                return Err(RecvTimeoutError::Disconnected);
+            }
+        }
+    }
+}
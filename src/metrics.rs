@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use log::debug;
+use tokio::net::TcpStream;
+
+use crate::http;
+use crate::output::{Event, EventKind, Output};
+use crate::runtime::{self, AcceptTask};
+use crate::Errors;
+
+#[derive(Debug, Clone)]
+struct SourceMetrics {
+    muted: bool,
+    volume: Option<u32>,
+    /// When the mute state last flipped, or when the source was first seen if it hasn't
+    mute_changed: DateTime<Local>,
+}
+
+/// Everything exported, kept up to date from events
+#[derive(Debug, Default)]
+struct Registry {
+    sources: BTreeMap<String, SourceMetrics>,
+    default_source: Option<String>,
+    /// Mute flips per source, keyed by the state flipped to
+    transitions: BTreeMap<(String, bool), u64>,
+    events: BTreeMap<&'static str, u64>,
+}
+
+/// Quote a label value as the text exposition format wants
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+impl Registry {
+    fn update(&mut self, event: &Event) {
+        let kind = &event.kind;
+        *self.events.entry(kind.name()).or_default() += 1;
+        match kind {
+            EventKind::NoSource => self.default_source = None,
+            EventKind::SourceRemoved { source, .. } => {
+                self.sources.remove(&**source);
+                if self.default_source.as_deref() == Some(&**source) {
+                    self.default_source = None;
+                }
+            }
+            kind if kind.facility() == "source" => {
+                let (Some(source), Some(muted)) = (kind.device(), kind.muted()) else {
+                    return;
+                };
+                if kind.is_default() {
+                    self.default_source = Some(source.to_string());
+                }
+                let metrics = self
+                    .sources
+                    .entry(source.to_string())
+                    .or_insert(SourceMetrics {
+                        muted,
+                        volume: None,
+                        mute_changed: event.timestamp,
+                    });
+                if metrics.muted != muted {
+                    metrics.muted = muted;
+                    metrics.mute_changed = event.timestamp;
+                    *self
+                        .transitions
+                        .entry((source.to_string(), muted))
+                        .or_default() += 1;
+                }
+                metrics.volume = kind.volume().or(metrics.volume);
+            }
+            _ => {}
+        }
+    }
+
+    /// The Prometheus text exposition format, each family's samples following its header
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        };
+        let sources = || {
+            self.sources
+                .iter()
+                .map(|(source, metrics)| (label(source), metrics))
+        };
+
+        family(
+            "pulse_source_muted",
+            "gauge",
+            "Whether the source is muted (1) or not (0)",
+            sources()
+                .map(|(source, metrics)| {
+                    (
+                        format!("source={}", source),
+                        (metrics.muted as u8).to_string(),
+                    )
+                })
+                .collect(),
+        );
+        family(
+            "pulse_source_volume_percent",
+            "gauge",
+            "Average volume across the source's channels",
+            sources()
+                .filter_map(|(source, metrics)| {
+                    let volume = metrics.volume?;
+                    Some((format!("source={}", source), volume.to_string()))
+                })
+                .collect(),
+        );
+        family(
+            "pulse_source_mute_changed_timestamp_seconds",
+            "gauge",
+            "When the source's mute state last changed, or when it was first seen",
+            sources()
+                .map(|(source, metrics)| {
+                    let secs = metrics.mute_changed.timestamp_millis() as f64 / 1000.0;
+                    (format!("source={}", source), format!("{:.3}", secs))
+                })
+                .collect(),
+        );
+        family(
+            "pulse_default_source_info",
+            "gauge",
+            "The default source, always 1",
+            self.default_source
+                .iter()
+                .map(|source| (format!("source={}", label(source)), "1".to_string()))
+                .collect(),
+        );
+        family(
+            "pulse_source_mute_transitions_total",
+            "counter",
+            "Times the source was muted or unmuted",
+            self.transitions
+                .iter()
+                .map(|((source, muted), count)| {
+                    let to = if *muted { "muted" } else { "unmuted" };
+                    (
+                        format!("source={},to=\"{}\"", label(source), to),
+                        count.to_string(),
+                    )
+                })
+                .collect(),
+        );
+        family(
+            "pulse_listener_events_total",
+            "counter",
+            "Events reported, by type",
+            self.events
+                .iter()
+                .map(|(event, count)| (format!("event=\"{}\"", event), count.to_string()))
+                .collect(),
+        );
+        out
+    }
+}
+
+/// Answer one scrape
+async fn scrape(mut stream: TcpStream, registry: Arc<Mutex<Registry>>) -> io::Result<()> {
+    let (method, path) = http::read_request(&mut stream).await?;
+    match (method.as_str(), path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = registry.lock().unwrap().render();
+            http::respond(
+                &mut stream,
+                "200 OK",
+                "text/plain; version=0.0.4",
+                body.as_bytes(),
+            )
+            .await
+        }
+        _ => http::respond(&mut stream, "404 Not Found", "text/plain", b"not found\n").await,
+    }
+}
+
+/// Serves `GET /metrics` for Prometheus to scrape, from a registry updated with every event
+pub struct MetricsOutput {
+    registry: Arc<Mutex<Registry>>,
+    _accepting: AcceptTask,
+}
+
+impl MetricsOutput {
+    pub fn start(addr: SocketAddr) -> Result<Self, Errors> {
+        let (runtime, listener) = runtime::listen(addr)?;
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let scraped = registry.clone();
+        let accepting = runtime::serve(&runtime, listener, "metrics", move |stream, _| {
+            scrape(stream, scraped.clone())
+        });
+        debug!("Serving metrics on {}", addr);
+        Ok(MetricsOutput {
+            registry,
+            _accepting: accepting,
+        })
+    }
+}
+
+impl Output for MetricsOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        self.registry.lock().unwrap().update(event);
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use pulse::context::introspect::ModuleInfo;
+
+/// Modules that create or reroute capture devices, the only ones worth reporting
+const CAPTURE_MODULES: &[&str] = &[
+    "module-echo-cancel",
+    "module-loopback",
+    "module-null-source",
+    "module-remap-source",
+    "module-virtual-source",
+];
+
+/// Loaded capture modules, keyed by module index
+pub type Modules = HashMap<u32, ModuleDatum>;
+
+#[derive(Debug, Clone)]
+pub struct ModuleDatum {
+    pub name: String,
+    pub argument: Option<String>,
+}
+
+impl ModuleDatum {
+    pub fn from_info(info: &ModuleInfo<'_>) -> Self {
+        ModuleDatum {
+            name: match &info.name {
+                None => "unknown".to_string(),
+                Some(name) => name.to_string(),
+            },
+            argument: info.argument.as_ref().map(|arg| arg.to_string()),
+        }
+    }
+
+    pub fn affects_capture(&self) -> bool {
+        CAPTURE_MODULES.contains(&self.name.as_str())
+    }
+}
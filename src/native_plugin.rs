@@ -0,0 +1,175 @@
+use std::env;
+use std::ffi::{c_char, c_void, CString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libloading::{Library, Symbol};
+use log::{debug, error, info};
+
+use crate::output::{Event, Output};
+use crate::Errors;
+
+/// Version of the plugin ABI this host speaks
+pub const ABI_VERSION: u32 = 1;
+
+/// What `emit` returns once a plugin has caught a panic of its own. Unwinding can't cross into
+/// the host (it aborts the process), so plugins have to catch panics at the boundary and report
+/// them, after which they're disabled.
+pub const PLUGIN_PANICKED: i32 = -1;
+
+/// Symbol every plugin exports: handed the host's ABI version, it returns its vtable for that
+/// version, or null if it doesn't support it
+const ENTRY_POINT: &[u8] = b"psl_output_plugin\0";
+
+type EntryPoint = unsafe extern "C" fn(host_abi_version: u32) -> *const OutputPluginVTable;
+
+/// What an output plugin provides, laid out for C
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct OutputPluginVTable {
+    /// Has to match the version the host asked for
+    pub abi_version: u32,
+    /// Set up the plugin's state, returning null on failure
+    pub create: unsafe extern "C" fn() -> *mut c_void,
+    /// Handle an event, given as NUL-terminated JSON shaped like the JSON output. Returns 0 on
+    /// success, or `PLUGIN_PANICKED`.
+    pub emit: unsafe extern "C" fn(state: *mut c_void, event_json: *const c_char) -> i32,
+    /// Free the plugin's state
+    pub destroy: unsafe extern "C" fn(state: *mut c_void),
+}
+
+/// A loaded cdylib plugin. A plugin that reports a panic is disabled rather than being trusted
+/// with more events.
+pub struct NativePlugin {
+    name: String,
+    vtable: OutputPluginVTable,
+    state: *mut c_void,
+    disabled: bool,
+    // Dropped last, as the vtable points into it
+    _library: Library,
+}
+
+impl NativePlugin {
+    pub fn load(path: &Path) -> Result<Self, Errors> {
+        let name = path.display().to_string();
+        let plugin_error =
+            |err: String| Errors::ConfigError(format!("failed to load plugin {}: {}", name, err));
+
+        // SAFETY: loading a library runs its initialisers; plugins are trusted code the user
+        // chose to install
+        let library = unsafe { Library::new(path) }.map_err(|err| plugin_error(err.to_string()))?;
+        // SAFETY: the entry point's signature is part of the ABI
+        let entry: Symbol<EntryPoint> =
+            unsafe { library.get(ENTRY_POINT) }.map_err(|err| plugin_error(err.to_string()))?;
+
+        // SAFETY: plugins return null or a pointer to a vtable that lives as long as the library
+        let vtable = unsafe { entry(ABI_VERSION).as_ref() }
+            .copied()
+            .ok_or_else(|| {
+                plugin_error(format!("it doesn't support ABI version {}", ABI_VERSION))
+            })?;
+        if vtable.abi_version != ABI_VERSION {
+            return Err(plugin_error(format!(
+                "it returned ABI version {}, expected {}",
+                vtable.abi_version, ABI_VERSION
+            )));
+        }
+
+        // SAFETY: create takes no arguments, so there's nothing to get wrong on our side
+        let state = unsafe { (vtable.create)() };
+        if state.is_null() {
+            return Err(plugin_error("it failed to start".to_string()));
+        }
+
+        debug!("Loaded native plugin {}", name);
+        Ok(NativePlugin {
+            name,
+            vtable,
+            state,
+            disabled: false,
+            _library: library,
+        })
+    }
+
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+        // Serialised JSON escapes control characters, so can't contain a NUL
+        let json = CString::new(serde_json::to_string(event)?)?;
+
+        // SAFETY: state came from create and hasn't been destroyed, and json outlives the call
+        match unsafe { (self.vtable.emit)(self.state, json.as_ptr()) } {
+            0 => {}
+            PLUGIN_PANICKED => {
+                error!("plugin {} panicked, disabling it", self.name);
+                self.disabled = true;
+            }
+            code => info!("plugin {} failed to handle event: {}", self.name, code),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NativePlugin {
+    fn drop(&mut self) {
+        if self.disabled {
+            // Its state may be half-updated, so it's leaked rather than risk another panic
+            return;
+        }
+        // SAFETY: state came from create and is never used again
+        unsafe { (self.vtable.destroy)(self.state) };
+    }
+}
+
+/// Where plugins are discovered: `$XDG_DATA_HOME/pulse-source-listener/plugins`
+fn plugin_dir() -> Option<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+    Some(data_home.join("pulse-source-listener/plugins"))
+}
+
+/// Every shared library in the plugin directory, in name order
+pub fn discover_plugins() -> Vec<PathBuf> {
+    let Some(dir) = plugin_dir() else {
+        return vec![];
+    };
+    let mut paths: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "so"))
+            .collect(),
+        Err(err) => {
+            debug!("No plugins from {}: {}", dir.display(), err);
+            vec![]
+        }
+    };
+    paths.sort();
+    paths
+}
+
+/// Sends every event to each loaded plugin
+pub struct NativePluginOutput {
+    plugins: Vec<NativePlugin>,
+}
+
+impl NativePluginOutput {
+    pub fn load(paths: &[PathBuf]) -> Result<Self, Errors> {
+        let plugins = paths
+            .iter()
+            .map(|path| NativePlugin::load(path))
+            .collect::<Result<_, _>>()?;
+        Ok(NativePluginOutput { plugins })
+    }
+}
+
+impl Output for NativePluginOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        for plugin in &mut self.plugins {
+            plugin.emit(event)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1026 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::info_span;
+
+use crate::latency::{self, Received};
+use crate::theme::Theme;
+
+/// How events are rendered on stdout
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Bare text lines, e.g. MUTED / UNMUTED
+    Plain,
+    /// One JSON object per event
+    Json,
+    /// JSON lines for a Waybar custom module (`return-type: json`), with the volume as its
+    /// percentage, so reporting volume changes too
+    Waybar,
+    /// i3bar/swaybar protocol, usable directly as a `status_command`
+    I3bar,
+    /// Single lines for a polybar `custom/script` module in tail mode
+    Polybar,
+    /// CSV rows, with a header row first
+    Csv,
+    /// Length-prefixed MessagePack frames
+    Msgpack,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// Mute state of the default source, emitted on startup and whenever it flips
+    Mute {
+        source: Arc<str>,
+        #[serde(flatten)]
+        device: DeviceState,
+        /// Whether the source is the server's default source
+        default: bool,
+    },
+    /// There is currently no default source to report on
+    NoSource,
+    /// Mute state of the default sink, emitted on startup and whenever it flips
+    SinkMute {
+        sink: String,
+        #[serde(flatten)]
+        device: DeviceState,
+        default: bool,
+    },
+    /// There is currently no default sink to report on
+    NoSink,
+    /// Volume of the default source changed
+    Volume {
+        source: Arc<str>,
+        #[serde(flatten)]
+        device: DeviceState,
+        default: bool,
+    },
+    /// Volume of the default sink changed
+    SinkVolume {
+        sink: String,
+        #[serde(flatten)]
+        device: DeviceState,
+        default: bool,
+    },
+    /// The server's default source is now a different source
+    DefaultChanged {
+        source: Arc<str>,
+        #[serde(flatten)]
+        device: DeviceState,
+    },
+    /// The server's default sink is now a different sink
+    SinkDefaultChanged {
+        sink: String,
+        #[serde(flatten)]
+        device: DeviceState,
+    },
+    /// The default source switched to a different port
+    PortChanged {
+        source: Arc<str>,
+        #[serde(flatten)]
+        device: DeviceState,
+        port: Option<String>,
+        port_description: Option<String>,
+        previous_port: Option<String>,
+    },
+    /// The default source started or stopped running, i.e. being recorded from
+    State {
+        source: Arc<str>,
+        #[serde(flatten)]
+        device: DeviceState,
+        running: bool,
+        state: String,
+    },
+    /// An application started or stopped recording from a source
+    Recording {
+        application: String,
+        source: Arc<str>,
+        index: u32,
+        /// Index of the application's recording stream (source output)
+        output_index: u32,
+        default: bool,
+        recording: bool,
+    },
+    /// A card switched profile, e.g. a Bluetooth headset moving between A2DP and HFP
+    ProfileChanged {
+        card: String,
+        /// Index of the card
+        index: u32,
+        profile: Option<String>,
+        profile_description: Option<String>,
+        previous_profile: Option<String>,
+    },
+    /// A PulseAudio client connected or disconnected
+    Client {
+        client: String,
+        /// Index of the client
+        index: u32,
+        binary: Option<String>,
+        process_id: Option<String>,
+        connected: bool,
+    },
+    /// The default source was suspended (e.g. by module-suspend-on-idle) or resumed
+    Suspended {
+        source: Arc<str>,
+        #[serde(flatten)]
+        device: DeviceState,
+        suspended: bool,
+    },
+    /// A module that creates or reroutes capture devices was loaded or unloaded
+    Module {
+        module: String,
+        /// Index of the module
+        index: u32,
+        argument: Option<String>,
+        loaded: bool,
+    },
+    /// A hook command kept failing or timing out, even after retries
+    HookFailed {
+        hook: String,
+        /// The event the hook was run for
+        triggered_by: String,
+        attempts: u32,
+        reason: String,
+    },
+    /// The connection to the server was lost and has been made again. The state as it now is
+    /// follows.
+    Reconnected,
+    /// The listener is stopping, and this is the last event
+    Shutdown,
+    /// Every tracked source, when asked for
+    SourceList { sources: Vec<ListedSource> },
+    /// A source was added
+    SourceAdded { source: Arc<str>, index: u32 },
+    /// A source was removed
+    SourceRemoved { source: Arc<str>, index: u32 },
+    /// The server reused the index of the source followed with `--index` for a different device
+    SourceReplaced {
+        /// The device now at the index
+        source: Arc<str>,
+        index: u32,
+        /// The device that was being followed
+        previous: Arc<str>,
+    },
+    /// A source kept its index but changed its name, e.g. after its card switched profile
+    SourceRenamed {
+        /// The name it has now
+        source: Arc<str>,
+        index: u32,
+        previous: Arc<str>,
+    },
+    /// The combined mute state of all sources changed
+    Aggregate {
+        muted: bool,
+        /// Names of the unmuted sources
+        live: Vec<Arc<str>>,
+        /// How many sources were considered
+        sources: usize,
+    },
+}
+
+/// Where the source or sink an event is about stands, shared by every event carrying it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub index: u32,
+    pub muted: bool,
+    /// Average volume across channels, in percent
+    pub volume: u32,
+}
+
+/// A source, as listed on request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListedSource {
+    pub source: Arc<str>,
+    pub index: u32,
+    pub muted: bool,
+    pub volume: u32,
+    pub default: bool,
+}
+
+impl EventKind {
+    /// The event type, matching the `event` key in serialized output
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Mute { .. } => "mute",
+            EventKind::NoSource => "no_source",
+            EventKind::SinkMute { .. } => "sink_mute",
+            EventKind::NoSink => "no_sink",
+            EventKind::Volume { .. } => "volume",
+            EventKind::SinkVolume { .. } => "sink_volume",
+            EventKind::DefaultChanged { .. } => "default_changed",
+            EventKind::SinkDefaultChanged { .. } => "sink_default_changed",
+            EventKind::PortChanged { .. } => "port_changed",
+            EventKind::State { .. } => "state",
+            EventKind::Recording { .. } => "recording",
+            EventKind::ProfileChanged { .. } => "profile_changed",
+            EventKind::Client { .. } => "client",
+            EventKind::Suspended { .. } => "suspended",
+            EventKind::Module { .. } => "module",
+            EventKind::Aggregate { .. } => "aggregate",
+            EventKind::SourceReplaced { .. } => "source_replaced",
+            EventKind::SourceRenamed { .. } => "source_renamed",
+            EventKind::SourceAdded { .. } => "source_added",
+            EventKind::SourceRemoved { .. } => "source_removed",
+            EventKind::HookFailed { .. } => "hook_failed",
+            EventKind::SourceList { .. } => "source_list",
+            EventKind::Reconnected => "reconnected",
+            EventKind::Shutdown => "shutdown",
+        }
+    }
+
+    /// Which kind of PulseAudio object the event is about
+    pub fn facility(&self) -> &'static str {
+        match self {
+            EventKind::Mute { .. }
+            | EventKind::Volume { .. }
+            | EventKind::PortChanged { .. }
+            | EventKind::State { .. }
+            | EventKind::Suspended { .. } => "source",
+            EventKind::SinkMute { .. } | EventKind::SinkVolume { .. } => "sink",
+            EventKind::Aggregate { .. }
+            | EventKind::SourceReplaced { .. }
+            | EventKind::SourceRenamed { .. }
+            | EventKind::SourceAdded { .. }
+            | EventKind::SourceRemoved { .. }
+            | EventKind::SourceList { .. } => "source",
+            EventKind::NoSource
+            | EventKind::NoSink
+            | EventKind::DefaultChanged { .. }
+            | EventKind::SinkDefaultChanged { .. }
+            | EventKind::Reconnected
+            | EventKind::Shutdown => "server",
+            EventKind::Recording { .. } => "source_output",
+            EventKind::ProfileChanged { .. } => "card",
+            EventKind::Client { .. } => "client",
+            EventKind::Module { .. } => "module",
+            EventKind::HookFailed { .. } => "hook",
+        }
+    }
+
+    /// Whether the event carries the state of a default device, which is all status bar outputs
+    /// display
+    pub fn is_device_state(&self) -> bool {
+        match self {
+            // Non-default devices are extra watched sources
+            EventKind::Mute { default, .. } | EventKind::Volume { default, .. } => *default,
+            EventKind::Recording { .. }
+            | EventKind::ProfileChanged { .. }
+            | EventKind::Client { .. }
+            | EventKind::Module { .. }
+            | EventKind::SourceReplaced { .. }
+            | EventKind::SourceRenamed { .. }
+            | EventKind::SourceAdded { .. }
+            | EventKind::SourceRemoved { .. }
+            | EventKind::HookFailed { .. }
+            | EventKind::Reconnected
+            | EventKind::Shutdown
+            | EventKind::SourceList { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Whether the event is about the default sink, rather than the default source
+    pub fn is_sink(&self) -> bool {
+        matches!(
+            self,
+            EventKind::SinkMute { .. }
+                | EventKind::NoSink
+                | EventKind::SinkVolume { .. }
+                | EventKind::SinkDefaultChanged { .. }
+        )
+    }
+
+    /// Where the source or sink the event is about stands, for events carrying it
+    pub fn device_state(&self) -> Option<&DeviceState> {
+        match self {
+            EventKind::Mute { device, .. }
+            | EventKind::SinkMute { device, .. }
+            | EventKind::Volume { device, .. }
+            | EventKind::SinkVolume { device, .. }
+            | EventKind::DefaultChanged { device, .. }
+            | EventKind::SinkDefaultChanged { device, .. }
+            | EventKind::PortChanged { device, .. }
+            | EventKind::State { device, .. }
+            | EventKind::Suspended { device, .. } => Some(device),
+            _ => None,
+        }
+    }
+
+    /// Name of the source or sink the event is about
+    pub fn device(&self) -> Option<&str> {
+        match self {
+            EventKind::Mute { source, .. }
+            | EventKind::Volume { source, .. }
+            | EventKind::DefaultChanged { source, .. }
+            | EventKind::PortChanged { source, .. }
+            | EventKind::State { source, .. }
+            | EventKind::Suspended { source, .. }
+            | EventKind::Recording { source, .. }
+            | EventKind::SourceReplaced { source, .. }
+            | EventKind::SourceRenamed { source, .. }
+            | EventKind::SourceAdded { source, .. }
+            | EventKind::SourceRemoved { source, .. } => Some(source),
+            EventKind::ProfileChanged { card, .. } => Some(card),
+            EventKind::Client { client, .. } => Some(client),
+            EventKind::Module { module, .. } => Some(module),
+            EventKind::SinkMute { sink, .. }
+            | EventKind::SinkVolume { sink, .. }
+            | EventKind::SinkDefaultChanged { sink, .. } => Some(sink),
+            _ => None,
+        }
+    }
+
+    pub fn index(&self) -> Option<u32> {
+        if let Some(device) = self.device_state() {
+            return Some(device.index);
+        }
+        match self {
+            EventKind::Recording { index, .. }
+            | EventKind::ProfileChanged { index, .. }
+            | EventKind::Client { index, .. }
+            | EventKind::Module { index, .. }
+            | EventKind::SourceReplaced { index, .. }
+            | EventKind::SourceRenamed { index, .. }
+            | EventKind::SourceAdded { index, .. }
+            | EventKind::SourceRemoved { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+
+    pub fn muted(&self) -> Option<bool> {
+        match self {
+            EventKind::Aggregate { muted, .. } => Some(*muted),
+            _ => self.device_state().map(|device| device.muted),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        match self {
+            EventKind::Mute { default, .. }
+            | EventKind::SinkMute { default, .. }
+            | EventKind::Volume { default, .. }
+            | EventKind::SinkVolume { default, .. }
+            | EventKind::Recording { default, .. } => *default,
+            EventKind::DefaultChanged { .. }
+            | EventKind::SinkDefaultChanged { .. }
+            | EventKind::PortChanged { .. }
+            | EventKind::State { .. }
+            | EventKind::Suspended { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn volume(&self) -> Option<u32> {
+        self.device_state().map(|device| device.volume)
+    }
+}
+
+/// How often a thread being waited on at shutdown is checked on
+const JOIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Wait for a thread to end, up to `deadline`, leaving it behind if it hasn't. Returns how it
+/// ended, if it did.
+pub fn join_until<T>(thread: JoinHandle<T>, deadline: Instant) -> Option<thread::Result<T>> {
+    while !thread.is_finished() {
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(JOIN_INTERVAL);
+    }
+    Some(thread.join())
+}
+
+/// Numbers one listener's events, shared by all its outputs so consumers can spot gaps. Clones
+/// share the count.
+#[derive(Debug, Clone)]
+pub struct Sequence(Arc<AtomicU64>);
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Sequence(Arc::new(AtomicU64::new(1)))
+    }
+}
+
+thread_local! {
+    /// Where events made on this thread take their numbers from
+    static SEQUENCE: RefCell<Sequence> = RefCell::new(Sequence::default());
+}
+
+/// Number the events made on this thread from `sequence`
+pub fn number_events(sequence: &Sequence) {
+    SEQUENCE.with(|current| *current.borrow_mut() = sequence.clone());
+}
+
+/// Envelope common to every event, regardless of how it is rendered
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    #[serde(flatten)]
+    pub kind: EventKind,
+    pub timestamp: DateTime<Local>,
+    /// Monotonically increasing per listener, starting at 1
+    pub seq: u64,
+    /// Which server it came from, when listening to more than one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// When the server told us about the change behind the event, with --measure-latency
+    #[serde(skip)]
+    pub received: Option<Received>,
+    /// Repeats the current state, for a heartbeat or a status request, rather than reporting a
+    /// change. Outputs acting on changes pass these over.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub snapshot: bool,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Self {
+        Event {
+            kind,
+            timestamp: Local::now(),
+            seq: SEQUENCE.with(|sequence| sequence.borrow().0.fetch_add(1, Ordering::Relaxed)),
+            server: None,
+            received: latency::received(),
+            snapshot: false,
+        }
+    }
+}
+
+/// Somewhere events end up
+pub trait Output {
+    fn emit(&mut self, event: &Event) -> io::Result<()>;
+
+    /// Whether events are only written later, by a thread of the output's own that measures
+    /// their latency itself, rather than by the time `emit` returns
+    fn writes_later(&self) -> bool {
+        false
+    }
+
+    /// Finish off work still queued from earlier events, on the way out, giving up on whatever
+    /// is left at `deadline`. Events emitted afterwards may go nowhere.
+    fn finish(&mut self, _deadline: Instant) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// What the output is called in traces
+    fn name(&self) -> &'static str {
+        // The type's own name, without its path or type parameters
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+/// Collects events, for answering questions about the current state
+impl Output for Vec<Event> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        self.push(event.clone());
+        Ok(())
+    }
+}
+
+/// Sends every event to each of several outputs
+pub struct FanoutOutput {
+    outputs: Vec<Box<dyn Output>>,
+}
+
+impl FanoutOutput {
+    pub fn new(outputs: Vec<Box<dyn Output>>) -> Self {
+        FanoutOutput { outputs }
+    }
+}
+
+impl Output for FanoutOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        // One broken output shouldn't starve the others, so report the first error only after
+        // everything has had a go.
+        let mut result = Ok(());
+        for output in self.outputs.iter_mut() {
+            let _span =
+                info_span!("output", output = output.name(), event = event.kind.name()).entered();
+            match output.emit(event) {
+                Ok(()) if !output.writes_later() => latency::written(output.name(), event),
+                Ok(()) => {}
+                Err(err) => {
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn finish(&mut self, deadline: Instant) -> io::Result<()> {
+        let mut result = Ok(());
+        for output in self.outputs.iter_mut() {
+            if let Err(err) = output.finish(deadline) {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// User-configurable text for each state the default source can be in
+#[derive(Debug, Clone)]
+pub struct StateTexts {
+    pub mute: String,
+    pub unmute: String,
+    pub nosource: String,
+    pub sink_mute: String,
+    pub sink_unmute: String,
+    pub nosink: String,
+}
+
+impl StateTexts {
+    /// Text for the state of the device the event is about, for outputs that only show the
+    /// current state (status bars)
+    pub fn for_event(&self, kind: &EventKind) -> &str {
+        match (kind.is_sink(), kind.muted()) {
+            (false, Some(true)) => &self.mute,
+            (false, Some(false)) => &self.unmute,
+            (false, None) => &self.nosource,
+            (true, Some(true)) => &self.sink_mute,
+            (true, Some(false)) => &self.sink_unmute,
+            (true, None) => &self.nosink,
+        }
+    }
+
+    /// A line describing the event, for line-oriented text output
+    pub fn plain_line(&self, kind: &EventKind) -> Cow<'_, str> {
+        match kind {
+            EventKind::Volume { device, .. } => Cow::Owned(format!("VOLUME {}", device.volume)),
+            EventKind::SinkVolume { device, .. } => {
+                Cow::Owned(format!("SINK_VOLUME {}", device.volume))
+            }
+            EventKind::DefaultChanged { source, .. } => {
+                Cow::Owned(format!("DEFAULT_CHANGED {}", source))
+            }
+            EventKind::SinkDefaultChanged { sink, .. } => {
+                Cow::Owned(format!("SINK_DEFAULT_CHANGED {}", sink))
+            }
+            EventKind::PortChanged { port, .. } => Cow::Owned(format!(
+                "PORT_CHANGED {}",
+                port.as_deref().unwrap_or("none")
+            )),
+            EventKind::State { state, .. } => Cow::Owned(format!("STATE {}", state.to_uppercase())),
+            EventKind::Suspended {
+                source, suspended, ..
+            } => Cow::Owned(format!(
+                "{} {}",
+                match suspended {
+                    true => "SUSPENDED",
+                    false => "RESUMED",
+                },
+                source
+            )),
+            EventKind::Recording {
+                application,
+                recording,
+                ..
+            } => Cow::Owned(format!(
+                "{} {}",
+                match recording {
+                    true => "RECORDING_STARTED",
+                    false => "RECORDING_STOPPED",
+                },
+                application
+            )),
+            EventKind::ProfileChanged { card, profile, .. } => Cow::Owned(format!(
+                "PROFILE_CHANGED {} {}",
+                card,
+                profile.as_deref().unwrap_or("none")
+            )),
+            EventKind::Client {
+                client, connected, ..
+            } => Cow::Owned(format!(
+                "{} {}",
+                match connected {
+                    true => "CLIENT_CONNECTED",
+                    false => "CLIENT_DISCONNECTED",
+                },
+                client
+            )),
+            EventKind::Module { module, loaded, .. } => Cow::Owned(format!(
+                "{} {}",
+                match loaded {
+                    true => "MODULE_LOADED",
+                    false => "MODULE_UNLOADED",
+                },
+                module
+            )),
+            EventKind::SourceReplaced {
+                source, previous, ..
+            } => Cow::Owned(format!("SOURCE_REPLACED {} {}", previous, source)),
+            EventKind::SourceRenamed {
+                source, previous, ..
+            } => Cow::Owned(format!("SOURCE_RENAMED {} {}", previous, source)),
+            EventKind::SourceAdded { source, .. } => Cow::Owned(format!("SOURCE_ADDED {}", source)),
+            EventKind::SourceRemoved { source, .. } => {
+                Cow::Owned(format!("SOURCE_REMOVED {}", source))
+            }
+            EventKind::HookFailed { hook, .. } => Cow::Owned(format!("HOOK_FAILED {}", hook)),
+            EventKind::Reconnected => Cow::Borrowed("RECONNECTED"),
+            EventKind::Shutdown => Cow::Borrowed("SHUTDOWN"),
+            // One line per source
+            EventKind::SourceList { sources } => Cow::Owned(
+                sources
+                    .iter()
+                    .map(|src| {
+                        format!(
+                            "SOURCE {} {} {}{}",
+                            src.index,
+                            src.source,
+                            match src.muted {
+                                true => "MUTED",
+                                false => "UNMUTED",
+                            },
+                            match src.default {
+                                true => " DEFAULT",
+                                false => "",
+                            }
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            _ => Cow::Borrowed(self.for_event(kind)),
+        }
+    }
+}
+
+pub struct PlainOutput<W: Write> {
+    writer: W,
+    texts: StateTexts,
+    timestamps: bool,
+    seq: bool,
+    theme: Option<Theme>,
+}
+
+impl<W: Write> PlainOutput<W> {
+    pub fn new(writer: W, texts: StateTexts) -> Self {
+        PlainOutput {
+            writer,
+            texts,
+            timestamps: false,
+            seq: false,
+            theme: None,
+        }
+    }
+
+    /// Color the state text
+    pub fn with_theme(mut self, theme: Option<Theme>) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Prefix each line with the event's ISO8601 timestamp
+    pub fn with_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Prefix each line with the event's sequence number
+    pub fn with_seq(mut self, seq: bool) -> Self {
+        self.seq = seq;
+        self
+    }
+}
+
+impl<W: Write> Output for PlainOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if self.timestamps {
+            write!(self.writer, "{} ", event.timestamp.to_rfc3339())?;
+        }
+        if self.seq {
+            write!(self.writer, "{} ", event.seq)?;
+        }
+        if let Some(server) = &event.server {
+            write!(self.writer, "{}: ", server)?;
+        }
+        let text = self.texts.plain_line(&event.kind);
+        match &self.theme {
+            Some(theme) => writeln!(self.writer, "{}", theme.paint(&event.kind, &text))?,
+            None => writeln!(self.writer, "{}", text)?,
+        }
+        self.writer.flush()
+    }
+}
+
+pub struct JsonOutput<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonOutput<W> {
+    pub fn new(writer: W) -> Self {
+        JsonOutput { writer }
+    }
+}
+
+impl<W: Write> Output for JsonOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+}
+
+/// The shape Waybar's custom module expects when `return-type` is `json`
+#[derive(Serialize)]
+struct WaybarLine<'a> {
+    text: &'a str,
+    tooltip: &'a str,
+    class: &'a str,
+    percentage: u32,
+}
+
+pub struct WaybarOutput<W: Write> {
+    writer: W,
+    texts: StateTexts,
+    /// Kept from one event to the next
+    tooltip: String,
+}
+
+impl<W: Write> WaybarOutput<W> {
+    pub fn new(writer: W, texts: StateTexts) -> Self {
+        WaybarOutput {
+            writer,
+            texts,
+            tooltip: String::new(),
+        }
+    }
+}
+
+impl<W: Write> Output for WaybarOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if !event.kind.is_device_state() {
+            return Ok(());
+        }
+        let text = self.texts.for_event(&event.kind);
+        let kind = &event.kind;
+        let tooltip = &mut self.tooltip;
+        tooltip.clear();
+        let class = match (kind.is_sink(), kind.device(), kind.muted()) {
+            (false, Some(source), Some(muted)) => {
+                let _ = write!(tooltip, "Default source: {}", source);
+                match muted {
+                    true => "muted",
+                    false => "unmuted",
+                }
+            }
+            (true, Some(sink), Some(muted)) => {
+                let _ = write!(tooltip, "Default sink: {}", sink);
+                match muted {
+                    true => "sink-muted",
+                    false => "sink-unmuted",
+                }
+            }
+            // Aggregated state isn't about any one source
+            (false, None, Some(muted)) => {
+                tooltip.push_str("All sources");
+                match muted {
+                    true => "muted",
+                    false => "unmuted",
+                }
+            }
+            (false, _, _) => {
+                tooltip.push_str("No default source");
+                "no-source"
+            }
+            (true, _, _) => {
+                tooltip.push_str("No default sink");
+                "no-sink"
+            }
+        };
+        let line = WaybarLine {
+            text,
+            tooltip,
+            class,
+            percentage: event.kind.volume().unwrap_or(0),
+        };
+        serde_json::to_writer(&mut self.writer, &line)?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+}
+
+/// Decorations for polybar output, in polybar's own format-tag syntax
+#[derive(Debug, Clone, Default)]
+pub struct PolybarStyle {
+    pub prefix: String,
+    pub suffix: String,
+    pub mute_color: Option<String>,
+    pub unmute_color: Option<String>,
+    pub nosource_color: Option<String>,
+}
+
+/// Single-line output for a polybar `custom/script` module with `tail = true`
+pub struct PolybarOutput<W: Write> {
+    writer: W,
+    texts: StateTexts,
+    style: PolybarStyle,
+}
+
+impl<W: Write> PolybarOutput<W> {
+    pub fn new(writer: W, texts: StateTexts, style: PolybarStyle) -> Self {
+        PolybarOutput {
+            writer,
+            texts,
+            style,
+        }
+    }
+}
+
+/// Keep user text on one line, and stop polybar interpreting it as format tags
+fn polybar_escape(text: &str) -> String {
+    text.replace(['\n', '\r'], " ").replace("%{", "%%{")
+}
+
+impl<W: Write> Output for PolybarOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if !event.kind.is_device_state() {
+            return Ok(());
+        }
+        let text = polybar_escape(self.texts.for_event(&event.kind));
+        let color = match event.kind.muted() {
+            Some(true) => &self.style.mute_color,
+            Some(false) => &self.style.unmute_color,
+            None => &self.style.nosource_color,
+        };
+        let text = match color {
+            Some(color) => format!("%{{F{}}}{}%{{F-}}", color, text),
+            None => text,
+        };
+        writeln!(
+            self.writer,
+            "{}{}{}",
+            self.style.prefix, text, self.style.suffix
+        )?;
+        self.writer.flush()
+    }
+}
+
+/// Quote a CSV field if it needs it (RFC 4180)
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// One row per event, with a header row written before the first event
+pub struct CsvOutput<W: Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvOutput<W> {
+    pub fn new(writer: W) -> Self {
+        CsvOutput {
+            writer,
+            header_written: false,
+        }
+    }
+}
+
+impl<W: Write> Output for CsvOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(
+                self.writer,
+                "timestamp,seq,event,facility,source,index,mute"
+            )?;
+            self.header_written = true;
+        }
+
+        let kind = &event.kind;
+        write!(
+            self.writer,
+            "{},{},{},{},{},",
+            event.timestamp.to_rfc3339(),
+            event.seq,
+            kind.name(),
+            kind.facility(),
+            csv_field(kind.device().unwrap_or_default()),
+        )?;
+        if let Some(index) = kind.index() {
+            write!(self.writer, "{}", index)?;
+        }
+        match kind.muted() {
+            Some(muted) => writeln!(self.writer, ",{}", muted)?,
+            None => writeln!(self.writer, ",")?,
+        }
+        self.writer.flush()
+    }
+}
+
+/// MessagePack-encoded events, each framed with a 4 byte big-endian length prefix so consumers
+/// can stream-decode them.
+pub struct MsgpackOutput<W: Write> {
+    writer: W,
+    /// The frame being put together, kept from one event to the next
+    buf: Vec<u8>,
+}
+
+impl<W: Write> MsgpackOutput<W> {
+    pub fn new(writer: W) -> Self {
+        MsgpackOutput {
+            writer,
+            buf: vec![],
+        }
+    }
+}
+
+impl<W: Write> Output for MsgpackOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        // Room for the length, filled in once it's known
+        self.buf.clear();
+        self.buf.extend_from_slice(&[0; 4]);
+        rmp_serde::encode::write_named(&mut self.buf, event)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let len = u32::try_from(self.buf.len() - 4)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.buf[..4].copy_from_slice(&len.to_be_bytes());
+        self.writer.write_all(&self.buf)?;
+        self.writer.flush()
+    }
+}
+
+/// Durable NDJSON journal of events.
+///
+/// Each event is written with a single `write` on an `O_APPEND` file, so concurrent writers
+/// can't interleave partial lines, and is synced to disk before `emit` returns.
+pub struct EventLogOutput {
+    file: File,
+    buf: Vec<u8>,
+}
+
+impl EventLogOutput {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLogOutput { file, buf: vec![] })
+    }
+}
+
+impl Output for EventLogOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if event.snapshot {
+            return Ok(());
+        }
+        self.buf.clear();
+        serde_json::to_writer(&mut self.buf, event)?;
+        self.buf.push(b'\n');
+        self.file.write_all(&self.buf)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_state_stays_flat_on_the_wire() {
+        let kind = EventKind::Mute {
+            source: "mic".into(),
+            device: DeviceState {
+                index: 3,
+                muted: true,
+                volume: 40,
+            },
+            default: true,
+        };
+        let json = serde_json::to_value(&kind).unwrap();
+        assert_eq!(json["event"], "mute");
+        assert_eq!(json["index"], 3);
+        assert_eq!(json["muted"], true);
+        assert_eq!(json["volume"], 40);
+
+        let parsed: EventKind = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.device_state(), kind.device_state());
+    }
+
+    #[test]
+    fn only_snapshots_say_so() {
+        let mut event = Event::new(EventKind::NoSource);
+        assert!(serde_json::to_value(&event)
+            .unwrap()
+            .get("snapshot")
+            .is_none());
+        event.snapshot = true;
+        assert_eq!(serde_json::to_value(&event).unwrap()["snapshot"], true);
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use log::{debug, error};
+use serde::Deserialize;
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, Trap, TypedFunc};
+
+use crate::output::{Event, EventKind, Output};
+use crate::Errors;
+
+/// Version of the guest ABI this host speaks, which plugins report from `psl_abi_version`.
+///
+/// A plugin is a WASM module with no imports, exporting:
+///
+/// - `memory`
+/// - `psl_abi_version() -> i32`
+/// - `psl_alloc(len: i32) -> i32`, returning where the host may write `len` bytes
+/// - `psl_on_event(ptr: i32, len: i32) -> i64`, handed an event as JSON (shaped like the JSON
+///   output), returning where its response is as `ptr << 32 | len`, or 0 for no response. The
+///   response stays valid until the next call.
+///
+/// A response is a JSON object with optional `lines`, printed to stdout, and optional `events`,
+/// replacing the event for later plugins and outputs. An empty `events` drops the event, and no
+/// `events` passes it on unchanged.
+///
+/// Each call gets [`FUEL_PER_CALL`] to run on. A plugin that runs out, likely stuck in a loop,
+/// is unloaded rather than holding up the listener.
+const ABI_VERSION: i32 = 1;
+
+/// Fuel a plugin gets to load, and then for each event, roughly one per WASM instruction
+const FUEL_PER_CALL: u64 = 10_000_000;
+
+/// Why a plugin couldn't handle an event
+enum Failure {
+    OutOfFuel,
+    Other(String),
+}
+
+impl From<wasmtime::Error> for Failure {
+    fn from(err: wasmtime::Error) -> Self {
+        match err.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => Failure::OutOfFuel,
+            _ => Failure::Other(format!("{:#}", err)),
+        }
+    }
+}
+
+impl From<String> for Failure {
+    fn from(err: String) -> Self {
+        Failure::Other(err)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Response {
+    #[serde(default)]
+    lines: Vec<String>,
+    events: Option<Vec<EventKind>>,
+}
+
+/// A loaded WASM plugin
+pub struct Plugin {
+    name: String,
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_event: TypedFunc<(i32, i32), i64>,
+}
+
+impl Plugin {
+    pub fn load(engine: &Engine, path: &Path) -> Result<Self, Errors> {
+        let name = path.display().to_string();
+        let plugin_error = |err: wasmtime::Error| {
+            Errors::ConfigError(format!("failed to load plugin {}: {:#}", name, err))
+        };
+
+        let module = Module::from_file(engine, path).map_err(plugin_error)?;
+        let mut store = Store::new(engine, ());
+        store.set_fuel(FUEL_PER_CALL).map_err(plugin_error)?;
+        // No imports, so plugins can't touch anything but their own memory
+        let instance = Instance::new(&mut store, &module, &[]).map_err(plugin_error)?;
+
+        let version: TypedFunc<(), i32> = instance
+            .get_typed_func(&mut store, "psl_abi_version")
+            .map_err(plugin_error)?;
+        let version = version.call(&mut store, ()).map_err(plugin_error)?;
+        if version != ABI_VERSION {
+            return Err(Errors::ConfigError(format!(
+                "plugin {} speaks ABI version {}, expected {}",
+                name, version, ABI_VERSION
+            )));
+        }
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| Errors::ConfigError(format!("plugin {} exports no memory", name)))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "psl_alloc")
+            .map_err(plugin_error)?;
+        let on_event = instance
+            .get_typed_func(&mut store, "psl_on_event")
+            .map_err(plugin_error)?;
+
+        debug!("Loaded plugin {}", name);
+        Ok(Plugin {
+            name,
+            store,
+            memory,
+            alloc,
+            on_event,
+        })
+    }
+
+    fn process(&mut self, event: &Event) -> Result<Response, Failure> {
+        let input = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+        let len = i32::try_from(input.len()).map_err(|err| err.to_string())?;
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, &input)
+            .map_err(|err| err.to_string())?;
+
+        let packed = self.on_event.call(&mut self.store, (ptr, len))? as u64;
+        if packed == 0 {
+            return Ok(Response::default());
+        }
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut output = vec![0; out_len];
+        self.memory
+            .read(&self.store, out_ptr, &mut output)
+            .map_err(|err| err.to_string())?;
+        Ok(serde_json::from_slice(&output).map_err(|err| format!("bad response: {}", err))?)
+    }
+}
+
+/// Load every plugin, in order
+pub fn load_plugins(paths: &[impl AsRef<Path>]) -> Result<Vec<Plugin>, Errors> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)
+        .map_err(|err| Errors::ConfigError(format!("failed to set up plugins: {:#}", err)))?;
+    paths
+        .iter()
+        .map(|path| Plugin::load(&engine, path.as_ref()))
+        .collect()
+}
+
+/// Passes events through each plugin in turn, then on to `next`
+pub struct PluginOutput<W: Write> {
+    plugins: Vec<Plugin>,
+    next: Box<dyn Output>,
+    writer: W,
+}
+
+impl<W: Write> PluginOutput<W> {
+    pub fn new(plugins: Vec<Plugin>, next: Box<dyn Output>, writer: W) -> Self {
+        PluginOutput {
+            plugins,
+            next,
+            writer,
+        }
+    }
+}
+
+impl<W: Write> Output for PluginOutput<W> {
+    fn finish(&mut self, deadline: Instant) -> io::Result<()> {
+        self.next.finish(deadline)
+    }
+
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let mut events = vec![event.clone()];
+        let mut unloaded = vec![];
+        for (index, plugin) in self.plugins.iter_mut().enumerate() {
+            let mut transformed = vec![];
+            for event in events {
+                if unloaded.contains(&index) {
+                    transformed.push(event);
+                    continue;
+                }
+                let response = match plugin.process(&event) {
+                    Ok(response) => response,
+                    Err(Failure::OutOfFuel) => {
+                        error!("plugin {} ran out of fuel, unloading it", plugin.name);
+                        unloaded.push(index);
+                        Response::default()
+                    }
+                    // A broken plugin shouldn't take the listener down with it
+                    Err(Failure::Other(err)) => {
+                        error!("plugin {} failed: {}", plugin.name, err);
+                        Response::default()
+                    }
+                };
+                for line in &response.lines {
+                    writeln!(self.writer, "{}", line)?;
+                }
+                match response.events {
+                    // Transformed events keep the original's place in the sequence
+                    Some(kinds) => transformed.extend(kinds.into_iter().map(|kind| Event {
+                        kind,
+                        timestamp: event.timestamp,
+                        seq: event.seq,
+                        server: event.server.clone(),
+                        received: event.received.clone(),
+                        snapshot: event.snapshot,
+                    })),
+                    None => transformed.push(event),
+                }
+            }
+            events = transformed;
+        }
+        for index in unloaded.into_iter().rev() {
+            self.plugins.remove(index);
+        }
+        self.writer.flush()?;
+
+        for event in &events {
+            self.next.emit(event)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::mem;
+
+/// Where finding the watched source has got to. It's followed by name, as indices get reused
+/// when devices come and go, with the index only remembering where the name was last found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Resolution {
+    /// There's nothing to look for, e.g. the server has no default source
+    #[default]
+    Unknown,
+    /// Known by name, but no source by that name has shown up (yet)
+    PendingByName(String),
+    Resolved {
+        name: String,
+        index: u32,
+    },
+}
+
+impl Resolution {
+    /// Look for the source called `name`, with `index_of` giving the index of a source by name
+    pub fn by_name(name: Option<String>, index_of: impl Fn(&str) -> Option<u32>) -> Self {
+        let mut resolution = match name {
+            Some(name) => Resolution::PendingByName(name),
+            None => Resolution::Unknown,
+        };
+        resolution.retry(index_of);
+        resolution
+    }
+
+    /// Look again, after sources came or went. A source that's gone is pending again, to be
+    /// found wherever it turns up next.
+    pub fn retry(&mut self, index_of: impl Fn(&str) -> Option<u32>) {
+        let name = match mem::take(self) {
+            Resolution::Unknown => return,
+            Resolution::PendingByName(name) | Resolution::Resolved { name, .. } => name,
+        };
+        *self = match index_of(&name) {
+            Some(index) => Resolution::Resolved { name, index },
+            None => Resolution::PendingByName(name),
+        };
+    }
+
+    /// Keep following the source at `index` under the name it's taken on
+    pub fn rename(&mut self, index: u32, renamed: &str) {
+        if let Resolution::Resolved { name, index: known } = self {
+            if *known == index {
+                *name = renamed.to_string();
+            }
+        }
+    }
+
+    pub fn index(&self) -> Option<u32> {
+        match self {
+            Resolution::Resolved { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Look a name up among sources given as index and name
+    fn listed<'a>(sources: &'a [(u32, &str)]) -> impl Fn(&str) -> Option<u32> + 'a {
+        move |name| {
+            sources
+                .iter()
+                .filter(|(_, source)| *source == name)
+                .map(|(index, _)| *index)
+                .min()
+        }
+    }
+
+    #[test]
+    fn nothing_to_look_for_stays_unknown() {
+        let mut resolution = Resolution::by_name(None, listed(&[(1, "mic")]));
+        assert_eq!(resolution, Resolution::Unknown);
+        resolution.retry(listed(&[(1, "mic"), (2, "headset")]));
+        assert_eq!(resolution, Resolution::Unknown);
+    }
+
+    #[test]
+    fn resolves_a_listed_source() {
+        let resolution =
+            Resolution::by_name(Some("mic".into()), listed(&[(1, "speaker"), (4, "mic")]));
+        assert_eq!(resolution.index(), Some(4));
+    }
+
+    #[test]
+    fn default_named_before_its_source_appears() {
+        let mut resolution = Resolution::by_name(Some("mic".into()), listed(&[(1, "speaker")]));
+        assert_eq!(resolution, Resolution::PendingByName("mic".into()));
+        assert_eq!(resolution.index(), None);
+
+        resolution.retry(listed(&[(1, "speaker"), (7, "mic")]));
+        assert_eq!(
+            resolution,
+            Resolution::Resolved {
+                name: "mic".into(),
+                index: 7
+            }
+        );
+    }
+
+    #[test]
+    fn replugged_source_is_found_at_its_new_index() {
+        let mut resolution = Resolution::by_name(Some("mic".into()), listed(&[(3, "mic")]));
+        assert_eq!(resolution.index(), Some(3));
+
+        // Unplugged
+        resolution.retry(listed(&[(1, "speaker")]));
+        assert_eq!(resolution, Resolution::PendingByName("mic".into()));
+
+        // Back, somewhere else
+        resolution.retry(listed(&[(1, "speaker"), (9, "mic")]));
+        assert_eq!(resolution.index(), Some(9));
+    }
+
+    #[test]
+    fn reused_index_is_not_mistaken_for_the_source() {
+        let mut resolution = Resolution::by_name(Some("mic".into()), listed(&[(3, "mic")]));
+        resolution.retry(listed(&[(3, "webcam")]));
+        assert_eq!(resolution.index(), None);
+    }
+
+    #[test]
+    fn renamed_source_is_still_followed() {
+        let mut resolution = Resolution::by_name(Some("mic".into()), listed(&[(3, "mic")]));
+        resolution.rename(4, "other");
+        resolution.rename(3, "mic.pro");
+        resolution.retry(listed(&[(3, "mic.pro")]));
+        assert_eq!(
+            resolution,
+            Resolution::Resolved {
+                name: "mic.pro".into(),
+                index: 3
+            }
+        );
+    }
+
+    #[test]
+    fn renaming_a_pending_source_does_nothing() {
+        let mut resolution = Resolution::by_name(Some("mic".into()), listed(&[]));
+        resolution.rename(3, "mic.pro");
+        assert_eq!(resolution, Resolution::PendingByName("mic".into()));
+    }
+}
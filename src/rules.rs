@@ -0,0 +1,228 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+use rhai::{Array, Dynamic, Engine, FnPtr, Map, AST, INT};
+
+use crate::hooks;
+use crate::output::{Event, EventKind, Output};
+use crate::Errors;
+
+/// The default source, as far as rules can tell from the events so far
+#[derive(Debug, Clone, Default)]
+struct RuleState {
+    source: Option<String>,
+    index: Option<u32>,
+    muted: Option<bool>,
+    volume: Option<u32>,
+    running: Option<bool>,
+}
+
+impl RuleState {
+    fn update(&mut self, kind: &EventKind) {
+        match kind {
+            EventKind::NoSource => *self = RuleState::default(),
+            kind if kind.is_default() && !kind.is_sink() => {
+                if let Some(source) = kind.device() {
+                    self.source = Some(source.to_string());
+                }
+                self.index = kind.index().or(self.index);
+                self.muted = kind.muted().or(self.muted);
+                self.volume = kind.volume().or(self.volume);
+                if let EventKind::State { running, .. } = kind {
+                    self.running = Some(*running);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// What rule conditions are handed, with unknowns as `()`
+    fn to_map(&self) -> Map {
+        let or_unit = |value: Option<Dynamic>| value.unwrap_or(Dynamic::UNIT);
+        let mut map = Map::new();
+        map.insert(
+            "source".into(),
+            or_unit(self.source.clone().map(Dynamic::from)),
+        );
+        map.insert(
+            "index".into(),
+            or_unit(self.index.map(|idx| Dynamic::from(idx as INT))),
+        );
+        map.insert("muted".into(), or_unit(self.muted.map(Dynamic::from)));
+        map.insert(
+            "volume".into(),
+            or_unit(self.volume.map(|vol| Dynamic::from(vol as INT))),
+        );
+        map.insert("running".into(), or_unit(self.running.map(Dynamic::from)));
+        map
+    }
+}
+
+/// Run a command once a condition has held for a while
+struct Rule {
+    name: String,
+    when: FnPtr,
+    hold: Duration,
+    run: String,
+    /// When the condition last became true, if it still is
+    since: Option<Instant>,
+    /// Whether the command already ran since the condition became true
+    fired: bool,
+}
+
+impl Rule {
+    fn from_map(map: Map) -> Result<Self, String> {
+        let field = |name: &str| map.get(name).cloned();
+        let name = field("name")
+            .and_then(|name| name.into_string().ok())
+            .ok_or("rule without a name")?;
+        let when = field("when")
+            .and_then(|when| when.try_cast::<FnPtr>())
+            .ok_or_else(|| format!("rule '{}' has no `when` function", name))?;
+        let run = field("run")
+            .and_then(|run| run.into_string().ok())
+            .ok_or_else(|| format!("rule '{}' has no `run` command", name))?;
+        let hold = match field("hold") {
+            None => Duration::ZERO,
+            Some(secs) => {
+                let secs = secs
+                    .as_float()
+                    .or_else(|_| secs.as_int().map(|secs| secs as f64))
+                    .map_err(|_| format!("rule '{}' has a non-numeric `hold`", name))?;
+                Duration::try_from_secs_f64(secs).map_err(|_| {
+                    format!("rule '{}' has an out of range `hold` of {}", name, secs)
+                })?
+            }
+        };
+        Ok(Rule {
+            name,
+            when,
+            hold,
+            run,
+            since: None,
+            fired: false,
+        })
+    }
+
+    /// When the rule fires, if its condition keeps holding
+    fn deadline(&self) -> Option<Instant> {
+        match (self.since, self.fired) {
+            (Some(since), false) => Some(since + self.hold),
+            _ => None,
+        }
+    }
+
+    fn evaluate(&mut self, engine: &Engine, ast: &AST, state: &Map, now: Instant) {
+        let holds = match self.when.call::<bool>(engine, ast, (state.clone(),)) {
+            Ok(holds) => holds,
+            Err(err) => {
+                // Usually a condition looking at something unknown yet, e.g. no source
+                debug!("rule '{}' condition failed: {}", self.name, err);
+                false
+            }
+        };
+        if !holds {
+            self.since = None;
+            self.fired = false;
+            return;
+        }
+
+        let since = *self.since.get_or_insert(now);
+        if !self.fired && now.duration_since(since) >= self.hold {
+            self.fired = true;
+            info!("rule '{}' matched, running '{}'", self.name, self.run);
+            let env = [("PSL_RULE", self.name.clone())];
+            match hooks::spawn_shell(&self.run, &env, Stdio::null()) {
+                Ok(child) => hooks::reap(child),
+                Err(err) => error!("failed to run rule '{}': {}", self.name, err),
+            }
+        }
+    }
+}
+
+/// The rules file evaluates to an array of rules, each a map like
+/// `#{ name: "webcam live", when: |s| s.source.contains("webcam") && !s.muted, hold: 10,
+/// run: "notify-send 'webcam mic is live'" }`, where `hold` is how many seconds the condition
+/// has to hold for (0 if left out)
+fn load_rules(path: &Path) -> Result<(Engine, AST, Vec<Rule>), String> {
+    let engine = Engine::new();
+    let ast = engine
+        .compile_file(path.to_path_buf())
+        .map_err(|err| err.to_string())?;
+    let rules: Array = engine.eval_ast(&ast).map_err(|err| err.to_string())?;
+    let rules = rules
+        .into_iter()
+        .map(|rule| {
+            rule.try_cast::<Map>()
+                .ok_or_else(|| "rules have to be maps".to_string())
+                .and_then(Rule::from_map)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((engine, ast, rules))
+}
+
+/// Keep rules up to date with events, and fire them as their conditions hold long enough
+fn run_rules(engine: Engine, ast: AST, mut rules: Vec<Rule>, events: Receiver<Event>) {
+    let mut state = RuleState::default();
+    loop {
+        let deadline = rules.iter().filter_map(Rule::deadline).min();
+        let received = match deadline {
+            Some(deadline) => {
+                events.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(event) => state.update(&event.kind),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let map = state.to_map();
+        let now = Instant::now();
+        for rule in &mut rules {
+            rule.evaluate(&engine, &ast, &map, now);
+        }
+    }
+}
+
+/// Evaluates a Rhai rules file against events. Rhai values can't cross threads, so the rules are
+/// loaded and run entirely on their own thread, with only events sent over.
+pub struct RulesOutput {
+    events: Sender<Event>,
+}
+
+impl RulesOutput {
+    pub fn load(path: PathBuf) -> Result<Self, Errors> {
+        let (events, rx) = mpsc::channel();
+        let (loaded_tx, loaded) = crossbeam_channel::bounded(1);
+        thread::spawn(move || match load_rules(&path) {
+            Ok((engine, ast, rules)) => {
+                debug!("Loaded {} rules from {}", rules.len(), path.display());
+                loaded_tx.send(Ok(())).unwrap();
+                run_rules(engine, ast, rules, rx);
+            }
+            Err(err) => loaded_tx
+                .send(Err(format!("invalid rules in {}: {}", path.display(), err)))
+                .unwrap(),
+        });
+        loaded.recv()?.map_err(Errors::ConfigError)?;
+        Ok(RulesOutput { events })
+    }
+}
+
+impl Output for RulesOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if event.snapshot {
+            return Ok(());
+        }
+        self.events
+            .send(event.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "rules have stopped"))
+    }
+}
@@ -0,0 +1,119 @@
+//! The tokio runtime the network outputs share. HTTP, metrics, websocket and webhook clients
+//! are each served by a task on it, rather than by a thread of their own.
+//!
+//! libpulse calls back on its mainloop's thread, which mustn't ever wait on the network, so
+//! events cross over to the tasks through each client's bounded [`consumer::queue`]: the
+//! output queues events without waiting, applying the drop policy to a client that falls
+//! behind, and the queue's `Notify` wakes the client's task to take them with
+//! [`Subscription::recv_async`]. Whatever a task asks of the listener goes the other way over
+//! the listener loop's channel, with [`answer`] waiting on the reply on the runtime's blocking
+//! threads rather than its workers. Each output keeps the [`AcceptTask`] serving it, so its
+//! port is freed along with it.
+//!
+//! [`consumer::queue`]: crate::consumer::queue
+//! [`Subscription::recv_async`]: crate::consumer::Subscription::recv_async
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+use log::{debug, info};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time;
+
+use crate::health::Health;
+use crate::socket::{self, Reply, Request, ACCEPT_BACKOFF, MAX_CLIENTS};
+use crate::RequestTx;
+
+/// Threads running the tasks, which spend nearly all their time waiting on clients
+const WORKERS: usize = 2;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The runtime, started by the first output needing it
+pub fn handle() -> io::Result<Handle> {
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime.handle().clone());
+    }
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(WORKERS)
+        .thread_name("network")
+        .enable_all()
+        .build()?;
+    Ok(RUNTIME.get_or_init(|| runtime).handle().clone())
+}
+
+/// Listen on `addr` right away, so failing to is reported at startup rather than by a task
+pub fn listen(addr: SocketAddr) -> io::Result<(Handle, TcpListener)> {
+    let handle = handle()?;
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let _entered = handle.enter();
+    let listener = TcpListener::from_std(listener)?;
+    Ok((handle, listener))
+}
+
+/// The task accepting an output's clients. Dropping it stops accepting, closing the listener,
+/// and hangs up on every client.
+pub struct AcceptTask(JoinHandle<()>);
+
+impl Drop for AcceptTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Accept clients on `listener` until the returned task is dropped, serving each with a task of
+/// its own. `what` names the clients in logs.
+pub fn serve<F, Fut>(
+    handle: &Handle,
+    listener: TcpListener,
+    what: &'static str,
+    serve: F,
+) -> AcceptTask
+where
+    F: Fn(TcpStream, SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    AcceptTask(handle.spawn(async move {
+        // Aborted along with the accepting task, as the set is dropped
+        let mut clients = JoinSet::new();
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        info!("Failed to accept {} client: {}", what, err);
+                        time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                },
+                // Reap clients that are done with, which is all `None` would say
+                Some(_) = clients.join_next() => continue,
+            };
+            if clients.len() >= MAX_CLIENTS {
+                info!(
+                    "Hanging up on {} client {}, {} are already served",
+                    what, peer, MAX_CLIENTS
+                );
+                continue;
+            }
+            let client = serve(stream, peer);
+            clients.spawn(async move {
+                if let Err(err) = client.await {
+                    debug!("{} client {} went away: {}", what, peer, err);
+                }
+            });
+        }
+    }))
+}
+
+/// Answer a request like [`socket::answer`], from a task
+pub async fn answer(request: Request, tx: RequestTx, health: Health) -> io::Result<Reply> {
+    task::spawn_blocking(move || socket::answer(request, &tx, &health))
+        .await
+        .map_err(io::Error::other)?
+}
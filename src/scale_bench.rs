@@ -0,0 +1,73 @@
+//! Times following a source among hundreds of others, with
+//! `cargo test --release scale_bench -- --ignored --nocapture` (or `just bench`). Each change
+//! to a source stores it again and looks the watched source up again by name, once through
+//! the index of names and once going through every source, as it was done before.
+
+use std::time::{Duration, Instant};
+
+use crate::resolve::Resolution;
+use crate::sources::Sources;
+use crate::SourceDatum;
+
+const CHANGES: u32 = 10_000;
+
+/// Virtual devices as a busy system has them, with the watched microphone among them
+fn sources(count: u32) -> Sources {
+    (0..count)
+        .map(|idx| {
+            let name = match idx % 4 {
+                0 => format!("jack_in.bridge-{}", idx),
+                1 => format!("loopback-{}.monitor", idx),
+                2 => format!("container-{}.remap", idx),
+                _ => format!("alsa_input.virtual-{}", idx),
+            };
+            (idx, SourceDatum::named(&name))
+        })
+        .chain([(count, SourceDatum::named("alsa_input.usb-mic"))])
+        .collect()
+}
+
+/// The lowest index going by `name`, looking at every source
+fn scan(sources: &Sources, name: &str) -> Option<u32> {
+    sources
+        .iter()
+        .filter(|(_, src)| &*src.name == name)
+        .map(|(idx, _)| *idx)
+        .min()
+}
+
+/// How long handling the changes takes, finding the watched source again with `index_of`
+fn follow(count: u32, index_of: fn(&Sources, &str) -> Option<u32>) -> Duration {
+    let mut sources = sources(count);
+    let mut watched = Resolution::by_name(Some("alsa_input.usb-mic".into()), |name| {
+        index_of(&sources, name)
+    });
+    let started = Instant::now();
+    for change in 0..CHANGES {
+        let idx = change % (count + 1);
+        let src = sources.remove(&idx).unwrap();
+        sources.insert(idx, src);
+        watched.retry(|name| index_of(&sources, name));
+    }
+    let taken = started.elapsed();
+    assert_eq!(watched.index(), Some(count));
+    taken
+}
+
+#[test]
+#[ignore]
+fn scale_bench() {
+    for count in [100, 500, 2000] {
+        let started = Instant::now();
+        let built = sources(count);
+        let building = started.elapsed();
+        assert_eq!(built.len() as u32, count + 1);
+
+        let indexed = follow(count, Sources::index_of);
+        let scanned = follow(count, scan);
+        println!(
+            "{:>5} sources: listed in {:>8.2?}, {} changes in {:>8.2?} indexed, {:>8.2?} scanning",
+            count, building, CHANGES, indexed, scanned
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use log::{error, info};
+use mlua::{Function, Lua, LuaSerdeExt};
+use pulse::context::Context;
+
+use crate::mainloop::Mainloop;
+use crate::output::{Event, Output};
+use crate::{
+    set_default_source, set_source_mute, Errors, ListenerRequest, ListenerState, RequestTx,
+};
+
+/// Something a script asked to be done to the server. The listener loop does it, as it owns the
+/// connection.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Mute or unmute a source by name, or the watched source if no name is given
+    SetMute {
+        source: Option<String>,
+        mute: bool,
+    },
+    SetDefault(String),
+}
+
+fn script_error(err: mlua::Error) -> Errors {
+    Errors::ConfigError(format!("lua script: {}", err))
+}
+
+/// Hands every event to a Lua script's `on_event` function, as a table shaped like the JSON
+/// output. The script owns stdout, printing whatever it passes to `psl.emit`, and can act on the
+/// server with `psl.mute`, `psl.unmute` and `psl.set_default`. Globals persist between events,
+/// so the script can keep its own state.
+pub struct ScriptOutput<W: Write> {
+    lua: Lua,
+    writer: W,
+    /// Lines emitted by the script, waiting to be written
+    lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl<W: Write> ScriptOutput<W> {
+    /// Run the script, which has to define `on_event`. Actions are sent to `actions`, if given,
+    /// otherwise they're ignored.
+    pub fn load(path: &Path, writer: W, actions: Option<RequestTx>) -> Result<Self, Errors> {
+        let source = fs::read_to_string(path)?;
+        let lua = Lua::new();
+        let lines = Rc::new(RefCell::new(vec![]));
+        register_api(&lua, lines.clone(), actions).map_err(script_error)?;
+        lua.load(&source)
+            .set_name(path.display().to_string())
+            .exec()
+            .map_err(script_error)?;
+
+        let on_event: Option<Function> = lua.globals().get("on_event").map_err(script_error)?;
+        if on_event.is_none() {
+            return Err(Errors::ConfigError(format!(
+                "lua script {} doesn't define on_event",
+                path.display()
+            )));
+        }
+
+        Ok(ScriptOutput { lua, writer, lines })
+    }
+
+    fn call(&self, event: &Event) -> mlua::Result<()> {
+        let on_event: Function = self.lua.globals().get("on_event")?;
+        on_event.call::<_, ()>(self.lua.to_value(event)?)
+    }
+}
+
+/// Expose the `psl` table to the script
+fn register_api(
+    lua: &Lua,
+    lines: Rc<RefCell<Vec<String>>>,
+    actions: Option<RequestTx>,
+) -> mlua::Result<()> {
+    let request = Rc::new(move |action: Action| match &actions {
+        Some(actions) => actions
+            .send(ListenerRequest::Action(action))
+            .map_err(|_| mlua::Error::RuntimeError("the listener has stopped".to_string())),
+        None => {
+            info!(
+                "Ignoring {:?} from script, there's no server to act on",
+                action
+            );
+            Ok(())
+        }
+    });
+
+    let psl = lua.create_table()?;
+    psl.set(
+        "emit",
+        lua.create_function(move |_, line: String| {
+            lines.borrow_mut().push(line);
+            Ok(())
+        })?,
+    )?;
+    for (name, mute) in [("mute", true), ("unmute", false)] {
+        let request = request.clone();
+        psl.set(
+            name,
+            lua.create_function(move |_, source: Option<String>| {
+                request(Action::SetMute { source, mute })
+            })?,
+        )?;
+    }
+    psl.set(
+        "set_default",
+        lua.create_function(move |_, name: String| request(Action::SetDefault(name)))?,
+    )?;
+    lua.globals().set("psl", psl)
+}
+
+impl<W: Write> Output for ScriptOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        // A broken handler shouldn't take the listener down with it
+        if let Err(err) = self.call(event) {
+            error!("lua on_event failed: {}", err);
+        }
+        for line in self.lines.borrow_mut().drain(..) {
+            writeln!(self.writer, "{}", line)?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Carry out an action a script asked for. Failures are the script's problem, so they're only
+/// logged.
+pub fn perform(
+    action: Action,
+    state: &ListenerState,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+) -> Result<(), Errors> {
+    let result = match &action {
+        Action::SetMute { source, mute } => {
+            let idx = match source {
+                None => state.default_source_id(),
+                Some(name) => state.sources.index_of(name),
+            };
+            match idx {
+                Some(idx) => set_source_mute(idx, *mute, &state.batch, context, mainloop),
+                None => {
+                    info!("Script action {:?} has no source to act on", action);
+                    Ok(())
+                }
+            }
+        }
+        Action::SetDefault(name) => set_default_source(name, &state.batch, context, mainloop),
+    };
+    match result {
+        Err(Errors::ContextError(err)) => {
+            info!("Script action {:?} failed: {}", action, err);
+            Ok(())
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,257 @@
+use std::io;
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::RecvTimeoutError;
+use log::{debug, info};
+
+use crate::mainloop::{self, LoopRx, LoopTx, Mainloop};
+use crate::output::{self, Event, Output};
+use crate::socket;
+use crate::{
+    connect_at_startup, hook_failed, listen, new_context, terminate, BusComms, CallbackComms,
+    ConnectOptions, Errors, ListenerConfig, ListenerRequest, CBRX, CBTX,
+};
+
+/// Passes a server's events on to the bus, tagged with the server's name
+struct ServerOutput {
+    server: String,
+    bus: LoopTx<BusComms>,
+}
+
+impl Output for ServerOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let mut event = event.clone();
+        event.server = Some(self.server.clone());
+        self.bus
+            .send(BusComms::ServerEvent(event))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event bus stopped"))
+    }
+}
+
+/// Listens to one server on a thread of its own, with its own mainloop and context
+struct ServerWorker {
+    name: String,
+    tx: CBTX,
+    thread: Option<JoinHandle<Result<(), Errors>>>,
+}
+
+impl ServerWorker {
+    fn spawn(
+        connect: ConnectOptions,
+        config: ListenerConfig,
+        reconnects: bool,
+        bus: LoopTx<BusComms>,
+    ) -> Result<Self, Errors> {
+        let name = connect.server.clone().unwrap_or_default();
+        let (tx, rx) = mainloop::channel();
+        let thread = thread::Builder::new()
+            .name(format!("server {}", name))
+            .spawn({
+                let name = name.clone();
+                let tx = tx.clone();
+                move || {
+                    let result = serve(&connect, &config, reconnects, &name, bus.clone(), tx, rx);
+                    let _ = bus.send(BusComms::ServerDone(name));
+                    result
+                }
+            })?;
+        Ok(ServerWorker {
+            name,
+            tx,
+            thread: Some(thread),
+        })
+    }
+
+    /// How the listener ended, once it has
+    fn join(&mut self) -> Result<(), Errors> {
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                Err(Errors::ContextError(format!(
+                    "listener for {} panicked",
+                    self.name
+                )))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn stop(&mut self) {
+        let _ = self
+            .tx
+            .send(CallbackComms::Request(ListenerRequest::Shutdown));
+        if let Err(err) = self.join() {
+            debug!("Listener for {} stopped: {}", self.name, err);
+        }
+    }
+}
+
+/// Connect to the server and listen to it, all on the worker's thread as neither the mainloop nor
+/// the context can move between threads
+fn serve(
+    connect: &ConnectOptions,
+    config: &ListenerConfig,
+    reconnects: bool,
+    name: &str,
+    bus: LoopTx<BusComms>,
+    tx: CBTX,
+    rx: CBRX,
+) -> Result<(), Errors> {
+    let mut mainloop = Mainloop::new(connect.mainloop)
+        .ok_or(Errors::ContextError("mainloop new failed".to_string()))?;
+    let mut context = new_context(&mainloop)?;
+    info!("Connecting to {}", name);
+    let mut output = ServerOutput {
+        server: name.to_string(),
+        bus,
+    };
+    let result = connect_at_startup(&mut context, &mut mainloop, connect, tx.clone(), &rx)
+        .and_then(|()| {
+            listen(
+                &mut mainloop,
+                &mut context,
+                connect,
+                config,
+                &mut output,
+                reconnects,
+                tx,
+                &rx,
+            )
+        });
+    terminate(mainloop, context, vec![]);
+    result
+}
+
+/// Listen to each server on a worker of its own, bringing their events together into the one
+/// output. Commands and questions for the listener go to every server.
+pub fn listen_to_servers(
+    servers: Vec<ConnectOptions>,
+    config: &ListenerConfig,
+    output: &mut dyn Output,
+    reconnects: bool,
+    mainloop: &mut Mainloop,
+    tx: LoopTx<BusComms>,
+    rx: &LoopRx<BusComms>,
+) -> Result<(), Errors> {
+    // Events the bus makes itself are numbered along with the servers'
+    output::number_events(&config.sequence);
+    let mut workers = vec![];
+    let mut result = Ok(());
+    for (n, connect) in servers.into_iter().enumerate() {
+        // Each server's connection makes for a health of its own, all reported together
+        let mut config = config.clone();
+        if n > 0 {
+            config.health = config.health.another_server();
+        }
+        match ServerWorker::spawn(connect, config, reconnects, tx.clone()) {
+            Ok(worker) => workers.push(worker),
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    if result.is_ok() {
+        result = run_bus(&mut workers, output, mainloop, rx);
+    }
+    for worker in &mut workers {
+        worker.stop();
+    }
+    result
+}
+
+/// Hand each worker's events to the output until one of them stops, or we're told to
+fn run_bus(
+    workers: &mut [ServerWorker],
+    output: &mut dyn Output,
+    mainloop: &mut Mainloop,
+    rx: &LoopRx<BusComms>,
+) -> Result<(), Errors> {
+    loop {
+        // Our own mainloop only runs the signal handlers here
+        let request = match mainloop.recv(rx)? {
+            BusComms::Request(request) => request,
+            BusComms::ServerEvent(event) => {
+                output.emit(&event)?;
+                continue;
+            }
+            BusComms::ServerDone(name) => {
+                // Reporting on only some of the servers would look like all is well, so one
+                // stopping stops them all
+                let worker = workers.iter_mut().find(|worker| worker.name == name);
+                return match worker {
+                    Some(worker) => worker.join(),
+                    None => Ok(()),
+                };
+            }
+        };
+        match request {
+            ListenerRequest::Shutdown => return Err(Errors::Shutdown),
+            ListenerRequest::HookFailed(failure) => output.emit(&hook_failed(failure))?,
+            ListenerRequest::Query(query, reply) => {
+                let mut events = vec![];
+                for worker in workers.iter() {
+                    let (worker_tx, worker_rx) = crossbeam_channel::bounded(1);
+                    if worker
+                        .tx
+                        .send(CallbackComms::Request(ListenerRequest::Query(
+                            query, worker_tx,
+                        )))
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    // A worker that's reconnecting has nothing to say, and one that's stuck
+                    // mustn't hold up the answer from the rest
+                    let answer = match worker_rx.recv_timeout(socket::QUERY_TIMEOUT) {
+                        Ok(answer) => answer,
+                        Err(RecvTimeoutError::Timeout) => {
+                            debug!("{} didn't answer {:?}, leaving it out", worker.name, query);
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => continue,
+                    };
+                    for mut event in answer {
+                        event.server = Some(worker.name.clone());
+                        events.push(event);
+                    }
+                }
+                let _ = reply.send(events);
+            }
+            ListenerRequest::Control(command, reply) => {
+                let mut refused = vec![];
+                for worker in workers.iter() {
+                    let (worker_tx, worker_rx) = crossbeam_channel::bounded(1);
+                    if worker
+                        .tx
+                        .send(CallbackComms::Request(ListenerRequest::Control(
+                            command,
+                            Some(worker_tx),
+                        )))
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    // A worker that's reconnecting drops the command, as a single listener would
+                    if let Ok(Err(reason)) = worker_rx.recv_timeout(socket::QUERY_TIMEOUT) {
+                        refused.push(format!("{}: {}", worker.name, reason));
+                    }
+                }
+                if let Some(reply) = reply {
+                    let _ = reply.send(match refused.is_empty() {
+                        true => Ok(()),
+                        false => Err(refused.join(", ")),
+                    });
+                }
+            }
+            request @ ListenerRequest::Click(..) => broadcast(workers, request),
+            #[cfg(feature = "lua")]
+            request @ ListenerRequest::Action(_) => broadcast(workers, request),
+        }
+    }
+}
+
+fn broadcast(workers: &[ServerWorker], request: ListenerRequest) {
+    for worker in workers {
+        let _ = worker.tx.send(CallbackComms::Request(request.clone()));
+    }
+}
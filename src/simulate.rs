@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::output::{DeviceState, Event, EventKind, Output};
+use crate::Errors;
+
+/// One line of a simulation script
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Mute(bool),
+    Volume(u32),
+    /// Make the named source the default
+    Default(String),
+    NoSource,
+    Sleep(Duration),
+}
+
+/// `line` without its comment, which starts with a `#` at the start of the line or after
+/// whitespace, so source names can still have one in them
+fn strip_comment(line: &str) -> &str {
+    let comment = line.char_indices().find(|&(at, c)| {
+        c == '#'
+            && line[..at]
+                .chars()
+                .next_back()
+                .is_none_or(char::is_whitespace)
+    });
+    match comment {
+        Some((at, _)) => &line[..at],
+        None => line,
+    }
+}
+
+/// Parse a script: one step per line (`mute`, `unmute`, `volume <pct>`, `default <name>`,
+/// `no-source`, `sleep <secs>`), with blank lines and `#` comments ignored.
+fn parse_script(script: &str) -> Result<Vec<Step>, String> {
+    let mut steps = vec![];
+    for (lineno, line) in script.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, Some(arg.trim())),
+            None => (line, None),
+        };
+        let step = match (command, arg) {
+            ("mute", None) => Step::Mute(true),
+            ("unmute", None) => Step::Mute(false),
+            ("no-source", None) => Step::NoSource,
+            ("default", Some(name)) => Step::Default(name.to_string()),
+            ("volume", Some(pct)) => Step::Volume(
+                pct.parse()
+                    .map_err(|_| format!("line {}: bad volume '{}'", lineno + 1, pct))?,
+            ),
+            ("sleep", Some(secs)) => Step::Sleep(
+                secs.parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| format!("line {}: bad duration '{}'", lineno + 1, secs))?,
+            ),
+            _ => return Err(format!("line {}: can't understand '{}'", lineno + 1, line)),
+        };
+        steps.push(step);
+    }
+    Ok(steps)
+}
+
+/// What the fake default source looks like
+struct Simulated {
+    names: Vec<Arc<str>>,
+    default: Option<usize>,
+    muted: bool,
+    volume: u32,
+}
+
+impl Simulated {
+    fn new() -> Self {
+        Simulated {
+            names: vec![Arc::from("simulated-source-0")],
+            default: Some(0),
+            muted: false,
+            volume: 100,
+        }
+    }
+
+    fn mute_event(&self) -> EventKind {
+        match self.default {
+            Some(index) => EventKind::Mute {
+                source: self.names[index].clone(),
+                device: DeviceState {
+                    index: index as u32,
+                    muted: self.muted,
+                    volume: self.volume,
+                },
+                default: true,
+            },
+            None => EventKind::NoSource,
+        }
+    }
+
+    /// Apply a step, returning the event it causes, if any
+    fn apply(&mut self, step: &Step) -> Option<EventKind> {
+        match step {
+            Step::Mute(muted) => {
+                self.muted = *muted;
+                Some(self.mute_event())
+            }
+            Step::Volume(volume) => {
+                self.volume = *volume;
+                let index = self.default?;
+                Some(EventKind::Volume {
+                    source: self.names[index].clone(),
+                    device: DeviceState {
+                        index: index as u32,
+                        muted: self.muted,
+                        volume: self.volume,
+                    },
+                    default: true,
+                })
+            }
+            Step::Default(name) => {
+                let index = match self.names.iter().position(|known| **known == **name) {
+                    Some(index) => index,
+                    None => {
+                        self.names.push(Arc::from(name.as_str()));
+                        self.names.len() - 1
+                    }
+                };
+                self.default = Some(index);
+                Some(EventKind::DefaultChanged {
+                    source: self.names[index].clone(),
+                    device: DeviceState {
+                        index: index as u32,
+                        muted: self.muted,
+                        volume: self.volume,
+                    },
+                })
+            }
+            Step::NoSource => {
+                self.default = None;
+                Some(EventKind::NoSource)
+            }
+            Step::Sleep(duration) => {
+                thread::sleep(*duration);
+                None
+            }
+        }
+    }
+}
+
+/// Feed fake events to `output`, from a script if given, otherwise forever on a timer
+pub fn simulate(
+    script: Option<&Path>,
+    interval: Duration,
+    output: &mut dyn Output,
+) -> Result<(), Errors> {
+    let mut simulated = Simulated::new();
+    output.emit(&Event::new(simulated.mute_event()))?;
+
+    if let Some(path) = script {
+        let steps = parse_script(&fs::read_to_string(path)?)
+            .map_err(|err| Errors::ConfigError(format!("invalid simulation script: {}", err)))?;
+        for step in &steps {
+            debug!("simulating {:?}", step);
+            if let Some(kind) = simulated.apply(step) {
+                output.emit(&Event::new(kind))?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Toggle mute on every tick, and swap between two sources every few toggles
+    let mut tick: u64 = 0;
+    loop {
+        thread::sleep(interval);
+        tick += 1;
+        let step = match tick % 5 {
+            0 => Step::Default(format!("simulated-source-{}", (tick / 5) % 2)),
+            _ => Step::Mute(!simulated.muted),
+        };
+        if let Some(kind) = simulated.apply(&step) {
+            output.emit(&Event::new(kind))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_start_after_whitespace() {
+        let script = "# a comment\ndefault mic#2 # the second mic\nmute#not a comment";
+        assert_eq!(
+            parse_script(script).unwrap_err(),
+            "line 3: can't understand 'mute#not a comment'"
+        );
+        let steps = parse_script("default mic#2 # the second mic\n\tunmute\t# again").unwrap();
+        assert_eq!(
+            steps,
+            vec![Step::Default("mic#2".to_string()), Step::Mute(false)]
+        );
+    }
+
+    #[test]
+    fn sleeps_must_be_finite_and_not_negative() {
+        assert_eq!(
+            parse_script("sleep 0.5").unwrap(),
+            vec![Step::Sleep(Duration::from_millis(500))]
+        );
+        for secs in ["-1", "NaN", "inf"] {
+            assert_eq!(
+                parse_script(&format!("mute\nsleep {}", secs)).unwrap_err(),
+                format!("line 2: bad duration '{}'", secs)
+            );
+        }
+    }
+}
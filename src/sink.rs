@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use log::debug;
+use pulse::{
+    context::{introspect::SinkInfo, Context},
+    volume::ChannelVolumes,
+};
+
+use crate::batch::{Batch, Query};
+use crate::mainloop::Mainloop;
+use crate::{volume_percent, Errors};
+
+pub type Sinks = HashMap<u32, SinkDatum>;
+
+#[derive(Debug, Clone)]
+pub struct SinkDatum {
+    pub name: String,
+    pub description: Option<String>,
+    pub mute: bool,
+    pub volume: ChannelVolumes,
+}
+
+impl SinkDatum {
+    pub fn from_info(info: &SinkInfo<'_>) -> Self {
+        SinkDatum {
+            name: match &info.name {
+                None => "unknown".to_string(),
+                Some(name) => name.to_string(),
+            },
+            description: info.description.as_ref().map(|desc| desc.to_string()),
+            mute: info.mute,
+            volume: info.volume,
+        }
+    }
+
+    pub fn volume_percent(&self) -> u32 {
+        volume_percent(&self.volume)
+    }
+}
+
+/// Index of the sink called `name`, the lowest should two share it
+pub fn find_sink_by_name(sinks: &Sinks, name: &str) -> Option<u32> {
+    sinks
+        .iter()
+        .filter(|(_, sink)| sink.name == name)
+        .map(|(index, _)| *index)
+        .min()
+}
+
+pub fn set_sink_mute(
+    idx: u32,
+    mute: bool,
+    batch: &Batch,
+    context: &mut Context,
+    mainloop: &mut Mainloop,
+) -> Result<(), Errors> {
+    batch.ask(&[Query::SetSinkMute(idx, mute)], context, mainloop)?;
+    debug!("Set mute to {} for sink {}", mute, idx);
+    Ok(())
+}
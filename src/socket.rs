@@ -0,0 +1,495 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::consumer::{self, ConsumerPolicy, Publisher};
+use crate::control::{ControlCommand, Query};
+use crate::health::{Health, HealthReport};
+use crate::output::{Event, Output};
+use crate::{Errors, ListenerRequest, RequestTx};
+
+/// How long a query or command waits on the listener loop before giving up
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a subscriber's writer waits on a stuck client before giving up on it
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client can go without sending a request before it's hung up on, unless it's
+/// subscribed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest request line taken, newline included, well beyond any real request
+const MAX_LINE: usize = 1024;
+
+/// Most clients served at once by each server, beyond which more are hung up on straight away
+pub const MAX_CLIENTS: usize = 64;
+
+/// How long to wait before accepting again after failing to, e.g. for want of file descriptors
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A request from a client, one per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Query(Query),
+    Control(ControlCommand),
+    /// Stream every event from now on, as JSON lines
+    Subscribe,
+    /// Whether the listener is connected and subscribed, answered without the listener loop
+    Health,
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        match line {
+            "status" => Ok(Request::Query(Query::Status)),
+            "list" => Ok(Request::Query(Query::List)),
+            "subscribe" => Ok(Request::Subscribe),
+            "health" => Ok(Request::Health),
+            // Stopping the listener is left to whoever started it
+            "quit" => Err("quit isn't accepted over the socket".to_string()),
+            command => command.parse().map(Request::Control),
+        }
+    }
+}
+
+/// Every request gets exactly one of these back, as a line of JSON
+#[derive(Debug, Serialize)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The answer to a query, as the events the JSON output would print
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    events: Vec<Event>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<HealthReport>,
+}
+
+impl Reply {
+    pub fn ok(events: Vec<Event>) -> Self {
+        Reply {
+            ok: true,
+            error: None,
+            events,
+            health: None,
+        }
+    }
+
+    pub fn health(report: HealthReport) -> Self {
+        Reply {
+            health: Some(report),
+            ..Reply::ok(vec![])
+        }
+    }
+
+    pub fn error(error: String) -> Self {
+        Reply {
+            ok: false,
+            error: Some(error),
+            events: vec![],
+            health: None,
+        }
+    }
+}
+
+/// Each subscriber's queue of lines, written out by its own thread
+type Subscribers = Arc<Mutex<Vec<Publisher<Arc<[u8]>>>>>;
+
+/// A stream clients connect over
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Answer a query or pass on a command, through the listener loop. Subscribing is up to the
+/// caller, so it's only acknowledged.
+pub fn answer(request: Request, tx: &RequestTx, health: &Health) -> io::Result<Reply> {
+    let stopped = |_| io::Error::new(io::ErrorKind::BrokenPipe, "listener stopped");
+    match request {
+        Request::Query(query) => {
+            let (reply_tx, reply) = crossbeam_channel::unbounded();
+            tx.send(ListenerRequest::Query(query, reply_tx))
+                .map_err(stopped)?;
+            Ok(match reply.recv_timeout(QUERY_TIMEOUT) {
+                Ok(events) => Reply::ok(events),
+                Err(_) => Reply::error("the listener didn't answer".to_string()),
+            })
+        }
+        Request::Control(command) => {
+            let (reply_tx, reply) = crossbeam_channel::bounded(1);
+            tx.send(ListenerRequest::Control(command, Some(reply_tx)))
+                .map_err(stopped)?;
+            Ok(match reply.recv_timeout(QUERY_TIMEOUT) {
+                Ok(Ok(())) => Reply::ok(vec![]),
+                Ok(Err(err)) => Reply::error(err),
+                Err(_) => Reply::error("the listener didn't answer".to_string()),
+            })
+        }
+        Request::Subscribe => Ok(Reply::ok(vec![])),
+        Request::Health => Ok(Reply::health(health.report())),
+    }
+}
+
+/// Answer one client until it disconnects. Subscribing hands the connection over to the
+/// subscribers, after which it only receives events. Commands are refused unless `control`.
+fn serve_client<S: Connection>(
+    stream: S,
+    client: String,
+    control: bool,
+    tx: RequestTx,
+    subscribers: Subscribers,
+    policy: ConsumerPolicy,
+    health: &Health,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.by_ref().take(MAX_LINE as u64).read_line(&mut line)?;
+        if read == 0 {
+            return Ok(());
+        }
+        if read == MAX_LINE && !line.ends_with('\n') {
+            let refusal = format!("requests are at most {} bytes", MAX_LINE);
+            return write_reply(&mut writer, &Reply::error(refusal));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let request = match Request::parse(line) {
+            Ok(Request::Control(_)) if !control => {
+                let refusal = "commands aren't taken on this socket, only queries";
+                write_reply(&mut writer, &Reply::error(refusal.to_string()))?;
+                continue;
+            }
+            Ok(request) => request,
+            Err(err) => {
+                write_reply(&mut writer, &Reply::error(err))?;
+                continue;
+            }
+        };
+        write_reply(&mut writer, &answer(request, &tx, health)?)?;
+        if request == Request::Subscribe {
+            writer.set_write_timeout(Some(SUBSCRIBER_TIMEOUT))?;
+            let (publisher, lines) = consumer::queue(policy, client, health);
+            subscribers.lock().unwrap().push(publisher);
+            // Ends once the client stops taking lines, or is hung up on for falling behind
+            while let Some(line) = lines.recv() {
+                writer.write_all(&line)?;
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn write_reply(writer: &mut impl Write, reply: &Reply) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, reply)?;
+    writer.write_all(b"\n")
+}
+
+/// Bind the socket, replacing a stale one left behind by a listener that's gone
+pub fn bind(path: &Path) -> Result<UnixListener, Errors> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Errors::ConfigError(format!(
+                "{} is in use by another listener",
+                path.display()
+            )));
+        }
+        debug!("Removing stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// A thread accepting an output's clients, answering each on a thread of its own. Stopping it,
+/// or dropping it, closes the listener.
+pub struct AcceptThread {
+    stopping: Arc<AtomicBool>,
+    /// Connects to the listener, so a thread waiting to accept notices it's stopping
+    wake: Box<dyn Fn() -> io::Result<()> + Send>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AcceptThread {
+    /// Accept clients until stopped. `accept` gives each client with what it's called in logs.
+    pub fn spawn<S: Send + 'static>(
+        name: &str,
+        mut accept: impl FnMut() -> io::Result<(S, String)> + Send + 'static,
+        serve: impl Fn(S, String) -> io::Result<()> + Send + Sync + 'static,
+        wake: impl Fn() -> io::Result<()> + Send + 'static,
+    ) -> io::Result<Self> {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let serve = Arc::new(serve);
+        let served = Arc::new(AtomicUsize::new(0));
+        let thread = thread::Builder::new().name(name.to_string()).spawn({
+            let stopping = stopping.clone();
+            move || loop {
+                let accepted = accept();
+                if stopping.load(Ordering::Relaxed) {
+                    return;
+                }
+                let (stream, client) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        info!("Failed to accept client: {}", err);
+                        thread::sleep(ACCEPT_BACKOFF);
+                        continue;
+                    }
+                };
+                if served.load(Ordering::Relaxed) >= MAX_CLIENTS {
+                    info!(
+                        "Hanging up on {}, {} clients are already served",
+                        client, MAX_CLIENTS
+                    );
+                    continue;
+                }
+                served.fetch_add(1, Ordering::Relaxed);
+                let (serve, served) = (serve.clone(), served.clone());
+                thread::spawn(move || {
+                    if let Err(err) = serve(stream, client.clone()) {
+                        debug!("{} went away: {}", client, err);
+                    }
+                    served.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        })?;
+        Ok(AcceptThread {
+            stopping,
+            wake: Box::new(wake),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop accepting, waiting for the listener to close. Clients already accepted are served
+    /// until they leave.
+    pub fn stop(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        self.stopping.store(true, Ordering::Relaxed);
+        match (self.wake)() {
+            Ok(()) => {
+                let _ = thread.join();
+            }
+            // Left to stop at the next client instead
+            Err(err) => warn!("Couldn't wake the thread accepting clients: {}", err),
+        }
+    }
+}
+
+impl Drop for AcceptThread {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Where to connect to reach a listener bound to `addr`, which may be any address
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Serves each accepted client, subscribing them to `subscribers`
+fn serving<S: Connection>(
+    control: bool,
+    tx: RequestTx,
+    subscribers: Subscribers,
+    policy: ConsumerPolicy,
+    health: Health,
+) -> impl Fn(S, String) -> io::Result<()> + Send + Sync + 'static {
+    move |stream, client| {
+        serve_client(
+            stream,
+            client,
+            control,
+            tx.clone(),
+            subscribers.clone(),
+            policy,
+            &health,
+        )
+    }
+}
+
+/// Serves a line protocol on a Unix socket or over TCP. Clients send `status`, `list`,
+/// `toggle`, `mute`, `unmute`, `health` or `subscribe`, one per line, and get a line of JSON back for
+/// each. Subscribing streams every later event to the client, as JSON lines like the JSON
+/// output.
+///
+/// Nothing authenticates clients: the Unix socket is guarded by its file permissions, but
+/// anyone who can reach the TCP address is served. So over TCP the commands are refused
+/// unless control was asked for.
+pub struct SocketOutput {
+    /// The Unix socket to clean up, if serving on one
+    path: Option<PathBuf>,
+    subscribers: Subscribers,
+    accepting: AcceptThread,
+}
+
+impl SocketOutput {
+    pub fn unix(
+        path: PathBuf,
+        tx: RequestTx,
+        policy: ConsumerPolicy,
+        health: Health,
+    ) -> Result<Self, Errors> {
+        let listener = bind(&path)?;
+        let subscribers = Subscribers::default();
+        // Unix socket clients have no address worth telling apart
+        let accept = move || {
+            listener
+                .accept()
+                .map(|(stream, _)| (stream, "socket subscriber".to_string()))
+        };
+        let wake = {
+            let path = path.clone();
+            move || UnixStream::connect(&path).map(drop)
+        };
+        let serve = serving(true, tx, subscribers.clone(), policy, health);
+        let accepting = AcceptThread::spawn("socket", accept, serve, wake)?;
+        debug!("Listening on {}", path.display());
+        Ok(SocketOutput {
+            path: Some(path),
+            subscribers,
+            accepting,
+        })
+    }
+
+    /// Serve on `addr`, taking commands from its clients only if `control`
+    pub fn tcp(
+        addr: SocketAddr,
+        control: bool,
+        tx: RequestTx,
+        policy: ConsumerPolicy,
+        health: Health,
+    ) -> Result<Self, Errors> {
+        let listener = TcpListener::bind(addr)?;
+        let bound = reachable(listener.local_addr()?);
+        let subscribers = Subscribers::default();
+        let accept = move || {
+            listener
+                .accept()
+                .map(|(stream, peer)| (stream, format!("TCP subscriber {}", peer)))
+        };
+        let wake = move || TcpStream::connect(bound).map(drop);
+        let serve = serving(control, tx, subscribers.clone(), policy, health);
+        let accepting = AcceptThread::spawn("tcp", accept, serve, wake)?;
+        if control && !addr.ip().is_loopback() {
+            warn!(
+                "Taking commands over TCP on {}, from anyone who can reach it",
+                addr
+            );
+        }
+        debug!("Listening on {}", addr);
+        Ok(SocketOutput {
+            path: None,
+            subscribers,
+            accepting,
+        })
+    }
+}
+
+impl Output for SocketOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let line: Arc<[u8]> = line.into();
+        // Clients that have gone away, or were hung up on for falling behind, are dropped
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(line.clone()));
+        Ok(())
+    }
+}
+
+impl Drop for SocketOutput {
+    fn drop(&mut self) {
+        // Reaching the thread accepting clients needs the socket still there
+        self.accepting.stop();
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stopping_frees_the_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = move || listener.accept().map(|(stream, _)| (stream, String::new()));
+        let wake = move || TcpStream::connect(addr).map(drop);
+        let accepting = AcceptThread::spawn("test", accept, |_, _| Ok(()), wake).unwrap();
+        drop(accepting);
+        TcpListener::bind(addr).unwrap();
+    }
+
+    #[test]
+    fn overlong_requests_are_refused() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(&[b'a'; MAX_LINE + 1]).unwrap();
+        let (tx, _rx) = crate::mainloop::channel();
+        let policy = ConsumerPolicy::default();
+        serve_client(
+            server,
+            String::new(),
+            true,
+            RequestTx::Listener(tx),
+            Subscribers::default(),
+            policy,
+            &Health::default(),
+        )
+        .unwrap();
+        let mut reply = String::new();
+        BufReader::new(client).read_line(&mut reply).unwrap();
+        assert!(reply.contains("at most"), "{}", reply);
+    }
+}
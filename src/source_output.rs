@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+
+use pulse::{context::introspect::SourceOutputInfo, proplist::properties};
+
+/// Streams recording from a source, keyed by source-output index
+pub type SourceOutputs = HashMap<u32, SourceOutputDatum>;
+
+#[derive(Debug, Clone)]
+pub struct SourceOutputDatum {
+    /// The recording application's name, falling back to the stream's name
+    pub application: String,
+    /// Index of the source being recorded from
+    pub source: u32,
+}
+
+impl SourceOutputDatum {
+    pub fn from_info(info: &SourceOutputInfo<'_>) -> Self {
+        SourceOutputDatum {
+            application: info
+                .proplist
+                .get_str(properties::APPLICATION_NAME)
+                .or_else(|| info.name.as_ref().map(|name| name.to_string()))
+                .unwrap_or_else(|| "unknown".to_string()),
+            source: info.source,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::hash_map::{self, HashMap};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use crate::SourceDatum;
+
+/// The server's sources by index, along with which index each name is at, so finding a source
+/// by name doesn't mean going through every one of them. Systems with lots of virtual devices
+/// (JACK bridges, loopbacks, containers) can have hundreds.
+#[derive(Debug, Default)]
+pub struct Sources {
+    by_index: HashMap<u32, SourceDatum>,
+    /// Names are unique on a server, except briefly while e.g. a card is reconfigured
+    by_name: HashMap<Arc<str>, BTreeSet<u32>>,
+}
+
+impl Sources {
+    pub fn get(&self, idx: &u32) -> Option<&SourceDatum> {
+        self.by_index.get(idx)
+    }
+
+    pub fn get_key_value(&self, idx: &u32) -> Option<(&u32, &SourceDatum)> {
+        self.by_index.get_key_value(idx)
+    }
+
+    pub fn contains_key(&self, idx: &u32) -> bool {
+        self.by_index.contains_key(idx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_index.is_empty()
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, u32, SourceDatum> {
+        self.by_index.iter()
+    }
+
+    pub fn values(&self) -> hash_map::Values<'_, u32, SourceDatum> {
+        self.by_index.values()
+    }
+
+    /// Index of the source called `name`, if it's there. Should two share it, the lowest index
+    /// is taken rather than whichever the map turns up first.
+    pub fn index_of(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name)?.first().copied()
+    }
+
+    pub fn insert(&mut self, idx: u32, src: SourceDatum) -> Option<SourceDatum> {
+        self.by_name
+            .entry(src.name.clone())
+            .or_default()
+            .insert(idx);
+        let old = self.by_index.insert(idx, src)?;
+        // Renamed, rather than just changed
+        if self.by_index[&idx].name != old.name {
+            self.unname(idx, &old.name);
+        }
+        Some(old)
+    }
+
+    pub fn remove(&mut self, idx: &u32) -> Option<SourceDatum> {
+        let old = self.by_index.remove(idx)?;
+        self.unname(*idx, &old.name);
+        Some(old)
+    }
+
+    fn unname(&mut self, idx: u32, name: &str) {
+        if let Some(indices) = self.by_name.get_mut(name) {
+            indices.remove(&idx);
+            if indices.is_empty() {
+                self.by_name.remove(name);
+            }
+        }
+    }
+}
+
+impl FromIterator<(u32, SourceDatum)> for Sources {
+    fn from_iter<I: IntoIterator<Item = (u32, SourceDatum)>>(iter: I) -> Self {
+        let mut sources = Sources::default();
+        for (idx, src) in iter {
+            sources.insert(idx, src);
+        }
+        sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(listed: &[(u32, &str)]) -> Sources {
+        listed
+            .iter()
+            .map(|(idx, name)| (*idx, SourceDatum::named(name)))
+            .collect()
+    }
+
+    #[test]
+    fn finds_a_source_by_name() {
+        let sources = sources(&[(1, "speaker.monitor"), (4, "mic")]);
+        assert_eq!(sources.index_of("mic"), Some(4));
+        assert_eq!(sources.index_of("webcam"), None);
+    }
+
+    #[test]
+    fn lowest_index_wins_between_duplicates() {
+        let mut sources = sources(&[(8, "mic"), (2, "mic")]);
+        assert_eq!(sources.index_of("mic"), Some(2));
+        sources.remove(&2);
+        assert_eq!(sources.index_of("mic"), Some(8));
+    }
+
+    #[test]
+    fn renamed_source_is_found_by_its_new_name_only() {
+        let mut sources = sources(&[(3, "mic")]);
+        sources.insert(3, SourceDatum::named("mic.pro"));
+        assert_eq!(sources.index_of("mic"), None);
+        assert_eq!(sources.index_of("mic.pro"), Some(3));
+    }
+
+    #[test]
+    fn removed_source_is_forgotten() {
+        let mut sources = sources(&[(3, "mic")]);
+        sources.insert(3, SourceDatum::named("mic"));
+        sources.remove(&3);
+        assert_eq!(sources.index_of("mic"), None);
+        assert!(sources.by_name.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+use pulse::context::Context;
+
+use crate::lock::MainloopGuard;
+use crate::mainloop::Mainloop;
+use crate::{callback, CallbackComms, Errors, CBTX};
+
+/// Whether the event loop is getting through its messages, shared with the supervisor
+struct Heartbeat {
+    start: Instant,
+    /// When the message being handled was taken off the queue, in ms since `start`, or 0 while
+    /// waiting for one
+    busy_since: AtomicU64,
+    /// Set once the loop has been stuck for longer than the timeout
+    stalled: AtomicBool,
+}
+
+impl Heartbeat {
+    fn now(&self) -> u64 {
+        // Never 0, which means idle
+        self.start.elapsed().as_millis() as u64 + 1
+    }
+
+    /// How long the current message has been in hand, if there is one
+    fn busy_for(&self) -> Option<Duration> {
+        match self.busy_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => Some(Duration::from_millis(self.now().saturating_sub(since))),
+        }
+    }
+}
+
+/// Watches the event loop from a thread of its own. A loop that's stuck handling a message is
+/// waiting on something that won't come, and can't be interrupted, so the supervisor says so
+/// through `on_stall`, which may end the process for the service manager to start afresh, and
+/// the loop gives up with [`Errors::Stalled`] if it ever gets through the message.
+pub struct Supervisor {
+    heartbeat: Arc<Heartbeat>,
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn spawn(timeout: Duration, on_stall: Option<fn(Duration)>) -> Result<Self, Errors> {
+        let heartbeat = Arc::new(Heartbeat {
+            start: Instant::now(),
+            busy_since: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+        });
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("supervisor".to_string())
+            .spawn({
+                let heartbeat = heartbeat.clone();
+                move || loop {
+                    match stopped.recv_timeout(timeout / 4) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => return,
+                    }
+                    let Some(busy) = heartbeat.busy_for().filter(|busy| *busy >= timeout) else {
+                        continue;
+                    };
+                    if !heartbeat.stalled.swap(true, Ordering::Relaxed) {
+                        error!("Event loop stuck on one message for {:?}", busy);
+                        if let Some(on_stall) = on_stall {
+                            on_stall(busy);
+                        }
+                    }
+                }
+            })?;
+        Ok(Supervisor {
+            heartbeat,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The loop took a message off its queue
+    pub fn busy(&self) {
+        let now = self.heartbeat.now();
+        self.heartbeat.busy_since.store(now, Ordering::Relaxed);
+    }
+
+    /// The loop is back to waiting for messages, which fails if it was stuck on the last
+    pub fn idle(&self) -> Result<(), Errors> {
+        self.heartbeat.busy_since.store(0, Ordering::Relaxed);
+        match self.heartbeat.stalled.load(Ordering::Relaxed) {
+            true => Err(Errors::Stalled),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Checks the server answers through the mainloop, so a stuck mainloop or server can be told
+/// apart from a quiet one. Asks every `timeout`, and gives up on an answer after as long again.
+pub struct Probe {
+    timeout: Duration,
+    /// When the unanswered question was asked
+    asked: Option<Instant>,
+    answered: Instant,
+}
+
+impl Probe {
+    pub fn new(timeout: Duration) -> Self {
+        Probe {
+            timeout,
+            asked: None,
+            answered: Instant::now(),
+        }
+    }
+
+    /// How long the loop can wait before checking again
+    pub fn wait(&self) -> Duration {
+        match self.asked {
+            Some(asked) => self.timeout.saturating_sub(asked.elapsed()),
+            None => self.timeout.saturating_sub(self.answered.elapsed()),
+        }
+    }
+
+    /// Ask the server something if it's time, or fail if it never answered
+    pub fn check(
+        &mut self,
+        context: &Context,
+        mainloop: &mut Mainloop,
+        tx: &CBTX,
+    ) -> Result<(), Errors> {
+        match self.asked {
+            Some(asked) if asked.elapsed() >= self.timeout => Err(Errors::Stalled),
+            None if self.answered.elapsed() >= self.timeout => {
+                debug!("Checking the server still answers");
+                let tx = tx.clone();
+                let guard = MainloopGuard::lock(mainloop);
+                context.introspect().get_server_info(move |_| {
+                    callback::notify(&tx, CallbackComms::Pong);
+                });
+                drop(guard);
+                self.asked = Some(Instant::now());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn answered(&mut self) {
+        self.asked = None;
+        self.answered = Instant::now();
+    }
+}
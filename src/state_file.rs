@@ -0,0 +1,119 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use log::error;
+
+use crate::output::{Event, Output};
+
+/// How often the file is rewritten when it carries a timestamp, so an old timestamp means the
+/// listener has stopped rather than nothing having changed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Collects what a formatter writes, so it can be taken after each event
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write the state to a temporary file beside the real one, then move it into place, so readers
+/// only ever see a whole state
+fn write_atomic(path: &Path, line: &[u8], timestamp: bool) -> io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut contents = line.to_vec();
+    contents.push(b'\n');
+    if timestamp {
+        contents.extend_from_slice(format!("updated {}\n", Local::now().timestamp()).as_bytes());
+    }
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Keeps a file holding the latest device state, formatted as it would be on stdout. With
+/// `timestamp`, a second line reads `updated <unix seconds>`.
+pub struct StateFileOutput {
+    path: PathBuf,
+    formatter: Box<dyn Output + Send>,
+    buffer: SharedBuffer,
+    /// The last line written, shared with the refresh thread. Holding the lock while writing
+    /// keeps the two from racing on the temporary file.
+    current: Arc<Mutex<Option<Vec<u8>>>>,
+    timestamp: bool,
+}
+
+impl StateFileOutput {
+    /// `formatter` has to write into `buffer`
+    pub fn new(
+        path: PathBuf,
+        formatter: Box<dyn Output + Send>,
+        buffer: SharedBuffer,
+        timestamp: bool,
+    ) -> io::Result<Self> {
+        let current: Arc<Mutex<Option<Vec<u8>>>> = Arc::default();
+        if timestamp {
+            let (path, current) = (path.clone(), current.clone());
+            thread::Builder::new()
+                .name("state-file".to_string())
+                .spawn(move || loop {
+                    thread::sleep(REFRESH_INTERVAL);
+                    let current = current.lock().unwrap();
+                    if let Some(line) = current.as_ref() {
+                        if let Err(err) = write_atomic(&path, line, true) {
+                            error!("failed to refresh {}: {}", path.display(), err);
+                        }
+                    }
+                })?;
+        }
+        Ok(StateFileOutput {
+            path,
+            formatter,
+            buffer,
+            current,
+            timestamp,
+        })
+    }
+}
+
+impl Output for StateFileOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if !event.kind.is_device_state() {
+            return Ok(());
+        }
+        self.formatter.emit(event)?;
+        let formatted = self.buffer.take();
+        // Formatters may write a header first, so only the last line is the state
+        let Some(line) = formatted
+            .split(|byte| *byte == b'\n')
+            .rfind(|line| !line.is_empty())
+        else {
+            return Ok(());
+        };
+        let mut current = self.current.lock().unwrap();
+        write_atomic(&self.path, line, self.timestamp)?;
+        *current = Some(line.to_vec());
+        Ok(())
+    }
+}
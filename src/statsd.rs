@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use clap::ValueEnum;
+use log::debug;
+
+use crate::output::{Event, Output};
+use crate::udp;
+use crate::Errors;
+
+/// Wire format for `--statsd`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StatsdFormat {
+    /// Plain StatsD, with the source in the metric name
+    #[default]
+    Statsd,
+    /// InfluxDB line protocol, with the source as a tag
+    Influx,
+}
+
+/// Metric names can't hold dots or spaces, which source names often do
+fn metric_name(source: &str) -> String {
+    source
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Tag values have to escape what separates tags and fields
+fn tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Sends gauges of each source's mute state and volume, and counts of mute flips, to a StatsD
+/// or InfluxDB UDP listener. Each event goes out as one datagram.
+pub struct StatsdOutput {
+    socket: UdpSocket,
+    target: SocketAddr,
+    format: StatsdFormat,
+    /// Last known mute state per source, to tell flips from repeats
+    muted: HashMap<String, bool>,
+}
+
+impl StatsdOutput {
+    pub fn new(target: &str, format: StatsdFormat) -> Result<Self, Errors> {
+        let target = udp::resolve(target)?;
+        let socket = UdpSocket::bind(match target {
+            SocketAddr::V6(_) => "[::]:0",
+            SocketAddr::V4(_) => "0.0.0.0:0",
+        })?;
+        Ok(StatsdOutput {
+            socket,
+            target,
+            format,
+            muted: HashMap::new(),
+        })
+    }
+
+    fn lines(&self, event: &Event, source: &str, muted: bool, flipped: bool) -> Vec<String> {
+        let kind = &event.kind;
+        let mut lines = vec![];
+        match self.format {
+            StatsdFormat::Statsd => {
+                let mut prefixes = vec![format!("pulse.source.{}", metric_name(source))];
+                // So the default source can be watched without knowing its name
+                if kind.is_default() {
+                    prefixes.push("pulse.default_source".to_string());
+                }
+                for prefix in prefixes {
+                    lines.push(format!("{}.muted:{}|g", prefix, muted as u8));
+                    if let Some(volume) = kind.volume() {
+                        lines.push(format!("{}.volume:{}|g", prefix, volume));
+                    }
+                    if flipped {
+                        let counter = if muted { "mutes" } else { "unmutes" };
+                        lines.push(format!("{}.{}:1|c", prefix, counter));
+                    }
+                }
+            }
+            StatsdFormat::Influx => {
+                let timestamp = event.timestamp.timestamp_nanos_opt().unwrap_or_default();
+                let tags = format!("source={},default={}", tag_value(source), kind.is_default());
+                let mut fields = format!("muted={}i", muted as u8);
+                if let Some(volume) = kind.volume() {
+                    fields.push_str(&format!(",volume={}i", volume));
+                }
+                lines.push(format!("pulse_source,{} {} {}", tags, fields, timestamp));
+                if flipped {
+                    let to = if muted { "muted" } else { "unmuted" };
+                    lines.push(format!(
+                        "pulse_source_transition,{},to={} count=1i {}",
+                        tags, to, timestamp
+                    ));
+                }
+            }
+        }
+        lines
+    }
+}
+
+impl Output for StatsdOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let kind = &event.kind;
+        if event.snapshot || kind.facility() != "source" {
+            return Ok(());
+        }
+        let (Some(source), Some(muted)) = (kind.device(), kind.muted()) else {
+            return Ok(());
+        };
+        let flipped = self
+            .muted
+            .insert(source.to_string(), muted)
+            .is_some_and(|was| was != muted);
+        let datagram = self.lines(event, source, muted, flipped).join("\n");
+        // Nobody may be listening, which is no reason to stop
+        if let Err(err) = self.socket.send_to(datagram.as_bytes(), self.target) {
+            debug!("Failed to send metrics to {}: {}", self.target, err);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::pin::Pin;
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::consumer::Subscription;
+use crate::listener::Waiting;
+use crate::output::Event;
+
+/// A listener's events, for async programs: woken as the listener's thread hands each over,
+/// rather than blocking on it. Streams share the events with each other and with
+/// [`SourceListener::events`](crate::SourceListener::events), each going to whichever takes it
+/// first.
+pub struct EventStream {
+    events: Arc<Subscription<Event>>,
+    waiting: Arc<Mutex<Waiting>>,
+}
+
+impl EventStream {
+    pub(crate) fn new(events: Arc<Subscription<Event>>, waiting: Arc<Mutex<Waiting>>) -> Self {
+        EventStream { events, waiting }
+    }
+
+    /// The next event, if there is one yet, or `None` once there won't be any more
+    fn next_event(&self) -> Poll<Option<Event>> {
+        match self.events.try_recv() {
+            Ok(event) => Poll::Ready(Some(event)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        if let Poll::Ready(next) = self.next_event() {
+            return Poll::Ready(next);
+        }
+        let closed = self.waiting.lock().unwrap().register(cx.waker());
+        // An event sent before registering woke nobody, so look again
+        match self.next_event() {
+            Poll::Pending if closed => Poll::Ready(None),
+            next => next,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Waker;
+
+    use super::*;
+    use crate::consumer::{self, ConsumerPolicy};
+    use crate::health::Health;
+    use crate::output::EventKind;
+
+    #[test]
+    fn ends_once_closed() {
+        let health = Health::default();
+        let (tx, rx) = consumer::queue(ConsumerPolicy::default(), "test".to_string(), &health);
+        let waiting = Arc::new(Mutex::new(Waiting::default()));
+        let mut stream = EventStream::new(Arc::new(rx), waiting.clone());
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        assert!(tx.send(Event::new(EventKind::NoSource)));
+        assert!(matches!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(Some(_))
+        ));
+        drop(tx);
+        assert!(matches!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+}
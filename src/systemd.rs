@@ -0,0 +1,113 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+/// Where to tell systemd how we're doing, when it started us as a `Type=notify` service
+struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// Last status sent, so it's only sent again once it changes
+    status: Mutex<String>,
+}
+
+impl Notifier {
+    fn connect(path: &OsStr) -> io::Result<Self> {
+        // A leading @ means a socket in the abstract namespace
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            status: Mutex::default(),
+        })
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            debug!("Failed to send {} to systemd: {}", state, err);
+        }
+    }
+}
+
+static NOTIFIER: OnceLock<Option<Notifier>> = OnceLock::new();
+
+fn notifier() -> Option<&'static Notifier> {
+    NOTIFIER
+        .get_or_init(|| {
+            let path = env::var_os("NOTIFY_SOCKET")?;
+            Notifier::connect(&path)
+                .map_err(|err| debug!("Can't notify systemd at {:?}: {}", path, err))
+                .ok()
+        })
+        .as_ref()
+}
+
+fn send(state: &str) {
+    if let Some(notifier) = notifier() {
+        notifier.send(state);
+    }
+}
+
+/// Tell systemd we're up, once we're subscribed to the server
+pub fn ready() {
+    send("READY=1");
+}
+
+/// Describe what we're watching for `systemctl status`
+pub fn status(status: &str) {
+    let Some(notifier) = notifier() else {
+        return;
+    };
+    let mut last = notifier.status.lock().unwrap();
+    if *last != status {
+        *last = status.to_string();
+        notifier.send(&format!("STATUS={}", status));
+    }
+}
+
+/// Pings systemd's watchdog from a loop, which has to wake up at least every `timeout()` for it
+pub struct Watchdog {
+    interval: Duration,
+    fed: Instant,
+}
+
+impl Watchdog {
+    /// Only when the service has a `WatchdogSec=` meant for us
+    pub fn from_env() -> Option<Self> {
+        notifier()?;
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse() != Ok(process::id()) {
+                return None;
+            }
+        }
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        send("WATCHDOG=1");
+        Some(Watchdog {
+            // Twice as often as needed leaves room for waking up late
+            interval: Duration::from_micros(usec) / 2,
+            fed: Instant::now(),
+        })
+    }
+
+    /// How long until the next ping is due
+    pub fn timeout(&self) -> Duration {
+        self.interval.saturating_sub(self.fed.elapsed())
+    }
+
+    pub fn feed(&mut self) {
+        if self.fed.elapsed() >= self.interval {
+            send("WATCHDOG=1");
+            self.fed = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use log::{debug, error};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::Errors;
+
+/// Exports the event pipeline's spans until dropped, which flushes whatever is still batched
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Send spans to an OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`
+    pub fn export(endpoint: &str) -> Result<Self, Errors> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|err| {
+                Errors::ConfigError(format!("can't export traces to {}: {}", endpoint, err))
+            })?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()
+            .map_err(|err| Errors::ConfigError(format!("can't set up tracing: {}", err)))?;
+        debug!("Exporting traces to {}", endpoint);
+        Ok(Telemetry { provider })
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(err) = self.provider.shutdown() {
+            error!("failed to flush traces: {}", err);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::output::{Event, Output, StateTexts};
+use crate::theme::Theme;
+
+/// Values that can be substituted into a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// Name of the source (or sink) the event is about
+    SourceName,
+    /// `true` / `false`
+    Muted,
+    /// PulseAudio's index for the source
+    Index,
+    /// Whether the source is the server's default source
+    Default,
+    /// Average volume, in percent
+    Volume,
+    /// The configured mute/unmute/no-source text
+    State,
+    /// Event type, as used for the `event` key in JSON output
+    Event,
+    /// RFC3339 timestamp of the event
+    Timestamp,
+    /// The event's sequence number
+    Seq,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "source_name" => Some(Field::SourceName),
+            "muted" => Some(Field::Muted),
+            "index" => Some(Field::Index),
+            "default" => Some(Field::Default),
+            "volume" => Some(Field::Volume),
+            "state" => Some(Field::State),
+            "event" => Some(Field::Event),
+            "timestamp" => Some(Field::Timestamp),
+            "seq" => Some(Field::Seq),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Field),
+}
+
+/// A parsed `--template` string, e.g. `{state} ({source_name}, {volume}%)`.
+///
+/// `{{` and `}}` produce literal braces.
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unterminated placeholder '{{{}'", name)),
+                        }
+                    }
+                    let field = Field::parse(name.trim())
+                        .ok_or_else(|| format!("unknown placeholder '{{{}}}'", name))?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(field));
+                }
+                '}' => return Err("unmatched '}', use '}}' for a literal brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template { segments })
+    }
+
+    /// Render an event, coloring the `{state}` placeholder if a theme is given
+    pub fn render(&self, event: &Event, texts: &StateTexts, theme: Option<&Theme>) -> String {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => rendered.push_str(text),
+                Segment::Placeholder(field) => {
+                    render_field(&mut rendered, *field, event, texts, theme)
+                }
+            }
+        }
+        rendered
+    }
+}
+
+fn render_field(
+    buf: &mut String,
+    field: Field,
+    event: &Event,
+    texts: &StateTexts,
+    theme: Option<&Theme>,
+) {
+    let kind = &event.kind;
+    // Writing into a String can't fail
+    let _ = match field {
+        Field::State => match theme {
+            Some(theme) => write!(buf, "{}", theme.paint(kind, texts.for_event(kind))),
+            None => write!(buf, "{}", texts.for_event(kind)),
+        },
+        Field::Timestamp => write!(buf, "{}", event.timestamp.to_rfc3339()),
+        Field::Seq => write!(buf, "{}", event.seq),
+        Field::Event => write!(buf, "{}", kind.name()),
+        Field::Default => write!(buf, "{}", kind.is_default()),
+        // Device-specific fields are left empty when there is no device
+        Field::SourceName => write!(buf, "{}", kind.device().unwrap_or_default()),
+        Field::Muted => write_opt(buf, kind.muted()),
+        Field::Index => write_opt(buf, kind.index()),
+        Field::Volume => write_opt(buf, kind.volume()),
+    };
+}
+
+fn write_opt<T: std::fmt::Display>(buf: &mut String, value: Option<T>) -> std::fmt::Result {
+    match value {
+        Some(value) => write!(buf, "{}", value),
+        None => Ok(()),
+    }
+}
+
+pub struct TemplateOutput<W: Write> {
+    writer: W,
+    template: Template,
+    texts: StateTexts,
+    theme: Option<Theme>,
+}
+
+impl<W: Write> TemplateOutput<W> {
+    pub fn new(writer: W, template: Template, texts: StateTexts, theme: Option<Theme>) -> Self {
+        TemplateOutput {
+            writer,
+            template,
+            texts,
+            theme,
+        }
+    }
+}
+
+impl<W: Write> Output for TemplateOutput<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{}",
+            self.template
+                .render(event, &self.texts, self.theme.as_ref())
+        )?;
+        self.writer.flush()
+    }
+}
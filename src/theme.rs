@@ -0,0 +1,58 @@
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+
+use crate::output::EventKind;
+
+const RESET: &str = "\x1b[0m";
+
+/// When to color terminal output
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+/// ANSI styles applied to the state text of each kind of event
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub mute: &'static str,
+    pub unmute: &'static str,
+    pub nosource: &'static str,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            // bold red, green, yellow
+            mute: "\x1b[1;31m",
+            unmute: "\x1b[32m",
+            nosource: "\x1b[33m",
+        }
+    }
+}
+
+impl Theme {
+    /// The theme to use for stdout, if any, given the user's `--color` choice
+    pub fn for_stdout(choice: ColorChoice) -> Option<Self> {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+        };
+        enabled.then(Theme::default)
+    }
+
+    pub fn paint(&self, kind: &EventKind, text: &str) -> String {
+        let style = match kind.muted() {
+            Some(true) => self.mute,
+            Some(false) => self.unmute,
+            None => self.nosource,
+        };
+        format!("{}{}{}", style, text, RESET)
+    }
+}
@@ -0,0 +1,127 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::output::{Event, EventKind, Output};
+use crate::Errors;
+
+/// The default source's state, as sent in each datagram
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+struct UdpState {
+    source: Option<String>,
+    muted: Option<bool>,
+    volume: Option<u32>,
+}
+
+impl UdpState {
+    /// Fold an event in, returning whether anything changed
+    fn update(&mut self, kind: &EventKind) -> bool {
+        let old = self.clone();
+        match kind {
+            EventKind::NoSource => *self = UdpState::default(),
+            kind if kind.is_default() && !kind.is_sink() && kind.facility() == "source" => {
+                if let Some(source) = kind.device() {
+                    self.source = Some(source.to_string());
+                }
+                self.muted = kind.muted().or(self.muted);
+                self.volume = kind.volume().or(self.volume);
+            }
+            _ => {}
+        }
+        *self != old
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Datagram<'a> {
+    #[serde(flatten)]
+    state: &'a UdpState,
+    /// Set on the periodic resends, rather than for a change
+    refresh: bool,
+}
+
+struct Sender {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+}
+
+impl Sender {
+    fn send(&self, state: &UdpState, refresh: bool) {
+        let datagram = match serde_json::to_vec(&Datagram { state, refresh }) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                info!("Failed to encode UDP datagram: {}", err);
+                return;
+            }
+        };
+        for target in &self.targets {
+            // Nobody may be listening yet, which is no reason to stop
+            if let Err(err) = self.socket.send_to(&datagram, target) {
+                debug!("Failed to send UDP datagram to {}: {}", target, err);
+            }
+        }
+    }
+}
+
+/// Resolve `host:port`, as given on the command line
+pub fn resolve(target: &str) -> Result<SocketAddr, Errors> {
+    target
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| Errors::ConfigError(format!("can't resolve UDP target '{}'", target)))
+}
+
+/// Sends a small JSON datagram with the default source's state to each target whenever it
+/// changes, and again every `interval` so listeners that missed one catch up
+pub struct UdpOutput {
+    sender: Arc<Sender>,
+    state: Arc<Mutex<UdpState>>,
+}
+
+impl UdpOutput {
+    pub fn start(targets: &[String], interval: Duration) -> Result<Self, Errors> {
+        let targets = targets
+            .iter()
+            .map(|target| resolve(target))
+            .collect::<Result<Vec<_>, _>>()?;
+        let bind_addr = match targets.first() {
+            Some(SocketAddr::V6(_)) => "[::]:0",
+            _ => "0.0.0.0:0",
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        // So a broadcast address can be a target
+        socket.set_broadcast(true)?;
+
+        let sender = Arc::new(Sender { socket, targets });
+        let state = Arc::new(Mutex::new(UdpState::default()));
+        let (refresh_sender, refresh_state) = (sender.clone(), state.clone());
+        thread::Builder::new()
+            .name("udp-refresh".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let state = refresh_state.lock().unwrap().clone();
+                refresh_sender.send(&state, true);
+            })?;
+        Ok(UdpOutput { sender, state })
+    }
+}
+
+impl Output for UdpOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let state = {
+            let mut state = self.state.lock().unwrap();
+            if !state.update(&event.kind) {
+                return Ok(());
+            }
+            state.clone()
+        };
+        self.sender.send(&state, false);
+        Ok(())
+    }
+}
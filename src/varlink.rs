@@ -0,0 +1,244 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::control::{ControlCommand, Query};
+use crate::output::{DeviceState, Event, EventKind, Output};
+use crate::socket::{self, AcceptThread};
+use crate::{Errors, ListenerRequest, RequestTx};
+
+const INTERFACE: &str = "org.pulse_source_listener";
+const DESCRIPTION: &str = include_str!("org.pulse_source_listener.varlink");
+
+/// How long a call waits on the listener loop before giving up
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a monitor gets to take an event before it's dropped, so a stuck client can't hold
+/// up the listener
+const MONITOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Monitors = Arc<Mutex<Vec<UnixStream>>>;
+
+#[derive(Debug, Deserialize)]
+struct Call {
+    method: String,
+    #[serde(default)]
+    parameters: Value,
+    #[serde(default)]
+    more: bool,
+    #[serde(default)]
+    oneway: bool,
+}
+
+/// What a call is answered with, before it's framed
+enum Answer {
+    Reply(Value),
+    Error(&'static str, Value),
+    /// The connection now belongs to the monitors
+    Monitoring,
+}
+
+/// Varlink messages are JSON, each terminated by a NUL
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\0")
+}
+
+/// The watched source, from the events that make up the current state
+fn watched_source(events: Vec<Event>) -> Value {
+    events
+        .into_iter()
+        .find_map(|event| match event.kind {
+            EventKind::Mute {
+                source,
+                device:
+                    DeviceState {
+                        index,
+                        muted,
+                        volume,
+                    },
+                default: true,
+            } => Some(json!({
+                "name": source,
+                "index": index,
+                "muted": muted,
+                "volume": volume,
+            })),
+            _ => None,
+        })
+        .unwrap_or(Value::Null)
+}
+
+fn answer(call: &Call, tx: &RequestTx) -> Answer {
+    let stopped = || Answer::Error("org.pulse_source_listener.ListenerUnavailable", json!({}));
+    match call.method.as_str() {
+        "org.varlink.service.GetInfo" => Answer::Reply(json!({
+            "vendor": "martsa1",
+            "product": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": "",
+            "interfaces": ["org.varlink.service", INTERFACE],
+        })),
+        "org.varlink.service.GetInterfaceDescription" => {
+            match call.parameters.get("interface").and_then(Value::as_str) {
+                Some(INTERFACE) => Answer::Reply(json!({ "description": DESCRIPTION })),
+                interface => Answer::Error(
+                    "org.varlink.service.InterfaceNotFound",
+                    json!({ "interface": interface }),
+                ),
+            }
+        }
+        "org.pulse_source_listener.GetState" => {
+            let (reply_tx, reply) = crossbeam_channel::unbounded();
+            if tx
+                .send(ListenerRequest::Query(Query::Status, reply_tx))
+                .is_err()
+            {
+                return stopped();
+            }
+            match reply.recv_timeout(QUERY_TIMEOUT) {
+                Ok(events) => Answer::Reply(json!({ "source": watched_source(events) })),
+                Err(_) => stopped(),
+            }
+        }
+        "org.pulse_source_listener.Monitor" if !call.more => {
+            Answer::Error("org.varlink.service.ExpectedMore", json!({}))
+        }
+        "org.pulse_source_listener.Monitor" => Answer::Monitoring,
+        "org.pulse_source_listener.SetMute" => {
+            let command = match call.parameters.get("muted").and_then(Value::as_bool) {
+                Some(true) => ControlCommand::Mute,
+                Some(false) => ControlCommand::Unmute,
+                None => {
+                    return Answer::Error(
+                        "org.varlink.service.InvalidParameter",
+                        json!({ "parameter": "muted" }),
+                    )
+                }
+            };
+            let (reply_tx, reply) = crossbeam_channel::bounded(1);
+            if tx
+                .send(ListenerRequest::Control(command, Some(reply_tx)))
+                .is_err()
+            {
+                return stopped();
+            }
+            match reply.recv_timeout(QUERY_TIMEOUT) {
+                Ok(Ok(())) => Answer::Reply(json!({})),
+                Ok(Err(reason)) => Answer::Error(
+                    "org.pulse_source_listener.SetMuteFailed",
+                    json!({ "reason": reason }),
+                ),
+                Err(_) => stopped(),
+            }
+        }
+        method => Answer::Error(
+            "org.varlink.service.MethodNotFound",
+            json!({ "method": method }),
+        ),
+    }
+}
+
+/// Answer one client's calls until it disconnects or starts monitoring
+fn serve_client(stream: UnixStream, tx: RequestTx, monitors: Monitors) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut message = vec![];
+    loop {
+        message.clear();
+        if reader.read_until(0, &mut message)? == 0 {
+            return Ok(());
+        }
+        if message.last() == Some(&0) {
+            message.pop();
+        }
+        let call: Call = match serde_json::from_slice(&message) {
+            Ok(call) => call,
+            Err(err) => {
+                // Nothing more can be made sense of on this connection
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        };
+        let answer = answer(&call, &tx);
+        if call.oneway {
+            continue;
+        }
+        match answer {
+            Answer::Reply(parameters) => {
+                write_message(&mut writer, &json!({ "parameters": parameters }))?
+            }
+            Answer::Error(error, parameters) => write_message(
+                &mut writer,
+                &json!({ "error": error, "parameters": parameters }),
+            )?,
+            Answer::Monitoring => {
+                writer.set_write_timeout(Some(MONITOR_TIMEOUT))?;
+                monitors.lock().unwrap().push(writer);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Serves the `org.pulse_source_listener` varlink interface on a Unix socket, e.g. for
+/// `varlinkctl call unix:PATH org.pulse_source_listener.GetState {}`
+pub struct VarlinkOutput {
+    path: PathBuf,
+    monitors: Monitors,
+    accepting: AcceptThread,
+}
+
+impl VarlinkOutput {
+    pub fn start(path: PathBuf, tx: RequestTx) -> Result<Self, Errors> {
+        let listener = socket::bind(&path)?;
+        let monitors = Monitors::default();
+        let accepted = monitors.clone();
+        let accept = move || {
+            listener
+                .accept()
+                .map(|(stream, _)| (stream, "Varlink client".to_string()))
+        };
+        let serve = move |stream, _| serve_client(stream, tx.clone(), accepted.clone());
+        let wake = {
+            let path = path.clone();
+            move || UnixStream::connect(&path).map(drop)
+        };
+        let accepting = AcceptThread::spawn("varlink", accept, serve, wake)?;
+        debug!("Serving {} on {}", INTERFACE, path.display());
+        Ok(VarlinkOutput {
+            path,
+            monitors,
+            accepting,
+        })
+    }
+}
+
+impl Output for VarlinkOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let message = json!({
+            "parameters": { "event": event },
+            "continues": true,
+        });
+        // Monitors that have gone away or can't keep up are dropped
+        self.monitors
+            .lock()
+            .unwrap()
+            .retain_mut(|monitor| write_message(monitor, &message).is_ok());
+        Ok(())
+    }
+}
+
+impl Drop for VarlinkOutput {
+    fn drop(&mut self) {
+        // Reaching the thread accepting clients needs the socket still there
+        self.accepting.stop();
+        let _ = fs::remove_file(&self.path);
+    }
+}
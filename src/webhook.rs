@@ -0,0 +1,208 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use log::{debug, error, info};
+use sha2::Sha256;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+use crate::hooks::{self, HookFailure};
+use crate::output::{Event, Output};
+use crate::runtime;
+use crate::{Errors, ListenerRequest, RequestTx};
+
+/// Header carrying the body's signature, when there's a secret to sign with
+const SIGNATURE_HEADER: &str = "X-PSL-Signature";
+
+/// How long a request gets before it counts as failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Split a `Name: value` header, as given on the command line
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected 'Name: value', got '{}'", header)),
+    }
+}
+
+/// How webhooks are sent, shared by every URL
+#[derive(Debug, Clone)]
+pub struct WebhookPolicy {
+    pub headers: Vec<(String, String)>,
+    /// Signs bodies with HMAC-SHA256, sent as `X-PSL-Signature: sha256=<hex>`
+    pub secret: Option<String>,
+    pub retries: u32,
+    /// Delay before the first retry, doubling with each one after
+    pub retry_delay: Duration,
+}
+
+impl Default for WebhookPolicy {
+    fn default() -> Self {
+        WebhookPolicy {
+            headers: vec![],
+            secret: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", digest)
+}
+
+/// One URL events are sent to
+struct Webhook {
+    agent: ureq::Agent,
+    url: String,
+    policy: WebhookPolicy,
+}
+
+impl Webhook {
+    /// POST one body, returning why it failed and whether it's worth trying again
+    fn post(&self, body: &[u8]) -> Result<(), (String, bool)> {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.policy.headers {
+            request = request.set(name, value);
+        }
+        if let Some(secret) = &self.policy.secret {
+            request = request.set(SIGNATURE_HEADER, &sign(secret, body));
+        }
+        match request.send_bytes(body) {
+            Ok(_) => Ok(()),
+            // The endpoint refusing the payload won't change by asking again
+            Err(ureq::Error::Status(code, _)) => Err((format!("HTTP {}", code), code >= 500)),
+            Err(err) => Err((err.to_string(), true)),
+        }
+    }
+}
+
+/// Send events to a URL one at a time, so a slow endpoint only holds up its own webhook.
+/// Requests block, so they're made on the runtime's blocking threads.
+async fn run_webhook(
+    webhook: Arc<Webhook>,
+    mut events: UnboundedReceiver<Event>,
+    failures: Option<RequestTx>,
+) {
+    let (url, policy) = (&webhook.url, &webhook.policy);
+    while let Some(event) = events.recv().await {
+        let body: Arc<[u8]> = match serde_json::to_vec(&event) {
+            Ok(body) => body.into(),
+            Err(err) => {
+                error!("failed to serialise event for {}: {}", url, err);
+                continue;
+            }
+        };
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let posted = {
+                let (webhook, body) = (webhook.clone(), body.clone());
+                task::spawn_blocking(move || webhook.post(&body)).await
+            };
+            let (reason, retry) = match posted.unwrap_or_else(|err| Err((err.to_string(), false))) {
+                Ok(()) => {
+                    debug!("Sent {} to {}", event.kind.name(), url);
+                    break;
+                }
+                Err(failure) => failure,
+            };
+            if retry && attempts <= policy.retries {
+                let delay = hooks::backoff(policy.retry_delay, attempts);
+                info!(
+                    "webhook {} failed ({}), retrying in {:?}",
+                    url, reason, delay
+                );
+                time::sleep(delay).await;
+                continue;
+            }
+            error!("webhook {} failed: {}", url, reason);
+            if let Some(failures) = &failures {
+                let _ = failures.send(ListenerRequest::HookFailed(HookFailure {
+                    command: url.clone(),
+                    event: event.kind.name(),
+                    attempts,
+                    reason,
+                }));
+            }
+            break;
+        }
+    }
+}
+
+/// POSTs every event, as JSON like the JSON output, to each URL. Failures after retries are
+/// sent to `failures`, if given, to be reported like failed hooks.
+pub struct WebhookOutput {
+    webhooks: Vec<(String, UnboundedSender<Event>, JoinHandle<()>)>,
+    runtime: Handle,
+}
+
+impl WebhookOutput {
+    pub fn new(
+        urls: Vec<String>,
+        policy: WebhookPolicy,
+        failures: Option<RequestTx>,
+    ) -> Result<Self, Errors> {
+        let runtime = runtime::handle()?;
+        let webhooks = urls
+            .into_iter()
+            .map(|url| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                let webhook = Arc::new(Webhook {
+                    agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+                    url: url.clone(),
+                    policy: policy.clone(),
+                });
+                let task = runtime.spawn(run_webhook(webhook, rx, failures.clone()));
+                (url, tx, task)
+            })
+            .collect();
+        Ok(WebhookOutput { webhooks, runtime })
+    }
+}
+
+impl Output for WebhookOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if event.snapshot {
+            return Ok(());
+        }
+        for (_, webhook, _) in &self.webhooks {
+            webhook
+                .send(event.clone())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "webhook has stopped"))?;
+        }
+        Ok(())
+    }
+
+    /// Send what's still queued, leaving webhooks that aren't done by `deadline` behind
+    fn finish(&mut self, deadline: Instant) -> io::Result<()> {
+        for (url, webhook, task) in self.webhooks.drain(..) {
+            drop(webhook);
+            let sent = self
+                .runtime
+                .block_on(time::timeout_at(deadline.into(), task));
+            if sent.is_err() {
+                info!("webhook {} still sending at shutdown, leaving it", url);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, warn};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_tungstenite::tungstenite::handshake::server::{self, ErrorResponse, Response};
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::consumer::{self, ConsumerPolicy, Publisher, Subscription};
+use crate::health::Health;
+use crate::output::{Event, Output};
+use crate::runtime::{self, AcceptTask};
+use crate::socket::{Reply, Request};
+use crate::{Errors, RequestTx};
+
+/// How long a client gets to finish the handshake before it's hung up on
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request message taken, well beyond any real request
+const MAX_MESSAGE: usize = 1024;
+
+fn ws_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+async fn send_reply(websocket: &mut WebSocketStream<TcpStream>, reply: &Reply) -> io::Result<()> {
+    let reply = serde_json::to_string(reply)?;
+    websocket.send(Message::text(reply)).await.map_err(ws_error)
+}
+
+/// Whether a handshake from `origin` is let through. Browsers say which page opened the
+/// connection, which has to be one of `allowed`, while other programs don't say.
+fn origin_allowed(origin: Option<&str>, allowed: &[String]) -> bool {
+    origin.is_none_or(|origin| {
+        allowed
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    })
+}
+
+/// Lets the handshake through only from an allowed origin, so any web page the user has open
+/// can't drive the listener
+struct OriginCheck(Arc<[String]>);
+
+impl server::Callback for OriginCheck {
+    fn on_request(
+        self,
+        handshake: &server::Request,
+        response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        let origin = handshake.headers().get(header::ORIGIN);
+        match origin.map(|origin| origin.to_str()) {
+            Some(Ok(origin)) if origin_allowed(Some(origin), &self.0) => Ok(response),
+            None => Ok(response),
+            _ => {
+                debug!("Refusing websocket client from origin {:?}", origin);
+                let mut refusal = ErrorResponse::new(Some("origin not allowed".to_string()));
+                *refusal.status_mut() = StatusCode::FORBIDDEN;
+                Err(refusal)
+            }
+        }
+    }
+}
+
+/// Talk to one client until it disconnects: passing on events as they come, and answering the
+/// requests it sends as text messages. Commands are refused unless `control`.
+async fn serve_client(
+    stream: TcpStream,
+    events: Subscription<String>,
+    control: bool,
+    origins: Arc<[String]>,
+    tx: RequestTx,
+    health: Health,
+) -> io::Result<()> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE),
+        max_frame_size: Some(MAX_MESSAGE),
+        ..WebSocketConfig::default()
+    };
+    let handshake =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, OriginCheck(origins), Some(config));
+    let mut websocket = time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    loop {
+        let text = tokio::select! {
+            event = events.recv_async() => match event {
+                Some(event) => {
+                    websocket.send(Message::text(event)).await.map_err(ws_error)?;
+                    continue;
+                }
+                // The listener is shutting down, or the client fell too far behind
+                None => return websocket.close(None).await.map_err(ws_error),
+            },
+            message = websocket.next() => match message {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                // Pings are answered by tungstenite itself
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(ws_error(err)),
+            },
+        };
+        // Every client gets every event anyway, so subscribing is only acknowledged
+        let reply = match Request::parse(text.trim()) {
+            Ok(Request::Control(_)) if !control => Reply::error(
+                "commands aren't taken over websockets without --ws-control, only queries"
+                    .to_string(),
+            ),
+            Ok(request) => runtime::answer(request, tx.clone(), health.clone()).await?,
+            Err(err) => Reply::error(err),
+        };
+        send_reply(&mut websocket, &reply).await?;
+    }
+}
+
+/// Pushes every event to WebSocket clients as JSON text messages, shaped like the JSON output.
+/// Clients can send the same requests as on `--socket`, each answered with a JSON reply, though
+/// commands are refused unless control was asked for. Browsers are only let connect from the
+/// allowed origins.
+pub struct WsOutput {
+    clients: Arc<Mutex<Vec<Publisher<String>>>>,
+    _accepting: AcceptTask,
+}
+
+impl WsOutput {
+    /// Serve on `addr`, taking commands from its clients only if `control`, and browsers only
+    /// from pages at one of `origins`
+    pub fn start(
+        addr: SocketAddr,
+        control: bool,
+        origins: &[String],
+        tx: RequestTx,
+        policy: ConsumerPolicy,
+        health: Health,
+    ) -> Result<Self, Errors> {
+        let (runtime, listener) = runtime::listen(addr)?;
+        let clients = Arc::new(Mutex::new(vec![]));
+        let accepted = clients.clone();
+        let origins: Arc<[String]> = origins.into();
+        let accepting = runtime::serve(&runtime, listener, "websocket", move |stream, peer| {
+            let (publisher, events) =
+                consumer::queue(policy, format!("websocket client {}", peer), &health);
+            accepted.lock().unwrap().push(publisher);
+            serve_client(
+                stream,
+                events,
+                control,
+                origins.clone(),
+                tx.clone(),
+                health.clone(),
+            )
+        });
+        if control && !addr.ip().is_loopback() {
+            warn!(
+                "Taking commands over websockets on {}, from anyone who can reach it",
+                addr
+            );
+        }
+        debug!("Serving websockets on {}", addr);
+        Ok(WsOutput {
+            clients,
+            _accepting: accepting,
+        })
+    }
+}
+
+impl Output for WsOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let json = serde_json::to_string(event)?;
+        // A client's task drops its end once the client has gone
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.send(json.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn browsers_must_come_from_an_allowed_origin() {
+        let allowed = ["http://localhost:8000/".to_string()];
+        assert!(origin_allowed(None, &allowed));
+        assert!(origin_allowed(Some("http://LOCALHOST:8000"), &allowed));
+        assert!(!origin_allowed(Some("https://evil.example"), &allowed));
+        assert!(!origin_allowed(Some("http://localhost:8000"), &[]));
+    }
+}
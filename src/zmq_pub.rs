@@ -0,0 +1,59 @@
+use std::io;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use log::{debug, error};
+
+use crate::output::{Event, Output};
+use crate::Errors;
+
+/// Publishes every event on a ZeroMQ PUB socket as two frames: the event's type as the topic
+/// (e.g. `mute`, `default_changed`, `volume`), then the event as JSON like the JSON output.
+/// Subscribers filter on topic prefixes, so `default` gets `default_changed`. ZeroMQ sockets
+/// can't be shared between threads, so the socket lives on its own thread.
+pub struct ZmqOutput {
+    events: Sender<Event>,
+}
+
+impl ZmqOutput {
+    pub fn bind(endpoint: &str) -> Result<Self, Errors> {
+        let zmq_error = |err: zmq::Error| {
+            Errors::ConfigError(format!("failed to publish on {}: {}", endpoint, err))
+        };
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::PUB).map_err(zmq_error)?;
+        socket.bind(endpoint).map_err(zmq_error)?;
+        debug!("Publishing events on {}", endpoint);
+
+        let (events, rx) = mpsc::channel::<Event>();
+        thread::Builder::new()
+            .name("zmq-pub".to_string())
+            .spawn(move || {
+                for event in rx {
+                    let json = match serde_json::to_vec(&event) {
+                        Ok(json) => json,
+                        Err(err) => {
+                            error!("failed to serialise event for zmq: {}", err);
+                            continue;
+                        }
+                    };
+                    // PUB sockets drop messages for slow subscribers rather than block, so
+                    // failing here means the socket itself is broken
+                    let frames = [event.kind.name().as_bytes(), json.as_slice()];
+                    if let Err(err) = socket.send_multipart(frames, 0) {
+                        error!("failed to publish event on zmq: {}", err);
+                        return;
+                    }
+                }
+            })?;
+        Ok(ZmqOutput { events })
+    }
+}
+
+impl Output for ZmqOutput {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        self.events
+            .send(event.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "zmq publisher has stopped"))
+    }
+}